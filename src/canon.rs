@@ -1,13 +1,30 @@
 //! Path canonicalization.
 
+use std::collections::HashMap;
 use std::hint::assert_unchecked;
 use std::mem::MaybeUninit;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
 
-/// An on-stack stack of values.
+/// Whether Windows drive paths are normalized; see normalize_drive_path.
+static NORMALIZE_DRIVES: AtomicBool = AtomicBool::new(true);
+
+/// Set whether canonicalization normalizes the spelling of Windows drive
+/// paths, which ninja compatibility mode turns off.  This applies to the
+/// whole process, as paths are canonicalized all over.
+pub fn set_normalize_drives(normalize: bool) {
+    NORMALIZE_DRIVES.store(normalize, Ordering::Relaxed);
+}
+
+/// An on-stack stack of values, which moves to the heap past CAPACITY.
 /// Used for tracking locations of parent components within a path.
 struct StackStack<T, const CAPACITY: usize> {
     n: usize,
     vals: [MaybeUninit<T>; CAPACITY],
+    /// Values pushed once vals is full.  Empty, and so not allocated, for
+    /// all but unusually deep paths.
+    spill: Vec<T>,
 }
 
 impl<T: Copy, const CAPACITY: usize> StackStack<T, CAPACITY> {
@@ -15,21 +32,26 @@ impl<T: Copy, const CAPACITY: usize> StackStack<T, CAPACITY> {
         StackStack {
             n: 0,
             vals: [MaybeUninit::uninit(); CAPACITY],
+            spill: Vec::new(),
         }
     }
 
     fn push(&mut self, val: T) {
-        if self.n >= self.vals.len() {
-            panic!("too many path components");
+        if self.n < CAPACITY {
+            self.vals[self.n].write(val);
+            self.n += 1;
+        } else {
+            self.spill.push(val);
         }
-        self.vals[self.n].write(val);
-        self.n += 1;
     }
 
     fn pop(&mut self) -> Option<T> {
+        if let Some(val) = self.spill.pop() {
+            return Some(val);
+        }
         if self.n > 0 {
             self.n -= 1;
-            // Safety: we only access vals[i] after setting it.
+            // Safety: vals[..n] have all been written by push.
             Some(unsafe { self.vals[self.n].assume_init() })
         } else {
             None
@@ -41,7 +63,13 @@ impl<T: Copy, const CAPACITY: usize> StackStack<T, CAPACITY> {
 /// Does not access the disk, but only simplifies things like
 /// "foo/./bar" => "foo/bar".
 /// These paths can show up due to variable expansion in particular.
+/// The path must not be empty.
 pub fn canonicalize_path(path: &mut String) {
+    canonicalize(path, cfg!(windows))
+}
+
+/// canonicalize_path, with Windows path syntax handled if windows is set.
+fn canonicalize(path: &mut String, windows: bool) {
     assert!(!path.is_empty());
     let mut components = StackStack::<usize, 60>::new();
 
@@ -49,14 +77,21 @@ pub fn canonicalize_path(path: &mut String) {
     // and shifting other contents left to fill the gaps,
     // so if it was valid UTF-8, it will remain that way.
     let data = unsafe { path.as_mut_vec() };
+    let (root, absolute) = if windows {
+        windows_root(data)
+    } else {
+        (0, false)
+    };
     // Invariant: dst <= src <= data.len()
-    let mut dst = 0;
-    let mut src = 0;
+    let mut dst = root;
+    let mut src = root;
 
-    if let Some(b'/' | b'\\') = data.get(src) {
-        src += 1;
-        dst += 1;
-    };
+    if root == 0 {
+        if let Some(b'/' | b'\\') = data.get(src) {
+            src += 1;
+            dst += 1;
+        };
+    }
 
     // One iteration per path component.
     while let Some(&current) = data.get(src) {
@@ -82,6 +117,8 @@ pub fn canonicalize_path(path: &mut String) {
                             // ".." component, try to back up.
                             if let Some(ofs) = components.pop() {
                                 dst = ofs;
+                            } else if absolute {
+                                // As in Windows, ".." of the root is the root.
                             } else {
                                 // Safety: our invariant is dst <= src and we are inside a branch,
                                 // where even src + 2 < data.len()
@@ -134,6 +171,85 @@ pub fn canonicalize_path(path: &mut String) {
     }
     // Safety: dst <= src <= len
     unsafe { data.set_len(dst) };
+
+    if windows && NORMALIZE_DRIVES.load(Ordering::Relaxed) {
+        normalize_drive_path(data);
+    }
+}
+
+/// Find the root of a Windows path, which is kept as is and which ".."
+/// components can't remove.  Returns its length, including any separator
+/// that follows it, and whether it's absolute.  Roots are:
+/// - `\\server\share\` (UNC paths)
+/// - `\\?\C:\`, `\\?\UNC\server\share\`, and `\\?\name\` (verbatim
+///   paths), and `\\.\name\` (device paths)
+/// - `C:\` (absolute drive paths) and `C:` (drive-relative paths like `C:foo`)
+///
+/// Other paths, including `\foo`, have no root here, i.e. a length of 0.
+fn windows_root(data: &[u8]) -> (usize, bool) {
+    let is_sep = |c: u8| c == b'/' || c == b'\\';
+    // The end of the non-empty component at start, after its separator.
+    let component = |start: usize| -> Option<usize> {
+        let rest = data.get(start..)?;
+        match rest.iter().position(|&c| is_sep(c)) {
+            Some(0) => None,
+            Some(len) => Some(start + len + 1),
+            None if rest.is_empty() => None,
+            None => Some(data.len()),
+        }
+    };
+    // The end of a component that must be followed by a separator, then a
+    // component, as in UNC server\share.
+    let server_share = |start: usize| -> Option<usize> {
+        let server = component(start)?;
+        if !is_sep(data[server - 1]) {
+            return None;
+        }
+        component(server)
+    };
+    let drive = |start: usize| -> Option<(usize, bool)> {
+        match data.get(start..)? {
+            [d, b':', sep, ..] if d.is_ascii_alphabetic() && is_sep(*sep) => {
+                Some((start + 3, true))
+            }
+            [d, b':', ..] if d.is_ascii_alphabetic() => Some((start + 2, false)),
+            _ => None,
+        }
+    };
+
+    let root = match data {
+        [a, b, b'?' | b'.', d, ..] if is_sep(*a) && is_sep(*b) && is_sep(*d) => {
+            let rest = &data[4..];
+            if rest.len() > 4 && rest[..3].eq_ignore_ascii_case(b"UNC") && is_sep(rest[3]) {
+                server_share(8)
+            } else if let Some((end, _)) = drive(4) {
+                Some(end)
+            } else {
+                component(4)
+            }
+        }
+        [a, b, ..] if is_sep(*a) && is_sep(*b) => server_share(2),
+        _ => return drive(0).unwrap_or((0, false)),
+    };
+    root.map_or((0, false), |end| (end, true))
+}
+
+/// Normalize the spelling of a Windows path starting with a drive letter, so
+/// that e.g. `C:\foo` and `c:/foo` from different generators map to the same
+/// file: the drive letter is lowercased and backslashes become slashes.
+/// Other paths keep their separators, which may matter to commands that
+/// receive them (e.g. cmd builtins).
+fn normalize_drive_path(data: &mut [u8]) {
+    if let [drive, b':', rest @ ..] = data {
+        if drive.is_ascii_alphabetic() {
+            drive.make_ascii_lowercase();
+            for c in rest {
+                if *c == b'\\' {
+                    *c = b'/';
+                }
+            }
+        }
+    }
 }
 
 #[must_use = "this methods returns the canonicalized version; if possible, prefer `canonicalize_path`"]
@@ -143,6 +259,94 @@ pub fn to_owned_canon_path(path: impl Into<String>) -> String {
     path
 }
 
+/// Canonicalizes paths like canonicalize_path, but first resolves symlinks
+/// in any directory that is followed by a ".." component, as the OS would.
+/// For example, if `a` is a symlink to `x/y`, then `a/../b` refers to `x/b`
+/// rather than `b`.  Resolved directories are cached, in a cache shared by
+/// the threads using it.
+pub struct PhysicalCanon {
+    /// The working directory, with symlinks resolved.
+    cwd: PathBuf,
+    /// Maps a directory to its resolved path, or None if it has no symlinks.
+    cache: Mutex<HashMap<String, Option<String>>>,
+}
+
+impl PhysicalCanon {
+    pub fn new() -> std::io::Result<Self> {
+        Ok(PhysicalCanon {
+            cwd: std::fs::canonicalize(std::env::current_dir()?)?,
+            cache: Mutex::default(),
+        })
+    }
+
+    pub fn canonicalize(&self, path: &mut String) {
+        if !path.contains("..") {
+            canonicalize_path(path);
+            return;
+        }
+        let mut out = String::with_capacity(path.len());
+        for (i, comp) in path.split(is_separator).enumerate() {
+            if comp == ".." && !out.is_empty() && !out.ends_with("..") {
+                if let Some(real) = self.resolve(&out) {
+                    out = real;
+                }
+            }
+            if i > 0 {
+                out.push('/');
+            }
+            out.push_str(comp);
+        }
+        canonicalize_path(&mut out);
+        *path = out;
+    }
+
+    /// Resolve symlinks in a directory, returning a path relative to the
+    /// working directory where possible.
+    fn resolve(&self, dir: &str) -> Option<String> {
+        if let Some(resolved) = self.cache.lock().unwrap().get(dir) {
+            return resolved.clone();
+        }
+        let resolved = std::fs::canonicalize(dir).ok().and_then(|real| {
+            let lexical = to_owned_canon_path(self.cwd.join(dir).to_string_lossy());
+            if real.to_string_lossy() == lexical {
+                return None;
+            }
+            Some(match real.strip_prefix(&self.cwd) {
+                Ok(rel) if rel.as_os_str().is_empty() => ".".to_owned(),
+                Ok(rel) => rel.to_string_lossy().into_owned(),
+                Err(_) => real.to_string_lossy().into_owned(),
+            })
+        });
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(dir.to_owned(), resolved.clone());
+        resolved
+    }
+}
+
+fn is_separator(c: char) -> bool {
+    c == '/' || (cfg!(windows) && c == '\\')
+}
+
+/// Reports whether a canonical path lies within one of the directories in
+/// roots, comparing lexically.  Relative paths are relative to the working
+/// directory, which is always allowed: they are within it unless they start
+/// with "..".
+pub fn is_within_roots(path: &str, roots: &[String]) -> bool {
+    if !std::path::Path::new(path).is_absolute() {
+        return match path.strip_prefix("..") {
+            Some(rest) => !(rest.is_empty() || rest.starts_with(is_separator)),
+            None => true,
+        };
+    }
+    roots.iter().any(|root| {
+        let root = root.trim_end_matches(is_separator);
+        path.strip_prefix(root)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with(is_separator))
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -202,4 +406,180 @@ mod tests {
         assert_canon_path_eq("foo/../../", "../");
         assert_canon_path_eq("foo/../../bar", "../bar");
     }
+
+    #[track_caller]
+    fn assert_windows_canon(path: &str, expected: &str) {
+        let mut path = path.to_owned();
+        canonicalize(&mut path, true);
+        assert_eq!(path, expected);
+    }
+
+    #[test]
+    fn windows_roots() {
+        assert_windows_canon("\\\\server\\share\\x\\..\\y", "\\\\server\\share\\y");
+        assert_windows_canon("\\\\server\\share\\..\\..\\y", "\\\\server\\share\\y");
+        assert_windows_canon("//server/share/./x", "//server/share/x");
+        assert_windows_canon("\\\\server\\share", "\\\\server\\share");
+        // Not UNC without a share, so as before.
+        assert_windows_canon("\\\\server", "\\server");
+
+        assert_windows_canon("\\\\?\\C:\\x\\..\\y", "\\\\?\\C:\\y");
+        assert_windows_canon(
+            "\\\\?\\UNC\\server\\share\\..\\y",
+            "\\\\?\\UNC\\server\\share\\y",
+        );
+        assert_windows_canon("\\\\.\\pipe\\..\\x", "\\\\.\\pipe\\x");
+
+        assert_windows_canon("C:\\x\\..\\..\\y", "c:/y");
+        assert_windows_canon("C:\\..", "c:/");
+        assert_windows_canon("C:foo\\..\\bar", "c:bar");
+        assert_windows_canon("C:..\\foo", "c:../foo");
+        assert_windows_canon("C:.", "c:");
+
+        // Other paths are unaffected.
+        assert_windows_canon("foo\\..\\bar", "bar");
+        assert_windows_canon("\\foo\\..\\bar", "\\bar");
+    }
+
+    #[test]
+    fn deep() {
+        let deep = "a/".repeat(1000);
+        assert_canon_path_eq(&format!("{}b", deep), &format!("{}b", deep));
+        assert_canon_path_eq(&format!("{}{}b", deep, "../".repeat(1000)), "b");
+        assert_canon_path_eq(&format!("{}{}b", deep, "../".repeat(1001)), "../b");
+    }
+
+    /// A simpler canonicalization to compare against: split into components
+    /// and resolve them, ignoring how separators are spelled.
+    fn reference_canon(path: &str) -> String {
+        let is_sep = |c| c == '/' || c == '\\';
+        let mut out: Vec<&str> = Vec::new();
+        let mut parents = 0;
+        for comp in path.split(is_sep) {
+            match comp {
+                "" | "." => {}
+                ".." if out.len() > parents => {
+                    out.pop();
+                }
+                ".." => {
+                    out.push("..");
+                    parents += 1;
+                }
+                _ => out.push(comp),
+            }
+        }
+        let root = if path.starts_with(is_sep) { "/" } else { "" };
+        match (root, out.join("/")) {
+            ("", joined) if joined.is_empty() => ".".to_owned(),
+            (root, joined) => format!("{}{}", root, joined),
+        }
+    }
+
+    #[test]
+    fn fuzz() {
+        // A fixed xorshift sequence, so failures reproduce.
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut next = |n: usize| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state % n as u64) as usize
+        };
+        let pieces = [
+            "a",
+            "C:",
+            "?",
+            "UNC",
+            "bc",
+            ".",
+            "..",
+            "...",
+            "/",
+            "\\",
+            "//",
+            ".a",
+            "a.",
+            "\u{e9}",
+            "\u{1f980}",
+        ];
+        for _ in 0..20_000 {
+            // Mostly short paths, with some deeper than StackStack's capacity.
+            let max = if next(10) == 0 { 400 } else { 12 };
+            let len = 1 + next(max);
+            let path: String = (0..len).map(|_| pieces[next(pieces.len())]).collect();
+            // Without Windows roots, which the reference doesn't know about.
+            let canon_plain = |path: &str| {
+                let mut path = path.to_owned();
+                canonicalize(&mut path, false);
+                path
+            };
+            let canon = canon_plain(&path);
+            assert!(!canon.is_empty(), "{:?}", path);
+            assert_eq!(canon_plain(&canon), canon, "{:?}", path);
+            let mut windows = path.clone();
+            canonicalize(&mut windows, true);
+            assert!(!windows.is_empty(), "{:?}", path);
+            let mut again = windows.clone();
+            canonicalize(&mut again, true);
+            assert_eq!(again, windows, "{:?}", path);
+            let normalized = canon.replace('\\', "/");
+            let trimmed = match normalized.trim_end_matches('/') {
+                "" => "/",
+                trimmed => trimmed,
+            };
+            assert_eq!(trimmed, reference_canon(&path), "{:?}", path);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn within_roots() {
+        let roots = ["/src".to_string(), "/opt/tools/".to_string()];
+        assert!(is_within_roots("foo/bar", &roots));
+        assert!(is_within_roots("..foo", &roots));
+        assert!(!is_within_roots("../foo", &roots));
+        assert!(!is_within_roots("..", &roots));
+        assert!(is_within_roots("/src/foo.c", &roots));
+        assert!(is_within_roots("/opt/tools/bin/cc", &roots));
+        assert!(!is_within_roots("/srcfoo/bar.c", &roots));
+        assert!(!is_within_roots("/usr/include/stdio.h", &roots));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn physical() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::create_dir_all(dir.path().join("x/y"))?;
+        std::os::unix::fs::symlink("x/y", dir.path().join("a"))?;
+        let canon = PhysicalCanon::new()?;
+        let base = std::fs::canonicalize(dir.path())?;
+        let base = base.to_string_lossy();
+
+        let mut path = format!("{}/a/../b", base);
+        canon.canonicalize(&mut path);
+        assert_eq!(path, format!("{}/x/b", base));
+
+        // Without symlinks, same as lexical canonicalization.
+        let mut path = format!("{}/x/y/../b", base);
+        canon.canonicalize(&mut path);
+        assert_eq!(path, format!("{}/x/b", base));
+
+        let mut path = "../foo/./bar".to_owned();
+        canon.canonicalize(&mut path);
+        assert_eq!(path, "../foo/bar");
+        Ok(())
+    }
+
+    #[test]
+    fn drive_letters() {
+        let normalize = |path: &str| {
+            let mut data = path.as_bytes().to_vec();
+            normalize_drive_path(&mut data);
+            String::from_utf8(data).unwrap()
+        };
+        assert_eq!(normalize("C:\\foo\\bar.h"), "c:/foo/bar.h");
+        assert_eq!(normalize("c:/foo/bar.h"), "c:/foo/bar.h");
+        assert_eq!(normalize("foo\\bar.h"), "foo\\bar.h");
+        assert_eq!(normalize("1:\\foo"), "1:\\foo");
+    }
 }
@@ -0,0 +1,291 @@
+//! Config files of default command line flags, so that e.g. -j or --wrapper
+//! needn't be retyped on every run.
+//!
+//! n2 reads n2.toml in the build directory (after any -C or --look-up) and
+//! .n2.toml in the home directory.  A flag given on the command line replaces
+//! the config files' setting of it, and the build directory's file replaces
+//! the home directory's; --no-config skips both, as does running n2 as
+//! `ninja`.  Keys are flag names without their dashes:
+//!
+//! ```toml
+//! # Like -j 8 -v --wrapper 'nice -n 10' --pool-depth link=2 --pool-depth cc=16
+//! j = 8                  # or jobs
+//! v = true               # or verbose; false leaves the flag out
+//! wrapper = "nice -n 10"
+//! pool-depth = ["link=2", "cc=16"]
+//! ```
+//!
+//! Only this much of TOML is understood: one `key = value` per line, where
+//! the value is a string, number, boolean, or an array of strings or numbers
+//! on one line.
+
+use anyhow::{anyhow, bail};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+/// Keys that are accepted as more readable names for short flags.
+const ALIASES: [(&str, &str); 3] = [("jobs", "j"), ("keep-going", "k"), ("verbose", "v")];
+
+/// Flags that make no sense as defaults.
+const DISALLOWED: [&str; 8] = [
+    "C",
+    "t",
+    "d",
+    "h",
+    "help",
+    "version",
+    "look-up",
+    "no-config",
+];
+
+#[derive(Debug, PartialEq)]
+enum Value {
+    Bool(bool),
+    /// A string, or a number as written.
+    Str(String),
+    Array(Vec<Value>),
+}
+
+/// A flag set by a config file.
+#[derive(Debug, PartialEq)]
+struct Setting {
+    /// The flag as given on the command line, e.g. "-j" or "--wrapper".
+    flag: String,
+    /// The command line arguments it stands for.
+    args: Vec<String>,
+}
+
+/// Parse a basic (double-quoted) string, after its opening quote.  Returns
+/// it and the text after its closing quote.
+fn parse_basic_string(text: &str) -> anyhow::Result<(String, &str)> {
+    let mut value = String::new();
+    let mut chars = text.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((value, &text[i + 1..])),
+            '\\' => value.push(match chars.next().map(|(_, c)| c) {
+                Some('"') => '"',
+                Some('\\') => '\\',
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some('r') => '\r',
+                Some(c) => bail!("unsupported escape \\{}", c),
+                None => break,
+            }),
+            c => value.push(c),
+        }
+    }
+    bail!("unterminated string")
+}
+
+/// Parse a value at the start of text, returning it and the text after it.
+fn parse_value(text: &str) -> anyhow::Result<(Value, &str)> {
+    if let Some(rest) = text.strip_prefix('"') {
+        let (value, rest) = parse_basic_string(rest)?;
+        return Ok((Value::Str(value), rest));
+    }
+    if let Some(rest) = text.strip_prefix('\'') {
+        let end = rest
+            .find('\'')
+            .ok_or_else(|| anyhow!("unterminated string"))?;
+        return Ok((Value::Str(rest[..end].to_owned()), &rest[end + 1..]));
+    }
+    if let Some(mut rest) = text.strip_prefix('[') {
+        let mut items = Vec::new();
+        loop {
+            rest = rest.trim_start();
+            if let Some(after) = rest.strip_prefix(']') {
+                return Ok((Value::Array(items), after));
+            }
+            let (item, after) = parse_value(rest)?;
+            if !matches!(item, Value::Str(_)) {
+                bail!("arrays may only hold strings and numbers");
+            }
+            items.push(item);
+            rest = after.trim_start();
+            match rest.strip_prefix(',') {
+                Some(after) => rest = after,
+                None if rest.starts_with(']') => {}
+                None => bail!("expected , or ] in array"),
+            }
+        }
+    }
+    let end = text
+        .find(|c: char| !(c.is_ascii_alphanumeric() || "+-._".contains(c)))
+        .unwrap_or(text.len());
+    let (word, rest) = text.split_at(end);
+    let value = match word {
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        _ if word.starts_with(|c: char| c.is_ascii_digit() || c == '+' || c == '-') => {
+            Value::Str(word.replace('_', ""))
+        }
+        _ => bail!("expected a value, got {:?}", text),
+    };
+    Ok((value, rest))
+}
+
+/// Turn a key and its value into the command line arguments they stand for.
+fn setting(key: &str, value: Value) -> anyhow::Result<Setting> {
+    if DISALLOWED.contains(&key) {
+        bail!("{} can't be set in a config file", key);
+    }
+    let name = ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == key)
+        .map_or(key, |(_, name)| name);
+    let flag = if name.len() == 1 {
+        format!("-{}", name)
+    } else {
+        format!("--{}", name)
+    };
+    let with_value = |value: String| {
+        if name.len() == 1 {
+            vec![flag.clone(), value]
+        } else {
+            vec![format!("{}={}", flag, value)]
+        }
+    };
+    let args = match value {
+        Value::Bool(true) => vec![flag.clone()],
+        Value::Bool(false) => Vec::new(),
+        Value::Str(value) => with_value(value),
+        Value::Array(items) => items
+            .into_iter()
+            .flat_map(|item| match item {
+                Value::Str(value) => with_value(value),
+                _ => unreachable!(),
+            })
+            .collect(),
+    };
+    Ok(Setting { flag, args })
+}
+
+/// Parse the text of a config file.
+fn parse(text: &str) -> anyhow::Result<Vec<Setting>> {
+    let mut settings: Vec<Setting> = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let parse_line = || -> anyhow::Result<Option<Setting>> {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                return Ok(None);
+            }
+            if line.starts_with('[') {
+                bail!("tables aren't supported");
+            }
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| anyhow!("expected key = value"))?;
+            let key = key.trim();
+            if key.is_empty()
+                || !key
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                bail!("invalid key {:?}", key);
+            }
+            let (value, rest) = parse_value(value.trim_start())?;
+            let rest = rest.trim_start();
+            if !(rest.is_empty() || rest.starts_with('#')) {
+                bail!("unexpected {:?} after value", rest);
+            }
+            let setting = setting(key, value)?;
+            if settings.iter().any(|prev| prev.flag == setting.flag) {
+                bail!("{} is set twice", key);
+            }
+            Ok(Some(setting))
+        };
+        if let Some(setting) = parse_line().map_err(|err| anyhow!("{}: {}", i + 1, err))? {
+            settings.push(setting);
+        }
+    }
+    Ok(settings)
+}
+
+/// The home directory's config file, if there's a home directory.
+fn user_path() -> Option<PathBuf> {
+    let home = if cfg!(windows) {
+        std::env::var_os("USERPROFILE")
+    } else {
+        std::env::var_os("HOME")
+    };
+    Some(Path::new(&home?).join(".n2.toml"))
+}
+
+/// Read the config files into command line arguments, leaving out the flags
+/// in given, which were given on the command line.  Returns each file that
+/// sets any flag with its arguments, so errors in them can name the file.
+pub fn args(given: &HashSet<String>) -> anyhow::Result<Vec<(PathBuf, Vec<String>)>> {
+    let mut given = given.clone();
+    let mut args = Vec::new();
+    let paths = std::iter::once(PathBuf::from("n2.toml")).chain(user_path());
+    for path in paths {
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(err) => bail!("read {}: {}", path.display(), err),
+        };
+        let settings = parse(&text).map_err(|err| anyhow!("{}:{}", path.display(), err))?;
+        let mut file_args = Vec::new();
+        for setting in settings {
+            if given.insert(setting.flag) {
+                file_args.extend(setting.args);
+            }
+        }
+        if !file_args.is_empty() {
+            args.push((path, file_args));
+        }
+    }
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(text: &str) -> anyhow::Result<Vec<String>> {
+        Ok(parse(text)?.into_iter().flat_map(|s| s.args).collect())
+    }
+
+    #[test]
+    fn values() -> anyhow::Result<()> {
+        let text = r#"
+# defaults
+jobs = 8
+v = true
+adopt = false
+wrapper = "nice \"-n\" 10"  # comment
+cpu-fraction = 0.5
+pool-depth = ['link=2', "cc=16",]
+"#;
+        assert_eq!(
+            args(text)?,
+            [
+                "-j",
+                "8",
+                "-v",
+                "--wrapper=nice \"-n\" 10",
+                "--cpu-fraction=0.5",
+                "--pool-depth=link=2",
+                "--pool-depth=cc=16"
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn errors() {
+        let err = |text: &str| args(text).unwrap_err().to_string();
+        assert_eq!(err("\n[n2]"), "2: tables aren't supported");
+        assert_eq!(err("j"), "1: expected key = value");
+        assert_eq!(err("j = 1\njobs = 2"), "2: jobs is set twice");
+        assert_eq!(err("C = 'dir'"), "1: C can't be set in a config file");
+        assert_eq!(err("wrapper = \"x"), "1: unterminated string");
+        assert_eq!(err("wrapper = x"), "1: expected a value, got \"x\"");
+        assert_eq!(err("j = 1 2"), "1: unexpected \"2\" after value");
+        assert_eq!(
+            err("pool-depth = [true]"),
+            "1: arrays may only hold strings and numbers"
+        );
+    }
+}
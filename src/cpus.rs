@@ -0,0 +1,147 @@
+//! Picking the default parallelism from the CPUs n2 may actually use.
+//!
+//! std's available_parallelism() counts the CPUs in the affinity mask, but
+//! a container or CI job is often limited by a CPU quota instead, which lets
+//! it use every CPU for a fraction of the time.  Running one command per CPU
+//! there just makes commands fight over the quota.
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::{parse_cfs_quota, parse_cpu_max};
+
+    /// The CPU quota of our cgroup, in CPUs, if any.
+    pub fn quota() -> Option<f64> {
+        let mut limit: Option<f64> = None;
+        let mut add = |quota: Option<f64>| {
+            if let Some(quota) = quota {
+                limit = Some(limit.map_or(quota, |limit| limit.min(quota)));
+            }
+        };
+
+        // cgroup v2: our group is listed as "0::/path", and a quota on it or
+        // any ancestor applies.
+        let cgroups = std::fs::read_to_string("/proc/self/cgroup").unwrap_or_default();
+        if let Some(path) = cgroups.lines().find_map(|line| line.strip_prefix("0::")) {
+            let mut dir = std::path::Path::new("/sys/fs/cgroup").join(path.trim_start_matches('/'));
+            loop {
+                if let Ok(max) = std::fs::read_to_string(dir.join("cpu.max")) {
+                    add(parse_cpu_max(&max));
+                }
+                if dir == std::path::Path::new("/sys/fs/cgroup") || !dir.pop() {
+                    break;
+                }
+            }
+        }
+
+        // cgroup v1, as mounted within a container.
+        let v1 = |name: &str| std::fs::read_to_string(format!("/sys/fs/cgroup/cpu/{}", name));
+        if let (Ok(quota), Ok(period)) = (v1("cpu.cfs_quota_us"), v1("cpu.cfs_period_us")) {
+            add(parse_cfs_quota(&quota, &period));
+        }
+
+        limit
+    }
+}
+
+#[cfg(target_os = "linux")]
+use linux::quota;
+
+#[cfg(windows)]
+mod windows {
+    use windows_sys::Win32::System::JobObjects::*;
+
+    /// The CPU rate cap of the job object we run in, in CPUs, if any.
+    pub fn quota() -> Option<f64> {
+        let cpus = usize::from(std::thread::available_parallelism().ok()?);
+        unsafe {
+            let mut info = std::mem::zeroed::<JOBOBJECT_CPU_RATE_CONTROL_INFORMATION>();
+            // A null job handle means the job of the calling process.
+            if QueryInformationJobObject(
+                0,
+                JobObjectCpuRateControlInformation,
+                &mut info as *mut _ as *mut std::ffi::c_void,
+                std::mem::size_of_val(&info) as u32,
+                std::ptr::null_mut(),
+            ) == 0
+            {
+                return None;
+            }
+            let flags = info.ControlFlags;
+            if flags & JOB_OBJECT_CPU_RATE_CONTROL_ENABLE == 0
+                || flags & JOB_OBJECT_CPU_RATE_CONTROL_HARD_CAP == 0
+            {
+                return None;
+            }
+            // CpuRate is in hundredths of a percent of all the CPUs.
+            let rate = info.Anonymous.CpuRate;
+            Some(cpus as f64 * rate as f64 / 10000.0)
+        }
+    }
+}
+
+#[cfg(windows)]
+use windows::quota;
+
+#[cfg(not(any(target_os = "linux", windows)))]
+fn quota() -> Option<f64> {
+    None
+}
+
+/// Parse a cgroup v2 cpu.max, "$QUOTA $PERIOD" or "max $PERIOD", into CPUs.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_cpu_max(text: &str) -> Option<f64> {
+    let mut fields = text.split_whitespace();
+    let quota: f64 = fields.next()?.parse().ok()?;
+    let period: f64 = fields.next()?.parse().ok()?;
+    (quota > 0.0 && period > 0.0).then(|| quota / period)
+}
+
+/// Parse cgroup v1's cpu.cfs_quota_us and cpu.cfs_period_us into CPUs.
+/// A quota of -1 means unlimited.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_cfs_quota(quota: &str, period: &str) -> Option<f64> {
+    let quota: f64 = quota.trim().parse().ok()?;
+    let period: f64 = period.trim().parse().ok()?;
+    (quota > 0.0 && period > 0.0).then(|| quota / period)
+}
+
+/// Scale a CPU count by fraction, rounding but keeping at least one.
+fn scale(cpus: f64, fraction: f64) -> usize {
+    ((cpus * fraction).round() as usize).max(1)
+}
+
+/// The default number of commands to run at once: the CPUs we can use,
+/// capped by any CPU quota, times fraction (from --cpu-fraction).
+pub fn default_parallelism(fraction: f64) -> anyhow::Result<usize> {
+    // Ninja uses available processors + a constant, but I don't think the
+    // difference matters too much.
+    let mut cpus = usize::from(std::thread::available_parallelism()?) as f64;
+    if let Some(quota) = quota() {
+        // A quota of 1.5 CPUs still keeps two commands busy.
+        cpus = cpus.min(quota.ceil());
+    }
+    Ok(scale(cpus, fraction))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quotas() {
+        assert_eq!(parse_cpu_max("max 100000\n"), None);
+        assert_eq!(parse_cpu_max("200000 100000\n"), Some(2.0));
+        assert_eq!(parse_cpu_max("50000 100000"), Some(0.5));
+        assert_eq!(parse_cpu_max(""), None);
+        assert_eq!(parse_cfs_quota("-1\n", "100000\n"), None);
+        assert_eq!(parse_cfs_quota("400000\n", "100000\n"), Some(4.0));
+    }
+
+    #[test]
+    fn scaling() {
+        assert_eq!(scale(16.0, 1.0), 16);
+        assert_eq!(scale(16.0, 0.5), 8);
+        assert_eq!(scale(3.0, 0.5), 2);
+        assert_eq!(scale(2.0, 0.1), 1);
+    }
+}
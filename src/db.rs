@@ -2,8 +2,14 @@
 //! which files are up to date.
 
 use crate::{
-    densemap, densemap::DenseMap, graph::BuildId, graph::FileId, graph::Graph, graph::Hashes,
-    hash::BuildHash,
+    densemap,
+    densemap::DenseMap,
+    graph::BuildId,
+    graph::FileId,
+    graph::Graph,
+    graph::Hashes,
+    hash::{self, BuildHash},
+    process::ResourceUsage,
 };
 use anyhow::{anyhow, bail};
 use std::collections::HashMap;
@@ -12,8 +18,32 @@ use std::io::BufReader;
 use std::io::Read;
 use std::io::Write;
 use std::path::Path;
-
-const VERSION: u32 = 1;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+
+/// Record marker for records other than paths and builds, followed by one of
+/// the kinds below.  Shares the encoding of build records, which always have
+/// at least one output.
+const SPECIAL_MARK: u16 = 0b1000_0000_0000_0000;
+/// The list of manifest files.
+const MANIFESTS_KIND: u8 = 0;
+/// A build whose command failed, with the hash of its inputs.  Superseded by
+/// FAILURE_DEPS_KIND, but still read.
+const FAILURE_KIND: u8 = 1;
+/// Clears all failures recorded before it.
+const FORGET_FAILURES_KIND: u8 = 2;
+/// A build whose command failed, with its discovered deps and the hash of
+/// its inputs.
+const FAILURE_DEPS_KIND: u8 = 3;
+/// A build whose command was interrupted, which must rerun even if its
+/// recorded hash matches, because its outputs may be half written.
+const INTERRUPTED_KIND: u8 = 4;
+/// The directory outputs are moved under (see Graph::set_output_base), or
+/// empty for none, for the records that follow until the next of its kind.
+const OUTPUT_BASE_KIND: u8 = 5;
+
+const VERSION: u32 = 3;
 
 /// Files are identified by integers that are stable across n2 executions.
 #[derive(Debug, Clone, Copy)]
@@ -40,7 +70,7 @@ pub struct IdMap {
 }
 
 /// RecordWriter buffers writes into a Vec<u8>.
-/// We attempt to write a full record per underlying finish() to lessen the chance of writing partial records.
+/// Each finish() hands a full record to the Sink, to lessen the chance of writing partial records.
 #[derive(Default)]
 struct RecordWriter(Vec<u8>);
 
@@ -49,6 +79,10 @@ impl RecordWriter {
         self.0.extend_from_slice(buf);
     }
 
+    fn write_u8(&mut self, n: u8) {
+        self.write(&[n]);
+    }
+
     fn write_u16(&mut self, n: u16) {
         self.write(&n.to_le_bytes());
     }
@@ -57,6 +91,10 @@ impl RecordWriter {
         self.write(&n.to_le_bytes()[..3]);
     }
 
+    fn write_u32(&mut self, n: u32) {
+        self.write(&n.to_le_bytes());
+    }
+
     fn write_u64(&mut self, n: u64) {
         self.write(&n.to_le_bytes());
     }
@@ -66,6 +104,12 @@ impl RecordWriter {
         self.write(s.as_bytes());
     }
 
+    /// Like write_str, for strings that may exceed 64kb, like command lines.
+    fn write_long_str(&mut self, s: &str) {
+        self.write_u32(s.len() as u32);
+        self.write(s.as_bytes());
+    }
+
     fn write_id(&mut self, id: Id) {
         if id.0 > (1 << 24) {
             panic!("too many fileids");
@@ -73,15 +117,126 @@ impl RecordWriter {
         self.write_u24(id.0);
     }
 
-    fn finish(&self, w: &mut impl Write) -> std::io::Result<()> {
-        w.write_all(&self.0)
+    fn finish(self, sink: &Sink) -> std::io::Result<()> {
+        sink.send(self.0)
     }
 }
 
+enum SinkMessage {
+    Record(Vec<u8>),
+    /// Reply once everything sent before has been written.
+    Flush(mpsc::Sender<()>),
+}
+
+/// How many records may be queued before writes block the build.
+const SINK_QUEUE: usize = 1024;
+
+/// Appends records to the db file from a dedicated thread, so a slow disk
+/// doesn't hold up the build after each task.  Records are written in the
+/// order sent, with those queued together going out in a single write.
+struct Sink {
+    tx: Option<mpsc::SyncSender<SinkMessage>>,
+    thread: Option<JoinHandle<()>>,
+    /// The first write error, set by the thread, which writes nothing more
+    /// after it.
+    error: Arc<Mutex<Option<std::io::Error>>>,
+}
+
+impl Sink {
+    fn new(f: File) -> Self {
+        let (tx, rx) = mpsc::sync_channel(SINK_QUEUE);
+        let error = Arc::new(Mutex::new(None));
+        let thread_error = error.clone();
+        let thread = std::thread::spawn(move || Self::write_records(f, rx, thread_error));
+        Sink {
+            tx: Some(tx),
+            thread: Some(thread),
+            error,
+        }
+    }
+
+    fn write_records(
+        mut f: File,
+        rx: mpsc::Receiver<SinkMessage>,
+        error: Arc<Mutex<Option<std::io::Error>>>,
+    ) {
+        let mut failed = false;
+        let mut batch = Vec::new();
+        let mut acks = Vec::new();
+        while let Ok(msg) = rx.recv() {
+            let mut next = Some(msg);
+            while let Some(msg) = next {
+                match msg {
+                    SinkMessage::Record(record) => batch.extend_from_slice(&record),
+                    SinkMessage::Flush(ack) => acks.push(ack),
+                }
+                next = rx.try_recv().ok();
+            }
+            if !failed && !batch.is_empty() {
+                if let Err(err) = f.write_all(&batch) {
+                    failed = true;
+                    *error.lock().unwrap() = Some(err);
+                }
+            }
+            batch.clear();
+            for ack in acks.drain(..) {
+                let _ = ack.send(());
+            }
+        }
+    }
+
+    /// Returns the error the thread hit writing earlier records, if any.
+    fn check(&self) -> std::io::Result<()> {
+        match &*self.error.lock().unwrap() {
+            Some(err) => Err(std::io::Error::new(err.kind(), err.to_string())),
+            None => Ok(()),
+        }
+    }
+
+    fn send(&self, record: Vec<u8>) -> std::io::Result<()> {
+        self.check()?;
+        self.tx
+            .as_ref()
+            .unwrap()
+            .send(SinkMessage::Record(record))
+            .map_err(|_| std::io::Error::other("db writer thread exited"))
+    }
+
+    /// Wait for all records sent so far to be written.
+    fn flush(&self) -> std::io::Result<()> {
+        let (ack_tx, ack_rx) = mpsc::channel();
+        self.tx
+            .as_ref()
+            .unwrap()
+            .send(SinkMessage::Flush(ack_tx))
+            .map_err(|_| std::io::Error::other("db writer thread exited"))?;
+        let _ = ack_rx.recv();
+        self.check()
+    }
+}
+
+impl Drop for Sink {
+    fn drop(&mut self) {
+        // Closing the channel lets the thread write what's queued and exit.
+        drop(self.tx.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// The build files (build.ninja and its includes) that were loaded, with
+/// their modification times.
+pub type Manifests = Vec<(FileId, SystemTime)>;
+
 /// An opened database, ready for writes.
 pub struct Writer {
     ids: IdMap,
-    w: File,
+    w: Sink,
+    /// The manifests recorded by the last run, if any.
+    manifests: Option<Manifests>,
+    /// The output base the records that follow are made under.
+    output_base: String,
 }
 
 impl Writer {
@@ -93,12 +248,123 @@ impl Writer {
     }
 
     fn from_opened(ids: IdMap, w: File) -> Self {
-        Writer { ids, w }
+        Writer {
+            ids,
+            w: Sink::new(w),
+            manifests: None,
+            output_base: String::new(),
+        }
+    }
+
+    /// Wait for everything recorded so far to reach the file, reporting any
+    /// error writing it.
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.w.flush()
+    }
+
+    /// The manifests recorded by the last write_manifests, if any.
+    pub fn recorded_manifests(&self) -> Option<&Manifests> {
+        self.manifests.as_ref()
+    }
+
+    pub fn write_manifests(&mut self, graph: &Graph, manifests: Manifests) -> std::io::Result<()> {
+        let mut ids = Vec::with_capacity(manifests.len());
+        for &(fileid, _) in &manifests {
+            ids.push(self.ensure_id(graph, fileid)?);
+        }
+        let mut w = RecordWriter::default();
+        w.write_u16(SPECIAL_MARK);
+        w.write_u8(MANIFESTS_KIND);
+        w.write_u16(manifests.len() as u16);
+        for (id, &(_, mtime)) in ids.into_iter().zip(&manifests) {
+            w.write_id(id);
+            let nanos = mtime
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos();
+            w.write_u64(nanos as u64);
+        }
+        w.finish(&self.w)?;
+        self.manifests = Some(manifests);
+        Ok(())
+    }
+
+    /// Record the output base that the build records after this are made
+    /// under, if it's not that of those before.  Written just ahead of the
+    /// first such record, so opening the db doesn't write to it.
+    fn write_output_base(&mut self, graph: &Graph) -> std::io::Result<()> {
+        let base = graph.output_base.as_deref().unwrap_or("");
+        if base == self.output_base {
+            return Ok(());
+        }
+        let mut w = RecordWriter::default();
+        w.write_u16(SPECIAL_MARK);
+        w.write_u8(OUTPUT_BASE_KIND);
+        w.write_str(base);
+        w.finish(&self.w)?;
+        self.output_base = base.to_owned();
+        Ok(())
+    }
+
+    /// Record that a build failed, given the hash of its inputs, along with
+    /// its discovered deps.  A later write_build for the same build
+    /// supersedes this.
+    pub fn write_failure(
+        &mut self,
+        graph: &Graph,
+        id: BuildId,
+        hash: BuildHash,
+    ) -> std::io::Result<()> {
+        self.write_output_base(graph)?;
+        let build = &graph.builds[id];
+        let outs = build.outs();
+        let mut w = RecordWriter::default();
+        w.write_u16(SPECIAL_MARK);
+        w.write_u8(FAILURE_DEPS_KIND);
+        w.write_u16(outs.len() as u16);
+        for &out in outs {
+            let id = self.ensure_id(graph, out)?;
+            w.write_id(id);
+        }
+        let deps = build.discovered_ins();
+        w.write_u16(deps.len() as u16);
+        for &dep in deps {
+            let id = self.ensure_id(graph, dep)?;
+            w.write_id(id);
+        }
+        w.write_u64(hash.0);
+        w.finish(&self.w)
+    }
+
+    /// Record that a build's command was interrupted.  A later write_build
+    /// for the same build supersedes this.
+    pub fn write_interrupted(&mut self, graph: &Graph, id: BuildId) -> std::io::Result<()> {
+        self.write_output_base(graph)?;
+        let outs = graph.builds[id].outs();
+        let mut w = RecordWriter::default();
+        w.write_u16(SPECIAL_MARK);
+        w.write_u8(INTERRUPTED_KIND);
+        w.write_u16(outs.len() as u16);
+        for &out in outs {
+            let id = self.ensure_id(graph, out)?;
+            w.write_id(id);
+        }
+        w.finish(&self.w)
+    }
+
+    /// Drop all recorded failures.
+    pub fn forget_failures(&mut self) -> std::io::Result<()> {
+        let mut w = RecordWriter::default();
+        w.write_u16(SPECIAL_MARK);
+        w.write_u8(FORGET_FAILURES_KIND);
+        w.finish(&self.w)
     }
 
     fn write_signature(&mut self) -> std::io::Result<()> {
-        self.w.write_all("n2db".as_bytes())?;
-        self.w.write_all(&u32::to_le_bytes(VERSION))
+        let mut w = RecordWriter::default();
+        w.write("n2db".as_bytes());
+        w.write_u32(VERSION);
+        w.finish(&self.w)
     }
 
     fn write_path(&mut self, name: &str) -> std::io::Result<()> {
@@ -107,7 +373,7 @@ impl Writer {
         }
         let mut w = RecordWriter::default();
         w.write_str(&name);
-        w.finish(&mut self.w)
+        w.finish(&self.w)
     }
 
     fn ensure_id(&mut self, graph: &Graph, fileid: FileId) -> std::io::Result<Id> {
@@ -128,7 +394,28 @@ impl Writer {
         graph: &Graph,
         id: BuildId,
         hash: BuildHash,
+        usage: ResourceUsage,
+        keep_cmdline: bool,
+    ) -> std::io::Result<()> {
+        let cmdline = graph.builds[id].cmdline.as_deref().unwrap_or("");
+        let text = if keep_cmdline { cmdline } else { "" };
+        let cmdline = (hash::hash_cmdline(cmdline), text);
+        self.write_build_record(graph, id, hash, usage, cmdline, Some(SystemTime::now()))
+    }
+
+    /// Record a build that ran a command line, given as its hash and its text
+    /// if kept (else empty), at time built.  These may differ from its current
+    /// command line and now when copying old records.
+    fn write_build_record(
+        &mut self,
+        graph: &Graph,
+        id: BuildId,
+        hash: BuildHash,
+        usage: ResourceUsage,
+        cmdline: (u64, &str),
+        built: Option<SystemTime>,
     ) -> std::io::Result<()> {
+        self.write_output_base(graph)?;
         let build = &graph.builds[id];
         let mut w = RecordWriter::default();
         let outs = build.outs();
@@ -147,18 +434,37 @@ impl Writer {
         }
 
         w.write_u64(hash.0);
-        w.finish(&mut self.w)
+        w.write_u64(usage.peak_rss_kb);
+        w.write_u64(usage.cpu_time.as_micros() as u64);
+        w.write_u64(cmdline.0);
+        w.write_long_str(cmdline.1);
+        // Microseconds since the epoch, or 0 if unknown.
+        let built = built
+            .and_then(|built| built.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map_or(0, |since| since.as_micros() as u64);
+        w.write_u64(built);
+        w.finish(&self.w)
     }
 }
 
 struct Reader<'a> {
     r: BufReader<&'a mut File>,
     ids: IdMap,
+    manifests: Option<Manifests>,
+    /// The output base of the records being read, from the last record of
+    /// OUTPUT_BASE_KIND.
+    output_base: String,
     graph: &'a mut Graph,
     hashes: &'a mut Hashes,
 }
 
 impl<'a> Reader<'a> {
+    fn read_u8(&mut self) -> std::io::Result<u8> {
+        let mut buf: [u8; 1] = [0; 1];
+        self.r.read_exact(&mut buf[..])?;
+        Ok(buf[0])
+    }
+
     fn read_u16(&mut self) -> std::io::Result<u16> {
         let mut buf: [u8; 2] = [0; 2];
         self.r.read_exact(&mut buf[..])?;
@@ -171,6 +477,12 @@ impl<'a> Reader<'a> {
         Ok(u32::from_le_bytes(buf))
     }
 
+    fn read_u32(&mut self) -> std::io::Result<u32> {
+        let mut buf: [u8; 4] = [0; 4];
+        self.r.read_exact(&mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
     fn read_u64(&mut self) -> std::io::Result<u64> {
         let mut buf: [u8; 8] = [0; 8];
         self.r.read_exact(&mut buf)?;
@@ -196,10 +508,12 @@ impl<'a> Reader<'a> {
         Ok(())
     }
 
-    fn read_build(&mut self, len: usize) -> std::io::Result<()> {
-        // This record logs a build.  We expect all the outputs to be
-        // outputs of the same build id; if not, that means the graph has
-        // changed since this log, in which case we just ignore it.
+    /// Read the outputs identifying the build of a record, returning the
+    /// build if the record still applies to the graph.
+    fn read_outputs(&mut self, len: usize) -> std::io::Result<Option<BuildId>> {
+        // We expect all the outputs to be outputs of the same build id; if
+        // not, that means the graph has changed since this log, in which case
+        // we just ignore it.
         //
         // It's possible we log a build that generates files A B, then
         // change the build file such that it only generates file A; this
@@ -209,7 +523,9 @@ impl<'a> Reader<'a> {
         // to affect dirty checking, not build order.
 
         let mut unique_bid = None;
-        let mut obsolete = false;
+        // Records made with outputs elsewhere describe other files, which may
+        // have the same names before they're moved.
+        let mut obsolete = self.output_base != self.graph.output_base.as_deref().unwrap_or("");
         for _ in 0..len {
             let fileid = self.read_id()?;
             if obsolete {
@@ -236,6 +552,12 @@ impl<'a> Reader<'a> {
                 }
             }
         }
+        Ok(unique_bid)
+    }
+
+    fn read_build(&mut self, len: usize) -> std::io::Result<()> {
+        // This record logs a build.
+        let unique_bid = self.read_outputs(len)?;
 
         let len = self.read_u16()?;
         let mut deps = Vec::new();
@@ -245,32 +567,112 @@ impl<'a> Reader<'a> {
         }
 
         let hash = BuildHash(self.read_u64()?);
+        let usage = ResourceUsage {
+            peak_rss_kb: self.read_u64()?,
+            cpu_time: std::time::Duration::from_micros(self.read_u64()?),
+        };
+        let cmdline_hash = self.read_u64()?;
+        let len = self.read_u32()?;
+        let cmdline = self.read_str(len as usize)?;
+        let built = match self.read_u64()? {
+            0 => None,
+            micros => Some(SystemTime::UNIX_EPOCH + Duration::from_micros(micros)),
+        };
 
         // unique_bid is set here if this record is valid.
         if let Some(id) = unique_bid {
             // Common case: only one associated build.
             self.graph.builds[id].set_discovered_ins(deps);
             self.hashes.set(id, hash);
+            self.graph.builds[id].last_usage = Some(usage);
+            self.graph.builds[id].last_cmdline_hash = Some(cmdline_hash);
+            self.graph.builds[id].last_cmdline = Some(cmdline).filter(|c| !c.is_empty());
+            self.graph.builds[id].last_failure = None;
+            self.graph.builds[id].last_interrupted = false;
+            self.graph.builds[id].last_built = built;
         }
         Ok(())
     }
 
-    fn read_signature(&mut self) -> anyhow::Result<()> {
+    /// Read a failure record; with_deps for FAILURE_DEPS_KIND.
+    fn read_failure(&mut self, with_deps: bool) -> std::io::Result<()> {
+        let len = self.read_u16()?;
+        let unique_bid = self.read_outputs(len as usize)?;
+        let mut deps = None;
+        if with_deps {
+            let len = self.read_u16()?;
+            let mut ids = Vec::with_capacity(len as usize);
+            for _ in 0..len {
+                let id = self.read_id()?;
+                ids.push(self.ids.fileids[id]);
+            }
+            deps = Some(ids);
+        }
+        let hash = BuildHash(self.read_u64()?);
+        if let Some(id) = unique_bid {
+            // The recorded hash of the last success is left alone, so this
+            // doesn't make the build look clean.
+            if let Some(deps) = deps {
+                self.graph.builds[id].set_discovered_ins(deps);
+            }
+            self.graph.builds[id].last_failure = Some(hash);
+            // Its discovered deps may no longer be those of the last success.
+            self.graph.builds[id].last_built = None;
+        }
+        Ok(())
+    }
+
+    fn read_interrupted(&mut self) -> std::io::Result<()> {
+        let len = self.read_u16()?;
+        if let Some(id) = self.read_outputs(len as usize)? {
+            self.graph.builds[id].last_interrupted = true;
+        }
+        Ok(())
+    }
+
+    fn forget_failures(&mut self) {
+        for id in self.graph.builds.all_ids() {
+            self.graph.builds[id].last_failure = None;
+        }
+    }
+
+    fn read_manifests(&mut self) -> std::io::Result<()> {
+        let len = self.read_u16()?;
+        let mut manifests = Vec::with_capacity(len as usize);
+        for _ in 0..len {
+            let id = self.read_id()?;
+            let id = self.ids.fileids[id];
+            let mtime = SystemTime::UNIX_EPOCH + Duration::from_nanos(self.read_u64()?);
+            manifests.push((id, mtime));
+        }
+        // A later record supersedes any earlier one.
+        self.manifests = Some(manifests);
+        Ok(())
+    }
+
+    fn read_output_base(&mut self) -> std::io::Result<()> {
+        let len = self.read_u16()?;
+        self.output_base = self.read_str(len as usize)?;
+        Ok(())
+    }
+
+    /// Reads the signature, returning the version of n2 db it names.
+    fn read_signature(&mut self) -> anyhow::Result<u32> {
         let mut buf: [u8; 4] = [0; 4];
         self.r.read_exact(&mut buf[..])?;
         if buf.as_slice() != "n2db".as_bytes() {
             bail!("invalid db signature");
         }
         self.r.read_exact(&mut buf[..])?;
-        let version = u32::from_le_bytes(buf);
-        if version != VERSION {
-            bail!("db version mismatch: got {version}, expected {VERSION}; TODO: db upgrades etc");
-        }
-        Ok(())
+        Ok(u32::from_le_bytes(buf))
     }
 
-    fn read_file(&mut self) -> anyhow::Result<()> {
-        self.read_signature()?;
+    /// Reads the records, or returns false without reading any if the file
+    /// is of another version.
+    fn read_file(&mut self) -> anyhow::Result<bool> {
+        if self.read_signature()? != VERSION {
+            return Ok(false);
+        }
         loop {
             let mut len = match self.read_u16() {
                 Ok(r) => r,
@@ -278,31 +680,51 @@ impl<'a> Reader<'a> {
                 Err(err) => bail!(err),
             };
             let mask = 0b1000_0000_0000_0000;
-            if len & mask == 0 {
+            if len == SPECIAL_MARK {
+                match self.read_u8()? {
+                    MANIFESTS_KIND => self.read_manifests()?,
+                    FAILURE_KIND => self.read_failure(false)?,
+                    FAILURE_DEPS_KIND => self.read_failure(true)?,
+                    FORGET_FAILURES_KIND => self.forget_failures(),
+                    INTERRUPTED_KIND => self.read_interrupted()?,
+                    OUTPUT_BASE_KIND => self.read_output_base()?,
+                    kind => bail!("unknown record kind {}", kind),
+                }
+            } else if len & mask == 0 {
                 self.read_path(len as usize)?;
             } else {
                 len &= !mask;
                 self.read_build(len as usize)?;
             }
         }
-        Ok(())
+        Ok(true)
     }
 
     /// Reads an on-disk database, loading its state into the provided Graph/Hashes.
-    fn read(f: &mut File, graph: &mut Graph, hashes: &mut Hashes) -> anyhow::Result<IdMap> {
+    /// Returns None if the database is from another version of n2.
+    fn read(
+        f: &mut File,
+        graph: &mut Graph,
+        hashes: &mut Hashes,
+    ) -> anyhow::Result<Option<(IdMap, Option<Manifests>, String)>> {
         let mut r = Reader {
             r: std::io::BufReader::new(f),
             ids: IdMap::default(),
+            manifests: None,
+            output_base: String::new(),
             graph,
             hashes,
         };
-        r.read_file()?;
-
-        Ok(r.ids)
+        if !r.read_file()? {
+            return Ok(None);
+        }
+        Ok(Some((r.ids, r.manifests, r.output_base)))
     }
 }
 
 /// Opens or creates an on-disk database, loading its state into the provided Graph.
+/// A database from another version of n2 is discarded, as if every build had
+/// yet to run.
 pub fn open(path: &Path, graph: &mut Graph, hashes: &mut Hashes) -> anyhow::Result<Writer> {
     match std::fs::OpenOptions::new()
         .read(true)
@@ -310,13 +732,63 @@ pub fn open(path: &Path, graph: &mut Graph, hashes: &mut Hashes) -> anyhow::Resu
         .open(path)
     {
         Ok(mut f) => {
-            let ids = Reader::read(&mut f, graph, hashes)?;
-            Ok(Writer::from_opened(ids, f))
-        }
-        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
-            let w = Writer::create(path)?;
+            let Some((ids, manifests, output_base)) = Reader::read(&mut f, graph, hashes)? else {
+                println!(
+                    "n2: warn: {} is from another version of n2; starting over",
+                    path.display()
+                );
+                return Ok(Writer::create(path)?);
+            };
+            let mut w = Writer::from_opened(ids, f);
+            w.manifests = manifests;
+            w.output_base = output_base;
             Ok(w)
         }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Writer::create(path)?),
         Err(err) => Err(anyhow!(err)),
     }
 }
+
+/// Rewrite the database at path with only the latest records of the builds
+/// in graph, as loaded into it and hashes by open().  Records that later ones
+/// superseded, and those of builds no longer in the graph, are dropped.
+/// Returns the file's size before and after.
+pub fn recompact(
+    path: &Path,
+    graph: &Graph,
+    hashes: &Hashes,
+    manifests: Option<Manifests>,
+) -> anyhow::Result<(u64, u64)> {
+    let before = std::fs::metadata(path)?.len();
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let temp = Path::new(&temp);
+    let mut w = Writer::create(temp)?;
+    if let Some(manifests) = manifests {
+        w.write_manifests(graph, manifests)?;
+    }
+    // Written in the order they were read back in, as each supersedes
+    // parts of those before it.
+    for id in graph.builds.all_ids() {
+        let build = &graph.builds[id];
+        if let Some(hash) = hashes.get(id) {
+            let usage = build.last_usage.unwrap_or_default();
+            let cmdline = (
+                build.last_cmdline_hash.unwrap_or_default(),
+                build.last_cmdline.as_deref().unwrap_or(""),
+            );
+            w.write_build_record(graph, id, hash, usage, cmdline, build.last_built)?;
+        }
+        if let Some(hash) = build.last_failure {
+            w.write_failure(graph, id, hash)?;
+        }
+        if build.last_interrupted {
+            w.write_interrupted(graph, id)?;
+        }
+    }
+    w.flush()?;
+    drop(w);
+    std::fs::rename(temp, path)
+        .map_err(|err| anyhow!("rename {} to {}: {}", temp.display(), path.display(), err))?;
+    Ok((before, std::fs::metadata(path)?.len()))
+}
@@ -8,6 +8,9 @@ pub trait Index: From<usize> {
 
 /// A map of a dense integer key to value, implemented as a vector.
 /// Effectively wraps Vec<V> to provided typed keys.
+///
+/// Keys are positions, so entries can only be added at the end and never
+/// removed; there's deliberately no remove() or retain().
 pub struct DenseMap<K, V> {
     vec: Vec<V>,
     key_type: std::marker::PhantomData<K>,
@@ -45,6 +48,48 @@ impl<K: Index, V> DenseMap<K, V> {
         K::from(self.vec.len())
     }
 
+    pub fn all_ids(&self) -> impl Iterator<Item = K> {
+        (0..self.vec.len()).map(K::from)
+    }
+
+    /// The keys in order; the same as all_ids.
+    pub fn keys(&self) -> impl Iterator<Item = K> {
+        self.all_ids()
+    }
+
+    pub fn len(&self) -> usize {
+        self.vec.len()
+    }
+
+    /// The number of entries there's room for without reallocating.
+    pub fn capacity(&self) -> usize {
+        self.vec.capacity()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.vec.is_empty()
+    }
+
+    /// Make room for at least additional more entries.
+    pub fn reserve(&mut self, additional: usize) {
+        self.vec.reserve(additional);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (K, &V)> {
+        self.vec.iter().enumerate().map(|(i, v)| (K::from(i), v))
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (K, &mut V)> {
+        self.vec
+            .iter_mut()
+            .enumerate()
+            .map(|(i, v)| (K::from(i), v))
+    }
+
+    pub fn values(&self) -> std::slice::Iter<'_, V> {
+        self.vec.iter()
+    }
+
     pub fn push(&mut self, val: V) -> K {
         let id = self.next_id();
         self.vec.push(val);
@@ -66,3 +111,43 @@ impl<K: Index, V: Clone> DenseMap<K, V> {
         self.vec[k.index()] = v
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct Id(usize);
+    impl Index for Id {
+        fn index(&self) -> usize {
+            self.0
+        }
+    }
+    impl From<usize> for Id {
+        fn from(i: usize) -> Self {
+            Id(i)
+        }
+    }
+
+    #[test]
+    fn iterate() {
+        let mut map: DenseMap<Id, &str> = DenseMap::default();
+        assert!(map.is_empty());
+        map.reserve(3);
+        assert_eq!(map.push("a"), Id(0));
+        assert_eq!(map.push("b"), Id(1));
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.keys().collect::<Vec<_>>(), [Id(0), Id(1)]);
+        assert_eq!(
+            map.iter().collect::<Vec<_>>(),
+            [(Id(0), &"a"), (Id(1), &"b")]
+        );
+        for (id, v) in map.iter_mut() {
+            if id == Id(1) {
+                *v = "c";
+            }
+        }
+        assert_eq!(map.values().collect::<Vec<_>>(), [&"a", &"c"]);
+        assert_eq!(map.lookup(Id(2)), None);
+    }
+}
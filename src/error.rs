@@ -0,0 +1,96 @@
+//! Errors that library users may want to distinguish programmatically.
+//!
+//! Most of n2 passes errors around as anyhow::Error; the failures listed here
+//! are carried inside those, and can be recovered with e.g.
+//! `err.downcast_ref::<n2::Error>()`.
+
+use crate::process::Termination;
+use std::path::PathBuf;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Syntax error in a .ninja or .d file.
+    Parse {
+        file: PathBuf,
+        /// 1-based line number of the error.
+        line: usize,
+        /// 0-based byte offset of the error within the line.
+        column: usize,
+        /// The text of the offending line, for display.
+        text: String,
+        msg: String,
+    },
+    /// An input to a build is missing and no build generates it.
+    MissingInput {
+        /// Source location of the build that needed the input.
+        location: String,
+        path: String,
+    },
+    /// A build's command ran and did not succeed.
+    CommandFailed {
+        /// Build status message, as shown in progress output.
+        desc: String,
+        /// The command's output; only its end, if it was very large.
+        output: Vec<u8>,
+        status: Termination,
+    },
+    /// The .n2_db file is unreadable.
+    DbCorrupt { msg: String },
+    /// The build graph contains a dependency cycle.
+    Cycle {
+        /// File names along the cycle, starting and ending with the same file.
+        path: Vec<String>,
+    },
+    /// The build was stopped through its CancelToken.
+    Cancelled,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Parse {
+                file,
+                line,
+                column,
+                text,
+                msg,
+            } => {
+                writeln!(f, "parse error: {}", msg)?;
+                let prefix = format!("{}:{}: ", file.display(), line);
+                f.write_str(&prefix)?;
+
+                let mut context = text.as_str();
+                let mut col = *column;
+                if col > 40 {
+                    // Trim beginning of line to fit it on screen.
+                    f.write_str("...")?;
+                    context = &context[col - 20..];
+                    col = 3 + 20;
+                }
+                if context.len() > 40 {
+                    f.write_str(&context[0..40])?;
+                    f.write_str("...")?;
+                } else {
+                    f.write_str(context)?;
+                }
+                writeln!(f)?;
+
+                f.write_str(&" ".repeat(prefix.len() + col))?;
+                writeln!(f, "^")
+            }
+            Error::MissingInput { location, path } => {
+                write!(f, "{}: input {} missing", location, path)
+            }
+            Error::CommandFailed { desc, status, .. } => match status {
+                Termination::Interrupted => write!(f, "interrupted: {}", desc),
+                Termination::TimedOut => write!(f, "timed out: {}", desc),
+                _ => write!(f, "failed: {}", desc),
+            },
+            Error::DbCorrupt { msg } => write!(f, "load .n2_db: {}", msg),
+            Error::Cycle { path } => write!(f, "dependency cycle: {}", path.join(" -> ")),
+            Error::Cancelled => write!(f, "build cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
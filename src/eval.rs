@@ -65,6 +65,34 @@ impl<T: AsRef<str>> EvalString<T> {
             .sum()
     }
 
+    fn undefined_vars_inner(&self, undefined: &mut Vec<String>, envs: &[&dyn Env]) {
+        for part in &self.0 {
+            if let EvalPart::VarRef(v) = part {
+                let found = envs
+                    .iter()
+                    .enumerate()
+                    .find_map(|(i, env)| Some((i, env.get_var(v.as_ref())?)));
+                match found {
+                    Some((i, v)) => v.undefined_vars_inner(undefined, &envs[i + 1..]),
+                    None => {
+                        if !undefined.iter().any(|u| u == v.as_ref()) {
+                            undefined.push(v.as_ref().to_owned());
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// The variables referenced, directly or through the values of others,
+    /// that none of the Envs defines, and which evaluate() thus expands to
+    /// nothing.
+    pub fn undefined_vars(&self, envs: &[&dyn Env]) -> Vec<String> {
+        let mut undefined = Vec::new();
+        self.undefined_vars_inner(&mut undefined, envs);
+        undefined
+    }
+
     /// evalulate turns the EvalString into a regular String, looking up the
     /// values of variable references in the provided Envs. It will look up
     /// its variables in the earliest Env that has them, and then those lookups
@@ -78,6 +106,19 @@ impl<T: AsRef<str>> EvalString<T> {
     }
 }
 
+/// Formats back to build file syntax, with variable references as `${var}`.
+impl<T: AsRef<str>> std::fmt::Display for EvalString<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for part in &self.0 {
+            match part {
+                EvalPart::Literal(s) => f.write_str(&s.as_ref().replace('$', "$$"))?,
+                EvalPart::VarRef(v) => write!(f, "${{{}}}", v.as_ref())?,
+            }
+        }
+        Ok(())
+    }
+}
+
 impl EvalString<&str> {
     pub fn into_owned(self) -> EvalString<String> {
         EvalString(
@@ -93,6 +134,18 @@ impl EvalString<&str> {
 }
 
 impl EvalString<String> {
+    /// Heap memory held, in bytes, for Graph::stats.
+    pub fn heap_size(&self) -> usize {
+        self.0.capacity() * std::mem::size_of::<EvalPart<String>>()
+            + self
+                .0
+                .iter()
+                .map(|part| match part {
+                    EvalPart::Literal(s) | EvalPart::VarRef(s) => s.capacity(),
+                })
+                .sum::<usize>()
+    }
+
     pub fn as_cow(&self) -> EvalString<Cow<str>> {
         EvalString(
             self.0
@@ -120,23 +173,99 @@ impl EvalString<&str> {
     }
 }
 
+/// Look up a `${env.NAME}` reference in the process environment.
+fn process_env_var(var: &str) -> Option<EvalString<Cow<'static, str>>> {
+    let val = std::env::var(var.strip_prefix("env.")?).ok()?;
+    Some(EvalString::new(vec![EvalPart::Literal(Cow::Owned(val))]))
+}
+
 /// A single scope's worth of variable definitions.
 #[derive(Debug, Default)]
-pub struct Vars<'text>(FxHashMap<&'text str, String>);
+pub struct Vars<'text> {
+    vars: FxHashMap<&'text str, String>,
+    /// Bumped on every insert, so that snapshots (see OwnedVars) can tell
+    /// whether they are still current.
+    generation: usize,
+    /// Whether undefined `env.NAME` variables come from the process
+    /// environment; see use_process_env.
+    process_env: bool,
+}
 
 impl<'text> Vars<'text> {
     pub fn insert(&mut self, key: &'text str, val: String) {
-        self.0.insert(key, val);
+        self.vars.insert(key, val);
+        self.generation += 1;
     }
     pub fn get(&self, key: &str) -> Option<&String> {
-        self.0.get(key)
+        self.vars.get(key)
+    }
+    pub fn generation(&self) -> usize {
+        self.generation
+    }
+    /// Resolve `${env.NAME}` to the environment variable NAME, unless the
+    /// build file defines a variable named env.NAME.  As this is the outermost
+    /// scope, this applies to every evaluation.  Not a Ninja feature.
+    pub fn use_process_env(&mut self) {
+        self.process_env = true;
     }
 }
 impl<'a> Env for Vars<'a> {
     fn get_var(&self, var: &str) -> Option<EvalString<Cow<str>>> {
-        Some(EvalString::new(vec![EvalPart::Literal(
-            std::borrow::Cow::Borrowed(self.get(var)?),
-        )]))
+        match self.get(var) {
+            Some(val) => Some(EvalString::new(vec![EvalPart::Literal(
+                std::borrow::Cow::Borrowed(val),
+            )])),
+            None if self.process_env => process_env_var(var),
+            None => None,
+        }
+    }
+}
+
+/// A snapshot of a Vars that doesn't borrow from the input text, used for
+/// evaluations that happen after parsing is complete.
+#[derive(Debug, Default)]
+pub struct OwnedVars {
+    vars: FxHashMap<String, String>,
+    process_env: bool,
+}
+
+impl OwnedVars {
+    pub fn get(&self, key: &str) -> Option<&String> {
+        self.vars.get(key)
+    }
+
+    /// Heap memory held, in bytes, for Graph::stats.  The table's control
+    /// bytes and spare buckets are approximated.
+    pub fn heap_size(&self) -> usize {
+        self.vars.capacity() * (std::mem::size_of::<(String, String)>() + 1)
+            + self
+                .vars
+                .iter()
+                .map(|(k, v)| k.capacity() + v.capacity())
+                .sum::<usize>()
+    }
+}
+impl<'text> From<&Vars<'text>> for OwnedVars {
+    fn from(vars: &Vars<'text>) -> Self {
+        OwnedVars {
+            vars: vars
+                .vars
+                .iter()
+                .map(|(&k, v)| (k.to_owned(), v.clone()))
+                .collect(),
+            process_env: vars.process_env,
+        }
+    }
+}
+impl Env for OwnedVars {
+    fn get_var(&self, var: &str) -> Option<EvalString<Cow<'_, str>>> {
+        match self.get(var) {
+            Some(val) => Some(EvalString::new(vec![EvalPart::Literal(
+                std::borrow::Cow::Borrowed(val),
+            )])),
+            None if self.process_env => process_env_var(var),
+            None => None,
+        }
     }
 }
 
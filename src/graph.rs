@@ -1,14 +1,21 @@
 //! The build graph, a graph between files and commands.
 
-use rustc_hash::FxHashMap;
+use rustc_hash::{FxHashMap, FxHasher};
 
 use crate::{
+    canon::to_owned_canon_path,
     densemap::{self, DenseMap},
+    eval::{EvalString, OwnedVars},
     hash::BuildHash,
+    process::ResourceUsage,
+    smallmap::SmallMap,
 };
-use std::collections::{hash_map::Entry, HashMap};
+use std::collections::{hash_map::Entry, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
 use std::path::{Path, PathBuf};
-use std::time::SystemTime;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
 /// Id for File nodes in the Graph.
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
@@ -25,7 +32,7 @@ impl From<usize> for FileId {
 }
 
 /// Id for Build nodes in the Graph.
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct BuildId(u32);
 impl densemap::Index for BuildId {
     fn index(&self) -> usize {
@@ -144,15 +151,45 @@ mod tests {
     }
 }
 
+/// A `rule` block from a build file.
+pub struct Rule {
+    pub name: String,
+    /// Variable bindings from the rule block, e.g. `command = ...`.
+    pub vars: SmallMap<String, EvalString<String>>,
+}
+
+/// The variable bindings needed to evaluate a Build's rule-derived attributes
+/// (command line, description, etc.).  These are kept unevaluated at load time
+/// because most builds in a large build file never run, and evaluated only
+/// once the build is needed; see load::evaluate_build.
+pub struct BuildBindings {
+    /// `key = val` bindings from the build block itself.
+    pub vars: SmallMap<String, EvalString<String>>,
+    /// The file-level variables in scope at the point the build was declared.
+    pub env: Rc<OwnedVars>,
+}
+
 /// A single build action, generating File outputs from File inputs with a command.
 pub struct Build {
     /// Source location this Build was declared.
     pub location: FileLoc,
 
+    /// The rule used by this build.
+    pub rule: Rc<Rule>,
+
+    /// Bindings used to evaluate the attributes below.  Present until the
+    /// build is evaluated, after which it is None.
+    pub bindings: Option<Box<BuildBindings>>,
+
+    /// Whether the build has a command to run, known even before the build
+    /// is evaluated.  Builds without commands are treated as phony.
+    has_command: bool,
+
     /// User-provided description of the build step.
     pub desc: Option<String>,
 
-    /// Command line to run.  Absent for phony builds.
+    /// Command line to run.  Absent for phony builds, and for all builds
+    /// until they are evaluated.
     pub cmdline: Option<String>,
 
     /// Path to generated `.d` file, if any.
@@ -161,12 +198,63 @@ pub struct Build {
     /// If true, extract "/showIncludes" lines from output.
     pub parse_showincludes: bool,
 
+    /// If true, the depfile is JSON as written by /sourceDependencies rather
+    /// than Makefile syntax.
+    pub depfile_json: bool,
+
     // Struct that contains the path to the rsp file and its contents, if any.
     pub rspfile: Option<RspFile>,
 
     /// Pool to execute this build in, if any.
     pub pool: Option<String>,
 
+    /// Text prepended to the command when running it, from the
+    /// `command_prefix` variable (e.g. "ccache ").  Not part of the command
+    /// as hashed, so changing it doesn't cause rebuilds.
+    pub command_prefix: Option<String>,
+
+    /// Directory to run the command in, from the `cwd` variable.  Relative to
+    /// the build root, as are all other paths of the build, including its
+    /// depfile.  The paths the command itself writes in the depfile or
+    /// /showIncludes output are relative to cwd, and are resolved from there.
+    /// Part of the build's hash, so changing it reruns the command.
+    pub cwd: Option<String>,
+
+    /// Number of times to rerun the command if it fails, from the `retries`
+    /// variable.  None means to use the global default.
+    pub retries: Option<usize>,
+
+    /// Expected peak memory use of the command in megabytes, from the
+    /// `memory_estimate` variable, used for --memory-budget.
+    pub memory_estimate: usize,
+
+    /// How long the command may run before it's killed and fails, from the
+    /// `timeout` variable.  None means to use the global default, if any;
+    /// zero means no limit.
+    pub timeout: Option<Duration>,
+
+    /// Whether the command writes its explicit outputs to temporary paths
+    /// (see temp_output) that are renamed into place only if it succeeds,
+    /// from the `atomic_outputs` variable.
+    pub atomic_outputs: bool,
+
+    /// Whether the outputs may be directories whose contents, not just their
+    /// own mtimes, mark them as changed, from the `dir_outputs` variable; see
+    /// tree_stamp.
+    pub dir_outputs: bool,
+
+    /// Whether to leave out the output of the command if it succeeds, from
+    /// the `hide_success` variable.
+    pub hide_success: bool,
+
+    /// Whether to not announce the build as it starts, on consoles that
+    /// print a line per build, from the `hide_progress` variable.
+    pub hide_progress: bool,
+
+    /// Whether the build writes build files, from the `generator` variable,
+    /// so that `-t clean` keeps its outputs unless asked not to.
+    pub generator: bool,
+
     pub ins: BuildIns,
 
     /// Additional inputs discovered from a previous build.
@@ -174,23 +262,119 @@ pub struct Build {
 
     /// Output files.
     pub outs: BuildOuts,
+
+    /// Resources used by the command the last time it ran, from the db, or
+    /// from this run once the command has run.
+    pub last_usage: Option<ResourceUsage>,
+
+    /// Hash (see hash::hash_cmdline) of the command line of the last run,
+    /// from the db.
+    pub last_cmdline_hash: Option<u64>,
+
+    /// Command line of the last run, from the db, for explaining rebuilds.
+    /// Only recorded by builds run with -d explain.
+    pub last_cmdline: Option<String>,
+
+    /// If the last run failed, the hash of its inputs (see hash::hash_inputs),
+    /// from the db.
+    pub last_failure: Option<BuildHash>,
+
+    /// Whether the last run was interrupted, possibly leaving its outputs
+    /// half written, from the db.
+    pub last_interrupted: bool,
+
+    /// When the db recorded the last successful run, if known.
+    pub last_built: Option<SystemTime>,
 }
 impl Build {
-    pub fn new(loc: FileLoc, ins: BuildIns, outs: BuildOuts) -> Self {
+    pub fn new(
+        loc: FileLoc,
+        rule: Rc<Rule>,
+        bindings: BuildBindings,
+        ins: BuildIns,
+        outs: BuildOuts,
+    ) -> Self {
+        let has_command =
+            bindings.vars.get("command").is_some() || rule.vars.get("command").is_some();
         Build {
             location: loc,
+            rule,
+            bindings: Some(Box::new(bindings)),
+            has_command,
             desc: None,
             cmdline: None,
             depfile: None,
             parse_showincludes: false,
+            depfile_json: false,
             rspfile: None,
             pool: None,
+            command_prefix: None,
+            cwd: None,
+            retries: None,
+            memory_estimate: 0,
+            timeout: None,
+            atomic_outputs: false,
+            dir_outputs: false,
+            hide_success: false,
+            hide_progress: false,
+            generator: false,
             ins,
             discovered_ins: Vec::new(),
             outs,
+            last_usage: None,
+            last_cmdline_hash: None,
+            last_cmdline: None,
+            last_failure: None,
+            last_interrupted: false,
+            last_built: None,
         }
     }
 
+    /// Heap memory held by the build itself, in bytes, for Graph::stats.
+    /// Before the build is evaluated, that is mostly its bindings; the rule
+    /// and scope they refer to are shared, and not included.
+    fn heap_size(&self) -> usize {
+        let strings = [
+            &self.desc,
+            &self.cmdline,
+            &self.depfile,
+            &self.pool,
+            &self.command_prefix,
+            &self.cwd,
+            &self.last_cmdline,
+        ];
+        let ids =
+            self.ins.ids.capacity() + self.outs.ids.capacity() + self.discovered_ins.capacity();
+        ids * std::mem::size_of::<FileId>()
+            + strings
+                .iter()
+                .filter_map(|s| s.as_ref())
+                .map(String::capacity)
+                .sum::<usize>()
+            + self
+                .rspfile
+                .as_ref()
+                .map_or(0, |rsp| rsp.path.capacity() + rsp.content.capacity())
+            + self.bindings.as_ref().map_or(0, |bindings| {
+                std::mem::size_of::<BuildBindings>() + vars_heap_size(&bindings.vars)
+            })
+    }
+
+    /// Phony builds have no command and never need to run anything.
+    pub fn is_phony(&self) -> bool {
+        !self.has_command
+    }
+
+    /// Console pool builds run with direct access to the terminal.
+    pub fn is_console(&self) -> bool {
+        self.pool.as_deref() == Some("console")
+    }
+
+    /// Whether the rule-derived attributes (cmdline etc.) have been evaluated.
+    pub fn is_evaluated(&self) -> bool {
+        self.bindings.is_none()
+    }
+
     /// Input paths that appear in `$in`.
     pub fn explicit_ins(&self) -> &[FileId] {
         &self.ins.ids[0..self.ins.explicit]
@@ -243,6 +427,22 @@ impl Build {
 pub struct Graph {
     pub builds: DenseMap<BuildId, Build>,
     pub files: GraphFiles,
+    /// The rspfile paths of evaluated builds, canonicalized, to catch two
+    /// builds writing the same one.  Builds that are never evaluated aren't
+    /// checked, but neither do they run to write theirs.
+    pub rspfiles: FxHashMap<String, BuildId>,
+    /// Rules whose description has been reported as referring to undefined
+    /// variables, to warn about each only once.
+    pub desc_warned: HashSet<String>,
+    /// glob_match patterns of discovered deps not to record, from the
+    /// deps_ignore variable.
+    pub deps_ignore: Vec<String>,
+    /// The newest mtime of the build files loaded.  Builds recorded before it
+    /// may have changed in ways their files' mtimes don't show.
+    pub newest_manifest: Option<SystemTime>,
+    /// The directory outputs were moved under, from --output-base; see
+    /// set_output_base.
+    pub output_base: Option<String>,
 }
 
 /// Files identified by FileId, as well as mapping string filenames to them.
@@ -250,7 +450,139 @@ pub struct Graph {
 #[derive(Default)]
 pub struct GraphFiles {
     pub by_id: DenseMap<FileId, File>,
-    by_name: FxHashMap<String, FileId>,
+    /// Shared with threads that add files, such as running tasks; the files
+    /// they add join by_id on the next sync().
+    names: Arc<FileNames>,
+}
+
+/// Number of separately locked parts of a FileNames.
+const NAME_SHARDS: usize = 16;
+
+/// Maps file names to FileIds.  It can be shared between threads that all
+/// add names, e.g. tasks turning the deps they discover into FileIds, so
+/// the map is split into shards by name hash, each with its own lock.
+pub struct FileNames {
+    shards: Vec<Mutex<FxHashMap<String, FileId>>>,
+    /// Names added since the last GraphFiles::sync().
+    added: Mutex<AddedNames>,
+    /// When true, names that differ only by case refer to the same file,
+    /// as on case-insensitive filesystems.  Files keep the spelling they were
+    /// first seen with.
+    fold_case: bool,
+}
+
+/// The names of FileIds next_id - names.len() up to next_id.
+#[derive(Default)]
+struct AddedNames {
+    next_id: usize,
+    names: Vec<String>,
+}
+
+impl Default for FileNames {
+    fn default() -> Self {
+        FileNames {
+            shards: (0..NAME_SHARDS).map(|_| Mutex::default()).collect(),
+            added: Mutex::default(),
+            fold_case: false,
+        }
+    }
+}
+
+impl AddedNames {
+    fn add(&mut self, name: String) -> FileId {
+        let id = FileId::from(self.next_id);
+        self.next_id += 1;
+        self.names.push(name);
+        id
+    }
+}
+
+/// Pick the shard for a map key.  Only the end of the key is hashed, where
+/// names differ most, to keep this cheap next to the map's own hashing.
+fn shard_index(key: &str) -> usize {
+    let mut hasher = FxHasher::default();
+    hasher.write(&key.as_bytes()[key.len().saturating_sub(8)..]);
+    hasher.finish() as usize % NAME_SHARDS
+}
+
+impl FileNames {
+    /// Heap memory held by the name maps, in bytes, for Graph::stats.  The
+    /// tables' control bytes and spare buckets are approximated.
+    fn heap_size(&self) -> usize {
+        let entry = std::mem::size_of::<(String, FileId)>() + 1;
+        let mut size =
+            self.shards.capacity() * std::mem::size_of::<Mutex<FxHashMap<String, FileId>>>();
+        for shard in &self.shards {
+            let shard = shard.lock().unwrap();
+            size += shard.capacity() * entry + shard.keys().map(String::capacity).sum::<usize>();
+        }
+        let added = self.added.lock().unwrap();
+        size + added.names.capacity() * std::mem::size_of::<String>()
+            + added.names.iter().map(String::capacity).sum::<usize>()
+    }
+
+    /// Look up a file by its name.  Name must have been canonicalized already.
+    pub fn lookup(&self, name: &str) -> Option<FileId> {
+        let folded = self.fold_case.then(|| fold_name(name));
+        let key = folded.as_deref().unwrap_or(name);
+        let shard = self.shards[shard_index(key)].lock().unwrap();
+        shard.get(key).copied()
+    }
+
+    /// Look up a file by its name, adding it if not already present.
+    /// Name must have been canonicalized already.
+    pub fn intern(&self, name: String) -> FileId {
+        let folded = self.fold_case.then(|| fold_name(&name));
+        let key = folded.as_deref().unwrap_or(&name);
+        let mut shard = self.shards[shard_index(key)].lock().unwrap();
+        if let Some(&id) = shard.get(key) {
+            return id;
+        }
+        let key = folded.unwrap_or_else(|| name.clone());
+        // Ids are handed out under the lock of added, so that they join
+        // by_id in order.
+        let id = self.added.lock().unwrap().add(name);
+        shard.insert(key, id);
+        id
+    }
+
+    /// Like intern, but without locking, when the names aren't shared, and
+    /// adding any new file straight to by_id.
+    fn intern_unshared(&mut self, name: String, by_id: &mut DenseMap<FileId, File>) -> FileId {
+        // The name moves into the map as its key, unless it's folded.
+        let (key, spelling) = match self.fold_case {
+            true => (fold_name(&name), Some(name)),
+            false => (name, None),
+        };
+        let shard = self.shards[shard_index(&key)].get_mut().unwrap();
+        match shard.entry(key) {
+            Entry::Occupied(o) => *o.get(),
+            Entry::Vacant(v) => {
+                let name = spelling.unwrap_or_else(|| v.key().clone());
+                let added = self.added.get_mut().unwrap();
+                // Names added while shared, and never synced, come first.
+                for name in added.names.drain(..) {
+                    by_id.push(File {
+                        name,
+                        input: None,
+                        dependents: Vec::new(),
+                    });
+                }
+                added.next_id += 1;
+                let id = by_id.push(File {
+                    name,
+                    input: None,
+                    dependents: Vec::new(),
+                });
+                v.insert(id);
+                id
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.added.lock().unwrap().next_id == 0
+    }
 }
 
 impl Graph {
@@ -259,6 +591,21 @@ impl Graph {
         &self.files.by_id[id]
     }
 
+    /// Stat the build files, given as ids, noting the newest mtime in
+    /// newest_manifest.
+    pub fn stat_manifests(&mut self, ids: &[FileId]) -> anyhow::Result<Vec<(FileId, SystemTime)>> {
+        let mut manifests = Vec::with_capacity(ids.len());
+        for &id in ids {
+            let path = self.file(id).path();
+            let mtime = std::fs::metadata(path)
+                .and_then(|meta| meta.modified())
+                .map_err(|err| anyhow::anyhow!("stat {:?}: {}", path, err))?;
+            manifests.push((id, mtime));
+        }
+        self.newest_manifest = manifests.iter().map(|&(_, mtime)| mtime).max();
+        Ok(manifests)
+    }
+
     /// Add a new Build, generating a BuildId for it.
     pub fn add_build(&mut self, mut build: Build) -> anyhow::Result<()> {
         let new_id = self.builds.next_id();
@@ -293,40 +640,318 @@ impl Graph {
         self.builds.push(build);
         Ok(())
     }
+
+    /// Move the outputs of every build that runs a command under base, as
+    /// with --output-base.  Their old names still look them up, so targets
+    /// can be named as in the build files, while commands, stats, and the db
+    /// see the new ones.  Files in keep, such as build files that are read in
+    /// place, and absolute paths stay where they are.
+    pub fn set_output_base(&mut self, base: &str, keep: &HashSet<FileId>) -> anyhow::Result<()> {
+        let ids: Vec<FileId> = self.files.all_ids().collect();
+        for id in ids {
+            let file = &self.files.by_id[id];
+            let Some(bid) = file.input else {
+                continue;
+            };
+            if self.builds[bid].is_phony() || keep.contains(&id) || file.path().is_absolute() {
+                continue;
+            }
+            let name = to_owned_canon_path(format!("{}/{}", base, file.name));
+            self.files.rename(id, name)?;
+        }
+        self.output_base = Some(base.to_owned());
+        Ok(())
+    }
+
+    /// Count what the graph holds, for --debug-graph-stats.
+    pub fn stats(&self) -> GraphStats {
+        let mut stats = GraphStats::default();
+        let mut by_rule: HashMap<&str, usize> = HashMap::new();
+        stats.memory += self.files.by_id.capacity() * std::mem::size_of::<File>()
+            + self.files.names.heap_size();
+        for id in self.files.all_ids() {
+            let file = &self.files.by_id[id];
+            stats.files += 1;
+            stats.memory +=
+                file.name.capacity() + file.dependents.capacity() * std::mem::size_of::<BuildId>();
+        }
+        stats.memory += self.builds.capacity() * std::mem::size_of::<Build>();
+        // Rules and scopes are shared between builds, so count each once.
+        let mut rules = HashSet::new();
+        let mut envs = HashSet::new();
+        for id in self.builds.all_ids() {
+            let build = &self.builds[id];
+            stats.builds += 1;
+            stats.edges += build.ins.ids.len() + build.outs.ids.len();
+            *by_rule.entry(&build.rule.name).or_default() += 1;
+            stats.memory += build.heap_size();
+            if rules.insert(Rc::as_ptr(&build.rule)) {
+                stats.memory += rc_size::<Rule>()
+                    + build.rule.name.capacity()
+                    + vars_heap_size(&build.rule.vars);
+            }
+            if let Some(bindings) = &build.bindings {
+                if envs.insert(Rc::as_ptr(&bindings.env)) {
+                    stats.memory += rc_size::<OwnedVars>() + bindings.env.heap_size();
+                }
+            }
+        }
+        stats.builds_by_rule = by_rule
+            .into_iter()
+            .map(|(rule, count)| (rule.to_owned(), count))
+            .collect();
+        stats
+            .builds_by_rule
+            .sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+        stats
+    }
+}
+
+/// The size of an Rc's allocation holding a T.
+fn rc_size<T>() -> usize {
+    2 * std::mem::size_of::<usize>() + std::mem::size_of::<T>()
+}
+
+/// Heap memory held by the variables of a rule or build block, in bytes.
+fn vars_heap_size(vars: &SmallMap<String, EvalString<String>>) -> usize {
+    vars.capacity() * std::mem::size_of::<(String, EvalString<String>)>()
+        + vars
+            .iter()
+            .map(|(k, v)| k.capacity() + v.heap_size())
+            .sum::<usize>()
+}
+
+/// Sizes of a loaded graph, to put numbers on performance reports.
+#[derive(Debug, Default)]
+pub struct GraphStats {
+    pub files: usize,
+    pub builds: usize,
+    /// Links from builds to their inputs and outputs.
+    pub edges: usize,
+    /// Build counts per rule, most used first.
+    pub builds_by_rule: Vec<(String, usize)>,
+    /// The memory the graph has allocated, in bytes.  Hash tables' control
+    /// bytes and spare buckets are approximated.
+    pub memory: usize,
+}
+
+/// The path a build with atomic_outputs writes the output name to before it
+/// is renamed into place.
+pub fn temp_output(name: &str) -> String {
+    format!("{}.n2tmp", name)
+}
+
+/// Remove a build output.  A directory is removed along with its contents
+/// only if it's a tree, the output of a build with dir_outputs; otherwise only
+/// an empty one can be removed.  An output that's already gone is fine.
+pub fn remove_output(path: &Path, tree: bool) -> std::io::Result<()> {
+    let result = match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() && tree => std::fs::remove_dir_all(path),
+        Ok(meta) if meta.is_dir() => std::fs::remove_dir(path),
+        Ok(_) => std::fs::remove_file(path),
+        Err(err) => Err(err),
+    };
+    match result {
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// The number of byte insertions, deletions, and substitutions needed to turn
+/// a into b (the Levenshtein distance).
+pub fn edit_distance(a: &[u8], b: &[u8]) -> usize {
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, &ca) in a.iter().enumerate() {
+        let mut diag = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitute = diag + (ca != cb) as usize;
+            diag = row[j + 1];
+            row[j + 1] = substitute.min(row[j] + 1).min(diag + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Match a path against a glob pattern, where `*` matches anything within
+/// one path component, `**` matches across components, and `?` matches any
+/// one byte other than a separator.
+pub fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => match rest.strip_prefix(b"*") {
+            Some(rest) => {
+                // "**/" may also match no directories at all.
+                rest.strip_prefix(b"/")
+                    .is_some_and(|after| glob_match(after, name))
+                    || (0..=name.len()).any(|i| glob_match(rest, &name[i..]))
+            }
+            None => {
+                let component = name.iter().position(|&c| c == b'/').unwrap_or(name.len());
+                (0..=component).any(|i| glob_match(rest, &name[i..]))
+            }
+        },
+        Some((b'?', rest)) => {
+            name.first().is_some_and(|&c| c != b'/') && glob_match(rest, &name[1..])
+        }
+        Some((c, rest)) => name.first() == Some(c) && glob_match(rest, &name[1..]),
+    }
 }
 
 impl GraphFiles {
-    /// Look up a file by its name.  Name must have been canonicalized already.
-    pub fn lookup(&self, file: &str) -> Option<FileId> {
-        self.by_name.get(file).copied()
+    /// Enable case folding of file names; see FileNames::fold_case.
+    /// Must be called before any files are added or the names are shared.
+    pub fn set_fold_case(&mut self, fold_case: bool) {
+        let names = Arc::get_mut(&mut self.names).expect("file names already shared");
+        assert!(names.is_empty());
+        names.fold_case = fold_case;
     }
 
-    /// Look up a file by its name, adding it if not already present.
-    /// Name must have been canonicalized already. Only accepting an owned
-    /// string allows us to avoid a string copy and a hashmap lookup when we
-    /// need to create a new id, but would also be possible to create a version
-    /// of this function that accepts string references that is more optimized
-    /// for the case where the entry already exists. But so far, all of our
-    /// usages of this function have an owned string easily accessible anyways.
-    pub fn id_from_canonical(&mut self, file: String) -> FileId {
-        // TODO: so many string copies :<
-        match self.by_name.entry(file) {
-            Entry::Occupied(o) => *o.get(),
-            Entry::Vacant(v) => {
-                let id = self.by_id.push(File {
-                    name: v.key().clone(),
+    /// The name to id map, for threads to add files to concurrently.
+    pub fn names(&self) -> Arc<FileNames> {
+        self.names.clone()
+    }
+
+    /// Add the files added through names() since the last call to by_id,
+    /// which must happen before their ids are looked up there.
+    pub fn sync(&mut self) {
+        let by_id = &mut self.by_id;
+        let mut add = |added: &mut AddedNames| {
+            for name in added.names.drain(..) {
+                by_id.push(File {
+                    name,
                     input: None,
                     dependents: Vec::new(),
                 });
+            }
+            debug_assert_eq!(by_id.next_id(), FileId::from(added.next_id));
+        };
+        match Arc::get_mut(&mut self.names) {
+            Some(names) => add(names.added.get_mut().unwrap()),
+            None => add(&mut self.names.added.lock().unwrap()),
+        }
+    }
+
+    /// Look up a file by its name.  Name must have been canonicalized already.
+    pub fn lookup(&self, file: &str) -> Option<FileId> {
+        self.names.lookup(file)
+    }
+
+    /// Give a file a new name, keeping the old one as an alias for it.
+    /// Fails if the new name is already another file's.
+    fn rename(&mut self, id: FileId, name: String) -> anyhow::Result<()> {
+        let names = Arc::get_mut(&mut self.names).expect("file names already shared");
+        let key = match names.fold_case {
+            true => fold_name(&name),
+            false => name.clone(),
+        };
+        let shard = names.shards[shard_index(&key)].get_mut().unwrap();
+        match shard.entry(key) {
+            Entry::Occupied(o) if *o.get() != id => anyhow::bail!(
+                "output {:?} moves to {:?}, which is already another file",
+                self.by_id[id].name,
+                name
+            ),
+            Entry::Occupied(_) => {}
+            Entry::Vacant(v) => {
                 v.insert(id);
-                id
             }
         }
+        self.by_id[id].name = name;
+        Ok(())
+    }
+
+    /// Look up a file by its name, adding it if not already present.
+    /// Name must have been canonicalized already.
+    pub fn id_from_canonical(&mut self, file: String) -> FileId {
+        if let Some(names) = Arc::get_mut(&mut self.names) {
+            // Not shared, as while loading, so there's no need to lock.
+            return names.intern_unshared(file, &mut self.by_id);
+        }
+        let id = self.names.intern(file);
+        if id.0 >= self.by_id.next_id().0 {
+            self.sync();
+        }
+        id
     }
 
     pub fn all_ids(&self) -> impl Iterator<Item = FileId> {
         (0..self.by_id.next_id().0).map(|id| FileId(id))
     }
+
+    /// Find the generated files whose names match a glob_match pattern,
+    /// sorted by name.
+    pub fn glob(&self, pattern: &str) -> Vec<FileId> {
+        let mut ids: Vec<FileId> = self
+            .all_ids()
+            .filter(|&id| {
+                let file = &self.by_id[id];
+                file.input.is_some() && glob_match(pattern.as_bytes(), file.name.as_bytes())
+            })
+            .collect();
+        ids.sort_by(|&a, &b| self.by_id[a].name.cmp(&self.by_id[b].name));
+        ids
+    }
+
+    /// Find the generated file (including phony aliases) whose name is
+    /// closest to a mistyped name, if any is close enough to suggest.
+    pub fn suggest(&self, name: &str) -> Option<&str> {
+        // As in ninja.
+        const MAX_DISTANCE: usize = 3;
+        self.all_ids()
+            .map(|id| &self.by_id[id])
+            .filter(|file| file.input.is_some())
+            .map(|file| {
+                let distance = edit_distance(name.as_bytes(), file.name.as_bytes());
+                (distance, file.name.as_str())
+            })
+            .filter(|&(distance, _)| distance <= MAX_DISTANCE && distance < name.len())
+            .min()
+            .map(|(_, name)| name)
+    }
+
+    /// The error for a requested target that isn't in the graph.
+    pub fn unknown_path(&self, name: &str) -> anyhow::Error {
+        match self.suggest(&to_owned_canon_path(name)) {
+            Some(suggestion) => anyhow::anyhow!(
+                "unknown path requested: {:?}, did you mean {:?}?",
+                name,
+                suggestion
+            ),
+            None => anyhow::anyhow!("unknown path requested: {:?}", name),
+        }
+    }
+
+    /// Find pairs of files whose names differ only by case (or separator),
+    /// which refer to the same file on case-insensitive filesystems.
+    pub fn case_conflicts(&self) -> Vec<(FileId, FileId)> {
+        let mut folded: FxHashMap<String, FileId> = FxHashMap::default();
+        let mut conflicts = Vec::new();
+        for id in self.all_ids() {
+            match folded.entry(fold_name(&self.by_id[id].name)) {
+                Entry::Occupied(o) => conflicts.push((*o.get(), id)),
+                Entry::Vacant(v) => {
+                    v.insert(id);
+                }
+            }
+        }
+        conflicts
+    }
+}
+
+/// The key under which a file name is stored when folding case.
+fn fold_name(name: &str) -> String {
+    name.to_lowercase().replace('\\', "/")
+}
+
+/// Reports whether the filesystem containing dir is case-insensitive, by
+/// creating a probe file there and looking it up with different case.
+pub fn probe_case_insensitive(dir: &Path) -> std::io::Result<bool> {
+    let probe = dir.join(".n2_case_probe");
+    std::fs::write(&probe, "")?;
+    let result = std::fs::metadata(dir.join(".N2_CASE_PROBE")).is_ok();
+    std::fs::remove_file(&probe)?;
+    Ok(result)
 }
 
 /// MTime info gathered for a file.  This also models "file is absent".
@@ -342,7 +967,44 @@ pub enum MTime {
 pub fn stat(path: &Path) -> std::io::Result<MTime> {
     // TODO: On Windows, use FindFirstFileEx()/FindNextFile() to get timestamps per
     //       directory, for better stat perf.
-    Ok(match std::fs::metadata(path) {
+    mtime(std::fs::metadata(path))
+}
+
+/// lstat() an on-disk path: like stat(), but if the path is a symlink, it
+/// produces the MTime of the link itself.  Used for build outputs, so that a
+/// build generating a symlink isn't dirty when the link's target is missing
+/// or older.
+pub fn lstat(path: &Path) -> std::io::Result<MTime> {
+    mtime(std::fs::symlink_metadata(path))
+}
+
+/// Which of stat() and lstat() to read a file's mtime with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum StatKind {
+    Stat,
+    Lstat,
+}
+
+/// Round an mtime down to a multiple of granularity, so that stamps from
+/// filesystems that store coarse times (e.g. FAT, some NFS) compare equal
+/// whether or not they've been truncated yet.
+pub fn truncate_mtime(mtime: SystemTime, granularity: Duration) -> SystemTime {
+    let granularity = granularity.as_nanos();
+    if granularity <= 1 {
+        return mtime;
+    }
+    match mtime.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(since) => {
+            let nanos = since.as_nanos();
+            SystemTime::UNIX_EPOCH + Duration::from_nanos((nanos - nanos % granularity) as u64)
+        }
+        // Before the epoch; not worth handling.
+        Err(_) => mtime,
+    }
+}
+
+fn mtime(meta: std::io::Result<std::fs::Metadata>) -> std::io::Result<MTime> {
+    Ok(match meta {
         Ok(meta) => MTime::Stamp(meta.modified().unwrap()),
         Err(err) => {
             if err.kind() == std::io::ErrorKind::NotFound {
@@ -354,23 +1016,339 @@ pub fn stat(path: &Path) -> std::io::Result<MTime> {
     })
 }
 
+/// FNV-1a, for the digests of directory outputs and file contents that build
+/// hashes include.  As those are recorded in the db, they mustn't change with
+/// the Rust version, as DefaultHasher's may.
+struct StableHasher(u64);
+
+impl Default for StableHasher {
+    fn default() -> Self {
+        StableHasher(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for StableHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// A directory's own mtime only changes when entries are added to or removed
+/// from it directly, not when something deeper down changes.  For a directory
+/// output, find the newest mtime of anything within it, and a digest of the
+/// names and mtimes of everything within it, which changes when anything is
+/// rewritten, added, or removed, even with an older mtime (as e.g. unzip
+/// restores).  Returns None if path isn't a directory.
+fn tree_stamp(path: &Path, granularity: Duration) -> std::io::Result<Option<(SystemTime, u64)>> {
+    let meta = std::fs::metadata(path)?;
+    if !meta.is_dir() {
+        return Ok(None);
+    }
+    let mut newest = truncate_mtime(meta.modified()?, granularity);
+    let mut entries = Vec::new();
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(path.join(&dir))? {
+            let entry = entry?;
+            // Not following symlinks, which may point back up the tree.
+            let meta = entry.metadata()?;
+            let name = dir.join(entry.file_name());
+            let mtime = truncate_mtime(meta.modified()?, granularity);
+            newest = newest.max(mtime);
+            if meta.is_dir() {
+                dirs.push(name.clone());
+            }
+            entries.push((name, mtime));
+        }
+    }
+    entries.sort();
+    // Hashed byte by byte rather than through Hash, whose output for paths
+    // and times is also free to change.
+    let mut hasher = StableHasher::default();
+    for (name, mtime) in &entries {
+        hasher.write(name.as_os_str().as_encoded_bytes());
+        hasher.write_u8(0);
+        let nanos = mtime
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |since| since.as_nanos());
+        hasher.write(&nanos.to_le_bytes());
+    }
+    Ok(Some((newest, hasher.finish())))
+}
+
+/// Digest the contents of a file, or return None for a directory.
+fn digest_contents(path: &Path) -> std::io::Result<Option<u64>> {
+    use std::io::Read;
+    if std::fs::metadata(path)?.is_dir() {
+        return Ok(None);
+    }
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = StableHasher::default();
+    let mut buf = vec![0; 64 << 10];
+    loop {
+        match file.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => hasher.write(&buf[..n]),
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(Some(hasher.finish()))
+}
+
 /// Gathered state of on-disk files.
 /// Due to discovered deps this map may grow after graph initialization.
-pub struct FileState(DenseMap<FileId, Option<MTime>>);
+pub struct FileState {
+    mtimes: DenseMap<FileId, Option<MTime>>,
+    /// Stamps are truncated to this; see truncate_mtime.
+    granularity: Duration,
+    /// Outputs of builds with dir_outputs, which are stat()ed as trees; see
+    /// add_dir_outputs.
+    trees: HashSet<FileId>,
+    /// For those of trees that are directories, digests of their contents;
+    /// see tree_stamp.
+    digests: HashMap<FileId, u64>,
+    /// Directories within which files are hashed by their contents rather
+    /// than their mtimes; see set_content_roots.
+    content_roots: Vec<String>,
+    /// For files within content_roots, digests of their contents.
+    contents: HashMap<FileId, u64>,
+    /// mtimes read ahead of time, each used by the next stat() or lstat() of
+    /// its file in place of reading it again; see prefetch.
+    prefetched: HashMap<(FileId, StatKind), std::io::Result<MTime>>,
+}
 
 impl FileState {
-    pub fn new(graph: &Graph) -> Self {
-        FileState(DenseMap::new_sized(graph.files.by_id.next_id(), None))
+    pub fn new(graph: &Graph, granularity: Duration) -> Self {
+        FileState {
+            mtimes: DenseMap::new_sized(graph.files.by_id.next_id(), None),
+            granularity,
+            trees: HashSet::new(),
+            digests: HashMap::new(),
+            content_roots: Vec::new(),
+            contents: HashMap::new(),
+            prefetched: HashMap::new(),
+        }
+    }
+
+    /// Hash the files stat()ed within these directories by their contents,
+    /// so that rewriting a file's mtime alone doesn't make its dependents
+    /// dirty, as when a tree is unpacked with normalized timestamps.  Paths
+    /// are canonical, and "." covers every relative path.
+    pub fn set_content_roots(&mut self, roots: Vec<String>) {
+        self.content_roots = roots;
+    }
+
+    fn within_content_roots(&self, path: &Path) -> bool {
+        self.content_roots.iter().any(|root| {
+            if root == "." {
+                path.is_relative()
+            } else {
+                path.starts_with(root)
+            }
+        })
+    }
+
+    /// Supply mtimes read elsewhere, e.g. on another thread, for the stat()s
+    /// and lstat()s of a build check that follows.  Trees are always read
+    /// afresh.
+    pub fn prefetch(&mut self, mtimes: Vec<(FileId, StatKind, std::io::Result<MTime>)>) {
+        for (id, kind, mtime) in mtimes {
+            if !self.trees.contains(&id) {
+                self.prefetched.insert((id, kind), mtime);
+            }
+        }
+    }
+
+    /// Drop the prefetched mtimes that weren't used, so later stat()s read
+    /// files as they are then.
+    pub fn clear_prefetched(&mut self) {
+        self.prefetched.clear();
+    }
+
+    /// Whether a file is stat()ed as a tree; see add_dir_outputs.
+    pub fn is_tree(&self, id: FileId) -> bool {
+        self.trees.contains(&id)
+    }
+
+    /// Read a file's mtime, or take the prefetched one.
+    fn read(&mut self, id: FileId, kind: StatKind, path: &Path) -> std::io::Result<MTime> {
+        if !self.prefetched.is_empty() {
+            if let Some(mtime) = self.prefetched.remove(&(id, kind)) {
+                return mtime;
+            }
+        }
+        match kind {
+            StatKind::Stat => stat(path),
+            StatKind::Lstat => lstat(path),
+        }
+    }
+
+    /// Note the outputs of a build with dir_outputs, which is only known once
+    /// it's evaluated, to stat() them as trees from then on.
+    pub fn add_dir_outputs(&mut self, build: &Build) {
+        if build.dir_outputs {
+            self.trees.extend(build.outs());
+        }
     }
 
     pub fn get(&self, id: FileId) -> Option<MTime> {
-        self.0.lookup(id).copied().unwrap_or(None)
+        self.mtimes.lookup(id).copied().unwrap_or(None)
+    }
+
+    fn set(&mut self, id: FileId, mut mtime: MTime) -> MTime {
+        if let MTime::Stamp(t) = &mut mtime {
+            *t = truncate_mtime(*t, self.granularity);
+        }
+        self.mtimes.set_grow(id, Some(mtime), None);
+        mtime
+    }
+
+    /// The digest of a directory output's contents, as of its last stat.
+    pub fn digest(&self, id: FileId) -> Option<u64> {
+        self.digests.get(&id).copied()
+    }
+
+    /// The digest of the contents of a file within the content roots, as of
+    /// its last stat.  When present it stands in for the file's mtime.
+    pub fn content_digest(&self, id: FileId) -> Option<u64> {
+        self.contents.get(&id).copied()
+    }
+
+    /// For a file within the content roots, note the digest of its contents.
+    fn stat_contents(&mut self, id: FileId, path: &Path, mtime: MTime) -> anyhow::Result<()> {
+        self.contents.remove(&id);
+        if mtime == MTime::Missing || self.trees.contains(&id) || !self.within_content_roots(path) {
+            return Ok(());
+        }
+        if let Some(digest) =
+            digest_contents(path).map_err(|err| anyhow::anyhow!("read {:?}: {}", path, err))?
+        {
+            self.contents.insert(id, digest);
+        }
+        Ok(())
+    }
+
+    /// For a directory output, stamp it with the newest mtime within it and
+    /// note the digest of its contents.
+    fn stat_tree(&mut self, id: FileId, path: &Path, mtime: MTime) -> anyhow::Result<MTime> {
+        if !self.trees.contains(&id) {
+            return Ok(mtime);
+        }
+        self.digests.remove(&id);
+        if mtime == MTime::Missing {
+            return Ok(mtime);
+        }
+        match tree_stamp(path, self.granularity)
+            .map_err(|err| anyhow::anyhow!("stat {:?}: {}", path, err))?
+        {
+            Some((newest, digest)) => {
+                self.digests.insert(id, digest);
+                Ok(MTime::Stamp(newest))
+            }
+            None => Ok(mtime),
+        }
     }
 
     pub fn stat(&mut self, id: FileId, path: &Path) -> anyhow::Result<MTime> {
-        let mtime = stat(path).map_err(|err| anyhow::anyhow!("stat {:?}: {}", path, err))?;
-        self.0.set_grow(id, Some(mtime), None);
-        Ok(mtime)
+        let mtime = self
+            .read(id, StatKind::Stat, path)
+            .map_err(|err| anyhow::anyhow!("stat {:?}: {}", path, err))?;
+        let mtime = self.stat_tree(id, path, mtime)?;
+        self.stat_contents(id, path, mtime)?;
+        Ok(self.set(id, mtime))
+    }
+
+    /// Like stat(), but for build outputs; see lstat().
+    pub fn lstat(&mut self, id: FileId, path: &Path) -> anyhow::Result<MTime> {
+        let mtime = self
+            .read(id, StatKind::Lstat, path)
+            .map_err(|err| anyhow::anyhow!("lstat {:?}: {}", path, err))?;
+        let mtime = self.stat_tree(id, path, mtime)?;
+        // Outputs are always hashed by mtime, as they're written by builds.
+        self.contents.remove(&id);
+        Ok(self.set(id, mtime))
+    }
+
+    /// Fill in the mtimes of source files ahead of time by listing their
+    /// directories, using up to `threads` threads.  Where each stat() is a
+    /// round trip (NFS, some sandboxes), a listing can fetch the attributes of
+    /// many files at once.  Files not found this way are left to be stat()ed
+    /// as usual, as are generated files, which may yet change, and files
+    /// within the content roots, which must be read anyway.
+    pub fn prestat(&mut self, graph: &Graph, ids: &[FileId], threads: usize) {
+        let mut dirs: HashMap<&Path, Vec<(FileId, &std::ffi::OsStr)>> = HashMap::new();
+        for &id in ids {
+            let file = graph.file(id);
+            let path = file.path();
+            if file.input.is_some() || self.get(id).is_some() || self.within_content_roots(path) {
+                continue;
+            }
+            if let (Some(dir), Some(name)) = (path.parent(), path.file_name()) {
+                let dir = if dir.as_os_str().is_empty() {
+                    Path::new(".")
+                } else {
+                    dir
+                };
+                dirs.entry(dir).or_default().push((id, name));
+            }
+        }
+        let dirs: Vec<_> = dirs.into_iter().collect();
+        let next = std::sync::atomic::AtomicUsize::new(0);
+        let found: Vec<(FileId, MTime)> = std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..threads.clamp(1, dirs.len().max(1)))
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut found = Vec::new();
+                        loop {
+                            let i = next.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                            let Some((dir, files)) = dirs.get(i) else {
+                                break;
+                            };
+                            list_mtimes(dir, files, &mut found);
+                        }
+                        found
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .flat_map(|worker| worker.join().unwrap())
+                .collect()
+        });
+        for (id, mtime) in found {
+            self.set(id, mtime);
+        }
+    }
+}
+
+/// Read the mtimes of the named files in dir from a directory listing.
+/// Errors are ignored; files they affect are just not reported.
+fn list_mtimes(dir: &Path, files: &[(FileId, &std::ffi::OsStr)], found: &mut Vec<(FileId, MTime)>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    let wanted: HashMap<&std::ffi::OsStr, FileId> =
+        files.iter().map(|&(id, name)| (name, id)).collect();
+    for entry in entries.flatten() {
+        let Some(&id) = wanted.get(entry.file_name().as_os_str()) else {
+            continue;
+        };
+        // Like stat(), follow symlinks, which the listing doesn't.
+        let meta = match entry.file_type() {
+            Ok(file_type) if file_type.is_symlink() => std::fs::metadata(entry.path()),
+            _ => entry.metadata(),
+        };
+        if let Ok(mtime) = mtime(meta) {
+            found.push((id, mtime));
+        }
     }
 }
 
@@ -415,3 +1393,174 @@ fn stat_mtime_resolution() {
     assert!(diff > Duration::ZERO);
     assert!(diff < Duration::from_millis(100));
 }
+
+#[test]
+fn mtime_truncation() {
+    let t = SystemTime::UNIX_EPOCH + Duration::new(1_700_000_001, 123_456_789);
+    assert_eq!(truncate_mtime(t, Duration::ZERO), t);
+    assert_eq!(
+        truncate_mtime(t, Duration::from_millis(1)),
+        SystemTime::UNIX_EPOCH + Duration::new(1_700_000_001, 123_000_000)
+    );
+    assert_eq!(
+        truncate_mtime(t, Duration::from_secs(2)),
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000)
+    );
+}
+
+#[test]
+fn tree_stamp_contents() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let tree = temp_dir.path().join("tree");
+    std::fs::create_dir_all(tree.join("sub")).unwrap();
+    std::fs::write(tree.join("sub/a"), "a").unwrap();
+    assert_eq!(
+        tree_stamp(&tree.join("sub/a"), Duration::ZERO).unwrap(),
+        None
+    );
+
+    let (newest, digest) = tree_stamp(&tree, Duration::ZERO).unwrap().unwrap();
+    assert_eq!(
+        newest,
+        std::fs::metadata(tree.join("sub/a"))
+            .unwrap()
+            .modified()
+            .unwrap()
+            .max(
+                std::fs::metadata(tree.join("sub"))
+                    .unwrap()
+                    .modified()
+                    .unwrap()
+            )
+    );
+    assert_eq!(
+        tree_stamp(&tree, Duration::ZERO).unwrap().unwrap().1,
+        digest
+    );
+
+    // Rewriting a file deep down, even back in time, changes the digest.
+    let file = std::fs::File::options()
+        .write(true)
+        .open(tree.join("sub/a"))
+        .unwrap();
+    file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(1))
+        .unwrap();
+    assert_ne!(
+        tree_stamp(&tree, Duration::ZERO).unwrap().unwrap().1,
+        digest
+    );
+}
+
+#[test]
+fn stable_hasher() {
+    // Published FNV-1a test vectors.
+    let hash = |bytes: &[u8]| {
+        let mut hasher = StableHasher::default();
+        hasher.write(bytes);
+        hasher.finish()
+    };
+    assert_eq!(hash(b""), 0xcbf29ce484222325);
+    assert_eq!(hash(b"a"), 0xaf63dc4c8601ec8c);
+    assert_eq!(hash(b"foobar"), 0x85944171f73967e8);
+}
+
+#[test]
+fn content_digest_is_stable() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let path = temp_dir.path().join("file");
+    std::fs::write(&path, "foobar").unwrap();
+    assert_eq!(digest_contents(&path).unwrap(), Some(0x85944171f73967e8));
+    assert_eq!(digest_contents(temp_dir.path()).unwrap(), None);
+}
+
+#[test]
+fn case_conflicts() {
+    let mut files = GraphFiles::default();
+    let a = files.id_from_canonical("src/Foo.h".to_owned());
+    files.id_from_canonical("src/bar.h".to_owned());
+    let b = files.id_from_canonical("src/foo.h".to_owned());
+    assert_eq!(files.case_conflicts(), vec![(a, b)]);
+}
+
+#[test]
+fn fold_case() {
+    let mut files = GraphFiles::default();
+    files.set_fold_case(true);
+    let a = files.id_from_canonical("src/Foo.h".to_owned());
+    assert_eq!(files.id_from_canonical("src/foo.h".to_owned()), a);
+    assert_eq!(files.lookup("SRC/FOO.H"), Some(a));
+    assert_eq!(files.by_id[a].name, "src/Foo.h");
+}
+
+#[test]
+fn rename() {
+    let mut files = GraphFiles::default();
+    let a = files.id_from_canonical("out/a.o".to_owned());
+    let b = files.id_from_canonical("base/out/b.o".to_owned());
+    files.rename(a, "base/out/a.o".to_owned()).unwrap();
+    assert_eq!(files.by_id[a].name, "base/out/a.o");
+    assert_eq!(files.lookup("out/a.o"), Some(a));
+    assert_eq!(files.lookup("base/out/a.o"), Some(a));
+    assert!(files.rename(a, "base/out/b.o".to_owned()).is_err());
+    assert_eq!(files.lookup("base/out/b.o"), Some(b));
+}
+
+#[test]
+fn globs() {
+    let matches = |pattern: &str, name: &str| glob_match(pattern.as_bytes(), name.as_bytes());
+    assert!(matches("obj/*.o", "obj/foo.o"));
+    assert!(!matches("obj/*.o", "obj/sub/foo.o"));
+    assert!(matches("obj/**/*.o", "obj/foo.o"));
+    assert!(matches("obj/**/*.o", "obj/sub/dir/foo.o"));
+    assert!(matches("obj/**", "obj/sub/foo.o"));
+    assert!(matches("foo?.o", "foo1.o"));
+    assert!(!matches("foo?.o", "foo/.o"));
+    assert!(!matches("*.o", "foo.obj"));
+}
+
+#[test]
+fn suggestions() {
+    assert_eq!(edit_distance(b"kitten", b"sitting"), 3);
+    assert_eq!(edit_distance(b"", b"abc"), 3);
+    assert_eq!(edit_distance(b"same", b"same"), 0);
+
+    let mut files = GraphFiles::default();
+    for name in ["out/foo", "out/bar", "tests", "src/foo.c"] {
+        let id = files.id_from_canonical(name.to_owned());
+        if !name.starts_with("src") {
+            files.by_id[id].input = Some(BuildId::from(0));
+        }
+    }
+    assert_eq!(files.suggest("out/fo"), Some("out/foo"));
+    assert_eq!(files.suggest("test"), Some("tests"));
+    // Only outputs are suggested.
+    assert_eq!(files.suggest("src/foo.cc"), None);
+    assert_eq!(files.suggest("something/else"), None);
+}
+
+#[test]
+fn concurrent_names() {
+    let mut files = GraphFiles::default();
+    let a = files.id_from_canonical("a".to_owned());
+    let names = files.names();
+    let threads: Vec<_> = (0..4)
+        .map(|t| {
+            let names = names.clone();
+            std::thread::spawn(move || {
+                (0..100)
+                    .map(|i| names.intern(format!("f{}", (i * (t + 1)) % 150)))
+                    .collect::<Vec<_>>()
+            })
+        })
+        .collect();
+    for thread in threads {
+        for id in thread.join().unwrap() {
+            // Files added by other threads are known once synced.
+            files.sync();
+            assert_eq!(files.lookup(&files.by_id[id].name), Some(id));
+        }
+    }
+    assert_eq!(files.id_from_canonical("a".to_owned()), a);
+    let b = files.id_from_canonical("b".to_owned());
+    assert_eq!(files.by_id[b].name, "b");
+}
@@ -30,6 +30,7 @@ trait Manifest {
     );
     fn write_rsp(&mut self, rspfile: &RspFile);
     fn write_cmdline(&mut self, cmdline: &str);
+    fn write_cwd(&mut self, cwd: &str);
 }
 
 fn get_fileid_status<'a>(
@@ -79,7 +80,16 @@ impl Manifest for TerseHash {
         for &id in ids {
             let (name, mtime) = get_fileid_status(files, file_state, id);
             self.write_string(name);
-            mtime.hash(&mut self.0);
+            match file_state.content_digest(id) {
+                Some(digest) => digest.hash(&mut self.0),
+                None => {
+                    mtime.hash(&mut self.0);
+                    // Only directory outputs have one.
+                    if let Some(digest) = file_state.digest(id) {
+                        digest.hash(&mut self.0);
+                    }
+                }
+            }
         }
         self.write_separator();
     }
@@ -89,12 +99,17 @@ impl Manifest for TerseHash {
         self.write_separator();
     }
 
+    fn write_cwd(&mut self, cwd: &str) {
+        self.write_string(cwd);
+        self.write_separator();
+    }
+
     fn write_rsp(&mut self, rspfile: &RspFile) {
         rspfile.hash(&mut self.0);
     }
 }
 
-fn build_manifest<M: Manifest>(
+fn inputs_manifest<M: Manifest>(
     manifest: &mut M,
     files: &GraphFiles,
     file_state: &FileState,
@@ -103,9 +118,22 @@ fn build_manifest<M: Manifest>(
     manifest.write_files("in", files, file_state, build.dirtying_ins());
     manifest.write_files("discovered", files, file_state, build.discovered_ins());
     manifest.write_cmdline(build.cmdline.as_deref().unwrap_or(""));
+    // Only when set, so builds without one keep their hashes.
+    if let Some(cwd) = &build.cwd {
+        manifest.write_cwd(cwd);
+    }
     if let Some(rspfile) = &build.rspfile {
         manifest.write_rsp(rspfile);
     }
+}
+
+fn build_manifest<M: Manifest>(
+    manifest: &mut M,
+    files: &GraphFiles,
+    file_state: &FileState,
+    build: &Build,
+) {
+    inputs_manifest(manifest, files, file_state, build);
     manifest.write_files("out", files, file_state, build.outs());
 }
 
@@ -119,6 +147,14 @@ pub fn hash_build(files: &GraphFiles, file_state: &FileState, build: &Build) ->
     hasher.finish()
 }
 
+/// Like hash_build, but omitting outputs, which may be missing after a failure.
+/// Prerequisite: all inputs have already been stat()ed and are present.
+pub fn hash_inputs(files: &GraphFiles, file_state: &FileState, build: &Build) -> BuildHash {
+    let mut hasher = TerseHash::default();
+    inputs_manifest(&mut hasher, files, file_state, build);
+    hasher.finish()
+}
+
 /// A BuildHasher that records human-readable text for "-d explain" debugging.
 #[derive(Default)]
 struct ExplainHash {
@@ -140,7 +176,12 @@ impl Manifest for ExplainHash {
                 .duration_since(SystemTime::UNIX_EPOCH)
                 .unwrap()
                 .as_millis();
-            writeln!(&mut self.text, "  {millis} {name}").unwrap();
+            match file_state.content_digest(id).or(file_state.digest(id)) {
+                Some(digest) => {
+                    writeln!(&mut self.text, "  {millis} {name} (contents {digest:x})").unwrap()
+                }
+                None => writeln!(&mut self.text, "  {millis} {name}").unwrap(),
+            }
         }
     }
 
@@ -155,6 +196,10 @@ impl Manifest for ExplainHash {
     fn write_cmdline(&mut self, cmdline: &str) {
         writeln!(&mut self.text, "cmdline: {}", cmdline).unwrap();
     }
+
+    fn write_cwd(&mut self, cwd: &str) {
+        writeln!(&mut self.text, "cwd: {}", cwd).unwrap();
+    }
 }
 
 /// Logs human-readable state of all the inputs used for hashing a given build.
@@ -164,3 +209,137 @@ pub fn explain_hash_build(files: &GraphFiles, file_state: &FileState, build: &Bu
     build_manifest(&mut explainer, files, file_state, build);
     explainer.text
 }
+
+/// Hash of a command line, which the db records to tell when it changes.
+pub fn hash_cmdline(cmdline: &str) -> u64 {
+    let mut h = DefaultHasher::new();
+    h.write(cmdline.as_bytes());
+    h.finish()
+}
+
+/// If the command line of a build differs from the one recorded in the db for
+/// its last run, describe the change.  Used for "-d explain" debugging output.
+pub fn explain_cmdline_change(build: &Build) -> Option<String> {
+    let last_hash = build.last_cmdline_hash?;
+    let cmdline = build.cmdline.as_deref().unwrap_or("");
+    if hash_cmdline(cmdline) == last_hash {
+        return None;
+    }
+    Some(match &build.last_cmdline {
+        Some(last) => diff_cmdlines(last, cmdline),
+        None => "the previous one wasn't recorded; build with -d explain to record it".into(),
+    })
+}
+
+/// Past this many cells, the table for a word diff costs more than the detail
+/// is worth, and the changed words are shown as replaced wholesale.
+const MAX_LCS_CELLS: usize = 1 << 20;
+
+/// Render the changes between two command lines word by word, marking
+/// removed words as [-word-] and added words as {+word+}, as in
+/// `git diff --word-diff`.  Runs of unchanged words are elided to keep long
+/// command lines readable.
+pub fn diff_cmdlines(old: &str, new: &str) -> String {
+    let old: Vec<&str> = old.split_whitespace().collect();
+    let new: Vec<&str> = new.split_whitespace().collect();
+
+    // Command lines usually differ in a few flags, so only the words between
+    // the common prefix and suffix need diffing.
+    let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let old_mid = &old[prefix..old.len() - suffix];
+    let new_mid = &new[prefix..new.len() - suffix];
+
+    enum Op<'a> {
+        Same(&'a str),
+        Removed(&'a str),
+        Added(&'a str),
+    }
+    let mut ops: Vec<Op> = old[..prefix].iter().map(|w| Op::Same(w)).collect();
+    if (old_mid.len() + 1).saturating_mul(new_mid.len() + 1) > MAX_LCS_CELLS {
+        ops.extend(old_mid.iter().map(|w| Op::Removed(w)));
+        ops.extend(new_mid.iter().map(|w| Op::Added(w)));
+    } else {
+        let (old, new) = (old_mid, new_mid);
+        // lcs[i][j] is the length of the longest common subsequence of
+        // old[i..] and new[j..].
+        let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+        for i in (0..old.len()).rev() {
+            for j in (0..new.len()).rev() {
+                lcs[i][j] = if old[i] == new[j] {
+                    lcs[i + 1][j + 1] + 1
+                } else {
+                    lcs[i + 1][j].max(lcs[i][j + 1])
+                };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < old.len() || j < new.len() {
+            if i < old.len() && j < new.len() && old[i] == new[j] {
+                ops.push(Op::Same(old[i]));
+                i += 1;
+                j += 1;
+            } else if i < old.len() && (j == new.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+                ops.push(Op::Removed(old[i]));
+                i += 1;
+            } else {
+                ops.push(Op::Added(new[j]));
+                j += 1;
+            }
+        }
+    }
+    ops.extend(old[old.len() - suffix..].iter().map(|w| Op::Same(w)));
+
+    // Keep one word of context on either side of each change.
+    let is_change = |k: usize| matches!(ops.get(k), Some(Op::Removed(_) | Op::Added(_)));
+    let mut words = Vec::new();
+    for (k, op) in ops.iter().enumerate() {
+        match *op {
+            Op::Same(w) if is_change(k + 1) || (k > 0 && is_change(k - 1)) => {
+                words.push(w.to_string())
+            }
+            Op::Same(_) => {
+                if words.last().map(String::as_str) != Some("...") {
+                    words.push("...".to_string());
+                }
+            }
+            Op::Removed(w) => words.push(format!("[-{}-]", w)),
+            Op::Added(w) => words.push(format!("{{+{}+}}", w)),
+        }
+    }
+    words.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_words() {
+        assert_eq!(
+            diff_cmdlines("cc -O2 -c foo.c -o foo.o", "cc -O3 -c foo.c -o foo.o"),
+            "cc [--O2-] {+-O3+} -c ..."
+        );
+        assert_eq!(diff_cmdlines("cc a.c", "cc -g a.c"), "cc {+-g+} a.c");
+        assert_eq!(diff_cmdlines("cc -g a.c", "cc a.c"), "cc [--g-] a.c");
+        assert_eq!(diff_cmdlines("a b c d", "a b x d"), "... b [-c-] {+x+} d");
+        assert_eq!(diff_cmdlines("a b a", "a a"), "a [-b-] a");
+    }
+
+    #[test]
+    fn diff_words_long() {
+        // Too many changed words to diff in detail: shown as replaced.
+        let old = (0..2000).map(|i| format!("o{}", i)).collect::<Vec<_>>();
+        let new = (0..2000).map(|i| format!("n{}", i)).collect::<Vec<_>>();
+        let old = format!("cc {} -c", old.join(" "));
+        let new = format!("cc {} -c", new.join(" "));
+        let diff = diff_cmdlines(&old, &new);
+        assert!(diff.starts_with("cc [-o0-] [-o1-] "), "{}", diff);
+        assert!(diff.ends_with(" {+n1998+} {+n1999+} -c"), "{}", diff);
+    }
+}
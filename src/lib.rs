@@ -1,11 +1,17 @@
 pub mod canon;
+mod config;
+mod cpus;
 mod db;
-mod densemap;
+pub mod densemap;
 mod depfile;
+mod error;
 mod eval;
-mod graph;
+pub mod graph;
 mod hash;
+mod json;
 pub mod load;
+mod lock;
+mod output;
 pub mod parse;
 mod process;
 #[cfg(unix)]
@@ -18,12 +24,20 @@ mod progress_fancy;
 pub mod run;
 pub mod scanner;
 mod signal;
-mod smallmap;
+pub mod smallmap;
+mod sourcedeps;
+mod summary;
 mod task;
+mod tempdir;
 mod terminal;
+mod tools;
 mod trace;
 mod work;
 
+pub use error::Error;
+pub use process::Termination;
+pub use signal::CancelToken;
+
 #[cfg(not(any(miri, windows, target_arch = "wasm32")))]
 use jemallocator::Jemalloc;
 
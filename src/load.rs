@@ -1,67 +1,249 @@
 //! Graph loading: runs .ninja parsing and constructs the build graph from it.
 
 use crate::{
-    canon::{canonicalize_path, to_owned_canon_path},
+    canon::{self, canonicalize_path, to_owned_canon_path, PhysicalCanon},
     db,
-    eval::{self, EvalPart, EvalString},
-    graph::{self, FileId, RspFile},
+    error::Error,
+    eval::{self, EvalPart, EvalString, OwnedVars},
+    graph::{self, BuildId, FileId, RspFile, Rule},
+    lock,
     parse::{self, Statement},
-    scanner,
+    process, scanner,
     smallmap::SmallMap,
     trace,
 };
 use anyhow::{anyhow, bail};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
+use std::rc::Rc;
 use std::{borrow::Cow, path::Path};
 
+/// How paths in $in/$in_newline are quoted, per the `rspfile_quoting`
+/// variable.  Only applies within `rspfile_content`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Quoting {
+    None,
+    /// Quote for a posix shell.
+    Posix,
+    /// Quote as parsed by CommandLineToArgvW.
+    Windows,
+}
+
+impl Quoting {
+    fn quote<'a>(self, path: &'a str) -> Cow<'a, str> {
+        match self {
+            Quoting::None => Cow::Borrowed(path),
+            Quoting::Posix => {
+                let safe = |c: char| c.is_ascii_alphanumeric() || "+-./_:@,=%".contains(c);
+                if !path.is_empty() && path.chars().all(safe) {
+                    return Cow::Borrowed(path);
+                }
+                Cow::Owned(format!("'{}'", path.replace('\'', "'\\''")))
+            }
+            Quoting::Windows => {
+                if !path.is_empty() && !path.contains([' ', '\t', '"']) {
+                    return Cow::Borrowed(path);
+                }
+                let mut out = String::from('"');
+                let mut backslashes = 0;
+                for c in path.chars() {
+                    match c {
+                        '\\' => backslashes += 1,
+                        '"' => {
+                            // Backslashes before a quote must be escaped, as must the quote.
+                            out.extend(std::iter::repeat('\\').take(backslashes * 2 + 1));
+                            backslashes = 0;
+                        }
+                        _ => {
+                            out.extend(std::iter::repeat('\\').take(backslashes));
+                            backslashes = 0;
+                        }
+                    }
+                    if c != '\\' {
+                        out.push(c);
+                    }
+                }
+                // Backslashes before the closing quote must be escaped too.
+                out.extend(std::iter::repeat('\\').take(backslashes * 2));
+                out.push('"');
+                Cow::Owned(out)
+            }
+        }
+    }
+}
+
 /// A variable lookup environment for magic $in/$out variables.
 struct BuildImplicitVars<'a> {
     graph: &'a graph::Graph,
     build: &'a graph::Build,
+    quoting: Quoting,
+    /// Replacement text for $in, used when the inputs were moved into a
+    /// response file because the command line was too long.
+    in_override: Option<&'a str>,
+    /// Value for $rspfile when evaluating `rspfile_template`.
+    rspfile: Option<&'a str>,
+    /// Whether $out names the temporary files the command writes in place of
+    /// its outputs; see Build::atomic_outputs.
+    atomic_outputs: bool,
 }
 impl<'a> BuildImplicitVars<'a> {
+    fn new(graph: &'a graph::Graph, build: &'a graph::Build) -> Self {
+        BuildImplicitVars {
+            graph,
+            build,
+            quoting: Quoting::None,
+            in_override: None,
+            rspfile: None,
+            atomic_outputs: false,
+        }
+    }
+
     fn file_list(&self, ids: &[FileId], sep: char) -> String {
         let mut out = String::new();
         for &id in ids {
             if !out.is_empty() {
                 out.push(sep);
             }
-            out.push_str(&self.graph.file(id).name);
+            out.push_str(&self.quoting.quote(&self.graph.file(id).name));
         }
         out
     }
+
+    fn out_list(&self, sep: char) -> String {
+        if !self.atomic_outputs {
+            return self.file_list(self.build.explicit_outs(), sep);
+        }
+        let temps: Vec<String> = self
+            .build
+            .explicit_outs()
+            .iter()
+            .map(|&id| {
+                let temp = graph::temp_output(&self.graph.file(id).name);
+                self.quoting.quote(&temp).into_owned()
+            })
+            .collect();
+        temps.join(&sep.to_string())
+    }
 }
 impl<'a> eval::Env for BuildImplicitVars<'a> {
     fn get_var(&self, var: &str) -> Option<EvalString<Cow<str>>> {
         let string_to_evalstring =
             |s: String| Some(EvalString::new(vec![EvalPart::Literal(Cow::Owned(s))]));
         match var {
-            "in" => string_to_evalstring(self.file_list(self.build.explicit_ins(), ' ')),
+            "in" => match self.in_override {
+                Some(text) => string_to_evalstring(text.to_owned()),
+                None => string_to_evalstring(self.file_list(self.build.explicit_ins(), ' ')),
+            },
             "in_newline" => string_to_evalstring(self.file_list(self.build.explicit_ins(), '\n')),
-            "out" => string_to_evalstring(self.file_list(self.build.explicit_outs(), ' ')),
-            "out_newline" => string_to_evalstring(self.file_list(self.build.explicit_outs(), '\n')),
+            "rspfile" => string_to_evalstring(self.rspfile?.to_owned()),
+            "out" => string_to_evalstring(self.out_list(' ')),
+            "out_newline" => string_to_evalstring(self.out_list('\n')),
             _ => None,
         }
     }
 }
 
+/// The Ninja version whose build file features n2 aims to support.
+pub const NINJA_COMPAT_VERSION: &str = "1.10.2";
+
+/// Ninja features that n2 doesn't implement, with the Ninja version that
+/// introduced them.
+const UNIMPLEMENTED_FEATURES: &[((u32, u32), &str)] = &[((1, 10), "dyndep")];
+
+/// Parse the major and minor components of a version like "1.10.2".  As in
+/// Ninja, anything after the minor version is ignored.
+fn parse_version(version: &str) -> (u32, u32) {
+    let mut parts = version
+        .split('.')
+        .map(|part| part.parse::<u32>().unwrap_or(0));
+    (parts.next().unwrap_or(0), parts.next().unwrap_or(0))
+}
+
+/// Describes how a file's `ninja_required_version` is newer than the Ninja
+/// n2 is compatible with, if it is.
+fn newer_version_required(filename: &Path, vars: &eval::Vars) -> Option<String> {
+    let required = vars.get("ninja_required_version")?;
+    if parse_version(required) <= parse_version(NINJA_COMPAT_VERSION) {
+        return None;
+    }
+    Some(format!(
+        "{}: ninja_required_version is {}, but n2 is compatible with ninja {}",
+        filename.display(),
+        required,
+        NINJA_COMPAT_VERSION
+    ))
+}
+
+/// Check a file's `ninja_required_version` against what n2 supports.
+/// Requiring a newer Ninja, or one with features n2 lacks, is only a
+/// warning, as the build file may not use anything n2 doesn't implement.
+fn check_required_version(filename: &Path, vars: &eval::Vars) {
+    let Some(required) = vars.get("ninja_required_version") else {
+        return;
+    };
+    if let Some(newer) = newer_version_required(filename, vars) {
+        println!("n2: warn: {}", newer);
+    }
+    let version = parse_version(required);
+    let missing: Vec<&str> = UNIMPLEMENTED_FEATURES
+        .iter()
+        .filter(|(introduced, _)| *introduced <= version)
+        .map(|(_, feature)| *feature)
+        .collect();
+    if !missing.is_empty() {
+        println!(
+            "n2: warn: {}: ninja_required_version is {}, but n2 doesn't implement: {}",
+            filename.display(),
+            required,
+            missing.join(", ")
+        );
+    }
+}
+
+/// Variables for n2 extensions that ninja would reject in a rule and ignore in
+/// a build.  Rather than have build files that use them act differently under
+/// ninja, they're rejected in ninja compatibility mode.
+const NINJA_UNSUPPORTED_VARS: &[&str] = &["hide_success", "hide_progress"];
+
+/// Reads included build files by path, in place of the filesystem; see
+/// read_from_bytes.
+type Resolver<'a> = Box<dyn FnMut(&Path) -> std::io::Result<Vec<u8>> + 'a>;
+
 /// Internal state used while loading.
 #[derive(Default)]
-pub struct Loader {
+pub struct Loader<'a> {
     graph: graph::Graph,
     default: Vec<FileId>,
     /// rule name -> list of (key, val)
-    rules: HashMap<String, SmallMap<String, eval::EvalString<String>>>,
+    rules: HashMap<String, Rc<Rule>>,
     pools: SmallMap<String, usize>,
     builddir: Option<String>,
+    /// When set, paths are canonicalized physically; see PhysicalCanon.
+    physical: Option<PhysicalCanon>,
+    /// Resolve `${env.NAME}` from the environment; see Vars::use_process_env.
+    process_env: bool,
+    /// Reject variables ninja doesn't support; see NINJA_UNSUPPORTED_VARS.
+    ninja_compat: bool,
+    /// Every build file read, including includes and subninjas.
+    manifests: Vec<FileId>,
+    /// Subninjas that didn't exist when loaded.  Those a build generates are
+    /// built, and then everything is loaded again; see State::pending_subninjas.
+    missing_subninjas: Vec<FileId>,
+    /// When set, build files are read through this rather than from disk.
+    resolver: Option<Resolver<'a>>,
 }
 
-impl Loader {
+impl<'a> Loader<'a> {
     pub fn new() -> Self {
         let mut loader = Loader::default();
 
-        loader.rules.insert("phony".to_owned(), SmallMap::default());
+        loader.rules.insert(
+            "phony".to_owned(),
+            Rc::new(Rule {
+                name: "phony".to_owned(),
+                vars: SmallMap::default(),
+            }),
+        );
 
         loader
     }
@@ -71,7 +253,10 @@ impl Loader {
         // Perf: this is called while parsing build.ninja files.  We go to
         // some effort to avoid allocating in the common case of a path that
         // refers to a file that is already known.
-        canonicalize_path(&mut path);
+        match &mut self.physical {
+            Some(physical) => physical.canonicalize(&mut path),
+            None => canonicalize_path(&mut path),
+        }
         self.graph.files.id_from_canonical(path)
     }
 
@@ -94,88 +279,78 @@ impl Loader {
         &mut self,
         filename: std::rc::Rc<PathBuf>,
         env: &eval::Vars,
+        scope: Rc<OwnedVars>,
         b: parse::Build,
     ) -> anyhow::Result<()> {
-        let ins = graph::BuildIns {
-            ids: self.evaluate_paths(b.ins, &[&b.vars, env]),
-            explicit: b.explicit_ins,
-            implicit: b.implicit_ins,
-            order_only: b.order_only_ins,
-            // validation is implied by the other counts
-        };
-        let outs = graph::BuildOuts {
-            ids: self.evaluate_paths(b.outs, &[&b.vars, env]),
-            explicit: b.explicit_outs,
+        if self.ninja_compat {
+            if let Some(var) = b
+                .vars
+                .keys()
+                .find(|var| NINJA_UNSUPPORTED_VARS.contains(var))
+            {
+                bail!(
+                    "{}:{}: {} is not supported in ninja compatibility mode",
+                    filename.display(),
+                    b.line,
+                    var
+                );
+            }
+        }
+        let (ins, outs) = trace::profile_scope("evaluate paths", || {
+            let ins = graph::BuildIns {
+                ids: self.evaluate_paths(b.ins, &[&b.vars, env]),
+                explicit: b.explicit_ins,
+                implicit: b.implicit_ins,
+                order_only: b.order_only_ins,
+                // validation is implied by the other counts
+            };
+            let outs = graph::BuildOuts {
+                ids: self.evaluate_paths(b.outs, &[&b.vars, env]),
+                explicit: b.explicit_outs,
+            };
+            (ins, outs)
+        });
+
+        let rule = match self.rules.get(b.rule) {
+            Some(r) => r.clone(),
+            None => bail!("unknown rule {:?}", b.rule),
         };
-        let mut build = graph::Build::new(
+
+        // Rule variables like `command` are evaluated later, only if the build
+        // is needed; see evaluate_build.  Stash what that evaluation needs.
+        let mut vars = SmallMap::default();
+        for (name, val) in b.vars.into_iter() {
+            vars.insert(name.to_owned(), val.into_owned());
+        }
+        let build = graph::Build::new(
             graph::FileLoc {
                 filename,
                 line: b.line,
             },
+            rule,
+            graph::BuildBindings { vars, env: scope },
             ins,
             outs,
         );
 
-        let rule = match self.rules.get(b.rule) {
-            Some(r) => r,
-            None => bail!("unknown rule {:?}", b.rule),
-        };
-
-        let implicit_vars = BuildImplicitVars {
-            graph: &self.graph,
-            build: &build,
-        };
-
-        // temp variable in order to not move all of b into the closure
-        let build_vars = &b.vars;
-        let lookup = |key: &str| -> Option<String> {
-            // Look up `key = ...` binding in build and rule block.
-            // See "Variable scope" in the design notes.
-            Some(match build_vars.get(key) {
-                Some(val) => val.evaluate(&[env]),
-                None => rule.get(key)?.evaluate(&[&implicit_vars, build_vars, env]),
-            })
-        };
-
-        let cmdline = lookup("command");
-        let desc = lookup("description");
-        let depfile = lookup("depfile");
-        let parse_showincludes = match lookup("deps").as_deref() {
-            None => false,
-            Some("gcc") => false,
-            Some("msvc") => true,
-            Some(other) => bail!("invalid deps attribute {:?}", other),
-        };
-        let pool = lookup("pool");
-
-        let rspfile_path = lookup("rspfile");
-        let rspfile_content = lookup("rspfile_content");
-        let rspfile = match (rspfile_path, rspfile_content) {
-            (None, None) => None,
-            (Some(path), Some(content)) => Some(RspFile {
-                path: std::path::PathBuf::from(path),
-                content,
-            }),
-            _ => bail!("rspfile and rspfile_content need to be both specified"),
-        };
-
-        build.cmdline = cmdline;
-        build.desc = desc;
-        build.depfile = depfile;
-        build.parse_showincludes = parse_showincludes;
-        build.rspfile = rspfile;
-        build.pool = pool;
-
-        self.graph.add_build(build)
+        trace::profile_scope("add build", || self.graph.add_build(build))
     }
 
     fn read_file(&mut self, id: FileId) -> anyhow::Result<()> {
+        self.manifests.push(id);
         let path = self.graph.file(id).path().to_path_buf();
-        let bytes = match trace::scope("read file", || scanner::read_file_with_nul(&path)) {
+        let bytes = match &mut self.resolver {
+            Some(resolve) => resolve(&path).map(|mut bytes| {
+                bytes.push(0);
+                bytes
+            }),
+            None => trace::scope("read file", || scanner::read_file_with_nul(&path)),
+        };
+        let bytes = match bytes {
             Ok(b) => b,
             Err(e) => bail!("read {}: {}", path.display(), e),
         };
-        self.parse(path, &bytes)
+        trace::scope("parse", || self.parse(path, &bytes))
     }
 
     fn evaluate_and_read_file(
@@ -191,14 +366,24 @@ impl Loader {
         let filename = std::rc::Rc::new(path);
 
         let mut parser = parse::Parser::new(&bytes);
+        if self.process_env {
+            parser.vars.use_process_env();
+        }
+        // A snapshot of parser.vars, shared by all the builds declared between
+        // changes to the file's variables.
+        let mut scope: Option<(usize, Rc<OwnedVars>)> = None;
 
         loop {
-            let stmt = match parser
-                .read()
-                .map_err(|err| anyhow!(parser.format_parse_error(&filename, err)))?
-            {
-                None => break,
-                Some(s) => s,
+            let stmt = match parser.read() {
+                Ok(None) => break,
+                Ok(Some(s)) => s,
+                Err(err) => {
+                    // Syntax from a newer Ninja is better reported as such.
+                    if let Some(newer) = newer_version_required(&filename, &parser.vars) {
+                        println!("n2: warn: {}", newer);
+                    }
+                    return Err(parser.to_error(&filename, err).into());
+                }
             };
             match stmt {
                 Statement::Include(id) => trace::scope("include", || {
@@ -206,13 +391,34 @@ impl Loader {
                 })?,
                 // TODO: implement scoping for subninja
                 Statement::Subninja(id) => trace::scope("subninja", || {
-                    self.evaluate_and_read_file(id, &[&parser.vars])
+                    let id = self.evaluate_path(id, &[&parser.vars]);
+                    if self.resolver.is_none() && !self.graph.file(id).path().exists() {
+                        // Its build may not have been declared yet, so
+                        // check once everything is loaded.
+                        self.missing_subninjas.push(id);
+                        return Ok(());
+                    }
+                    self.read_file(id)
                 })?,
                 Statement::Default(defaults) => {
                     let evaluated = self.evaluate_paths(defaults, &[&parser.vars]);
                     self.default.extend(evaluated);
                 }
                 Statement::Rule(rule) => {
+                    if self.ninja_compat {
+                        if let Some(var) = rule
+                            .vars
+                            .keys()
+                            .find(|var| NINJA_UNSUPPORTED_VARS.contains(var))
+                        {
+                            bail!(
+                                "{}: rule {}: {} is not supported in ninja compatibility mode",
+                                filename.display(),
+                                rule.name,
+                                var
+                            );
+                        }
+                    }
                     let mut vars: SmallMap<String, eval::EvalString<String>> = SmallMap::default();
                     for (name, val) in rule.vars.into_iter() {
                         // TODO: We should not need to call .into_owned() here
@@ -220,15 +426,38 @@ impl Loader {
                         // memory.
                         vars.insert(name.to_owned(), val.into_owned());
                     }
-                    self.rules.insert(rule.name.to_owned(), vars);
+                    self.rules.insert(
+                        rule.name.to_owned(),
+                        Rc::new(Rule {
+                            name: rule.name.to_owned(),
+                            vars,
+                        }),
+                    );
+                }
+                Statement::Build(build) => {
+                    let generation = parser.vars.generation();
+                    let env = match &scope {
+                        Some((g, env)) if *g == generation => env.clone(),
+                        _ => {
+                            let env = Rc::new(OwnedVars::from(&parser.vars));
+                            scope = Some((generation, env.clone()));
+                            env
+                        }
+                    };
+                    self.add_build(filename.clone(), &parser.vars, env, build)?
                 }
-                Statement::Build(build) => self.add_build(filename.clone(), &parser.vars, build)?,
                 Statement::Pool(pool) => {
                     self.pools.insert(pool.name.to_string(), pool.depth);
                 }
             };
         }
-        self.builddir = parser.vars.get("builddir").cloned();
+        check_required_version(&filename, &parser.vars);
+        if let Some(builddir) = parser.vars.get("builddir") {
+            self.builddir = Some(builddir.clone());
+        }
+        if let Some(patterns) = parser.vars.get("deps_ignore") {
+            self.graph.deps_ignore = patterns.split_whitespace().map(String::from).collect();
+        }
         Ok(())
     }
 }
@@ -240,46 +469,714 @@ pub struct State {
     pub hashes: graph::Hashes,
     pub default: Vec<FileId>,
     pub pools: SmallMap<String, usize>,
+    /// Rules by name, including the builtin phony rule.
+    pub rules: HashMap<String, Rc<Rule>>,
+    /// Build files that changed, appeared, or disappeared since the last
+    /// run, or None if the last run's build files are unknown.
+    pub manifest_changes: Option<Vec<String>>,
+    /// The builddir variable, if set; .n2_db is kept there.
+    pub builddir: Option<String>,
+    /// The path of .n2_db.
+    pub db_path: PathBuf,
+    /// Subninjas that were skipped because they don't exist yet, but are
+    /// outputs of builds.  They must be built and the build files loaded
+    /// again before the graph is complete.
+    pub pending_subninjas: Vec<String>,
+    /// Every build file read, including includes and subninjas.
+    pub manifests: Vec<String>,
+    /// The build files and their mtimes, if they differ from those in the
+    /// db; a build records them with record_manifests().
+    pub unrecorded_manifests: Option<db::Manifests>,
+    /// Held while building, so another n2 doesn't build here at once; see
+    /// Options::take_lock.
+    pub lock: Option<lock::BuildLock>,
+}
+
+impl State {
+    /// Record the build files loaded in the db, for the next run's
+    /// manifest_changes.  Only builds do this, so tools leave the db alone.
+    pub fn record_manifests(&mut self) -> std::io::Result<()> {
+        match self.unrecorded_manifests.take() {
+            Some(manifests) => self.db.write_manifests(&self.graph, manifests),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Compare the build files loaded now against those recorded in the db.
+fn manifest_changes(
+    graph: &graph::Graph,
+    prev: &db::Manifests,
+    now: &db::Manifests,
+) -> Vec<String> {
+    let mut changes = Vec::new();
+    for (id, mtime) in now {
+        match prev.iter().find(|(prev_id, _)| prev_id == id) {
+            Some((_, prev_mtime)) if prev_mtime == mtime => {}
+            Some(_) => changes.push(format!("{} modified", graph.file(*id).name)),
+            None => changes.push(format!("{} added", graph.file(*id).name)),
+        }
+    }
+    for (id, _) in prev {
+        if !now.iter().any(|(now_id, _)| now_id == id) {
+            changes.push(format!("{} no longer loaded", graph.file(*id).name));
+        }
+    }
+    changes
+}
+
+/// Options affecting how files are identified while loading.
+#[derive(Clone, Default)]
+pub struct Options {
+    /// Canonicalize paths with symlinks resolved; see PhysicalCanon.
+    pub physical_paths: bool,
+    /// Probe whether the filesystem is case-insensitive, and if so treat
+    /// file names differing only by case as the same file.
+    pub fold_case: bool,
+    /// Drop the record of failed builds; see work::Options::skip_known_failures.
+    pub forget_failures: bool,
+    /// Expand `${env.NAME}` in build files from the environment.
+    pub env_vars: bool,
+    /// Reject n2 extensions that ninja would ignore or reject, for
+    /// ninja compatibility mode.
+    pub ninja_compat: bool,
+    /// What to do if another n2 is building in the same directory.
+    pub lock: lock::LockMode,
+    /// Lock this file rather than .n2_lock in builddir, from --debug-lock.
+    pub lock_path: Option<PathBuf>,
+    /// Lock the build directory before reading the db, for a build or a
+    /// tool that rewrites the db.  Tools that only read go without, so they
+    /// can run alongside a build, or from within one of its commands.
+    pub take_lock: bool,
+    /// Move build outputs under this directory; see Graph::set_output_base.
+    pub output_base: Option<String>,
+    /// Set aside subninjas that a build generates and that don't exist yet,
+    /// rather than failing; see State::pending_subninjas.
+    pub build_subninjas: bool,
+}
+
+/// Search the parent directories of the working directory for build_filename,
+/// returning the nearest directory containing it.
+pub fn find_up(build_filename: &str) -> std::io::Result<Option<PathBuf>> {
+    let cwd = std::env::current_dir()?;
+    Ok(cwd
+        .ancestors()
+        .skip(1)
+        .find(|dir| dir.join(build_filename).is_file())
+        .map(Path::to_path_buf))
+}
+
+/// Explain a missing top-level build file, guessing at what to do about it
+/// from other files in the directory.
+fn missing_manifest(build_filename: &str) -> String {
+    let mut msg = format!("{} not found", build_filename);
+    let exists = |path: &str| Path::new(path).exists();
+    let hint = if exists("CMakeCache.txt") {
+        "this CMake build directory wasn't configured for ninja; \
+         reconfigure it with cmake -G Ninja"
+            .to_owned()
+    } else if exists("meson-info") {
+        "this Meson build directory is incomplete; run meson setup --reconfigure".to_owned()
+    } else if exists("CMakeLists.txt") {
+        "this looks like a CMake source directory; configure a build directory \
+         with cmake -G Ninja -B build, then run n2 -C build"
+            .to_owned()
+    } else if exists("meson.build") {
+        "this looks like a Meson source directory; configure a build directory \
+         with meson setup build, then run n2 -C build"
+            .to_owned()
+    } else if let Ok(Some(dir)) = find_up(build_filename) {
+        format!(
+            "found {} in {}; pass -C {} or --look-up",
+            build_filename,
+            dir.display(),
+            dir.display()
+        )
+    } else {
+        return msg;
+    };
+    msg.push_str("\n  hint: ");
+    msg.push_str(&hint);
+    msg
 }
 
 /// Load build.ninja/.n2_db and return the loaded build graph and state.
-pub fn read(build_filename: &str) -> anyhow::Result<State> {
+/// Multiple build files are read in order, as if included by one file.
+pub fn read(build_filenames: &[&str], options: &Options) -> anyhow::Result<State> {
+    for build_filename in build_filenames {
+        if !Path::new(build_filename).exists() {
+            bail!("{}", missing_manifest(build_filename));
+        }
+    }
     let mut loader = Loader::new();
-    trace::scope("loader.read_file", || {
-        let id = loader
+    if options.physical_paths {
+        loader.physical = Some(PhysicalCanon::new()?);
+    }
+    loader.process_env = options.env_vars;
+    loader.ninja_compat = options.ninja_compat;
+    // Ninja takes paths as spelled.
+    canon::set_normalize_drives(!options.ninja_compat);
+    let fold_case = options.fold_case && graph::probe_case_insensitive(Path::new("."))?;
+    loader.graph.files.set_fold_case(fold_case);
+    for &build_filename in build_filenames {
+        trace::scope("loader.read_file", || {
+            let id = loader
+                .graph
+                .files
+                .id_from_canonical(to_owned_canon_path(build_filename));
+            loader.read_file(id)
+        })?;
+    }
+    let mut pending_subninjas = Vec::new();
+    for id in std::mem::take(&mut loader.missing_subninjas) {
+        if loader.graph.file(id).input.is_none() {
+            // Nothing generates it; fail as for any unreadable build file.
+            loader.read_file(id)?;
+            continue;
+        }
+        if !options.build_subninjas {
+            bail!(
+                "subninja {} doesn't exist yet; pass --build-subninjas to build it first",
+                loader.graph.file(id).name
+            );
+        }
+        pending_subninjas.push(loader.graph.file(id).name.clone());
+    }
+    if let Some(base) = &options.output_base {
+        // Build files, including those yet to be generated, are read where
+        // the build files name them.
+        let mut keep: HashSet<FileId> = loader.manifests.iter().copied().collect();
+        keep.extend(
+            pending_subninjas
+                .iter()
+                .filter_map(|name| loader.graph.files.lookup(name)),
+        );
+        loader
             .graph
-            .files
-            .id_from_canonical(to_owned_canon_path(build_filename));
-        loader.read_file(id)
-    })?;
+            .set_output_base(base, &keep)
+            .map_err(|err| anyhow!("--output-base: {}", err))?;
+    }
+    let mut db_path = PathBuf::from(".n2_db");
+    if let Some(builddir) = &loader.builddir {
+        db_path = Path::new(&builddir).join(db_path);
+        if let Some(parent) = db_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+    };
+    let lock_path = match &options.lock_path {
+        Some(path) => path.clone(),
+        None => db_path.with_file_name(".n2_lock"),
+    };
+    let lock = match options.take_lock {
+        true => Some(trace::scope("lock", || {
+            lock::acquire(&lock_path, options.lock)
+        })?),
+        false => None,
+    };
     let mut hashes = graph::Hashes::default();
-    let db = trace::scope("db::open", || {
-        let mut db_path = PathBuf::from(".n2_db");
-        if let Some(builddir) = &loader.builddir {
-            db_path = Path::new(&builddir).join(db_path);
-            if let Some(parent) = db_path.parent() {
-                std::fs::create_dir_all(parent)?;
-            }
-        };
+    let mut db = trace::scope("db::open", || {
         db::open(&db_path, &mut loader.graph, &mut hashes)
     })
-    .map_err(|err| anyhow!("load .n2_db: {}", err))?;
+    .map_err(|err| Error::DbCorrupt {
+        msg: err.to_string(),
+    })?;
+    if options.forget_failures {
+        db.forget_failures()?;
+        for id in loader.graph.builds.all_ids() {
+            loader.graph.builds[id].last_failure = None;
+        }
+    }
+
+    let manifest_names = loader
+        .manifests
+        .iter()
+        .map(|&id| loader.graph.file(id).name.clone())
+        .collect();
+    let (manifest_changes, unrecorded_manifests) = trace::scope("stat build files", || {
+        let manifests = loader.graph.stat_manifests(&loader.manifests)?;
+        let manifest_changes = db
+            .recorded_manifests()
+            .map(|prev| manifest_changes(&loader.graph, prev, &manifests));
+        let unchanged = manifest_changes
+            .as_ref()
+            .is_some_and(|changes| changes.is_empty());
+        anyhow::Ok((manifest_changes, (!unchanged).then_some(manifests)))
+    })?;
+
     Ok(State {
         graph: loader.graph,
         db,
         hashes,
         default: loader.default,
         pools: loader.pools,
+        rules: loader.rules,
+        manifest_changes,
+        builddir: loader.builddir,
+        db_path,
+        pending_subninjas,
+        manifests: manifest_names,
+        unrecorded_manifests,
+        lock,
     })
 }
 
-/// Parse a single file's content.
-#[cfg(test)]
-pub fn parse(name: &str, mut content: Vec<u8>) -> anyhow::Result<graph::Graph> {
-    content.push(0);
+/// Parse the value of a boolean variable: unset, empty, "0", or "false" is
+/// false, and "1" or "true" is true.
+fn parse_bool(build: &graph::Build, key: &str, val: Option<String>) -> anyhow::Result<bool> {
+    match val.as_deref() {
+        None | Some("" | "0" | "false") => Ok(false),
+        Some("1" | "true") => Ok(true),
+        Some(other) => bail!(
+            "{}: invalid {} {:?}, expected 0 or 1",
+            build.location,
+            key,
+            other
+        ),
+    }
+}
+
+/// The most characters of a command shown in place of an empty description.
+const MAX_COMMAND_DESC: usize = 80;
+
+/// Shorten a command line to stand in for a description that expanded to
+/// nothing: its first line, cut to MAX_COMMAND_DESC characters.  (A build
+/// without a description shows its whole command, as in Ninja.)
+fn command_as_desc(cmdline: &str) -> String {
+    let line = cmdline.lines().next().unwrap_or("");
+    match line.char_indices().nth(MAX_COMMAND_DESC) {
+        Some((end, _)) => format!("{}...", &line[..end]),
+        None if line.len() < cmdline.trim_end().len() => format!("{}...", line),
+        None => line.to_owned(),
+    }
+}
+
+/// Evaluate the rule-derived attributes of a build (command line, description,
+/// etc.), if it hasn't been already.  This is deferred from load time because
+/// most builds in a large build file aren't needed by any given invocation.
+pub fn evaluate_build(graph: &mut graph::Graph, id: BuildId) -> anyhow::Result<()> {
+    let build = &graph.builds[id];
+    let bindings = match &build.bindings {
+        None => return Ok(()),
+        Some(b) => b,
+    };
+
+    let env = &*bindings.env;
+    let lookup_with = |key: &str, implicit_vars: &BuildImplicitVars| -> Option<String> {
+        // Look up `key = ...` binding in build and rule block.
+        // See "Variable scope" in the design notes.
+        Some(match bindings.vars.get(key) {
+            Some(val) => val.evaluate(&[env]),
+            None => build
+                .rule
+                .vars
+                .get(key)?
+                .evaluate(&[implicit_vars, &bindings.vars, env]),
+        })
+    };
+    let atomic_outputs = parse_bool(
+        build,
+        "atomic_outputs",
+        lookup_with("atomic_outputs", &BuildImplicitVars::new(graph, build)),
+    )?;
+    // With atomic_outputs, $out names the temporary files in every variable
+    // but the description, so that e.g. a depfile named after $out matches.
+    let new_vars = || BuildImplicitVars {
+        atomic_outputs,
+        ..BuildImplicitVars::new(graph, build)
+    };
+    let implicit_vars = new_vars();
+    let lookup = |key: &str| lookup_with(key, &implicit_vars);
+
+    let mut cmdline = lookup("command");
+    let desc_vars = BuildImplicitVars::new(graph, build);
+    let desc = lookup_with("description", &desc_vars);
+    let desc_undefined = match bindings.vars.get("description") {
+        Some(val) => val.undefined_vars(&[env]),
+        None => build
+            .rule
+            .vars
+            .get("description")
+            .map_or_else(Vec::new, |val| {
+                val.undefined_vars(&[&desc_vars, &bindings.vars, env])
+            }),
+    };
+    let depfile = lookup("depfile");
+    let (parse_showincludes, depfile_json) = match lookup("deps").as_deref() {
+        None => (false, false),
+        Some("gcc") => (false, false),
+        Some("msvc") => (true, false),
+        Some("json") => (false, true),
+        Some(other) => bail!("{}: invalid deps attribute {:?}", build.location, other),
+    };
+    let pool = lookup("pool");
+    let command_prefix = lookup("command_prefix");
+    let cwd = lookup("cwd");
+    let dir_outputs = parse_bool(build, "dir_outputs", lookup("dir_outputs"))?;
+    let hide_success = parse_bool(build, "hide_success", lookup("hide_success"))?;
+    let hide_progress = parse_bool(build, "hide_progress", lookup("hide_progress"))?;
+    // As in Ninja, any value marks a generator.
+    let generator = lookup("generator").is_some_and(|val| !val.is_empty());
+    let retries = match lookup("retries") {
+        None => None,
+        Some(val) => Some(
+            val.parse()
+                .map_err(|_| anyhow!("{}: invalid retries {:?}", build.location, val))?,
+        ),
+    };
+    let memory_estimate = match lookup("memory_estimate") {
+        None => 0,
+        Some(val) => val.parse().map_err(|_| {
+            anyhow!(
+                "{}: invalid memory_estimate {:?}, expected megabytes",
+                build.location,
+                val
+            )
+        })?,
+    };
+    let timeout = match lookup("timeout") {
+        None => None,
+        Some(val) => Some(
+            val.parse()
+                .ok()
+                .and_then(|secs| std::time::Duration::try_from_secs_f64(secs).ok())
+                .ok_or_else(|| {
+                    anyhow!(
+                        "{}: invalid timeout {:?}, expected seconds",
+                        build.location,
+                        val
+                    )
+                })?,
+        ),
+    };
+
+    let quoting = match lookup("rspfile_quoting").as_deref() {
+        None | Some("none") => Quoting::None,
+        Some("posix") => Quoting::Posix,
+        Some("windows") => Quoting::Windows,
+        Some(other) => bail!(
+            "{}: invalid rspfile_quoting {:?}, expected posix or windows",
+            build.location,
+            other
+        ),
+    };
+    let rsp_vars = BuildImplicitVars {
+        quoting,
+        ..new_vars()
+    };
+
+    let rspfile_path = lookup("rspfile");
+    let rspfile_content = lookup_with("rspfile_content", &rsp_vars);
+    let mut rspfile = match (rspfile_path, rspfile_content) {
+        (None, None) => None,
+        (Some(path), Some(content)) => Some(RspFile {
+            path: std::path::PathBuf::from(path),
+            content,
+        }),
+        _ => bail!(
+            "{}: rspfile and rspfile_content need to be both specified",
+            build.location
+        ),
+    };
+
+    // If the command is too long to run, move $in into a response file
+    // when the rule says how to refer to one.
+    let too_long = |cmd: &Option<String>| match (cmd, process::MAX_COMMAND_LEN) {
+        (Some(cmd), Some(max)) => cmd.len() > max,
+        _ => false,
+    };
+    if rspfile.is_none() && too_long(&cmdline) {
+        let path = format!("{}.rsp", graph.file(build.outs()[0]).name);
+        let template_vars = BuildImplicitVars {
+            rspfile: Some(&path),
+            ..new_vars()
+        };
+        if let Some(template) = lookup_with("rspfile_template", &template_vars) {
+            let command_vars = BuildImplicitVars {
+                in_override: Some(&template),
+                ..new_vars()
+            };
+            cmdline = lookup_with("command", &command_vars);
+            rspfile = Some(RspFile {
+                content: rsp_vars.file_list(build.explicit_ins(), '\n'),
+                path: std::path::PathBuf::from(path),
+            });
+        }
+    }
+
+    // Builds run concurrently would overwrite each other's rspfiles, just as
+    // with duplicate outputs.  Unlike duplicate outputs this can't be caught
+    // at load time without evaluating every build; instead it's caught once
+    // the second build is needed, still before it can run.
+    if let Some(rspfile) = &rspfile {
+        let key = to_owned_canon_path(rspfile.path.to_string_lossy().as_ref());
+        match graph.rspfiles.get(&key) {
+            Some(&other) if other != id => bail!(
+                "{}: rspfile {:?} is also written by the build at {}",
+                build.location,
+                key,
+                graph.builds[other].location
+            ),
+            Some(_) => {}
+            None => {
+                graph.rspfiles.insert(key, id);
+            }
+        }
+    }
+
+    // A description that refers to undefined variables is likely a mistake
+    // in the generator, which shows as blank progress lines, so report it
+    // (once per rule, as its builds will share it).
+    if !desc_undefined.is_empty() && graph.desc_warned.insert(build.rule.name.clone()) {
+        let vars: Vec<String> = desc_undefined
+            .iter()
+            .map(|var| format!("${}", var))
+            .collect();
+        println!(
+            "n2: warn: {}: description of rule {} refers to undefined {}",
+            build.location,
+            build.rule.name,
+            vars.join(", ")
+        );
+    }
+    let desc = match desc {
+        Some(desc) if desc.trim().is_empty() => cmdline.as_deref().map(command_as_desc),
+        desc => desc,
+    };
+
+    let build = &mut graph.builds[id];
+    build.cmdline = cmdline;
+    build.desc = desc;
+    build.depfile = depfile;
+    build.parse_showincludes = parse_showincludes;
+    build.depfile_json = depfile_json;
+    build.rspfile = rspfile;
+    build.pool = pool;
+    build.command_prefix = command_prefix;
+    build.cwd = cwd;
+    build.retries = retries;
+    build.memory_estimate = memory_estimate;
+    build.timeout = timeout;
+    build.atomic_outputs = atomic_outputs;
+    build.dir_outputs = dir_outputs;
+    build.hide_success = hide_success;
+    build.hide_progress = hide_progress;
+    build.generator = generator;
+    build.bindings = None;
+    Ok(())
+}
+
+/// Load a build graph from memory rather than disk, e.g. for benchmarks,
+/// fuzzing, or tools embedding n2.  content is the top-level build file,
+/// read as if from the path name; the files it includes or subninjas are
+/// read by calling resolve with their paths.  Unlike read(), this doesn't
+/// touch the db or the filesystem.
+pub fn read_from_bytes<'a>(
+    name: &str,
+    content: &[u8],
+    resolve: impl FnMut(&Path) -> std::io::Result<Vec<u8>> + 'a,
+) -> anyhow::Result<graph::Graph> {
+    let mut bytes = Vec::with_capacity(content.len() + 1);
+    bytes.extend_from_slice(content);
+    bytes.push(0);
     let mut loader = Loader::new();
-    trace::scope("loader.read_file", || {
-        loader.parse(PathBuf::from(name), &content)
-    })?;
+    loader.resolver = Some(Box::new(resolve));
+    let id = loader
+        .graph
+        .files
+        .id_from_canonical(to_owned_canon_path(name));
+    loader.manifests.push(id);
+    loader.parse(PathBuf::from(name), &bytes)?;
     Ok(loader.graph)
 }
+
+/// Parse a single file's content.
+#[cfg(test)]
+pub fn parse(name: &str, content: Vec<u8>) -> anyhow::Result<graph::Graph> {
+    read_from_bytes(name, &content, |path| {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("{} not available in tests", path.display()),
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evaluate_with_declaration_scope() -> anyhow::Result<()> {
+        let mut graph = parse(
+            "build.ninja",
+            b"
+var = 1
+rule echo
+  command = echo $var $in
+build a: echo x
+var = 2
+build b: echo y
+"
+            .to_vec(),
+        )?;
+        let (a, b) = (BuildId::from(0), BuildId::from(1));
+        assert!(!graph.builds[a].is_evaluated());
+        assert_eq!(graph.builds[a].cmdline, None);
+
+        evaluate_build(&mut graph, a)?;
+        evaluate_build(&mut graph, b)?;
+        assert_eq!(graph.builds[a].cmdline.as_deref(), Some("echo 1 x"));
+        assert_eq!(graph.builds[b].cmdline.as_deref(), Some("echo 2 y"));
+        Ok(())
+    }
+
+    #[test]
+    fn stats_count_bindings() -> anyhow::Result<()> {
+        let flags = "-Dflag".repeat(1000);
+        let mut graph = parse(
+            "build.ninja",
+            format!("rule cc\n  command = cc $flags $in\nbuild a.o: cc a.c\n  flags = {flags}\n")
+                .into_bytes(),
+        )?;
+        // Before evaluation, the command is held in the build's bindings.
+        let before = graph.stats().memory;
+        assert!(before > flags.len(), "{}", before);
+
+        evaluate_build(&mut graph, BuildId::from(0))?;
+        // After, in its command line.
+        let after = graph.stats().memory;
+        assert!(after > flags.len(), "{}", after);
+        Ok(())
+    }
+
+    #[test]
+    fn description_fallback() -> anyhow::Result<()> {
+        let mut graph = parse(
+            "build.ninja",
+            b"
+rule cc
+  command = cc $in -o $out
+  description = $ccdesc$typo
+build a.o: cc a.c
+  ccdesc = CC a.o
+build b.o: cc b.c
+"
+            .to_vec(),
+        )?;
+        let (a, b) = (BuildId::from(0), BuildId::from(1));
+        evaluate_build(&mut graph, a)?;
+        evaluate_build(&mut graph, b)?;
+        assert_eq!(graph.builds[a].desc.as_deref(), Some("CC a.o"));
+        assert_eq!(graph.builds[b].desc.as_deref(), Some("cc b.c -o b.o"));
+        assert!(graph.desc_warned.contains("cc"));
+
+        assert_eq!(command_as_desc("a\nb"), "a...");
+        let long = "x".repeat(MAX_COMMAND_DESC + 1);
+        assert_eq!(command_as_desc(&long).len(), MAX_COMMAND_DESC + 3);
+        Ok(())
+    }
+
+    #[test]
+    fn versions() {
+        assert_eq!(parse_version("1.10.2"), (1, 10));
+        assert_eq!(parse_version("1.8"), (1, 8));
+        assert!(parse_version("1.9") < parse_version("1.10"));
+        assert_eq!(parse_version("2"), (2, 0));
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(Quoting::None.quote("a b"), "a b");
+        assert_eq!(Quoting::Posix.quote("foo/bar.c"), "foo/bar.c");
+        assert_eq!(Quoting::Posix.quote("a b"), "'a b'");
+        assert_eq!(Quoting::Posix.quote("it's"), "'it'\\''s'");
+        assert_eq!(Quoting::Windows.quote("foo\\bar.c"), "foo\\bar.c");
+        assert_eq!(Quoting::Windows.quote("a b"), "\"a b\"");
+        assert_eq!(Quoting::Windows.quote("a b\\"), "\"a b\\\\\"");
+        assert_eq!(Quoting::Windows.quote("a\\\"b"), "\"a\\\\\\\"b\"");
+    }
+
+    #[test]
+    fn rspfile_quoting() -> anyhow::Result<()> {
+        let mut graph = parse(
+            "build.ninja",
+            b"
+rule link
+  command = link @$out.rsp
+  rspfile = $out.rsp
+  rspfile_content = $in
+  rspfile_quoting = posix
+build out: link a b$ c
+"
+            .to_vec(),
+        )?;
+        let id = BuildId::from(0);
+        evaluate_build(&mut graph, id)?;
+        let rspfile = graph.builds[id].rspfile.as_ref().unwrap();
+        assert_eq!(rspfile.content, "a 'b c'");
+        Ok(())
+    }
+
+    #[test]
+    fn rspfile_collision() -> anyhow::Result<()> {
+        let mut graph = parse(
+            "build.ninja",
+            b"
+rule link
+  command = link @$rsp
+  rspfile = $rsp
+  rspfile_content = $in
+build a: link x
+  rsp = out.rsp
+build b: link y
+  rsp = ./out.rsp
+"
+            .to_vec(),
+        )?;
+        let (a, b) = (BuildId::from(0), BuildId::from(1));
+        evaluate_build(&mut graph, a)?;
+        let err = evaluate_build(&mut graph, b).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "build.ninja:8: rspfile \"out.rsp\" is also written by the build at build.ninja:6"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn read_includes_from_bytes() -> anyhow::Result<()> {
+        let files: HashMap<&str, &str> = [
+            ("rules.ninja", "rule cc\n  command = cc $in -o $out\n"),
+            ("sub/build.ninja", "build b.o: cc b.c\n"),
+        ]
+        .into_iter()
+        .collect();
+        let mut read = Vec::new();
+        let mut graph = read_from_bytes(
+            "build.ninja",
+            b"include rules.ninja\nbuild a.o: cc a.c\nsubninja sub/build.ninja\n",
+            |path| {
+                let name = path.to_str().unwrap().to_owned();
+                let content = files
+                    .get(name.as_str())
+                    .map(|content| content.as_bytes().to_vec());
+                read.push(name);
+                content.ok_or_else(|| std::io::ErrorKind::NotFound.into())
+            },
+        )?;
+        assert_eq!(read, ["rules.ninja", "sub/build.ninja"]);
+        let id = BuildId::from(1);
+        evaluate_build(&mut graph, id)?;
+        assert_eq!(graph.builds[id].cmdline.as_deref(), Some("cc b.c -o b.o"));
+
+        let err = read_from_bytes("build.ninja", b"include missing.ninja\n", |path| {
+            files
+                .get(path.to_str().unwrap())
+                .map(|content| content.as_bytes().to_vec())
+                .ok_or_else(|| std::io::ErrorKind::NotFound.into())
+        })
+        .err()
+        .unwrap();
+        assert!(err.to_string().starts_with("read missing.ninja: "));
+        Ok(())
+    }
+}
@@ -0,0 +1,149 @@
+//! An advisory lock on the build directory, so that two n2s running there
+//! at once don't both write .n2_db and the same outputs.
+//!
+//! The lock is held on .n2_lock next to .n2_db, which also records the pid of
+//! the holder for the benefit of whoever is waiting.  Ninja takes no such
+//! lock, so a concurrent ninja in the same directory can't be detected.
+
+use anyhow::bail;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+
+/// What to do when another n2 holds the lock, from --lock.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LockMode {
+    /// Wait for it to finish.
+    #[default]
+    Wait,
+    /// Fail immediately.
+    Fail,
+}
+
+/// A held lock, released when the last clone is dropped.
+#[derive(Clone)]
+pub struct BuildLock {
+    _file: Arc<File>,
+}
+
+/// Locks held by this process.  Loading the build files again after
+/// regenerating them locks the same file, which must not wait on itself.
+static HELD: Mutex<Vec<(PathBuf, Weak<File>)>> = Mutex::new(Vec::new());
+
+#[cfg(unix)]
+fn lock(file: &File, wait: bool) -> std::io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+    let flags = libc::LOCK_EX | if wait { 0 } else { libc::LOCK_NB };
+    loop {
+        if unsafe { libc::flock(file.as_raw_fd(), flags) } == 0 {
+            return Ok(true);
+        }
+        let err = std::io::Error::last_os_error();
+        match err.kind() {
+            std::io::ErrorKind::WouldBlock => return Ok(false),
+            std::io::ErrorKind::Interrupted => continue,
+            _ => return Err(err),
+        }
+    }
+}
+
+#[cfg(windows)]
+fn lock(file: &File, wait: bool) -> std::io::Result<bool> {
+    use std::os::windows::io::AsRawHandle;
+    use windows_sys::Win32::Foundation::ERROR_LOCK_VIOLATION;
+    use windows_sys::Win32::Storage::FileSystem::{
+        LockFileEx, LOCKFILE_EXCLUSIVE_LOCK, LOCKFILE_FAIL_IMMEDIATELY,
+    };
+    use windows_sys::Win32::System::IO::OVERLAPPED;
+
+    let mut flags = LOCKFILE_EXCLUSIVE_LOCK;
+    if !wait {
+        flags |= LOCKFILE_FAIL_IMMEDIATELY;
+    }
+    unsafe {
+        // Windows locks are mandatory, so lock a byte past the end of the
+        // file, keeping the pid readable by waiters.
+        let mut overlapped = std::mem::zeroed::<OVERLAPPED>();
+        overlapped.Anonymous.Anonymous.OffsetHigh = 1;
+        if LockFileEx(file.as_raw_handle() as _, flags, 0, 1, 0, &mut overlapped) != 0 {
+            return Ok(true);
+        }
+    }
+    let err = std::io::Error::last_os_error();
+    if err.raw_os_error() == Some(ERROR_LOCK_VIOLATION as i32) {
+        return Ok(false);
+    }
+    Err(err)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn lock(_file: &File, _wait: bool) -> std::io::Result<bool> {
+    Ok(true)
+}
+
+/// Lock path, waiting for or failing on another holder according to mode.
+pub fn acquire(path: &Path, mode: LockMode) -> anyhow::Result<BuildLock> {
+    let mut held = HELD.lock().unwrap();
+    held.retain(|(_, file)| file.strong_count() > 0);
+    if let Some(file) = held
+        .iter()
+        .find(|(held_path, _)| held_path == path)
+        .and_then(|(_, file)| file.upgrade())
+    {
+        return Ok(BuildLock { _file: file });
+    }
+
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map_err(|err| anyhow::anyhow!("open {}: {}", path.display(), err))?;
+    if !lock(&file, false)? {
+        let holder = match std::fs::read_to_string(path) {
+            Ok(pid) if !pid.trim().is_empty() => format!("pid {}", pid.trim()),
+            _ => "unknown pid".to_owned(),
+        };
+        if mode == LockMode::Fail {
+            bail!(
+                "another n2 ({}) is building here; {} is locked",
+                holder,
+                path.display()
+            );
+        }
+        println!("n2: waiting for other n2, {}", holder);
+        lock(&file, true)?;
+    }
+    file.set_len(0)?;
+    write!(file, "{}", std::process::id())?;
+
+    let file = Arc::new(file);
+    held.push((path.to_owned(), Arc::downgrade(&file)));
+    Ok(BuildLock { _file: file })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reentrant() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join(".n2_lock");
+        let first = acquire(&path, LockMode::Fail)?;
+        // Taken again by this process, e.g. to reload the build files.
+        let second = acquire(&path, LockMode::Fail)?;
+        assert_eq!(
+            std::fs::read_to_string(&path)?,
+            std::process::id().to_string()
+        );
+        drop(first);
+        drop(second);
+        // Released, so another file handle can take it.
+        let file = File::open(&path)?;
+        assert!(lock(&file, false)?);
+        Ok(())
+    }
+}
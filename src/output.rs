@@ -0,0 +1,224 @@
+//! Console output captured from build tasks.
+//!
+//! Output is normally small and kept in memory, but some commands (e.g. test
+//! runners) can print enormous amounts.  Past a threshold we spill output to
+//! a file in the temporary directory (see tempdir.rs) and keep only its tail
+//! in memory.
+
+use crate::tempdir::TempDir;
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Output larger than this is spilled to disk.
+const SPILL_THRESHOLD: usize = 1 << 20;
+
+/// Amount of spilled output kept in memory, for status display and for
+/// Error::CommandFailed.
+const TAIL_LEN: usize = 16 << 10;
+
+/// A temporary file holding spilled output, deleted when dropped.
+struct SpillFile {
+    path: PathBuf,
+    /// Kept alive until the file is deleted.
+    _dir: Arc<TempDir>,
+}
+
+impl SpillFile {
+    fn create(dir: &Arc<TempDir>) -> std::io::Result<(Self, File)> {
+        let (path, file) = dir.create("output")?;
+        Ok((
+            SpillFile {
+                path,
+                _dir: dir.clone(),
+            },
+            file,
+        ))
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// The complete output of a task.  Cheap to clone.
+#[derive(Clone, Default)]
+pub struct TaskOutput {
+    /// The output if it's in memory, otherwise its last TAIL_LEN bytes.
+    buf: Vec<u8>,
+    spill: Option<Arc<SpillFile>>,
+}
+
+impl TaskOutput {
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// Whether the output was too large to keep in memory.
+    pub fn is_spilled(&self) -> bool {
+        self.spill.is_some()
+    }
+
+    pub fn ends_with_newline(&self) -> bool {
+        self.buf.ends_with(b"\n")
+    }
+
+    /// The end of the output; all of it, unless it was spilled to disk.
+    pub fn tail(&self) -> &[u8] {
+        &self.buf
+    }
+
+    /// Copy the full output to w, streaming it from disk if spilled.
+    pub fn write_to(&self, w: &mut dyn Write) -> std::io::Result<()> {
+        match &self.spill {
+            None => w.write_all(&self.buf),
+            Some(spill) => {
+                let mut f = File::open(&spill.path)?;
+                std::io::copy(&mut f, w)?;
+                Ok(())
+            }
+        }
+    }
+}
+
+impl From<Vec<u8>> for TaskOutput {
+    fn from(buf: Vec<u8>) -> Self {
+        TaskOutput { buf, spill: None }
+    }
+}
+
+/// Append data to out, inserting prefix at the start of each line.
+/// at_line_start carries whether a line was ended across calls.
+fn push_prefixed(out: &mut Vec<u8>, data: &[u8], prefix: &[u8], at_line_start: &mut bool) {
+    for line in data.split_inclusive(|&c| c == b'\n') {
+        if *at_line_start {
+            out.extend_from_slice(prefix);
+        }
+        out.extend_from_slice(line);
+        *at_line_start = line.ends_with(b"\n");
+    }
+}
+
+/// Insert prefix at the start of each line of data.
+pub fn prefix_lines(data: &[u8], prefix: &str) -> Vec<u8> {
+    let mut out = Vec::new();
+    push_prefixed(&mut out, data, prefix.as_bytes(), &mut true);
+    out
+}
+
+/// Accumulates output from a running task into a TaskOutput.
+pub struct OutputCapture {
+    out: TaskOutput,
+    file: Option<File>,
+    /// Where to spill large output, or None to keep everything in memory
+    /// regardless of size, for output that is post-processed after the task
+    /// finishes.
+    spill_dir: Option<Arc<TempDir>>,
+    /// Text to insert at the start of each line, from --prefix-output.
+    prefix: Option<String>,
+    at_line_start: bool,
+}
+
+impl OutputCapture {
+    /// Capture output, spilling it within spill_dir if it grows large, or
+    /// keeping it all in memory if None.
+    pub fn new(spill_dir: Option<Arc<TempDir>>) -> Self {
+        OutputCapture {
+            out: TaskOutput::default(),
+            file: None,
+            spill_dir,
+            prefix: None,
+            at_line_start: true,
+        }
+    }
+
+    pub fn with_prefix(spill_dir: Option<Arc<TempDir>>, prefix: String) -> Self {
+        OutputCapture {
+            prefix: Some(prefix),
+            ..Self::new(spill_dir)
+        }
+    }
+
+    pub fn push(&mut self, data: &[u8]) -> std::io::Result<()> {
+        match &self.prefix {
+            None => self.push_raw(data),
+            Some(prefix) => {
+                let mut buf = Vec::with_capacity(data.len());
+                push_prefixed(&mut buf, data, prefix.as_bytes(), &mut self.at_line_start);
+                self.push_raw(&buf)
+            }
+        }
+    }
+
+    fn push_raw(&mut self, data: &[u8]) -> std::io::Result<()> {
+        let spill_dir = self.spill_dir.as_ref().filter(|_| self.file.is_none());
+        if let Some(dir) = spill_dir.filter(|_| self.out.buf.len() + data.len() > SPILL_THRESHOLD) {
+            let (spill, mut file) = SpillFile::create(dir)?;
+            file.write_all(&self.out.buf)?;
+            self.out.spill = Some(Arc::new(spill));
+            self.file = Some(file);
+        }
+        self.out.buf.extend_from_slice(data);
+        if let Some(file) = &mut self.file {
+            file.write_all(data)?;
+            if self.out.buf.len() > TAIL_LEN {
+                self.out.buf.drain(..self.out.buf.len() - TAIL_LEN);
+            }
+        }
+        Ok(())
+    }
+
+    /// The end of the output so far; see TaskOutput::tail.
+    pub fn tail(&self) -> &[u8] {
+        self.out.tail()
+    }
+
+    pub fn finish(self) -> TaskOutput {
+        self.out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spill() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let spill_dir = Arc::new(TempDir::new(Some(dir.path().to_owned())));
+        let mut capture = OutputCapture::new(Some(spill_dir));
+        let line = [b'x'; 1023]
+            .iter()
+            .chain(b"\n")
+            .copied()
+            .collect::<Vec<_>>();
+        for _ in 0..2048 {
+            capture.push(&line)?;
+        }
+        let out = capture.finish();
+        assert!(out.spill.is_some());
+        assert_eq!(out.tail().len(), TAIL_LEN);
+
+        let mut full = Vec::new();
+        out.write_to(&mut full)?;
+        assert_eq!(full.len(), 2048 * 1024);
+
+        let path = out.spill.as_ref().unwrap().path.clone();
+        assert!(path.exists());
+        drop(out);
+        assert!(!path.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn prefix() -> std::io::Result<()> {
+        let mut capture = OutputCapture::with_prefix(None, "[out] ".into());
+        capture.push(b"a\nb")?;
+        capture.push(b"c\n\nd\n")?;
+        assert_eq!(capture.tail(), b"[out] a\n[out] bc\n[out] \n[out] d\n");
+        Ok(())
+    }
+}
@@ -6,6 +6,7 @@
 //! text, marked with the lifetime `'text`.
 
 use crate::{
+    error::Error,
     eval::{EvalPart, EvalString, Vars},
     scanner::{ParseError, ParseResult, Scanner},
     smallmap::SmallMap,
@@ -37,6 +38,8 @@ pub struct Build<'text> {
 #[derive(Debug)]
 pub struct Pool<'text> {
     pub name: &'text str,
+    /// How many builds in the pool may run at once.  0, as when the pool has
+    /// no depth line, means unbounded.
     pub depth: usize,
 }
 
@@ -70,6 +73,10 @@ impl<'text> Parser<'text> {
         self.scanner.format_parse_error(filename, err)
     }
 
+    pub fn to_error(&self, filename: &Path, err: ParseError) -> Error {
+        self.scanner.to_error(filename, err)
+    }
+
     pub fn read(&mut self) -> ParseResult<Option<Statement<'text>>> {
         loop {
             match self.scanner.peek() {
@@ -156,7 +163,17 @@ impl<'text> Parser<'text> {
                     | "restat"
                     | "rspfile"
                     | "rspfile_content"
+                    | "rspfile_quoting"
+                    | "rspfile_template"
                     | "msvc_deps_prefix"
+                    | "memory_estimate"
+                    | "retries"
+                    | "command_prefix"
+                    | "cwd"
+                    | "atomic_outputs"
+                    | "dir_outputs"
+                    | "hide_success"
+                    | "hide_progress"
             )
         })?;
         Ok(Rule { name, vars })
@@ -165,13 +182,19 @@ impl<'text> Parser<'text> {
     fn read_pool(&mut self) -> ParseResult<Pool<'text>> {
         let name = self.read_ident()?;
         self.scanner.expect('\n')?;
+        let ofs = self.scanner.ofs;
         let vars = self.read_scoped_vars(|var| matches!(var, "depth"))?;
         let mut depth = 0;
         if let Some((_, val)) = vars.into_iter().next() {
-            let val = val.evaluate(&[]);
+            let val = val.evaluate(&[&self.vars]);
             depth = match val.parse::<usize>() {
                 Ok(d) => d,
-                Err(err) => return self.scanner.parse_error(format!("pool depth: {}", err)),
+                Err(_) => {
+                    return self.scanner.parse_error_at(
+                        ofs,
+                        format!("pool depth must be a non-negative integer, got {:?}", val),
+                    )
+                }
             }
         }
         Ok(Pool { name, depth })
@@ -485,6 +508,34 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn parse_pool_depth() {
+        let read = |text: &str| -> Result<usize, String> {
+            let mut buf = test_case_buffer(text);
+            let mut parser = Parser::new(&mut buf);
+            loop {
+                match parser.read() {
+                    Ok(Some(Statement::Pool(pool))) => return Ok(pool.depth),
+                    Ok(_) => {}
+                    Err(err) => {
+                        return Err(parser.format_parse_error(Path::new("build.ninja"), err))
+                    }
+                }
+            }
+        };
+        assert_eq!(read("pool p\n  depth = 4\n"), Ok(4));
+        assert_eq!(read("n = 3\npool p\n  depth = $n\n"), Ok(3));
+        assert_eq!(read("pool p\n  depth = 0\n"), Ok(0));
+        assert_eq!(read("pool p\n"), Ok(0));
+        let err = read("pool p\n  depth = -1\n").unwrap_err();
+        assert!(
+            err.starts_with("parse error: pool depth must be a non-negative integer, got \"-1\"")
+        );
+        assert!(err.contains("build.ninja:2:   depth = -1"));
+        let err = read("pool p\n  depth = $missing\n").unwrap_err();
+        assert!(err.contains("got \"\""));
+    }
+
     #[test]
     fn parse_trailing_newline() {
         test_for_line_endings(
@@ -1,21 +1,220 @@
-//! Exposes process::run_command, a wrapper around platform-native process execution.
+//! Exposes process::run_command, a wrapper around platform-native process execution,
+//! and the CommandRunner trait that builds run commands through.
 
 #[cfg(unix)]
-pub use crate::process_posix::run_command;
+pub use crate::process_posix::{interrupt_group, kill, kill_group, run_command};
 #[cfg(windows)]
-pub use crate::process_win::run_command;
+pub use crate::process_win::{kill, kill_group, run_command};
 
 #[cfg(target_arch = "wasm32")]
 fn run_command(
     cmdline: &str,
+    cwd: Option<&std::path::Path>,
+    console: bool,
+    new_group: bool,
+    spawned_cb: impl FnOnce(u32),
     mut output_cb: impl FnMut(&[u8]),
-) -> anyhow::Result<(Termination, Vec<u8>)> {
+) -> anyhow::Result<(Termination, ResourceUsage)> {
     anyhow::bail!("wasm cannot run commands");
 }
 
-#[derive(Debug, PartialEq)]
+#[cfg(target_arch = "wasm32")]
+pub fn kill(_pid: u32) {}
+
+#[cfg(target_arch = "wasm32")]
+pub fn kill_group(_pid: u32) {}
+
+/// Runs build commands.  Indirected as a trait so that tests can run
+/// FakeRunner's canned commands instead of processes.
+pub trait CommandRunner: Send + Sync {
+    /// Run cmdline, as run_command does: spawned is passed the process id
+    /// once it has started, and output each chunk of its output.
+    fn run(
+        &self,
+        cmdline: &str,
+        cwd: Option<&std::path::Path>,
+        console: bool,
+        new_group: bool,
+        spawned: &mut dyn FnMut(u32),
+        output: &mut dyn FnMut(&[u8]),
+    ) -> anyhow::Result<(Termination, ResourceUsage)>;
+}
+
+/// Runs commands as processes, with run_command.
+pub struct ProcessRunner;
+
+impl CommandRunner for ProcessRunner {
+    fn run(
+        &self,
+        cmdline: &str,
+        cwd: Option<&std::path::Path>,
+        console: bool,
+        new_group: bool,
+        spawned: &mut dyn FnMut(u32),
+        output: &mut dyn FnMut(&[u8]),
+    ) -> anyhow::Result<(Termination, ResourceUsage)> {
+        run_command(cmdline, cwd, console, new_group, spawned, output)
+    }
+}
+
+/// A CommandRunner that runs nothing, but answers each command line with
+/// the output and termination given for it.  It never reports a process id,
+/// so there's nothing for timeouts or kill_all() to kill.
+#[cfg(test)]
+#[derive(Default)]
+pub struct FakeRunner {
+    commands: std::collections::HashMap<String, (Termination, Vec<Vec<u8>>)>,
+    /// The command lines run, in the order they started.
+    pub ran: std::sync::Mutex<Vec<String>>,
+}
+
+#[cfg(test)]
+impl FakeRunner {
+    /// Answer cmdline with termination, after passing on output in the
+    /// chunks given.
+    pub fn add(&mut self, cmdline: &str, termination: Termination, output: &[&str]) {
+        let output = output.iter().map(|chunk| chunk.as_bytes().to_vec());
+        self.commands
+            .insert(cmdline.to_owned(), (termination, output.collect()));
+    }
+}
+
+#[cfg(test)]
+impl CommandRunner for FakeRunner {
+    fn run(
+        &self,
+        cmdline: &str,
+        _cwd: Option<&std::path::Path>,
+        _console: bool,
+        _new_group: bool,
+        _spawned: &mut dyn FnMut(u32),
+        output: &mut dyn FnMut(&[u8]),
+    ) -> anyhow::Result<(Termination, ResourceUsage)> {
+        self.ran.lock().unwrap().push(cmdline.to_owned());
+        let Some((termination, chunks)) = self.commands.get(cmdline) else {
+            anyhow::bail!("no fake command {:?}", cmdline);
+        };
+        for chunk in chunks {
+            output(chunk);
+        }
+        Ok((*termination, ResourceUsage::default()))
+    }
+}
+
+/// A std Command that runs cmdline with the platform's shell, for hooks run
+/// outside the build like --hang-hook.
+pub fn shell_command(cmdline: &str) -> std::process::Command {
+    let mut cmd = if cfg!(windows) {
+        let mut cmd = std::process::Command::new("cmd");
+        cmd.arg("/c");
+        cmd
+    } else {
+        let mut cmd = std::process::Command::new("/bin/sh");
+        cmd.arg("-c");
+        cmd
+    };
+    cmd.arg(cmdline);
+    cmd
+}
+
+/// The longest command line the platform can run, if limited.
+/// On Windows, CreateProcess takes at most 32767 characters including the
+/// trailing nul.
+pub const MAX_COMMAND_LEN: Option<usize> = if cfg!(windows) { Some(32766) } else { None };
+
+/// cmd.exe's builtin commands, which only exist within cmd.
+const CMD_BUILTINS: &[&str] = &[
+    "assoc", "break", "call", "cd", "chdir", "cls", "copy", "date", "del", "dir", "echo",
+    "endlocal", "erase", "exit", "for", "ftype", "goto", "if", "md", "mkdir", "mklink", "move",
+    "path", "pause", "popd", "prompt", "pushd", "rd", "rem", "ren", "rename", "rmdir", "set",
+    "setlocal", "shift", "start", "time", "title", "type", "ver", "verify", "vol",
+];
+
+/// On Windows, commands are passed to CreateProcess as they are, which
+/// avoids cmd's 8191 character limit.  With --windows-shell, commands that
+/// need cmd -- those that start with one of its builtins, or use its &, |, <
+/// or > operators outside of quotes -- run as `cmd /s /c "command"` instead,
+/// which runs command verbatim.  Returns the command line to run.
+pub fn windows_command_line(cmdline: &str) -> std::borrow::Cow<'_, str> {
+    let end = cmdline
+        .find(|c: char| c.is_ascii_whitespace() || matches!(c, '(' | '&' | '|' | '<' | '>'))
+        .unwrap_or(cmdline.len());
+    let program = &cmdline[..end];
+    let file_name = program.rsplit(['\\', '/']).next().unwrap_or(program);
+    if file_name.eq_ignore_ascii_case("cmd") || file_name.eq_ignore_ascii_case("cmd.exe") {
+        return cmdline.into();
+    }
+    // A program named with a path or an extension, e.g. echo.exe, isn't a
+    // builtin, though cmd allows a trailing dot as in `echo.`.
+    let name = program.strip_suffix('.').unwrap_or(program);
+    let builtin = !name.contains(['\\', '/', ':', '.'])
+        && CMD_BUILTINS
+            .iter()
+            .any(|builtin| name.eq_ignore_ascii_case(builtin));
+    let mut quoted = false;
+    let operator = cmdline.chars().any(|c| {
+        if c == '"' {
+            quoted = !quoted;
+        }
+        !quoted && matches!(c, '&' | '|' | '<' | '>')
+    });
+    if builtin || operator {
+        format!("cmd /s /c \"{}\"", cmdline).into()
+    } else {
+        cmdline.into()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Termination {
     Success,
     Interrupted,
     Failure,
+    /// Killed for running past its timeout.
+    TimedOut,
+}
+
+/// Resources consumed by a finished subprocess.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ResourceUsage {
+    /// Peak resident set size, in kilobytes.
+    pub peak_rss_kb: u64,
+    /// User plus system CPU time.
+    pub cpu_time: std::time::Duration,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn windows_shell() {
+        let run = |cmdline| windows_command_line(cmdline).into_owned();
+        // Programs run directly.
+        assert_eq!(run("cl /c foo.c"), "cl /c foo.c");
+        assert_eq!(
+            run("c:\\bin\\link.exe @out.rsp"),
+            "c:\\bin\\link.exe @out.rsp"
+        );
+        assert_eq!(run("tool \"a&b\" \"<x>\""), "tool \"a&b\" \"<x>\"");
+        assert_eq!(run("echoes hi"), "echoes hi");
+        assert_eq!(run("echo.exe hi"), "echo.exe hi");
+        assert_eq!(run("bin\\echo hi"), "bin\\echo hi");
+        assert_eq!(run("./copy a b"), "./copy a b");
+        assert_eq!(
+            run("c:\\windows\\cmd.exe /c x>y"),
+            "c:\\windows\\cmd.exe /c x>y"
+        );
+        assert_eq!(run("cmd /c echo hi"), "cmd /c echo hi");
+        assert_eq!(run("CMD.EXE /c echo hi"), "CMD.EXE /c echo hi");
+        assert_eq!(run(""), "");
+
+        // Builtins and operators need cmd.
+        assert_eq!(run("echo hi"), "cmd /s /c \"echo hi\"");
+        assert_eq!(run("ECHO.>out"), "cmd /s /c \"ECHO.>out\"");
+        assert_eq!(run("copy /y a b"), "cmd /s /c \"copy /y a b\"");
+        assert_eq!(run("echo(hi"), "cmd /s /c \"echo(hi\"");
+        assert_eq!(run("gen \"x\" > out"), "cmd /s /c \"gen \"x\" > out\"");
+        assert_eq!(run("a.exe && b.exe"), "cmd /s /c \"a.exe && b.exe\"");
+    }
 }
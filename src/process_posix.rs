@@ -1,10 +1,12 @@
 //! Implements run_command on posix using posix_spawn.
 //! See run_command comments for why.
 
-use crate::process::Termination;
+use crate::process::{ResourceUsage, Termination};
 use std::io::{Error, Read};
 use std::os::fd::FromRawFd;
+use std::os::unix::ffi::OsStrExt;
 use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
 
 // https://github.com/rust-lang/libc/issues/2520
 // libc crate doesn't expose the 'environ' pointer.
@@ -56,6 +58,15 @@ impl PosixSpawnAttr {
             )
         }
     }
+
+    fn setpgroup(&mut self, pgroup: libc::pid_t) -> anyhow::Result<()> {
+        unsafe {
+            check_posix_spawn(
+                "posix_spawnattr_setpgroup",
+                libc::posix_spawnattr_setpgroup(self.as_ptr(), pgroup),
+            )
+        }
+    }
 }
 
 impl Drop for PosixSpawnAttr {
@@ -115,6 +126,22 @@ impl PosixSpawnFileActions {
         }
     }
 
+    /// Change directory in the child before exec.
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    fn addchdir(&mut self, path: &std::ffi::CStr) -> anyhow::Result<()> {
+        unsafe {
+            check_posix_spawn(
+                "posix_spawn_file_actions_addchdir_np",
+                libc::posix_spawn_file_actions_addchdir_np(self.as_ptr(), path.as_ptr()),
+            )
+        }
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    fn addchdir(&mut self, _path: &std::ffi::CStr) -> anyhow::Result<()> {
+        anyhow::bail!("cwd is not supported on this platform");
+    }
+
     fn addclose(&mut self, fd: i32) -> anyhow::Result<()> {
         unsafe {
             check_posix_spawn(
@@ -150,7 +177,42 @@ fn pipe2() -> anyhow::Result<[libc::c_int; 2]> {
     }
 }
 
-pub fn run_command(cmdline: &str, mut output_cb: impl FnMut(&[u8])) -> anyhow::Result<Termination> {
+/// Ask a running command to exit, as when a build is cancelled.
+pub fn kill(pid: u32) {
+    // Safety: just a syscall; a pid that already exited is harmless.
+    unsafe {
+        libc::kill(pid as libc::pid_t, libc::SIGTERM);
+    }
+}
+
+/// Ask a command started with new_group, and everything it started, to
+/// exit.
+pub fn kill_group(pid: u32) {
+    // Safety: just a syscall.  The process group id is the command's pid.
+    unsafe {
+        libc::kill(-(pid as libc::pid_t), libc::SIGTERM);
+    }
+}
+
+/// Pass on a ctrl-c to a command started with new_group, which the terminal
+/// doesn't deliver to.
+pub fn interrupt_group(pid: u32) {
+    // Safety: as in kill_group.
+    unsafe {
+        libc::kill(-(pid as libc::pid_t), libc::SIGINT);
+    }
+}
+
+/// Run cmdline.  With new_group, the command is started in its own process
+/// group, so that kill_group() reaches any processes it starts too.
+pub fn run_command(
+    cmdline: &str,
+    cwd: Option<&Path>,
+    console: bool,
+    new_group: bool,
+    spawned_cb: impl FnOnce(u32),
+    mut output_cb: impl FnMut(&[u8]),
+) -> anyhow::Result<(Termination, ResourceUsage)> {
     // Spawn the subprocess using posix_spawn with output redirected to the pipe.
     // We don't use Rust's process spawning because of issue #14 and because
     // we want to feed both stdout and stderr into the same pipe, which cannot
@@ -160,16 +222,34 @@ pub fn run_command(cmdline: &str, mut output_cb: impl FnMut(&[u8])) -> anyhow::R
 
         let mut attr = PosixSpawnAttr::new()?;
 
+        #[allow(unused_mut)]
+        let mut flags = 0;
         // Apple-specific extension: close any open fds.
         #[cfg(target_os = "macos")]
-        attr.setflags(libc::POSIX_SPAWN_CLOEXEC_DEFAULT as _)?;
+        {
+            flags |= libc::POSIX_SPAWN_CLOEXEC_DEFAULT as libc::c_short;
+        }
+        if new_group {
+            flags |= libc::POSIX_SPAWN_SETPGROUP as libc::c_short;
+            attr.setpgroup(0)?;
+        }
+        if flags != 0 {
+            attr.setflags(flags)?;
+        }
 
         let mut actions = PosixSpawnFileActions::new()?;
-        // open /dev/null over stdin
-        actions.addopen(0, c"/dev/null", libc::O_RDONLY, 0)?;
-        // stdout/stderr => pipe
-        actions.adddup2(pipe[1], 1)?;
-        actions.adddup2(pipe[1], 2)?;
+        // Console pool jobs inherit our stdio, so they can interact with the
+        // terminal.  Their output is then never seen by the pipe.
+        if !console {
+            // open /dev/null over stdin
+            actions.addopen(0, c"/dev/null", libc::O_RDONLY, 0)?;
+            // stdout/stderr => pipe
+            actions.adddup2(pipe[1], 1)?;
+            actions.adddup2(pipe[1], 2)?;
+        }
+        if let Some(cwd) = cwd {
+            actions.addchdir(&std::ffi::CString::new(cwd.as_os_str().as_bytes())?)?;
+        }
         // close pipe in child
         actions.addclose(pipe[0])?;
         actions.addclose(pipe[1])?;
@@ -202,6 +282,7 @@ pub fn run_command(cmdline: &str, mut output_cb: impl FnMut(&[u8])) -> anyhow::R
 
         (pid, std::fs::File::from_raw_fd(pipe[0]))
     };
+    spawned_cb(pid as u32);
 
     let mut buf: [u8; 4 << 10] = [0; 4 << 10];
     loop {
@@ -213,10 +294,22 @@ pub fn run_command(cmdline: &str, mut output_cb: impl FnMut(&[u8])) -> anyhow::R
     }
     drop(pipe);
 
-    let status = unsafe {
+    let (status, rusage) = unsafe {
         let mut status: i32 = 0;
-        check_ret_errno("waitpid", libc::waitpid(pid, &mut status, 0))?;
-        std::process::ExitStatus::from_raw(status)
+        let mut rusage: libc::rusage = std::mem::zeroed();
+        check_ret_errno("wait4", libc::wait4(pid, &mut status, 0, &mut rusage))?;
+        (std::process::ExitStatus::from_raw(status), rusage)
+    };
+    let timeval =
+        |tv: libc::timeval| std::time::Duration::new(tv.tv_sec as u64, tv.tv_usec as u32 * 1000);
+    // ru_maxrss is in bytes on Mac, kilobytes elsewhere.
+    #[cfg(target_os = "macos")]
+    let peak_rss_kb = rusage.ru_maxrss as u64 / 1024;
+    #[cfg(not(target_os = "macos"))]
+    let peak_rss_kb = rusage.ru_maxrss as u64;
+    let usage = ResourceUsage {
+        peak_rss_kb,
+        cpu_time: timeval(rusage.ru_utime) + timeval(rusage.ru_stime),
     };
 
     let termination = if status.success() {
@@ -236,5 +329,5 @@ pub fn run_command(cmdline: &str, mut output_cb: impl FnMut(&[u8])) -> anyhow::R
         Termination::Failure
     };
 
-    Ok(termination)
+    Ok((termination, usage))
 }
@@ -1,16 +1,19 @@
 //! Implements run_command on Windows using native Windows calls.
 //! See run_command comments for why.
 
-use crate::process::Termination;
+use crate::process::{ResourceUsage, Termination};
 use std::ffi::c_void;
 use std::io::Read;
 use std::os::windows::io::{FromRawHandle, OwnedHandle};
 use std::os::windows::prelude::AsRawHandle;
-use std::pin::{pin, Pin};
+use std::path::Path;
+use std::pin::Pin;
 use windows_sys::Win32::{
     Foundation::*,
     Security::SECURITY_ATTRIBUTES,
-    System::{Console::*, Diagnostics::Debug::*, Pipes::CreatePipe, Threading::*},
+    System::{
+        Console::*, Diagnostics::Debug::*, Pipes::CreatePipe, ProcessStatus::*, Threading::*,
+    },
 };
 
 fn get_error_string(err: u32) -> String {
@@ -153,7 +156,34 @@ impl<'a> Drop for ProcThreadAttributeList<'a> {
     }
 }
 
-pub fn run_command(cmdline: &str, mut output_cb: impl FnMut(&[u8])) -> anyhow::Result<Termination> {
+/// Ask a running command to exit, as when a build is cancelled.
+pub fn kill(pid: u32) {
+    // Safety: plain Win32 calls; failure (e.g. the process already exited)
+    // is ignored.
+    unsafe {
+        let process = OpenProcess(PROCESS_TERMINATE, 0, pid);
+        if process != 0 {
+            TerminateProcess(process, 1);
+            CloseHandle(process);
+        }
+    }
+}
+
+/// Ask a command to exit.  Windows commands aren't started in groups, so this
+/// is just kill().
+pub fn kill_group(pid: u32) {
+    kill(pid);
+}
+
+/// Run cmdline.  new_group only has an effect on posix.
+pub fn run_command(
+    cmdline: &str,
+    cwd: Option<&Path>,
+    console: bool,
+    _new_group: bool,
+    spawned_cb: impl FnOnce(u32),
+    mut output_cb: impl FnMut(&[u8]),
+) -> anyhow::Result<(Termination, ResourceUsage)> {
     // Don't want to run `cmd /c` since that limits cmd line length to 8192 bytes.
     // std::process::Command can't take a string and pass it through to CreateProcess unchanged,
     // so call that ourselves.
@@ -181,20 +211,41 @@ pub fn run_command(cmdline: &str, mut output_cb: impl FnMut(&[u8])) -> anyhow::R
     };
 
     let process_info = unsafe {
-        // TODO: Set this to just 0 for console pool jobs.
-        let process_flags = CREATE_NEW_PROCESS_GROUP | EXTENDED_STARTUPINFO_PRESENT;
+        // Console pool jobs stay in our process group, so they see Ctrl-C
+        // like an interactive program would.
+        let process_flags = if console {
+            EXTENDED_STARTUPINFO_PRESENT
+        } else {
+            CREATE_NEW_PROCESS_GROUP | EXTENDED_STARTUPINFO_PRESENT
+        };
 
         let mut startup_info = std::mem::zeroed::<STARTUPINFOEXA>();
         startup_info.StartupInfo.cb = std::mem::size_of::<STARTUPINFOEXA>() as u32;
         startup_info.StartupInfo.dwFlags = STARTF_USESTDHANDLES;
         startup_info.StartupInfo.hStdInput = GetStdHandle(STD_INPUT_HANDLE);
         let raw_pipe_write = pipe_write.as_raw_handle() as isize;
-        startup_info.StartupInfo.hStdOutput = raw_pipe_write;
-        startup_info.StartupInfo.hStdError = raw_pipe_write;
+        if console {
+            // Console pool jobs write directly to our console.
+            startup_info.StartupInfo.hStdOutput = GetStdHandle(STD_OUTPUT_HANDLE);
+            startup_info.StartupInfo.hStdError = GetStdHandle(STD_ERROR_HANDLE);
+        } else {
+            startup_info.StartupInfo.hStdOutput = raw_pipe_write;
+            startup_info.StartupInfo.hStdError = raw_pipe_write;
+        }
 
         // Safely inherit in/out handles.
         // https://devblogs.microsoft.com/oldnewthing/20111216-00/?p=8873
-        let handles = pin!([startup_info.StartupInfo.hStdInput, raw_pipe_write]);
+        // The list must not contain duplicates.
+        let mut handle_list = vec![startup_info.StartupInfo.hStdInput];
+        for handle in [
+            startup_info.StartupInfo.hStdOutput,
+            startup_info.StartupInfo.hStdError,
+        ] {
+            if !handle_list.contains(&handle) {
+                handle_list.push(handle);
+            }
+        }
+        let handles = Pin::new(handle_list.as_slice());
         let mut attrs = ProcThreadAttributeList::new(1)?;
         attrs.inherit_handles(handles)?;
         startup_info.lpAttributeList = attrs.as_mut_ptr();
@@ -203,6 +254,11 @@ pub fn run_command(cmdline: &str, mut output_cb: impl FnMut(&[u8])) -> anyhow::R
 
         let mut cmdline_nul: Vec<u8> = String::from(cmdline).into_bytes();
         cmdline_nul.push(0);
+        let cwd_nul = cwd.map(|cwd| {
+            let mut buf = cwd.to_string_lossy().into_owned().into_bytes();
+            buf.push(0);
+            buf
+        });
 
         if CreateProcessA(
             std::ptr::null_mut(),
@@ -212,7 +268,9 @@ pub fn run_command(cmdline: &str, mut output_cb: impl FnMut(&[u8])) -> anyhow::R
             /*inherit handles = */ TRUE,
             process_flags,
             std::ptr::null_mut(),
-            std::ptr::null_mut(),
+            cwd_nul
+                .as_ref()
+                .map_or(std::ptr::null(), |cwd| cwd.as_ptr()),
             &mut startup_info.StartupInfo,
             process_info.as_mut_ptr(),
         ) == 0
@@ -234,6 +292,7 @@ pub fn run_command(cmdline: &str, mut output_cb: impl FnMut(&[u8])) -> anyhow::R
 
         process_info
     };
+    spawned_cb(process_info.dwProcessId);
 
     let mut pipe = std::fs::File::from(pipe_read);
     let mut buf: [u8; 4 << 10] = [0; 4 << 10];
@@ -258,13 +317,33 @@ pub fn run_command(cmdline: &str, mut output_cb: impl FnMut(&[u8])) -> anyhow::R
         exit_code
     };
 
+    let usage = unsafe {
+        let mut counters = std::mem::zeroed::<PROCESS_MEMORY_COUNTERS>();
+        counters.cb = std::mem::size_of::<PROCESS_MEMORY_COUNTERS>() as u32;
+        if GetProcessMemoryInfo(process_info.hProcess, &mut counters, counters.cb) == 0 {
+            win_bail!(GetProcessMemoryInfo);
+        }
+
+        let mut times = [std::mem::zeroed::<FILETIME>(); 4];
+        let [creation, exit, kernel, user] = &mut times;
+        if GetProcessTimes(process_info.hProcess, creation, exit, kernel, user) == 0 {
+            win_bail!(GetProcessTimes);
+        }
+        // FILETIMEs count 100ns intervals.
+        let ticks = |t: &FILETIME| ((t.dwHighDateTime as u64) << 32) | t.dwLowDateTime as u64;
+        ResourceUsage {
+            peak_rss_kb: counters.PeakWorkingSetSize as u64 / 1024,
+            cpu_time: std::time::Duration::from_nanos((ticks(kernel) + ticks(user)) * 100),
+        }
+    };
+
     let termination = match exit_code {
         0 => Termination::Success,
         0xC000013A => Termination::Interrupted,
         _ => Termination::Failure,
     };
 
-    Ok(termination)
+    Ok((termination, usage))
 }
 
 #[cfg(test)]
@@ -275,17 +354,47 @@ mod tests {
     #[test]
     fn run_echo() -> anyhow::Result<()> {
         let mut output = Vec::new();
-        run_command("cmd /c echo hello", |buf| output.extend_from_slice(buf))?;
+        run_command(
+            "cmd /c echo hello",
+            None,
+            false,
+            false,
+            |_| {},
+            |buf| output.extend_from_slice(buf),
+        )?;
         assert_eq!(output, b"hello\r\n");
         Ok(())
     }
 
+    /// A cmd builtin runs without an explicit `cmd /c`, with --windows-shell.
+    #[test]
+    fn run_builtin() -> anyhow::Result<()> {
+        let mut output = Vec::new();
+        run_command(
+            &crate::process::windows_command_line("echo hello && echo there"),
+            None,
+            false,
+            false,
+            |_| {},
+            |buf| output.extend_from_slice(buf),
+        )?;
+        assert_eq!(output, b"hello \r\nthere\r\n");
+        Ok(())
+    }
+
     /// Expect empty command to be specially handled in errors.
     #[test]
     fn empty_command() -> anyhow::Result<()> {
         let mut output = Vec::new();
-        let err =
-            run_command("", |buf| output.extend_from_slice(buf)).expect_err("expected failure");
+        let err = run_command(
+            "",
+            None,
+            false,
+            false,
+            |_| {},
+            |buf| output.extend_from_slice(buf),
+        )
+        .expect_err("expected failure");
         assert!(err.to_string().contains("command is empty"));
         Ok(())
     }
@@ -294,8 +403,15 @@ mod tests {
     #[test]
     fn initial_space() -> anyhow::Result<()> {
         let mut output = Vec::new();
-        let err = run_command(" cmd /c echo hello", |buf| output.extend_from_slice(buf))
-            .expect_err("expected failure");
+        let err = run_command(
+            " cmd /c echo hello",
+            None,
+            false,
+            false,
+            |_| {},
+            |buf| output.extend_from_slice(buf),
+        )
+        .expect_err("expected failure");
         assert!(err.to_string().contains("command has leading whitespace"));
         Ok(())
     }
@@ -1,7 +1,13 @@
 //! Build progress tracking and reporting, for the purpose of display to the
 //! user.
 
-use crate::{graph::Build, graph::BuildId, task::TaskResult, work::StateCounts};
+use crate::{
+    graph::Build, graph::BuildId, output::TaskOutput, process::Termination, task::TaskResult,
+    trace, work::StateCounts,
+};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
 
 /// Compute the message to display on the console for a given build.
 pub fn build_message(build: &Build) -> &str {
@@ -12,11 +18,72 @@ pub fn build_message(build: &Build) -> &str {
         .unwrap_or_else(|| build.cmdline.as_ref().unwrap())
 }
 
+/// The stages of an n2 invocation, in order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// Reading the build files and db.
+    Load,
+    /// Bringing the build files themselves up to date.  If they changed,
+    /// they are loaded again.
+    Regen,
+    /// Finding the builds the requested targets need.  Whether each is dirty
+    /// is decided once its inputs are ready, during Build.
+    Plan,
+    /// Running commands.
+    Build,
+}
+
+impl Phase {
+    pub fn name(self) -> &'static str {
+        match self {
+            Phase::Load => "load",
+            Phase::Regen => "regen",
+            Phase::Plan => "plan",
+            Phase::Build => "build",
+        }
+    }
+}
+
+/// The outcome of a build, reported once all phases are done.
+#[derive(Clone, Debug)]
+pub struct BuildSummary {
+    pub success: bool,
+    /// Commands run, including those that regenerated build files.
+    pub tasks_run: usize,
+    pub elapsed: Duration,
+}
+
+/// The load on a pool with a depth limit.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PoolGauge {
+    pub name: String,
+    pub running: usize,
+    pub depth: usize,
+    /// Builds that are ready but waiting for room in the pool.
+    pub queued: usize,
+}
+
 /// Trait for build progress notifications.
+/// The phase and summary events default to doing nothing, as console output
+/// only cares about tasks.
 pub trait Progress {
+    /// Called as each Phase begins.
+    fn phase_started(&self, _phase: Phase) {}
+
+    /// Called as each Phase ends, even if it failed.
+    fn phase_finished(&self, _phase: Phase) {}
+
+    /// Called once at the end of a build that got to run, whether or not it
+    /// succeeded.
+    fn build_finished(&self, _summary: &BuildSummary) {}
+
     /// Called as individual build tasks progress through build states.
     fn update(&self, counts: &StateCounts);
 
+    /// Called alongside update() with the pools that have builds running
+    /// or waiting in them.
+    fn update_pools(&self, _pools: &[PoolGauge]) {}
+
     /// Called when a task starts.
     fn task_started(&self, id: BuildId, build: &Build);
 
@@ -31,4 +98,262 @@ pub trait Progress {
     /// used when a task fails; we want the final output to show that failed
     /// task's output even if we do more work after it fails.
     fn log(&self, msg: &str);
+
+    /// Log a line of output followed by a task's output, like log().
+    fn log_output(&self, msg: &str, output: &TaskOutput);
+}
+
+/// The line printed before a finished task's output, if any.
+pub fn finished_message(build: &Build, result: &TaskResult) -> Option<String> {
+    if let Some((attempt, retries)) = result.retry {
+        return Some(format!(
+            "retrying ({}/{}): {}",
+            attempt,
+            retries,
+            build_message(build)
+        ));
+    }
+    Some(match result.termination {
+        Termination::Success if result.output.is_empty() || build.hide_success => return None,
+        Termination::Success => build_message(build).to_string(),
+        Termination::Interrupted => format!("interrupted: {}", build_message(build)),
+        Termination::Failure => format!("failed: {}", build_message(build)),
+        Termination::TimedOut => format!("timed out: {}", build_message(build)),
+    })
+}
+
+/// The order in which the output of finished tasks is printed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputOrder {
+    /// As each task finishes.
+    #[default]
+    Finished,
+    /// In the order tasks were started, holding back the output of tasks
+    /// that finish before an earlier-started one does.  Makes logs of
+    /// parallel builds easier to compare.
+    Started,
+}
+
+/// A task started by StartedOrderProgress whose output may be held back.
+struct HeldTask {
+    id: BuildId,
+    /// Set once the task finishes, to what to print when its turn comes.
+    finished: Option<Option<(String, TaskOutput)>>,
+}
+
+/// Wraps another Progress to print task output in OutputOrder::Started order.
+/// The wrapped Progress sees tasks finish as they do, but without their
+/// output, which is printed later via log_output().
+pub struct StartedOrderProgress<'a> {
+    inner: &'a dyn Progress,
+    held: RefCell<VecDeque<HeldTask>>,
+}
+
+impl<'a> StartedOrderProgress<'a> {
+    pub fn new(inner: &'a dyn Progress) -> Self {
+        StartedOrderProgress {
+            inner,
+            held: RefCell::new(VecDeque::new()),
+        }
+    }
+
+    /// Print the output of finished tasks, up to the first unfinished one.
+    /// If all is set, print everything finished regardless.
+    fn flush(&self, all: bool) {
+        let mut held = self.held.borrow_mut();
+        while let Some(task) = held.front() {
+            if task.finished.is_none() && !all {
+                break;
+            }
+            if let Some(Some((msg, output))) = held.pop_front().unwrap().finished {
+                self.inner.log_output(&msg, &output);
+            }
+        }
+    }
+}
+
+impl<'a> Progress for StartedOrderProgress<'a> {
+    fn phase_started(&self, phase: Phase) {
+        self.inner.phase_started(phase);
+    }
+
+    fn phase_finished(&self, phase: Phase) {
+        self.inner.phase_finished(phase);
+    }
+
+    fn build_finished(&self, summary: &BuildSummary) {
+        self.inner.build_finished(summary);
+    }
+
+    fn update(&self, counts: &StateCounts) {
+        self.inner.update(counts);
+    }
+
+    fn update_pools(&self, pools: &[PoolGauge]) {
+        self.inner.update_pools(pools);
+    }
+
+    fn task_started(&self, id: BuildId, build: &Build) {
+        self.held
+            .borrow_mut()
+            .push_back(HeldTask { id, finished: None });
+        self.inner.task_started(id, build);
+    }
+
+    fn task_output(&self, id: BuildId, line: Vec<u8>) {
+        self.inner.task_output(id, line);
+    }
+
+    fn task_finished(&self, id: BuildId, build: &Build, result: &TaskResult) {
+        let report = finished_message(build, result).map(|msg| (msg, result.output.clone()));
+        if let Some(task) = self
+            .held
+            .borrow_mut()
+            .iter_mut()
+            .find(|task| task.id == id && task.finished.is_none())
+        {
+            task.finished = Some(report);
+        }
+
+        // Let the inner progress know the task is done, but silently.
+        let quiet = TaskResult {
+            termination: Termination::Success,
+            output: TaskOutput::default(),
+            discovered_deps: None,
+            warning: None,
+            deps_unreadable: false,
+            usage: result.usage,
+            retry: None,
+        };
+        self.inner.task_finished(id, build, &quiet);
+        self.flush(false);
+    }
+
+    fn log(&self, msg: &str) {
+        self.inner.log(msg);
+    }
+
+    fn log_output(&self, msg: &str, output: &TaskOutput) {
+        self.inner.log_output(msg, output);
+    }
+}
+
+impl<'a> Drop for StartedOrderProgress<'a> {
+    fn drop(&mut self) {
+        // The build may stop early, e.g. on failure, leaving finished tasks
+        // queued behind ones that never finished.
+        self.flush(true);
+    }
+}
+
+/// Passes every notification on to each of a list of Progress sinks, so e.g.
+/// console output and a trace can be produced together.
+#[derive(Default)]
+pub struct MultiProgress<'a> {
+    sinks: Vec<&'a dyn Progress>,
+}
+
+impl<'a> MultiProgress<'a> {
+    pub fn add(&mut self, sink: &'a dyn Progress) {
+        self.sinks.push(sink);
+    }
+}
+
+impl<'a> Progress for MultiProgress<'a> {
+    fn phase_started(&self, phase: Phase) {
+        for sink in &self.sinks {
+            sink.phase_started(phase);
+        }
+    }
+
+    fn phase_finished(&self, phase: Phase) {
+        for sink in &self.sinks {
+            sink.phase_finished(phase);
+        }
+    }
+
+    fn build_finished(&self, summary: &BuildSummary) {
+        for sink in &self.sinks {
+            sink.build_finished(summary);
+        }
+    }
+
+    fn update(&self, counts: &StateCounts) {
+        for sink in &self.sinks {
+            sink.update(counts);
+        }
+    }
+
+    fn update_pools(&self, pools: &[PoolGauge]) {
+        for sink in &self.sinks {
+            sink.update_pools(pools);
+        }
+    }
+
+    fn task_started(&self, id: BuildId, build: &Build) {
+        for sink in &self.sinks {
+            sink.task_started(id, build);
+        }
+    }
+
+    fn task_output(&self, id: BuildId, line: Vec<u8>) {
+        for sink in &self.sinks {
+            sink.task_output(id, line.clone());
+        }
+    }
+
+    fn task_finished(&self, id: BuildId, build: &Build, result: &TaskResult) {
+        for sink in &self.sinks {
+            sink.task_finished(id, build, result);
+        }
+    }
+
+    fn log(&self, msg: &str) {
+        for sink in &self.sinks {
+            sink.log(msg);
+        }
+    }
+
+    fn log_output(&self, msg: &str, output: &TaskOutput) {
+        for sink in &self.sinks {
+            sink.log_output(msg, output);
+        }
+    }
+}
+
+/// Records each Phase as a span in the performance trace, and the summary as
+/// trace metadata.
+#[derive(Default)]
+pub struct TraceProgress {
+    phase_start: RefCell<Option<Instant>>,
+}
+
+impl Progress for TraceProgress {
+    fn phase_started(&self, _phase: Phase) {
+        *self.phase_start.borrow_mut() = Some(Instant::now());
+    }
+
+    fn phase_finished(&self, phase: Phase) {
+        if let Some(start) = self.phase_start.take() {
+            trace::write_complete(phase.name(), 0, start, Instant::now());
+        }
+    }
+
+    fn build_finished(&self, summary: &BuildSummary) {
+        trace::write_metadata(
+            "n2_summary",
+            &[
+                ("success", &summary.success.to_string()),
+                ("tasks_run", &summary.tasks_run.to_string()),
+                ("elapsed_ms", &summary.elapsed.as_millis().to_string()),
+            ],
+        );
+    }
+
+    fn update(&self, _counts: &StateCounts) {}
+    fn task_started(&self, _id: BuildId, _build: &Build) {}
+    fn task_output(&self, _id: BuildId, _line: Vec<u8>) {}
+    fn task_finished(&self, _id: BuildId, _build: &Build, _result: &TaskResult) {}
+    fn log(&self, _msg: &str) {}
+    fn log_output(&self, _msg: &str, _output: &TaskOutput) {}
 }
@@ -1,8 +1,9 @@
 //! Build progress reporting for a "dumb" console, without any overprinting.
 
-use crate::progress::{build_message, Progress};
+use crate::progress::{build_message, finished_message, Progress};
 use crate::{
-    graph::Build, graph::BuildId, process::Termination, task::TaskResult, work::StateCounts,
+    graph::Build, graph::BuildId, output::TaskOutput, process::Termination, task::TaskResult,
+    work::StateCounts,
 };
 use std::cell::Cell;
 use std::io::Write;
@@ -33,6 +34,9 @@ impl Progress for DumbConsoleProgress {
     }
 
     fn task_started(&self, id: BuildId, build: &Build) {
+        if build.hide_progress && !self.verbose {
+            return;
+        }
         self.log(if self.verbose {
             build.cmdline.as_ref().unwrap()
         } else {
@@ -46,23 +50,25 @@ impl Progress for DumbConsoleProgress {
     }
 
     fn task_finished(&self, id: BuildId, build: &Build, result: &TaskResult) {
-        match result.termination {
-            Termination::Success => {
-                if result.output.is_empty() || self.last_started.get() == Some(id) {
-                    // Output is empty, or we just printed the command, don't print it again.
-                } else {
-                    self.log(build_message(build))
-                }
-            }
-            Termination::Interrupted => self.log(&format!("interrupted: {}", build_message(build))),
-            Termination::Failure => self.log(&format!("failed: {}", build_message(build))),
+        let msg = match finished_message(build, result) {
+            None => return,
+            Some(msg) => msg,
         };
-        if !result.output.is_empty() {
-            std::io::stdout().write_all(&result.output).unwrap();
+        if self.last_started.get() == Some(id) && result.termination == Termination::Success {
+            // We just printed the command, don't print it again.
+            result.output.write_to(&mut std::io::stdout()).unwrap();
+        } else {
+            self.log_output(&msg, &result.output);
         }
     }
 
     fn log(&self, msg: &str) {
         println!("{}", msg);
     }
+
+    fn log_output(&self, msg: &str, output: &TaskOutput) {
+        let mut stdout = std::io::stdout().lock();
+        writeln!(stdout, "{}", msg).unwrap();
+        output.write_to(&mut stdout).unwrap();
+    }
 }
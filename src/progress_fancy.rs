@@ -1,27 +1,32 @@
 //! Build progress reporting for a "fancy" console, with progress bar etc.
 
-use crate::progress::{build_message, Progress};
+use crate::progress::{build_message, finished_message, PoolGauge, Progress};
 use crate::{
-    graph::Build, graph::BuildId, process::Termination, task::TaskResult, terminal,
+    graph::Build, graph::BuildId, output::TaskOutput, signal, task::TaskResult, terminal,
     work::BuildState, work::StateCounts,
 };
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, HashMap};
 use std::io::Write;
 use std::sync::Arc;
 use std::sync::Condvar;
 use std::sync::Mutex;
 use std::time::Duration;
 use std::time::Instant;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
 /// Currently running build task, as tracked for progress updates.
 struct Task {
-    id: BuildId,
     /// When the task started running.
     start: Instant,
     /// Build status message for the task.
     message: String,
+    /// The task's full command, printed on demand as the status message
+    /// is often too short to tell what a stuck task is doing.
+    cmdline: String,
     /// Last line of output from the task.
     last_line: Option<String>,
+    /// The task's key in FancyState::order.
+    seq: usize,
 }
 
 /// Progress implementation for "fancy" console, with progress bar etc.
@@ -43,6 +48,10 @@ const UPDATE_DELAY: Duration = std::time::Duration::from_millis(50);
 /// do not appear hung.
 const TIMEOUT_DELAY: Duration = std::time::Duration::from_millis(500);
 
+/// Tasks running for at least this many seconds are flagged as slow in the
+/// status display, to make a stuck task easy to spot.
+const SLOW_TASK_SECS: usize = 60;
+
 impl FancyConsoleProgress {
     pub fn new(verbose: bool) -> Self {
         let dirty_cond = Arc::new(Condvar::new());
@@ -52,7 +61,11 @@ impl FancyConsoleProgress {
             dirty: false,
             dirty_cond: dirty_cond.clone(),
             counts: StateCounts::default(),
-            tasks: VecDeque::new(),
+            tasks: HashMap::new(),
+            order: BTreeMap::new(),
+            started: 0,
+            console_tasks: 0,
+            pools: Vec::new(),
             verbose,
         }));
 
@@ -63,7 +76,7 @@ impl FancyConsoleProgress {
             move || loop {
                 // Wait to be notified of a display update or timeout.
                 {
-                    let (state, _) = dirty_cond
+                    let (mut state, _) = dirty_cond
                         .wait_timeout_while(
                             state_lock.lock().unwrap(),
                             TIMEOUT_DELAY - UPDATE_DELAY,
@@ -74,6 +87,9 @@ impl FancyConsoleProgress {
                         std::io::stdout().write_all(&state.pending).unwrap();
                         break;
                     }
+                    if signal::take_show_commands() {
+                        state.show_commands();
+                    }
                 }
 
                 // Delay a little bit in case more display updates come in.
@@ -97,6 +113,10 @@ impl Progress for FancyConsoleProgress {
         self.state.lock().unwrap().update(counts);
     }
 
+    fn update_pools(&self, pools: &[PoolGauge]) {
+        self.state.lock().unwrap().update_pools(pools);
+    }
+
     fn task_started(&self, id: BuildId, build: &Build) {
         self.state.lock().unwrap().task_started(id, build);
     }
@@ -112,6 +132,10 @@ impl Progress for FancyConsoleProgress {
     fn log(&self, msg: &str) {
         self.state.lock().unwrap().log(msg);
     }
+
+    fn log_output(&self, msg: &str, output: &TaskOutput) {
+        self.state.lock().unwrap().log_output(msg, output);
+    }
 }
 
 impl Drop for FancyConsoleProgress {
@@ -136,8 +160,17 @@ struct FancyState {
     /// Counts of tasks in each state.  TODO: pass this as function args?
     counts: StateCounts,
     /// Build tasks that are currently executing.
-    /// Pushed to as tasks are started, so it's always in order of age.
-    tasks: VecDeque<Task>,
+    tasks: HashMap<BuildId, Task>,
+    /// The ids of tasks, keyed by the count of tasks started before them, so
+    /// it's always in order of age.
+    order: BTreeMap<usize, BuildId>,
+    /// The number of tasks started so far.
+    started: usize,
+    /// Number of running console pool tasks.  While nonzero, those tasks own
+    /// the terminal, so we don't draw the progress bar over them.
+    console_tasks: usize,
+    /// Load on the bounded pools that are in use.
+    pools: Vec<PoolGauge>,
     /// Whether to print command lines of started programs.
     verbose: bool,
 }
@@ -153,52 +186,103 @@ impl FancyState {
         self.dirty();
     }
 
+    fn update_pools(&mut self, pools: &[PoolGauge]) {
+        if self.pools != pools {
+            self.pools = pools.to_vec();
+            self.dirty();
+        }
+    }
+
     fn task_started(&mut self, id: BuildId, build: &Build) {
         if self.verbose {
             write!(&mut self.pending, "{}\n", build.cmdline.as_ref().unwrap()).ok();
         }
         let message = build_message(build);
-        self.tasks.push_back(Task {
+        if build.is_console() {
+            // Clear the progress bar and leave the terminal to the task.
+            writeln!(&mut self.pending, "{}", message).ok();
+            std::io::stdout().write_all(&self.pending).unwrap();
+            self.pending.clear();
+            self.console_tasks += 1;
+        }
+        // Label tasks in named pools, so it's clear what a full pool holds.
+        let message = match build.pool.as_deref() {
+            Some(pool) if !pool.is_empty() && !build.is_console() => {
+                format!("[{}] {}", pool, message)
+            }
+            _ => message.to_string(),
+        };
+        self.tasks.insert(
             id,
-            start: Instant::now(),
-            message: message.to_string(),
-            last_line: None,
-        });
+            Task {
+                start: Instant::now(),
+                message,
+                cmdline: build.cmdline.clone().unwrap_or_default(),
+                last_line: None,
+                seq: self.started,
+            },
+        );
+        self.order.insert(self.started, id);
+        self.started += 1;
         self.dirty();
     }
 
     fn task_output(&mut self, id: BuildId, line: Vec<u8>) {
-        let task = self.tasks.iter_mut().find(|t| t.id == id).unwrap();
+        let task = self.tasks.get_mut(&id).unwrap();
         task.last_line = Some(String::from_utf8_lossy(&line).into_owned());
         self.dirty();
     }
 
     fn task_finished(&mut self, id: BuildId, build: &Build, result: &TaskResult) {
-        self.tasks
-            .remove(self.tasks.iter().position(|t| t.id == id).unwrap());
+        let task = self.tasks.remove(&id).unwrap();
+        self.order.remove(&task.seq);
+        if build.is_console() {
+            self.console_tasks -= 1;
+        }
 
         // Show task name, status, and output.
-        let buf = &mut self.pending;
-        match result.termination {
-            Termination::Success if result.output.is_empty() => {
-                // Common case: don't show anything.
-                return;
-            }
-            Termination::Success => write!(buf, "{}\n", build_message(build)).ok(),
-            Termination::Interrupted => write!(buf, "interrupted: {}\n", build_message(build)).ok(),
-            Termination::Failure => write!(buf, "failed: {}\n", build_message(build)).ok(),
-        };
-        buf.extend_from_slice(&result.output);
-        if !result.output.ends_with(b"\n") {
-            buf.push(b'\n');
+        if let Some(msg) = finished_message(build, result) {
+            self.log_output(&msg, &result.output);
         }
+    }
 
+    fn log(&mut self, msg: &str) {
+        self.pending.extend_from_slice(msg.as_bytes());
+        self.pending.push(b'\n');
         self.dirty();
     }
 
-    fn log(&mut self, msg: &str) {
+    fn log_output(&mut self, msg: &str, output: &TaskOutput) {
         self.pending.extend_from_slice(msg.as_bytes());
         self.pending.push(b'\n');
+        if output.is_spilled() {
+            // Too big to buffer; print what's pending and stream the output
+            // now.  The next progress print picks up where it leaves off.
+            let mut stdout = std::io::stdout().lock();
+            stdout.write_all(&self.pending).unwrap();
+            self.pending.clear();
+            output.write_to(&mut stdout).unwrap();
+        } else {
+            self.pending.extend_from_slice(output.tail());
+        }
+        if !output.ends_with_newline() {
+            self.pending.push(b'\n');
+        }
+        self.dirty();
+    }
+
+    /// Print the full commands of the running tasks, oldest first.
+    fn show_commands(&mut self) {
+        let now = Instant::now();
+        for task in self.order.values().map(|id| &self.tasks[id]) {
+            let seconds = now.duration_since(task.start).as_secs() as usize;
+            writeln!(
+                &mut self.pending,
+                "{} ({}s)\n  {}",
+                task.message, seconds, task.cmdline
+            )
+            .ok();
+        }
         self.dirty();
     }
 
@@ -208,14 +292,25 @@ impl FancyState {
     }
 
     fn print_progress(&mut self) {
+        if self.console_tasks > 0 {
+            // Hold any pending output until the console is ours again.
+            self.dirty = false;
+            return;
+        }
         let failed = self.counts.get(BuildState::Failed);
         let mut buf: &mut Vec<u8> = &mut self.pending;
+        let ran = match self.counts.to_run() {
+            Some(to_run) => format!("{}/{}", self.counts.ran(), to_run.max(self.counts.ran())),
+            None => self.counts.ran().to_string(),
+        };
         write!(
             &mut buf,
-            "[{}] {}/{} done, ",
+            "[{}] {}/{} done ({} run, {} up to date), ",
             progress_bar(&self.counts, 40),
             self.counts.get(BuildState::Done) + failed,
-            self.counts.total()
+            self.counts.total(),
+            ran,
+            self.counts.up_to_date()
         )
         .ok();
         if failed > 0 {
@@ -223,11 +318,13 @@ impl FancyState {
         }
         write!(
             &mut buf,
-            "{}/{} running\n",
+            "{}/{} running{}\n",
             self.tasks.len(),
             self.counts.get(BuildState::Queued)
+                + self.counts.get(BuildState::Waiting)
                 + self.counts.get(BuildState::Running)
                 + self.counts.get(BuildState::Ready),
+            pools_status(&self.pools),
         )
         .ok();
         let mut lines = 1;
@@ -235,7 +332,12 @@ impl FancyState {
         let max_cols = terminal::get_cols().unwrap_or(80);
         let max_tasks = 8;
         let now = Instant::now();
-        for task in self.tasks.iter().take(max_tasks) {
+        for task in self
+            .order
+            .values()
+            .take(max_tasks)
+            .map(|id| &self.tasks[id])
+        {
             let delta = now.duration_since(task.start).as_secs() as usize;
             write!(
                 &mut buf,
@@ -245,7 +347,7 @@ impl FancyState {
             .ok();
             lines += 1;
             if let Some(line) = &task.last_line {
-                let max_len = max_cols - 2;
+                let max_len = max_cols.saturating_sub(2);
                 write!(&mut buf, "  {}\n", truncate(line, max_len)).ok();
                 lines += 1;
             }
@@ -274,51 +376,86 @@ impl FancyState {
 /// Format a task's status message to optionally include how long it has been running
 /// and also to fit within a maximum number of terminal columns.
 fn task_message(message: &str, seconds: usize, max_cols: usize) -> String {
-    let time_note = if seconds > 2 {
+    let time_note = if seconds >= SLOW_TASK_SECS {
+        format!(" ({}s, slow)", seconds)
+    } else if seconds > 2 {
         format!(" ({}s)", seconds)
     } else {
         "".into()
     };
     let mut out = message.to_owned();
-    if out.len() + time_note.len() >= max_cols {
-        out.truncate(max_cols - time_note.len() - 3);
+    if out.width() + time_note.len() >= max_cols {
+        let len = truncate(&out, max_cols.saturating_sub(time_note.len() + 3)).len();
+        out.truncate(len);
         out.push_str("...");
     }
     out.push_str(&time_note);
     out
 }
 
-fn truncate(s: &str, mut max: usize) -> &str {
-    if max >= s.len() {
-        return s;
+/// Format the load on pools for the status line, e.g. ", link 2/2 (3 waiting)".
+fn pools_status(pools: &[PoolGauge]) -> String {
+    let mut out = String::new();
+    for pool in pools {
+        out.push_str(&format!(", {} {}/{}", pool.name, pool.running, pool.depth));
+        if pool.queued > 0 {
+            out.push_str(&format!(" ({} waiting)", pool.queued));
+        }
     }
-    while !s.is_char_boundary(max) {
-        max -= 1;
+    out
+}
+
+/// Truncate s to fit in max terminal columns.  Wide characters (e.g. CJK)
+/// take two columns, and are dropped entirely rather than split.
+fn truncate(s: &str, max: usize) -> &str {
+    let mut cols = 0;
+    for (i, c) in s.char_indices() {
+        cols += c.width().unwrap_or(0);
+        if cols > max {
+            return &s[..i];
+        }
     }
-    &s[..max]
+    s
 }
 
-/// Render a StateCounts as an ASCII progress bar.
+/// Render a StateCounts as an ASCII progress bar.  Builds found up to date
+/// are drawn apart from those that ran, as they can fill most of the bar of
+/// an incremental build in an instant.  When it's known how many builds will
+/// run, the bar shows only those.
 fn progress_bar(counts: &StateCounts, bar_size: usize) -> String {
     let mut bar = String::with_capacity(bar_size);
     let mut sum: usize = 0;
-    let total = counts.total();
+    let finished = counts.ran() + counts.get(BuildState::Failed);
+    let segments = match counts.to_run() {
+        Some(to_run) => {
+            let running = counts.get(BuildState::Queued)
+                + counts.get(BuildState::Waiting)
+                + counts.get(BuildState::Running);
+            [
+                (0, '+'),
+                (finished, '='),
+                (running, '-'),
+                (to_run.saturating_sub(finished + running), ' '),
+            ]
+        }
+        None => [
+            (counts.up_to_date(), '+'),
+            (finished, '='),
+            (
+                counts.get(BuildState::Queued)
+                    + counts.get(BuildState::Waiting)
+                    + counts.get(BuildState::Running)
+                    + counts.get(BuildState::Ready),
+                '-',
+            ),
+            (counts.get(BuildState::Want), ' '),
+        ],
+    };
+    let total: usize = segments.iter().map(|&(count, _)| count).sum();
     if total == 0 {
         return " ".repeat(bar_size);
     }
-    for (count, ch) in [
-        (
-            counts.get(BuildState::Done) + counts.get(BuildState::Failed),
-            '=',
-        ),
-        (
-            counts.get(BuildState::Queued)
-                + counts.get(BuildState::Running)
-                + counts.get(BuildState::Ready),
-            '-',
-        ),
-        (counts.get(BuildState::Want), ' '),
-    ] {
+    for (count, ch) in segments {
         sum += count;
         let mut target_size = sum * bar_size / total;
         if count > 0 && target_size == bar.len() && target_size < bar_size {
@@ -366,6 +503,36 @@ mod tests {
         counts.add(BuildState::Want, -1);
         counts.add(BuildState::Ready, 1);
         assert_eq!(progress_bar(&counts, 10), "=---------");
+
+        // Most ready -> done without running.
+        counts.add(BuildState::Ready, -60);
+        counts.add(BuildState::Done, 60);
+        for _ in 0..60 {
+            counts.add_up_to_date();
+        }
+        assert_eq!(progress_bar(&counts, 10), "++++++=---");
+
+        // Known to run 4 in all, leaving out those up to date.
+        counts.set_to_run(4);
+        assert_eq!(progress_bar(&counts, 10), "==        ");
+        counts.add(BuildState::Ready, -1);
+        counts.add(BuildState::Running, 1);
+        assert_eq!(progress_bar(&counts, 10), "==---     ");
+    }
+
+    #[test]
+    fn pools_rendering() {
+        assert_eq!(pools_status(&[]), "");
+        let gauge = |name: &str, running, queued| PoolGauge {
+            name: name.into(),
+            running,
+            depth: 2,
+            queued,
+        };
+        assert_eq!(
+            pools_status(&[gauge("link", 2, 3), gauge("heavy", 1, 0)]),
+            ", link 2/2 (3 waiting), heavy 1/2"
+        );
     }
 
     #[test]
@@ -379,6 +546,10 @@ mod tests {
     fn task_rendering_with_time() {
         assert_eq!(task_message("building foo.o", 5, 80), "building foo.o (5s)");
         assert_eq!(task_message("building foo.o", 5, 10), "bu... (5s)");
+        assert_eq!(
+            task_message("building foo.o", 75, 80),
+            "building foo.o (75s, slow)"
+        );
     }
 
     #[test]
@@ -389,4 +560,21 @@ mod tests {
             truncate(text, len);
         }
     }
+
+    #[test]
+    fn truncate_wide() {
+        // Each of these characters takes two columns.
+        assert_eq!(truncate("構建中", 6), "構建中");
+        assert_eq!(truncate("構建中", 5), "構建");
+        assert_eq!(truncate("構建中", 4), "構建");
+        assert_eq!(truncate("a🦀b", 2), "a");
+        assert_eq!(truncate("a🦀b", 3), "a🦀");
+    }
+
+    #[test]
+    fn task_rendering_wide() {
+        assert_eq!(task_message("構建 foo.o", 0, 80), "構建 foo.o");
+        assert_eq!(task_message("構建構建構建", 0, 10), "構建構...");
+        assert_eq!(task_message("構建構建構建", 5, 12), "構建... (5s)");
+    }
 }
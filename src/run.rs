@@ -1,34 +1,174 @@
 //! Command line argument parsing and initial build invocation.
 
 use crate::{
-    load, progress::Progress, progress_dumb::DumbConsoleProgress,
-    progress_fancy::FancyConsoleProgress, terminal, trace, work,
+    canon::to_owned_canon_path,
+    config, cpus,
+    error::Error,
+    graph, load, lock,
+    progress::{
+        BuildSummary, MultiProgress, OutputOrder, Phase, Progress, StartedOrderProgress,
+        TraceProgress,
+    },
+    progress_dumb::DumbConsoleProgress,
+    progress_fancy::FancyConsoleProgress,
+    signal::{self, CancelToken},
+    summary, task, terminal, tools, trace, work,
 };
 use anyhow::anyhow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use std::time::Instant;
 
 /// Arguments to start a build, after parsing all the command line etc.
 #[derive(Default)]
 struct BuildArgs {
     fake_ninja_compat: bool,
     options: work::Options,
-    build_filename: Option<String>,
+    load_options: load::Options,
+    /// Build files from -f, which are loaded in order into one graph.
+    build_filenames: Vec<String>,
     targets: Vec<String>,
     verbose: bool,
+    output_order: OutputOrder,
+    color: terminal::ColorMode,
+    /// A graph tool (see tools.rs) to run instead of building, and its args.
+    tool: Option<(String, Vec<String>)>,
+    /// From -d mtime: print the mtimes of the targets instead of building.
+    debug_mtime: bool,
+    /// From --cpu-fraction: scales the default parallelism.
+    cpu_fraction: Option<f64>,
+    /// From --on-complete: a command to run after the build.
+    on_complete: Option<String>,
+    /// From --debug-graph-stats: print the graph's size after loading.
+    graph_stats: bool,
+    /// From --profile-startup: print the time taken by each loading phase.
+    profile_startup: bool,
 }
 
-/// Returns the number of completed tasks on a successful build.
-fn build(args: BuildArgs) -> anyhow::Result<Option<usize>> {
+impl BuildArgs {
+    /// The build files to load, defaulting to build.ninja.
+    fn build_filenames(&self) -> Vec<&str> {
+        if self.build_filenames.is_empty() {
+            vec!["build.ninja"]
+        } else {
+            self.build_filenames.iter().map(String::as_str).collect()
+        }
+    }
+}
+
+/// Returns the summary of a successful build, or None if the build failed;
+/// the failing tasks have said why.
+fn build(args: BuildArgs) -> anyhow::Result<Option<summary::Summary>> {
+    if args.color.enabled() {
+        terminal::force_child_color();
+    }
+    let _utf8_console = terminal::use_utf8_console();
     let (dumb_console, fancy_console);
-    let progress: &dyn Progress = if terminal::use_fancy() {
+    let console: &dyn Progress = if terminal::use_fancy() {
+        #[cfg(unix)]
+        signal::register_show_commands();
         fancy_console = FancyConsoleProgress::new(args.verbose);
         &fancy_console
     } else {
         dumb_console = DumbConsoleProgress::new(args.verbose);
         &dumb_console
     };
+    let started_order;
+    let console: &dyn Progress = match args.output_order {
+        OutputOrder::Finished => console,
+        OutputOrder::Started => {
+            started_order = StartedOrderProgress::new(console);
+            &started_order
+        }
+    };
+    let trace_progress = TraceProgress::default();
+    let mut progress = MultiProgress::default();
+    progress.add(console);
+    if trace::enabled() {
+        progress.add(&trace_progress);
+    }
+
+    let start = Instant::now();
+    let mut summary = summary::Summary::default();
+    let result = build_phases(&args, &progress, &mut summary);
+    summary.elapsed = start.elapsed();
+    summary.interrupted = signal::was_interrupted()
+        || result
+            .as_ref()
+            .is_err_and(|err| matches!(err.downcast_ref::<Error>(), Some(Error::Cancelled)));
+    if let Ok(success) = result {
+        summary.status = if success {
+            summary::Status::Success
+        } else {
+            summary::Status::Failure
+        };
+        progress.build_finished(&BuildSummary {
+            success,
+            tasks_run: summary.tasks_run,
+            elapsed: summary.elapsed,
+        });
+    }
+    match summary::write(&summary) {
+        Ok(path) => {
+            if let Some(cmd) = &args.on_complete {
+                if let Err(err) = summary::on_complete(cmd, &summary, &path) {
+                    progress.log(&format!("n2: --on-complete {}: {}", cmd, err));
+                }
+            }
+        }
+        Err(err) => progress.log(&format!("n2: writing .n2_summary.json: {}", err)),
+    }
+    let success = result?;
+    Ok(if success { Some(summary) } else { None })
+}
+
+/// Run f as phase, reporting its start and end to progress.
+fn phase<T>(progress: &dyn Progress, phase: Phase, f: impl FnOnce() -> T) -> T {
+    progress.phase_started(phase);
+    let result = f();
+    progress.phase_finished(phase);
+    result
+}
+
+/// How many times build_phases loads the build files, as building
+/// subninjas can reveal more subninjas to build.
+const MAX_LOADS: usize = 10;
 
-    let build_filename = args.build_filename.as_deref().unwrap_or("build.ninja");
-    let mut state = trace::scope("load::read", || load::read(build_filename))?;
+/// Load and build, counting commands run and failed builds into summary.
+/// Returns whether the build succeeded.
+fn build_phases(
+    args: &BuildArgs,
+    progress: &dyn Progress,
+    summary: &mut summary::Summary,
+) -> anyhow::Result<bool> {
+    let build_filenames = args.build_filenames();
+    let load = || {
+        phase(progress, Phase::Load, || {
+            trace::scope("load::read", || {
+                load::read(&build_filenames, &args.load_options)
+            })
+        })
+    };
+    if args.profile_startup {
+        trace::profile_start();
+    }
+    let loaded = load();
+    if args.profile_startup {
+        print_profile(&trace::profile_finish(), progress);
+    }
+    let mut state = loaded?;
+    state.record_manifests()?;
+    summary::reserve(state.builddir.as_deref(), args.on_complete.is_some())?;
+    if args.graph_stats {
+        print_graph_stats(&state, progress);
+    }
+    summary.builddir = state.builddir.take();
+    if args.options.explain {
+        for change in state.manifest_changes.iter().flatten() {
+            progress.log(&format!("explain: build file {}", change));
+        }
+    }
     let mut work = work::Work::new(
         state.graph,
         state.hashes,
@@ -38,66 +178,260 @@ fn build(args: BuildArgs) -> anyhow::Result<Option<usize>> {
         state.pools,
     );
 
-    let mut tasks_run = 0;
-
-    // Attempt to rebuild build.ninja.
-    let build_file_target = work.lookup(&build_filename);
-    if let Some(target) = build_file_target {
-        work.want_file(target)?;
-        if !trace::scope("work.run", || work.run())? {
-            return Ok(None);
+    // Attempt to rebuild build.ninja, and build any subninjas that don't
+    // exist yet.
+    let mut pending = std::mem::take(&mut state.pending_subninjas);
+    let mut regen_targets: Vec<_> = build_filenames
+        .iter()
+        .filter_map(|name| work.lookup(name))
+        .collect();
+    let mut loads = 1;
+    loop {
+        regen_targets.extend(pending.iter().filter_map(|name| work.lookup(name)));
+        if regen_targets.is_empty() {
+            break;
         }
-        if work.tasks_run == 0 {
+        let regen = phase(progress, Phase::Regen, || -> anyhow::Result<_> {
+            for &target in &regen_targets {
+                work.want_file(target)?;
+            }
+            // To tell afterwards whether regenerating changed the build files.
+            let digest = if pending.is_empty() {
+                manifest_digest(&state.manifests)
+            } else {
+                None
+            };
+            Ok((trace::scope("work.run", || work.run())?, digest))
+        });
+        let (ok, digest) = regen?;
+        if !ok {
+            summarize_work(summary, &work);
+            return Ok(false);
+        }
+        if work.tasks_run == 0 && pending.is_empty() {
             // build.ninja already up to date.
             // TODO: this logic is not right in the case where a build has
             // a step that doesn't touch build.ninja.  We should instead
             // verify the specific FileId was updated.
-        } else {
-            // Regenerated build.ninja; start over.
-            tasks_run = work.tasks_run;
-            state = trace::scope("load::read", || load::read(&build_filename))?;
-            work = work::Work::new(
-                state.graph,
-                state.hashes,
-                state.db,
-                &args.options,
-                progress,
-                state.pools,
+            break;
+        }
+        if pending.is_empty() && digest.is_some() && digest == manifest_digest(&state.manifests) {
+            // The generator ran but wrote the same build files, so the graph
+            // we have is still right; skip reloading it.  Their mtimes did
+            // change, which the next run must not see as a change.
+            if args.options.explain {
+                progress.log("explain: build files regenerated without changes");
+            }
+            work.record_manifests(&state.manifests)?;
+            break;
+        }
+        for name in &pending {
+            if !std::path::Path::new(name).exists() {
+                anyhow::bail!("subninja {}: its build ran but didn't create it", name);
+            }
+        }
+        if loads == MAX_LOADS {
+            anyhow::bail!(
+                "build files still incomplete after loading {} times",
+                MAX_LOADS
             );
         }
+
+        // Regenerated build.ninja or built subninjas; start over.
+        summarize_work(summary, &work);
+        state = load()?;
+        state.record_manifests()?;
+        summary::reserve(state.builddir.as_deref(), args.on_complete.is_some())?;
+        loads += 1;
+        if args.graph_stats {
+            print_graph_stats(&state, progress);
+        }
+        summary.builddir = state.builddir.take();
+        pending = std::mem::take(&mut state.pending_subninjas);
+        work = work::Work::new(
+            state.graph,
+            state.hashes,
+            state.db,
+            &args.options,
+            progress,
+            state.pools,
+        );
+        // build.ninja was just brought up to date; only newly found
+        // subninjas need building.
+        regen_targets = Vec::new();
     }
+    let build_file_targets: Vec<_> = build_filenames
+        .iter()
+        .filter_map(|name| work.lookup(name))
+        .collect();
 
-    if !args.targets.is_empty() {
-        for name in &args.targets {
-            let target = work
-                .lookup(name)
-                .ok_or_else(|| anyhow::anyhow!("unknown path requested: {:?}", name))?;
-            if Some(target) == build_file_target {
-                // Already built above.
-                continue;
+    phase(progress, Phase::Plan, || -> anyhow::Result<()> {
+        if !args.targets.is_empty() {
+            for name in &args.targets {
+                if name == "all" && work.lookup(name).is_none() {
+                    // Unless the build files define it, "all" means everything,
+                    // as if no targets or defaults had been given.
+                    work.want_every_file(&build_file_targets)?;
+                    continue;
+                }
+                if let Some(source) = name.strip_suffix('^') {
+                    // As in ninja, foo.c^ means whatever foo.c is built into.
+                    let file = work
+                        .lookup(source)
+                        .ok_or_else(|| anyhow::anyhow!("unknown path requested: {:?}", source))?;
+                    let target = work
+                        .consumer(file)
+                        .ok_or_else(|| anyhow::anyhow!("{:?} isn't used by any build", source))?;
+                    work.want_target(target)?;
+                    continue;
+                }
+                if let Some(pattern) = target_pattern(name) {
+                    let matches = work.glob(&pattern);
+                    if matches.is_empty() {
+                        anyhow::bail!(
+                            "no outputs match {:?} (quote patterns so the shell leaves them to n2)",
+                            name
+                        );
+                    }
+                    for target in matches {
+                        work.want_target(target)?;
+                    }
+                    continue;
+                }
+                let target = work.lookup(name).ok_or_else(|| work.unknown_path(name))?;
+                if build_file_targets.contains(&target) {
+                    // Already built above.
+                    continue;
+                }
+                work.want_target(target)?;
             }
-            work.want_file(target)?;
-        }
-    } else if !state.default.is_empty() {
-        for target in state.default {
-            work.want_file(target)?;
+        } else if !state.default.is_empty() {
+            for &target in &state.default {
+                work.want_target(target)?;
+            }
+        } else {
+            work.want_every_file(&build_file_targets)?;
         }
-    } else {
-        work.want_every_file(build_file_target)?;
-    }
+        Ok(())
+    })?;
 
-    if !trace::scope("work.run", || work.run())? {
-        return Ok(None);
+    let success = phase(progress, Phase::Build, || {
+        trace::scope("work.run", || work.run())
+    })?;
+    if work.adopted > 0 {
+        progress.log(&format!(
+            "n2: adopted {} build{} as up to date",
+            work.adopted,
+            if work.adopted == 1 { "" } else { "s" }
+        ));
     }
     // Include any tasks from initial build in final count of steps.
-    Ok(Some(tasks_run + work.tasks_run))
+    summarize_work(summary, &work);
+    if let Some(msg) = format_target_statuses(&summary.targets) {
+        progress.log(&format!("n2: {}", msg));
+    }
+    Ok(success)
+}
+
+/// Add the outcome of a run of work to summary.
+fn summarize_work(summary: &mut summary::Summary, work: &work::Work) {
+    summary.tasks_run += work.tasks_run;
+    summary.tasks_skipped += work.skipped;
+    summary.up_to_date += work.up_to_date();
+    if let Some(predicted) = work.predicted_tasks() {
+        *summary.tasks_predicted.get_or_insert(0) += predicted;
+    }
+    summary.failed.extend(work.failed_builds());
+    summary.add_slowest(work.slowest_builds(summary::SLOWEST));
+    summary.targets.extend(work.target_statuses());
+}
+
+/// When some of several requested targets weren't built, say which, e.g.
+/// "1 of 3 targets built; not built: b (failed), c".
+fn format_target_statuses(targets: &[summary::Target]) -> Option<String> {
+    let not_built: Vec<String> = targets
+        .iter()
+        .filter_map(|target| match target.status {
+            summary::TargetStatus::Satisfied => None,
+            summary::TargetStatus::Failed => Some(format!("{} (failed)", target.name)),
+            summary::TargetStatus::NotBuilt => Some(target.name.clone()),
+        })
+        .collect();
+    if targets.len() < 2 || not_built.is_empty() {
+        return None;
+    }
+    Some(format!(
+        "{} of {} targets built; not built: {}",
+        targets.len() - not_built.len(),
+        targets.len(),
+        not_built.join(", ")
+    ))
+}
+
+/// Hash the contents of the build files, to tell whether regenerating them
+/// changed anything.  None if any of them can't be read.
+fn manifest_digest(names: &[String]) -> Option<u64> {
+    let mut hasher = DefaultHasher::new();
+    for name in names {
+        name.hash(&mut hasher);
+        std::fs::read(name).ok()?.hash(&mut hasher);
+    }
+    Some(hasher.finish())
+}
+
+/// Print where loading the build files spent its time, for --profile-startup.
+fn print_profile(phases: &[trace::ProfilePhase], progress: &dyn Progress) {
+    let mut out = String::from("startup profile:\n");
+    out.push_str(&format!(
+        "  {:<20} {:>10} {:>8}\n",
+        "phase", "time", "count"
+    ));
+    let ms = |time: std::time::Duration| format!("{:.1}ms", time.as_secs_f64() * 1000.0);
+    for phase in phases {
+        out.push_str(&format!(
+            "  {:<20} {:>10} {:>8}\n",
+            phase.name,
+            ms(phase.time),
+            phase.count
+        ));
+    }
+    let total = phases.iter().map(|phase| phase.time).sum();
+    out.push_str(&format!("  {:<20} {:>10}", "total", ms(total)));
+    progress.log(&out);
+}
+
+/// Print the size of the loaded build graph, for --debug-graph-stats.
+fn print_graph_stats(state: &load::State, progress: &dyn Progress) {
+    let stats = state.graph.stats();
+    let mut out = String::from("graph stats:\n");
+    let mut line = |name: &str, value: String| {
+        out.push_str(&format!("  {:<16} {}\n", name, value));
+    };
+    line("files", stats.files.to_string());
+    line("builds", stats.builds.to_string());
+    line("edges", stats.edges.to_string());
+    line("pools", state.pools.len().to_string());
+    line("defaults", state.default.len().to_string());
+    line(
+        "memory estimate",
+        format!("{} KiB", stats.memory.div_ceil(1024)),
+    );
+    for (rule, count) in &stats.builds_by_rule {
+        line(&format!("rule {}", rule), count.to_string());
+    }
+    progress.log(out.trim_end());
 }
 
-fn default_parallelism() -> anyhow::Result<usize> {
-    // Ninja uses available processors + a constant, but I don't think the
-    // difference matters too much.
-    let par = std::thread::available_parallelism()?;
-    Ok(usize::from(par))
+/// If a command line target is a pattern rather than a path, return it as a
+/// graph::glob_match pattern.  "dir/..." means everything under dir.
+fn target_pattern(name: &str) -> Option<String> {
+    if let Some(dir) = name.strip_suffix("/...") {
+        return Some(format!("{}/**", to_owned_canon_path(dir)));
+    }
+    if name.contains(['*', '?']) {
+        return Some(to_owned_canon_path(name));
+    }
+    None
 }
 
 /// Run a tool as specified by the `-t` flag`.
@@ -105,21 +439,32 @@ fn subtool(args: &mut BuildArgs, tool: &str) -> anyhow::Result<Option<i32>> {
     match tool {
         "list" => {
             println!("subcommands:");
-            println!(
-                "  (none yet, but see README if you're looking here trying to get CMake to work)"
-            );
+            println!("  aliases    list phony targets and what they build");
+            println!("  caseconflicts  list files whose names differ only by case");
+            println!("  changes    list what changed since targets were last built");
+            println!("  clean      remove built outputs (-n to list them, -r by rule)");
+            println!("  dump       print the evaluated build graph as json");
+            println!("  inputs     list the source files targets are built from");
+            println!("  outputs    list the outputs that depend on files");
+            println!("  recompact  drop superseded records from .n2_db");
+            println!("  rules      list rules with their commands");
+            println!("  touch      touch targets' outputs and record them as up to date");
+            println!("  verify     report built outputs that would be rebuilt (as --verify)");
+            println!("  why        explain why targets are out of date, without building");
+            println!("(see README if you're looking here trying to get CMake to work)");
             return Ok(Some(1));
         }
-        "recompact" if args.fake_ninja_compat => {
-            // CMake unconditionally invokes this tool, yuck.
-            return Ok(Some(0)); // do nothing
-        }
         "restat" if args.fake_ninja_compat => {
             // CMake invokes this after generating build files; mark build
-            // targets as up to date by running the build with "adopt" flag
-            // on.
+            // targets as up to date, as with --adopt.
             args.options.adopt = true;
         }
+        "touch" => {
+            // After editing outputs by hand, mark them as fresh, as --adopt
+            // does for every build, so that what uses them is rebuilt but
+            // they aren't.
+            args.options.touch = true;
+        }
         _ => {
             anyhow::bail!("unknown -t {:?}, use -t list to list", tool);
         }
@@ -127,6 +472,23 @@ fn subtool(args: &mut BuildArgs, tool: &str) -> anyhow::Result<Option<i32>> {
     Ok(None)
 }
 
+/// Handle a -w flag, adjusting how a problem is reported.
+fn warning_flag(args: &mut BuildArgs, flag: &str) -> anyhow::Result<Option<i32>> {
+    match flag {
+        "list" => {
+            println!("warning flags:");
+            println!("  depfileparse={{err,warn}}  unparseable depfile of a succeeding command");
+            return Ok(Some(1));
+        }
+
+        "depfileparse=err" => args.options.depfile_errors_warn = false,
+        "depfileparse=warn" => args.options.depfile_errors_warn = true,
+
+        _ => anyhow::bail!("unknown -w {:?}, use -w list to list", flag),
+    }
+    Ok(None)
+}
+
 /// Run a debug tool as specified by the `-d` flag.
 fn debugtool(args: &mut BuildArgs, tool: &str) -> anyhow::Result<Option<i32>> {
     match tool {
@@ -134,20 +496,137 @@ fn debugtool(args: &mut BuildArgs, tool: &str) -> anyhow::Result<Option<i32>> {
             println!("debug tools:");
             println!("  ninja_compat  enable ninja quirks compatibility mode");
             println!("  explain       print why each target is considered out of date");
-            println!("  trace         generate json performance trace");
+            println!("  trace         generate json performance trace (see also --chrome-trace)");
+            println!("  mtime         print the raw and truncated mtimes of the target paths");
+            println!("  keepdepfile   keep each command's depfile as written and print its path");
+            println!("  keeprsp       keep each command's rspfile as written and print its path");
             return Ok(Some(1));
         }
 
         "ninja_compat" => args.fake_ninja_compat = true,
         "explain" => args.options.explain = true,
-        "trace" => trace::open("trace.json")?,
+        "trace" => trace::open(std::path::Path::new("trace.json"))?,
+        "mtime" => args.debug_mtime = true,
+        "keepdepfile" => args.options.keep_depfile = true,
+        "keeprsp" => args.options.keep_rsp = true,
 
         _ => anyhow::bail!("unknown -d {:?}, use -d list to list", tool),
     }
     Ok(None)
 }
 
-fn parse_args() -> anyhow::Result<Result<BuildArgs, i32>> {
+/// Print the mtimes of paths as stat()ed, and as compared after truncating to
+/// granularity, for debugging spurious rebuilds.
+fn debug_mtime(paths: &[String], granularity: std::time::Duration) -> anyhow::Result<i32> {
+    let format = |t: std::time::SystemTime| {
+        let since = t.duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+        format!("{}.{:09}", since.as_secs(), since.subsec_nanos())
+    };
+    for path in paths {
+        match graph::stat(std::path::Path::new(path))? {
+            graph::MTime::Missing => println!("{}: missing", path),
+            graph::MTime::Stamp(t) => println!(
+                "{}: raw {}, truncated {}",
+                path,
+                format(t),
+                format(graph::truncate_mtime(t, granularity))
+            ),
+        }
+    }
+    Ok(0)
+}
+
+/// Parse a duration with a unit suffix: ns, us, ms, or s.
+fn parse_duration(value: &str) -> anyhow::Result<std::time::Duration> {
+    let digits = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (count, unit) = value.split_at(digits);
+    let err = || anyhow!("expected a duration like 1s or 100ms, got {:?}", value);
+    let count: u64 = count.parse().map_err(|_| err())?;
+    Ok(match unit {
+        "ns" => std::time::Duration::from_nanos(count),
+        "us" => std::time::Duration::from_micros(count),
+        "ms" => std::time::Duration::from_millis(count),
+        "s" => std::time::Duration::from_secs(count),
+        _ => return Err(err()),
+    })
+}
+
+/// Parse a "name=N" flag value, as used by --pool-depth and --rule-jobs.
+fn parse_name_count(value: &str) -> anyhow::Result<(String, usize)> {
+    let (name, count) = value
+        .split_once('=')
+        .ok_or_else(|| anyhow!("expected name=N, got {:?}", value))?;
+    let count = count
+        .parse()
+        .map_err(|err| anyhow!("expected name=N, got {:?}: {}", value, err))?;
+    Ok((name.to_string(), count))
+}
+
+/// Change to the build directory, as for -C.  Programs embedding n2 share our
+/// current directory, so run_with() leaves it alone.
+fn change_dir(dir: &std::path::Path, embedded: bool) -> anyhow::Result<()> {
+    if embedded {
+        anyhow::bail!(
+            "can't change to {:?} within a program embedding n2; run from the build directory instead",
+            dir
+        );
+    }
+    std::env::set_current_dir(dir).map_err(|err| anyhow!("chdir {:?}: {}", dir, err))
+}
+
+/// With --look-up, change to the nearest directory with the build file, if
+/// the current one doesn't have it.
+fn look_up_build_dir(args: &BuildArgs, look_up: bool, embedded: bool) -> anyhow::Result<()> {
+    if !look_up {
+        return Ok(());
+    }
+    let build_filename = args.build_filenames()[0];
+    if !std::path::Path::new(build_filename).exists() {
+        if let Some(dir) = load::find_up(build_filename)? {
+            // Same message as ninja -C, which editors parse to resolve
+            // paths in error messages.
+            change_dir(&dir, embedded)?;
+            println!("n2: Entering directory `{}'", dir.display());
+        }
+    }
+    Ok(())
+}
+
+/// Read the targets listed, one per line, in "@file" or, for "-", stdin, for
+/// more targets than fit in a command line.
+fn read_targets(source: &str) -> anyhow::Result<Vec<String>> {
+    let text = match source.strip_prefix('@') {
+        Some(path) => {
+            std::fs::read_to_string(path).map_err(|err| anyhow!("read {}: {}", path, err))?
+        }
+        None => std::io::read_to_string(std::io::stdin())
+            .map_err(|err| anyhow!("read targets from stdin: {}", err))?,
+    };
+    Ok(text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_owned)
+        .collect())
+}
+
+fn parse_args(parser: lexopt::Parser, embedded: bool) -> anyhow::Result<Result<BuildArgs, i32>> {
+    let mut config_file = None;
+    parse_args_from(parser, embedded, &mut config_file).map_err(|err| match config_file {
+        Some(path) => anyhow!("{}: {}", path.display(), err),
+        None => err,
+    })
+}
+
+/// Parse the command line, then the config files.  While parsing a config
+/// file's flags, config_file names it, for parse_args to blame any error on.
+fn parse_args_from(
+    mut parser: lexopt::Parser,
+    embedded: bool,
+    config_file: &mut Option<std::path::PathBuf>,
+) -> anyhow::Result<Result<BuildArgs, i32>> {
     let mut args = BuildArgs::default();
     args.fake_ninja_compat = std::path::Path::new(&std::env::args().next().unwrap())
         .file_name()
@@ -155,37 +634,191 @@ fn parse_args() -> anyhow::Result<Result<BuildArgs, i32>> {
         == std::ffi::OsStr::new(&format!("ninja{}", std::env::consts::EXE_SUFFIX));
 
     use lexopt::prelude::*;
-    let mut parser = lexopt::Parser::from_env();
-    while let Some(arg) = parser.next()? {
+    let mut look_up = false;
+    // Pretending to be ninja, take only ninja's flags.
+    let mut no_config = args.fake_ninja_compat;
+    // Flags given on the command line, which config files don't override.
+    let mut given = HashSet::new();
+    // The config files left to parse, once the command line is done.
+    let mut config_files = None;
+    loop {
+        let arg = match parser.next()? {
+            Some(arg) => arg,
+            None if no_config => break,
+            None => {
+                let files = match &mut config_files {
+                    Some(files) => files,
+                    None => {
+                        // The build directory is known now that the command
+                        // line is done, once any --look-up finds it.
+                        look_up_build_dir(&args, look_up, embedded)?;
+                        config_files.insert(config::args(&given)?.into_iter())
+                    }
+                };
+                match files.next() {
+                    Some((path, file_args)) => {
+                        *config_file = Some(path);
+                        parser = lexopt::Parser::from_args(file_args);
+                        continue;
+                    }
+                    None => {
+                        *config_file = None;
+                        break;
+                    }
+                }
+            }
+        };
+        if config_files.is_none() {
+            match &arg {
+                Short(flag) => given.insert(format!("-{}", flag)),
+                Long(flag) => given.insert(format!("--{}", flag)),
+                Value(_) => false,
+            };
+        }
         match arg {
             Short('h') | Long("help") => {
                 println!(
                     "n2: a ninja-compatible build tool
 usage: n2 [options] [targets...]
+(the target all builds everything, even if the build files set defaults;
+targets may be patterns like 'obj/*.o', 'obj/**', or obj/..., and foo.c^
+means the output foo.c is built into; @file or - reads more targets, one per
+line, from file or stdin)
 
 options:
 -C dir   chdir before running
--f file  input build file [default: build.ninja]
--j N     parallelism [default: use system thread count]
+-f file  input build file [default: build.ninja]; repeat to load several
+-j N     parallelism [default: the CPUs available, within any cgroup or job
+         object CPU quota]
 -k N     keep going until at least N failures [default: 1]
 -v       print executed command lines
 
+--cpu-fraction F     without -j, run commands on fraction F of the CPUs, e.g. 0.5
+                     to skip hyperthreads or share a machine
+--look-up            if the build file isn't in the current directory, use the
+                     nearest parent directory that has one, as with -C
+--no-config          ignore n2.toml in the build directory and ~/.n2.toml,
+                     which otherwise supply default flags (see src/config.rs)
+--pool-depth name=N  override the depth of pool name, with 0 for unbounded
+--rule-jobs name=N   run at most N commands of rule name at once
+--physical-paths     resolve symlinks before .. in paths, as the OS does,
+                     rather than canonicalizing paths lexically
+--prestat            read input mtimes by listing their directories in parallel
+                     up front, for filesystems where each stat is slow
+--prescan            check which builds will run before starting any, to show
+                     progress against them rather than all builds
+--stat-threads N     check whether builds are up to date on N threads while
+                     commands run, for filesystems where each stat is slow
+--deterministic      start commands in the same order on every run, waiting for
+                     running ones as needed, to reproduce order-dependent bugs
+--dir-cache          create each output directory once per build, rather than
+                     checking for it before every command
+--env-vars           expand ${{env.NAME}} in build files to the environment
+                     variable NAME, so changing it reruns the affected commands
+--build-subninjas    if a subninja doesn't exist yet but a build generates it,
+                     build it first and then load the build files again
+--fold-case          if the filesystem is case-insensitive, treat paths that
+                     differ only by case as the same file
+--hermetic           fail builds with inputs outside the build directory
+--hermetic-root dir  allow inputs within dir too (implies --hermetic)
+--retries N          rerun failed commands up to N times, for rules that
+                     don't set the retries variable
+--skip-known-failures
+                     don't rerun commands that failed last time, until their
+                     inputs or command line change
+--forget-failures    clear the record of failed commands
+--halt-on-error=MODE once the build has failed, wait (the default) for running
+                     commands to finish, or kill them to exit promptly
+--lock=MODE          if another n2 is building in the same directory, wait for
+                     it (the default), or fail
+--debug-lock file    lock file rather than .n2_lock in builddir
+--adopt              record the targets' outputs as up to date as they are,
+                     without running commands, e.g. after restoring them
+                     from a cache
+--dedup-commands     run a command once for builds with the same command line
+                     and inputs, e.g. generated stamp or mkdir edges
+--memory-budget MB   only run commands while the sum of their memory_estimate
+                     variables stays under MB
+--serialize-output[=started]
+                     print each task's output in one piece as it finishes (the
+                     default), or with =started, in the order tasks started
+--fail-on-output[=rules]
+                     fail commands that succeed but print anything, except
+                     those of the comma-separated rules
+--color=WHEN         force commands to print colored output: auto (when
+                     stdout is a color terminal), always, or never (the
+                     default)
+--prefix-output[=rule]
+                     start each line of task output with the task's first
+                     output path, or with =rule, its rule name
+--wrapper cmd        run each command under cmd, e.g. a sandbox or strace
+--windows-shell      on Windows, run commands that start with a cmd builtin
+                     (e.g. echo) or use &, |, < or > under cmd /c, rather
+                     than as programs
+--rule-stats         after building, print the number of commands run and
+                     time taken per rule
+--verify             instead of building, print a JSON line for each built
+                     output that would be rebuilt, and why; fails if any
+--dump-critical-path
+                     after building, print the chain of commands that took
+                     the longest, which bounds the build's wall time
+--chrome-trace path  write a json performance trace to path; if path is a
+                     directory, write a new timestamped trace file into it
+--temp-dir dir       put n2's own temporary files, such as large command output,
+                     in dir rather than $TMPDIR, e.g. on a ramdisk
+--output-base dir    write build outputs under dir, e.g. on a ramdisk, as if the
+                     build files named them there; targets keep their names
+--mtime-granularity D
+                     compare file mtimes truncated to D (e.g. 1s or 2s), for
+                     filesystems that store coarse times; changing it
+                     rebuilds everything once
+--trust-mtimes       take builds whose files all predate their last run as up
+                     to date without hashing them, which misses files replaced
+                     by older versions
+--hash-contents dir  hash the files within dir by their contents rather than
+                     their mtimes, for trees unpacked with normalized
+                     timestamps (e.g. SOURCE_DATE_EPOCH)
+--timeout SECS       kill commands that run longer than SECS and fail them, for
+                     builds that don't set the timeout variable
+--hang-timeout N     print the command of any task running over N minutes (or
+                     send SIGUSR1 to print those of all running tasks)
+--hang-hook cmd      with --hang-timeout, run cmd on a hung task, passing its
+                     pid and command in $N2_HUNG_PID and $N2_HUNG_COMMAND
+--profile-startup    print the time spent reading, parsing, and otherwise loading
+                     the build files and .n2_db, per phase
+--debug-graph-stats after loading the build files, print the number of files,
+                     builds, and edges in the graph, and builds per rule
+--on-complete cmd    after the build, and writing .n2_summary.json to builddir,
+                     run cmd, passing the outcome in $N2_STATUS, $N2_TASKS_RUN,
+                     $N2_DURATION, $N2_SUMMARY, and $N2_FAILED_EDGES
+
 -t tool  tools (`-t list` to list)
 -d tool  debugging tools (use `-d list` to list)
+-w flag  adjust warnings (use `-w list` to list)
 "
                 );
                 return Ok(Err(0));
             }
 
             Short('C') => {
-                let dir = parser.value()?;
-                std::env::set_current_dir(&dir)
-                    .map_err(|err| anyhow!("chdir {:?}: {}", dir, err))?;
+                change_dir(parser.value()?.as_ref(), embedded)?;
             }
 
-            Short('f') => args.build_filename = Some(parser.value()?.to_string_lossy().into()),
+            Short('f') => args
+                .build_filenames
+                .push(parser.value()?.to_string_lossy().into()),
             Short('t') => {
-                if let Some(exit) = subtool(&mut args, &*parser.value()?.to_string_lossy())? {
+                let tool = parser.value()?.to_string_lossy().into_owned();
+                if tools::is_graph_tool(&tool) {
+                    // As in ninja, the remaining arguments belong to the tool.
+                    let tool_args = parser
+                        .raw_args()?
+                        .map(|arg| arg.to_string_lossy().into_owned())
+                        .collect();
+                    args.tool = Some((tool, tool_args));
+                    continue;
+                }
+                if let Some(exit) = subtool(&mut args, &tool)? {
                     return Ok(Err(exit));
                 }
             }
@@ -194,54 +827,280 @@ options:
                     return Ok(Err(exit));
                 }
             }
+            Short('w') => {
+                if let Some(exit) = warning_flag(&mut args, &parser.value()?.to_string_lossy())? {
+                    return Ok(Err(exit));
+                }
+            }
             Short('j') => args.options.parallelism = parser.value()?.parse()?,
             Short('k') => args.options.failures_left = Some(parser.value()?.parse()?),
             Short('v') => args.verbose = true,
+            Long("pool-depth") => {
+                let (name, depth) = parse_name_count(&parser.value()?.to_string_lossy())?;
+                args.options.pool_depths.push((name, depth));
+            }
+            Long("rule-jobs") => {
+                let (name, jobs) = parse_name_count(&parser.value()?.to_string_lossy())?;
+                args.options.rule_jobs.push((name, jobs));
+            }
+            Long("physical-paths") => {
+                args.options.physical_paths = true;
+                args.load_options.physical_paths = true;
+            }
+            Long("look-up") => look_up = true,
+            Long("no-config") => no_config = true,
+            Long("prestat") => args.options.prestat = true,
+            Long("prescan") => args.options.prescan = true,
+            Long("stat-threads") => args.options.stat_threads = parser.value()?.parse()?,
+            Long("dir-cache") => args.options.dir_cache = true,
+            Long("deterministic") => args.options.deterministic = true,
+            Long("fold-case") => args.load_options.fold_case = true,
+            Long("env-vars") => args.load_options.env_vars = true,
+            Long("build-subninjas") => args.load_options.build_subninjas = true,
+            Long("hermetic") => {
+                args.options.hermetic_roots.get_or_insert_with(Vec::new);
+            }
+            Long("hermetic-root") => {
+                let root = parser.value()?.to_string_lossy().into_owned();
+                let root = std::path::absolute(&root)
+                    .map_err(|err| anyhow!("--hermetic-root {:?}: {}", root, err))?;
+                args.options
+                    .hermetic_roots
+                    .get_or_insert_with(Vec::new)
+                    .push(to_owned_canon_path(root.to_string_lossy()));
+            }
+            Long("retries") => args.options.retries = parser.value()?.parse()?,
+            Long("trust-mtimes") => args.options.trust_mtimes = true,
+            Long("hash-contents") => {
+                let dir = parser.value()?.to_string_lossy().into_owned();
+                args.options.content_roots.push(to_owned_canon_path(dir));
+            }
+            Long("mtime-granularity") => {
+                let value = parser.value()?.to_string_lossy().into_owned();
+                args.options.mtime_granularity = parse_duration(&value)
+                    .map_err(|err| anyhow!("--mtime-granularity: {}", err))?;
+            }
+            Long("rule-stats") => args.options.rule_stats = true,
+            Long("verify") => args.tool = Some(("verify".to_owned(), Vec::new())),
+            Long("dump-critical-path") => args.options.critical_path = true,
+            Long("skip-known-failures") => args.options.skip_known_failures = true,
+            Long("forget-failures") => args.load_options.forget_failures = true,
+            Long("cpu-fraction") => {
+                let fraction: f64 = parser.value()?.parse()?;
+                if !fraction.is_finite() || fraction <= 0.0 {
+                    anyhow::bail!("--cpu-fraction must be positive");
+                }
+                args.cpu_fraction = Some(fraction);
+            }
+            Long("halt-on-error") => {
+                args.options.halt = match parser.value()?.to_string_lossy().as_ref() {
+                    "wait" => work::HaltMode::Wait,
+                    "kill" => work::HaltMode::Kill,
+                    other => {
+                        anyhow::bail!("--halt-on-error: expected wait or kill, got {:?}", other)
+                    }
+                };
+            }
+            Long("lock") => {
+                args.load_options.lock = match parser.value()?.to_string_lossy().as_ref() {
+                    "wait" => lock::LockMode::Wait,
+                    "fail" => lock::LockMode::Fail,
+                    other => anyhow::bail!("--lock: expected wait or fail, got {:?}", other),
+                };
+            }
+            Long("debug-lock") => {
+                args.load_options.lock_path = Some(parser.value()?.into());
+            }
+            Long("adopt") => args.options.adopt = true,
+            Long("dedup-commands") => args.options.dedup_commands = true,
+            Long("memory-budget") => {
+                args.options.memory_budget = Some(parser.value()?.parse()?);
+            }
+            Long("timeout") => {
+                let secs: f64 = parser.value()?.parse()?;
+                let timeout = std::time::Duration::try_from_secs_f64(secs)
+                    .map_err(|err| anyhow!("--timeout: {}", err))?;
+                args.options.timeout = Some(timeout);
+            }
+            Long("hang-timeout") => {
+                let minutes: f64 = parser.value()?.parse()?;
+                let timeout = std::time::Duration::try_from_secs_f64(minutes * 60.0)
+                    .map_err(|err| anyhow!("--hang-timeout: {}", err))?;
+                args.options.hang_timeout = Some(timeout);
+            }
+            Long("windows-shell") => args.options.windows_shell = true,
+            Long("wrapper") => {
+                args.options.wrapper = Some(parser.value()?.to_string_lossy().into())
+            }
+            Long("hang-hook") => {
+                args.options.hang_hook = Some(parser.value()?.to_string_lossy().into())
+            }
+            Long("debug-graph-stats") => args.graph_stats = true,
+            Long("profile-startup") => args.profile_startup = true,
+            Long("on-complete") => {
+                args.on_complete = Some(parser.value()?.to_string_lossy().into())
+            }
+            Long("serialize-output") => {
+                args.output_order = match parser.optional_value() {
+                    None => OutputOrder::Finished,
+                    Some(order) => match order.to_string_lossy().as_ref() {
+                        "finished" => OutputOrder::Finished,
+                        "started" => OutputOrder::Started,
+                        other => anyhow::bail!(
+                            "--serialize-output: expected finished or started, got {:?}",
+                            other
+                        ),
+                    },
+                };
+            }
+            Long("fail-on-output") => {
+                args.options.fail_on_output = Some(match parser.optional_value() {
+                    None => Vec::new(),
+                    Some(rules) => rules
+                        .to_string_lossy()
+                        .split(',')
+                        .map(str::to_owned)
+                        .collect(),
+                });
+            }
+            Long("color") => {
+                args.color = match parser.value()?.to_string_lossy().as_ref() {
+                    "auto" => terminal::ColorMode::Auto,
+                    "always" => terminal::ColorMode::Always,
+                    "never" => terminal::ColorMode::Never,
+                    other => {
+                        anyhow::bail!("--color: expected auto, always, or never, got {:?}", other)
+                    }
+                };
+            }
+            Long("prefix-output") => {
+                args.options.output_prefix = Some(match parser.optional_value() {
+                    None => task::OutputPrefix::Output,
+                    Some(prefix) => match prefix.to_string_lossy().as_ref() {
+                        "output" => task::OutputPrefix::Output,
+                        "rule" => task::OutputPrefix::Rule,
+                        other => anyhow::bail!(
+                            "--prefix-output: expected output or rule, got {:?}",
+                            other
+                        ),
+                    },
+                });
+            }
+            Long("temp-dir") => args.options.temp_dir = Some(parser.value()?.into()),
+            Long("output-base") => {
+                let dir = parser.value()?.to_string_lossy().into_owned();
+                args.load_options.output_base = Some(to_owned_canon_path(dir));
+            }
+            Long("chrome-trace") => {
+                let path = parser.value()?;
+                trace::open(std::path::Path::new(&path))
+                    .map_err(|err| anyhow!("open trace {:?}: {}", path, err))?;
+            }
 
             Long("version") => {
                 if args.fake_ninja_compat {
                     // CMake requires a particular Ninja version.
-                    println!("1.10.2");
+                    println!("{}", load::NINJA_COMPAT_VERSION);
                 } else {
                     println!("{}", env!("CARGO_PKG_VERSION"));
                 }
                 return Ok(Err(0));
             }
 
-            Value(arg) => args.targets.push(arg.to_string_lossy().into()),
+            Value(arg) => {
+                let arg = arg.to_string_lossy();
+                if arg == "-" || arg.starts_with('@') {
+                    args.targets.extend(read_targets(&arg)?);
+                } else {
+                    args.targets.push(arg.into());
+                }
+            }
 
             _ => anyhow::bail!("{}", arg.unexpected()),
         }
     }
 
+    if args.fake_ninja_compat {
+        // Ninja would leave these references empty.
+        args.load_options.env_vars = false;
+        args.load_options.ninja_compat = true;
+    }
+
+    if no_config {
+        look_up_build_dir(&args, look_up, embedded)?;
+    }
+
+    if args.options.touch && args.targets.is_empty() {
+        anyhow::bail!("-t touch: expected at least one target");
+    }
+
+    if let Some(roots) = &mut args.options.hermetic_roots {
+        // The build directory, as finally chosen by any -C, is always allowed.
+        let cwd = std::env::current_dir()?;
+        roots.push(to_owned_canon_path(cwd.to_string_lossy()));
+    }
+
     if args.options.parallelism == 0 {
-        args.options.parallelism = default_parallelism()?;
+        args.options.parallelism = cpus::default_parallelism(args.cpu_fraction.unwrap_or(1.0))?;
+    }
+
+    if trace::enabled() {
+        let argv = std::env::args().collect::<Vec<_>>().join(" ");
+        trace::write_metadata("process_name", &[("name", "n2")]);
+        trace::write_metadata(
+            "n2_invocation",
+            &[
+                ("argv", &argv),
+                ("parallelism", &args.options.parallelism.to_string()),
+                ("version", env!("CARGO_PKG_VERSION")),
+            ],
+        );
     }
 
     Ok(Ok(args))
 }
 
-fn run_impl() -> anyhow::Result<i32> {
-    let args = match parse_args()? {
+fn run_impl(parser: lexopt::Parser, cancel: Option<CancelToken>) -> anyhow::Result<i32> {
+    let mut args = match parse_args(parser, cancel.is_some())? {
         Ok(args) => args,
         Err(exit) => return Ok(exit),
     };
+    args.options.cancel = cancel;
 
+    if args.debug_mtime {
+        return debug_mtime(&args.targets, args.options.mtime_granularity);
+    }
+
+    if let Some((tool, tool_args)) = args.tool.take() {
+        // Of the tools, only recompact writes to the build directory.
+        args.load_options.take_lock = tool == "recompact";
+        let state = trace::scope("load::read", || {
+            load::read(&args.build_filenames(), &args.load_options)
+        })?;
+        return tools::run(&tool, tool_args, state, &args.options);
+    }
+
+    args.load_options.take_lock = true;
     match build(args)? {
         None => {
             // Don't print any summary, the failing task is enough info.
             return Ok(1);
         }
-        Some(0) => {
+        Some(summary) if summary.tasks_run == 0 => {
             // Special case: don't print numbers when no work done.
             println!("n2: no work to do");
         }
-        Some(n) => {
-            println!(
-                "n2: ran {} task{}, now up to date",
-                n,
-                if n == 1 { "" } else { "s" }
-            );
+        Some(summary) => {
+            let n = summary.tasks_run;
+            let plural = if n == 1 { "" } else { "s" };
+            if summary.up_to_date > 0 {
+                println!(
+                    "n2: ran {} task{}, {} already up to date",
+                    n, plural, summary.up_to_date
+                );
+            } else {
+                println!("n2: ran {} task{}, now up to date", n, plural);
+            }
         }
     }
 
@@ -249,7 +1108,20 @@ fn run_impl() -> anyhow::Result<i32> {
 }
 
 pub fn run() -> anyhow::Result<i32> {
-    let res = run_impl();
+    let res = run_impl(lexopt::Parser::from_env(), None);
+    trace::close();
+    res
+}
+
+/// Run as if invoked with the command line args (not including the program
+/// name), for programs embedding n2.  Cancelling cancel stops the build, which
+/// then fails with Error::Cancelled.  The build runs in the current directory:
+/// -C and --look-up, which would change it for the whole program, fail.
+pub fn run_with(
+    args: impl IntoIterator<Item = impl Into<std::ffi::OsString>>,
+    cancel: CancelToken,
+) -> anyhow::Result<i32> {
+    let res = run_impl(lexopt::Parser::from_args(args), Some(cancel));
     trace::close();
     res
 }
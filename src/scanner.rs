@@ -1,5 +1,6 @@
 //! Scans an input string (source file) character by character.
 
+use crate::error::Error;
 use std::{io::Read, path::Path};
 
 #[derive(Debug)]
@@ -113,44 +114,35 @@ impl<'a> Scanner<'a> {
     }
 
     pub fn parse_error<T, S: Into<String>>(&self, msg: S) -> ParseResult<T> {
+        self.parse_error_at(self.ofs, msg)
+    }
+
+    /// Like parse_error, but for text already read, starting at ofs.
+    pub fn parse_error_at<T, S: Into<String>>(&self, ofs: usize, msg: S) -> ParseResult<T> {
         Err(ParseError {
             msg: msg.into(),
-            ofs: self.ofs,
+            ofs,
         })
     }
 
     pub fn format_parse_error(&self, filename: &Path, err: ParseError) -> String {
+        self.to_error(filename, err).to_string()
+    }
+
+    /// Convert a ParseError into an Error that carries the location of the
+    /// failure within the input.
+    pub fn to_error(&self, filename: &Path, err: ParseError) -> Error {
         let mut ofs = 0;
         let lines = self.buf.split(|&c| c == b'\n');
         for (line_number, line) in lines.enumerate() {
             if ofs + line.len() >= err.ofs {
-                let mut msg = "parse error: ".to_string();
-                msg.push_str(&err.msg);
-                msg.push('\n');
-
-                let prefix = format!("{}:{}: ", filename.display(), line_number + 1);
-                msg.push_str(&prefix);
-
-                let mut context = unsafe { std::str::from_utf8_unchecked(line) };
-                let mut col = err.ofs - ofs;
-                if col > 40 {
-                    // Trim beginning of line to fit it on screen.
-                    msg.push_str("...");
-                    context = &context[col - 20..];
-                    col = 3 + 20;
-                }
-                if context.len() > 40 {
-                    context = &context[0..40];
-                    msg.push_str(context);
-                    msg.push_str("...");
-                } else {
-                    msg.push_str(context);
-                }
-                msg.push('\n');
-
-                msg.push_str(&" ".repeat(prefix.len() + col));
-                msg.push_str("^\n");
-                return msg;
+                return Error::Parse {
+                    file: filename.to_owned(),
+                    line: line_number + 1,
+                    column: err.ofs - ofs,
+                    text: unsafe { std::str::from_utf8_unchecked(line) }.to_owned(),
+                    msg: err.msg,
+                };
             }
             ofs += line.len() + 1;
         }
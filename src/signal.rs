@@ -1,12 +1,18 @@
-//! Unix signal handling (SIGINT).
+//! Unix signal handling (SIGINT, and SIGUSR1/SIGINFO).
 //!
 //! We let the first SIGINT reach child processes, which ought to build-fail
 //! and let the parent properly print that progress.  This also lets us still
 //! write out pending debug traces, too.
+//!
+//! SIGUSR1, or SIGINFO (ctrl-t) where there is one, asks the progress display
+//! to print the full commands of the running tasks.
 
-use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static SHOW_COMMANDS: AtomicBool = AtomicBool::new(false);
 
 #[cfg(unix)]
 extern "C" fn sigint_handler(_sig: libc::c_int) {
@@ -29,3 +35,67 @@ pub fn register_sigint() {
 pub fn was_interrupted() -> bool {
     INTERRUPTED.load(std::sync::atomic::Ordering::Relaxed)
 }
+
+#[cfg(unix)]
+extern "C" fn show_commands_handler(_sig: libc::c_int) {
+    SHOW_COMMANDS.store(true, Ordering::Relaxed);
+}
+
+#[cfg(unix)]
+pub fn register_show_commands() {
+    // Safety: registering a signal handler is libc unsafe code.
+    unsafe {
+        let mut sa: libc::sigaction = std::mem::zeroed();
+        sa.sa_sigaction = show_commands_handler as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        sa.sa_flags = libc::SA_RESTART;
+        #[cfg(not(miri))]
+        libc::sigaction(libc::SIGUSR1, &sa, std::ptr::null_mut());
+        #[cfg(all(not(miri), any(target_os = "macos", target_os = "freebsd")))]
+        libc::sigaction(libc::SIGINFO, &sa, std::ptr::null_mut());
+    }
+}
+
+/// Whether the running tasks' commands were asked for since the last call.
+pub fn take_show_commands() -> bool {
+    SHOW_COMMANDS.swap(false, Ordering::Relaxed)
+}
+
+/// Lets a program embedding n2 stop a build, e.g. from a UI thread.  A
+/// cancelled build kills its running commands and fails with
+/// Error::Cancelled.  Clones share the same cancellation state.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+    deadline: Option<Instant>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A token that also cancels itself once deadline passes.
+    pub fn with_deadline(deadline: Instant) -> Self {
+        CancelToken {
+            cancelled: Arc::default(),
+            deadline: Some(deadline),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+            || self
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    /// How long until the deadline, if there is one.
+    pub(crate) fn time_left(&self) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+}
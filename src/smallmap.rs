@@ -38,16 +38,61 @@ impl<K: PartialEq, V> SmallMap<K, V> {
         None
     }
 
-    pub fn iter(&self) -> std::slice::Iter<(K, V)> {
+    pub fn get_mut<Q>(&mut self, q: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: PartialEq + ?Sized,
+    {
+        self.0
+            .iter_mut()
+            .find(|(k, _)| k.borrow() == q)
+            .map(|(_, v)| v)
+    }
+
+    /// Remove an entry, keeping the order of the others.
+    pub fn remove<Q>(&mut self, q: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: PartialEq + ?Sized,
+    {
+        let index = self.0.iter().position(|(k, _)| k.borrow() == q)?;
+        Some(self.0.remove(index).1)
+    }
+
+    /// Keep only the entries for which f returns true, in their order.
+    pub fn retain(&mut self, mut f: impl FnMut(&K, &mut V) -> bool) {
+        self.0.retain_mut(|(k, v)| f(k, v));
+    }
+
+    /// Make room for at least additional more entries.
+    pub fn reserve(&mut self, additional: usize) {
+        self.0.reserve(additional);
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// The number of entries there's room for without reallocating.
+    pub fn capacity(&self) -> usize {
+        self.0.capacity()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Entries in the order they were first inserted.
+    pub fn iter(&self) -> std::slice::Iter<'_, (K, V)> {
         self.0.iter()
     }
 
-    pub fn iter_mut(&mut self) -> std::slice::IterMut<(K, V)> {
+    pub fn iter_mut(&mut self) -> std::slice::IterMut<'_, (K, V)> {
         self.0.iter_mut()
     }
 
-    pub fn into_iter(self) -> std::vec::IntoIter<(K, V)> {
-        self.0.into_iter()
+    pub fn keys(&self) -> impl Iterator<Item = &K> + '_ {
+        self.0.iter().map(|x| &x.0)
     }
 
     pub fn values(&self) -> impl Iterator<Item = &V> + '_ {
@@ -55,6 +100,24 @@ impl<K: PartialEq, V> SmallMap<K, V> {
     }
 }
 
+impl<K, V> IntoIterator for SmallMap<K, V> {
+    type Item = (K, V);
+    type IntoIter = std::vec::IntoIter<(K, V)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl<'a, K, V> IntoIterator for &'a SmallMap<K, V> {
+    type Item = &'a (K, V);
+    type IntoIter = std::slice::Iter<'a, (K, V)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
 impl<K: PartialEq, V, const N: usize> std::convert::From<[(K, V); N]> for SmallMap<K, V> {
     fn from(value: [(K, V); N]) -> Self {
         let mut result = SmallMap::default();
@@ -78,3 +141,27 @@ impl<K: PartialEq, V: PartialEq> PartialEq for SmallMap<K, V> {
         return self.0 == other.0;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn update() {
+        let mut map = SmallMap::from([("a", 1), ("b", 2), ("c", 3)]);
+        map.reserve(1);
+        map.insert("b", 20);
+        assert_eq!(map.len(), 3);
+        *map.get_mut("c").unwrap() += 1;
+        assert_eq!(map.keys().collect::<Vec<_>>(), [&"a", &"b", &"c"]);
+        assert_eq!(map.values().collect::<Vec<_>>(), [&1, &20, &4]);
+
+        map.retain(|_, v| *v % 2 == 0);
+        assert_eq!(map, SmallMap::from([("b", 20), ("c", 4)]));
+        assert_eq!(map.remove("b"), Some(20));
+        assert_eq!(map.remove("b"), None);
+        assert_eq!(map, SmallMap::from([("c", 4)]));
+        map.retain(|_, _| false);
+        assert!(map.is_empty());
+    }
+}
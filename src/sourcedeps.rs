@@ -0,0 +1,277 @@
+//! Parsing of the JSON dependency files written by MSVC and clang-cl's
+//! `/sourceDependencies` flag, an alternative to scraping `/showIncludes`
+//! output.  These look like:
+//!
+//! ```json
+//! {
+//!     "Version": "1.1",
+//!     "Data": {
+//!         "Source": "c:\\src\\foo.cc",
+//!         "Includes": ["c:\\src\\foo.h"],
+//!         "ImportedModules": [],
+//!         "ImportedHeaderUnits": [{"Header": "c:\\src\\bar.h", "BMI": "..."}]
+//!     }
+//! }
+//! ```
+
+/// A parsed JSON value.  Only as much of JSON as these files need.
+#[derive(Debug, PartialEq)]
+enum Value {
+    Null,
+    Bool(bool),
+    Number,
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn as_array(&self) -> &[Value] {
+        match self {
+            Value::Array(values) => values,
+            _ => &[],
+        }
+    }
+}
+
+struct Parser<'a> {
+    buf: &'a [u8],
+    ofs: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error<T>(&self, msg: &str) -> Result<T, String> {
+        Err(format!("{} at offset {}", msg, self.ofs))
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.buf.get(self.ofs), Some(b' ' | b'\t' | b'\r' | b'\n')) {
+            self.ofs += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_whitespace();
+        self.buf.get(self.ofs).copied()
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), String> {
+        if self.peek() != Some(c) {
+            return self.error(&format!("expected {:?}", c as char));
+        }
+        self.ofs += 1;
+        Ok(())
+    }
+
+    fn literal(&mut self, text: &str, value: Value) -> Result<Value, String> {
+        if !self.buf[self.ofs..].starts_with(text.as_bytes()) {
+            return self.error("invalid literal");
+        }
+        self.ofs += text.len();
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        match self.peek() {
+            Some(b'{') => self.object(),
+            Some(b'[') => self.array(),
+            Some(b'"') => Ok(Value::String(self.string()?)),
+            Some(b't') => self.literal("true", Value::Bool(true)),
+            Some(b'f') => self.literal("false", Value::Bool(false)),
+            Some(b'n') => self.literal("null", Value::Null),
+            Some(b'-' | b'0'..=b'9') => {
+                while matches!(
+                    self.buf.get(self.ofs),
+                    Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9')
+                ) {
+                    self.ofs += 1;
+                }
+                Ok(Value::Number)
+            }
+            _ => self.error("expected value"),
+        }
+    }
+
+    fn object(&mut self) -> Result<Value, String> {
+        self.expect(b'{')?;
+        let mut fields = Vec::new();
+        if self.peek() == Some(b'}') {
+            self.ofs += 1;
+            return Ok(Value::Object(fields));
+        }
+        loop {
+            if self.peek() != Some(b'"') {
+                return self.error("expected key");
+            }
+            let key = self.string()?;
+            self.expect(b':')?;
+            fields.push((key, self.value()?));
+            match self.peek() {
+                Some(b',') => self.ofs += 1,
+                Some(b'}') => {
+                    self.ofs += 1;
+                    return Ok(Value::Object(fields));
+                }
+                _ => return self.error("expected ',' or '}'"),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, String> {
+        self.expect(b'[')?;
+        let mut values = Vec::new();
+        if self.peek() == Some(b']') {
+            self.ofs += 1;
+            return Ok(Value::Array(values));
+        }
+        loop {
+            values.push(self.value()?);
+            match self.peek() {
+                Some(b',') => self.ofs += 1,
+                Some(b']') => {
+                    self.ofs += 1;
+                    return Ok(Value::Array(values));
+                }
+                _ => return self.error("expected ',' or ']'"),
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let digits = self
+            .buf
+            .get(self.ofs..self.ofs + 4)
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok());
+        match digits {
+            Some(n) => {
+                self.ofs += 4;
+                Ok(n)
+            }
+            None => self.error("invalid \\u escape"),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect(b'"')?;
+        let mut out = Vec::new();
+        loop {
+            let Some(&c) = self.buf.get(self.ofs) else {
+                return self.error("unterminated string");
+            };
+            self.ofs += 1;
+            match c {
+                b'"' => break,
+                b'\\' => {
+                    let Some(&esc) = self.buf.get(self.ofs) else {
+                        return self.error("unterminated string");
+                    };
+                    self.ofs += 1;
+                    let c = match esc {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let mut n = self.hex4()?;
+                            if (0xD800..0xDC00).contains(&n)
+                                && self.buf[self.ofs..].starts_with(b"\\u")
+                            {
+                                self.ofs += 2;
+                                let low = self.hex4()?;
+                                n = 0x10000 + ((n - 0xD800) << 10) + (low.wrapping_sub(0xDC00));
+                            }
+                            char::from_u32(n).unwrap_or(char::REPLACEMENT_CHARACTER)
+                        }
+                        _ => return self.error("invalid escape"),
+                    };
+                    let mut buf = [0; 4];
+                    out.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                }
+                _ => out.push(c),
+            }
+        }
+        String::from_utf8(out).or_else(|_| self.error("invalid utf-8"))
+    }
+}
+
+/// Parse a /sourceDependencies file into the headers the source depends on.
+pub fn parse(buf: &[u8]) -> Result<Vec<String>, String> {
+    let mut parser = Parser { buf, ofs: 0 };
+    let root = parser.value()?;
+    if parser.peek().is_some() {
+        return parser.error("trailing data");
+    }
+    let Some(data) = root.get("Data") else {
+        return Err("missing Data".into());
+    };
+    let mut deps = Vec::new();
+    for include in data.get("Includes").map_or(&[][..], Value::as_array) {
+        if let Value::String(path) = include {
+            deps.push(path.clone());
+        }
+    }
+    let header_units = data.get("ImportedHeaderUnits");
+    for unit in header_units.map_or(&[][..], Value::as_array) {
+        if let Some(Value::String(path)) = unit.get("Header") {
+            deps.push(path.clone());
+        }
+    }
+    Ok(deps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn source_dependencies() {
+        let json = r#"{
+    "Version": "1.1",
+    "Data": {
+        "Source": "c:\\src\\foo.cc",
+        "ProvidedModule": "",
+        "Includes": [
+            "c:\\src\\foo.h",
+            "c:\\src\\caf\u00e9.h"
+        ],
+        "ImportedModules": [],
+        "ImportedHeaderUnits": [
+            {"Header": "c:\\src\\unit.h", "BMI": "unit.ifc", "Flag": true, "N": -1.5e3}
+        ]
+    }
+}"#;
+        assert_eq!(
+            parse(json.as_bytes()),
+            Ok(vec![
+                "c:\\src\\foo.h".to_owned(),
+                "c:\\src\\café.h".to_owned(),
+                "c:\\src\\unit.h".to_owned(),
+            ])
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(parse(b"{}"), Err("missing Data".to_owned()));
+        assert_eq!(
+            parse(b"{\"Data\": [}"),
+            Err("expected value at offset 10".to_owned())
+        );
+        assert_eq!(
+            parse(b"{\"Data\": {}} x"),
+            Err("trailing data at offset 13".to_owned())
+        );
+    }
+}
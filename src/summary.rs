@@ -0,0 +1,341 @@
+//! After every build, n2 writes a summary of how it went under builddir, for
+//! e.g. CI to surface build metrics without parsing console output.  The
+//! --on-complete hook runs a command to consume it, e.g. to ship the metrics
+//! somewhere central.
+//!
+//! The summary, .n2_summary.json, looks like:
+//!
+//! ```json
+//! {
+//!   "version": 1,
+//!   "status": "failure",
+//!   "interrupted": false,
+//!   "tasks_run": 12,
+//!   "tasks_failed": 1,
+//!   "tasks_skipped": 0,
+//!   "tasks_predicted": null,
+//!   "duration_ms": 3021,
+//!   "failed": [{"output": "foo.o", "description": "CC foo.o"}],
+//!   "slowest": [{"output": "bar.o", "description": "CC bar.o", "duration_ms": 1800,
+//!                "peak_rss_kb": 90112, "cpu_ms": 1750}],
+//!   "targets": [{"target": "app", "status": "not_built"}, {"target": "foo.o", "status": "failed"}]
+//! }
+//! ```
+//!
+//! status is "success", "failure" (some commands failed or were
+//! interrupted), or "error" (the build couldn't go on, e.g. due to a bad
+//! build file).  interrupted is true if the build was stopped by ctrl-c or
+//! cancellation.  tasks_skipped counts builds not run because they failed
+//! last time with the same inputs, with --skip-known-failures; they're
+//! listed in failed too.  tasks_predicted is how many commands --prescan
+//! expected to run, or null without it.  slowest lists the longest-running commands,
+//! longest first, with the peak memory and CPU time each used.  targets
+//! lists the targets requested on the command line (or the defaults) with
+//! whether each is "satisfied", "failed" (its own command failed), or
+//! "not_built" (e.g. as one of its inputs failed).
+//! Fields may be added, but existing ones only change along with "version".
+
+use crate::{json, process};
+use anyhow::bail;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// How many of the longest-running commands the summary lists.
+pub const SLOWEST: usize = 10;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Status {
+    Success,
+    Failure,
+    #[default]
+    Error,
+}
+
+impl Status {
+    fn name(self) -> &'static str {
+        match self {
+            Status::Success => "success",
+            Status::Failure => "failure",
+            Status::Error => "error",
+        }
+    }
+}
+
+/// A build whose command failed.
+pub struct FailedBuild {
+    /// The build's first output, which identifies it.
+    pub output: String,
+    /// The build's status message, as shown in progress output.
+    pub description: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TargetStatus {
+    /// Up to date, whether built now or already.
+    Satisfied,
+    /// The command that builds it failed.
+    Failed,
+    /// Not reached, e.g. as one of its inputs failed.
+    NotBuilt,
+}
+
+impl TargetStatus {
+    fn name(self) -> &'static str {
+        match self {
+            TargetStatus::Satisfied => "satisfied",
+            TargetStatus::Failed => "failed",
+            TargetStatus::NotBuilt => "not_built",
+        }
+    }
+}
+
+/// A requested target, and how it fared.
+pub struct Target {
+    pub name: String,
+    pub status: TargetStatus,
+}
+
+/// A build whose command ran, and how long it took.
+pub struct TimedBuild {
+    /// The build's first output, which identifies it.
+    pub output: String,
+    /// The build's status message, as shown in progress output.
+    pub description: String,
+    pub duration: Duration,
+    pub usage: process::ResourceUsage,
+}
+
+#[derive(Default)]
+pub struct Summary {
+    pub status: Status,
+    /// Whether the build was stopped by ctrl-c or cancellation.
+    pub interrupted: bool,
+    /// Commands run, including those to regenerate the build files.
+    pub tasks_run: usize,
+    /// Builds skipped as known failures, which are also in failed.
+    pub tasks_skipped: usize,
+    /// Commands --prescan expected to run, if it was used.
+    pub tasks_predicted: Option<usize>,
+    /// Builds already up to date, for the final message; not in the JSON.
+    pub up_to_date: usize,
+    pub elapsed: Duration,
+    pub failed: Vec<FailedBuild>,
+    /// The longest-running commands, longest first; at most SLOWEST.
+    pub slowest: Vec<TimedBuild>,
+    /// The targets requested, in order.
+    pub targets: Vec<Target>,
+    /// The builddir variable, if the build files were loaded and set it.
+    pub builddir: Option<String>,
+}
+
+impl Summary {
+    /// Merge in more timed builds, keeping the slowest.
+    pub fn add_slowest(&mut self, builds: Vec<TimedBuild>) {
+        self.slowest.extend(builds);
+        self.slowest
+            .sort_by(|a, b| b.duration.cmp(&a.duration).then(a.output.cmp(&b.output)));
+        self.slowest.truncate(SLOWEST);
+    }
+
+    fn to_json(&self) -> String {
+        let failed: Vec<String> = self
+            .failed
+            .iter()
+            .map(|failed| {
+                format!(
+                    "{{\"output\": {}, \"description\": {}}}",
+                    json::quote(&failed.output),
+                    json::quote(&failed.description)
+                )
+            })
+            .collect();
+        let slowest: Vec<String> = self
+            .slowest
+            .iter()
+            .map(|timed| {
+                format!(
+                    "{{\"output\": {}, \"description\": {}, \"duration_ms\": {}, \"peak_rss_kb\": {}, \"cpu_ms\": {}}}",
+                    json::quote(&timed.output),
+                    json::quote(&timed.description),
+                    timed.duration.as_millis(),
+                    timed.usage.peak_rss_kb,
+                    timed.usage.cpu_time.as_millis()
+                )
+            })
+            .collect();
+        let targets: Vec<String> = self
+            .targets
+            .iter()
+            .map(|target| {
+                format!(
+                    "{{\"target\": {}, \"status\": {}}}",
+                    json::quote(&target.name),
+                    json::quote(target.status.name())
+                )
+            })
+            .collect();
+        format!(
+            "{{\n  \"version\": 1,\n  \"status\": {},\n  \"interrupted\": {},\n  \"tasks_run\": {},\n  \"tasks_failed\": {},\n  \"tasks_skipped\": {},\n  \"tasks_predicted\": {},\n  \"duration_ms\": {},\n  \"failed\": [{}],\n  \"slowest\": [{}],\n  \"targets\": [{}]\n}}\n",
+            json::quote(self.status.name()),
+            self.interrupted,
+            self.tasks_run,
+            self.failed.len() - self.tasks_skipped,
+            self.tasks_skipped,
+            self.tasks_predicted
+                .map_or("null".to_owned(), |n| n.to_string()),
+            self.elapsed.as_millis(),
+            failed.join(", "),
+            slowest.join(", "),
+            targets.join(", ")
+        )
+    }
+}
+
+/// The path of the summary, in builddir if it's set.
+fn path(builddir: Option<&str>) -> PathBuf {
+    Path::new(builddir.unwrap_or(".")).join(".n2_summary.json")
+}
+
+/// The path of the failed outputs listed for --on-complete, next to the
+/// summary.
+fn failed_edges_path(builddir: Option<&str>) -> PathBuf {
+    Path::new(builddir.unwrap_or(".")).join(".n2_failed_edges")
+}
+
+/// Create the summary file ahead of the build if it doesn't exist, and the
+/// failed outputs file if on_complete will write it.  Adding them afterwards
+/// would change the mtime of their directory, which builds may depend on,
+/// making them dirty next time.
+pub fn reserve(builddir: Option<&str>, on_complete: bool) -> std::io::Result<()> {
+    let mut paths = vec![path(builddir)];
+    if on_complete {
+        paths.push(failed_edges_path(builddir));
+    }
+    for path in paths {
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+    }
+    Ok(())
+}
+
+/// Write the summary to .n2_summary.json in builddir, returning its path.
+pub fn write(summary: &Summary) -> anyhow::Result<PathBuf> {
+    let path = path(summary.builddir.as_deref());
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(&path, summary.to_json())?;
+    Ok(path)
+}
+
+/// Run cmd after the summary was written to summary_path.  cmd gets the
+/// outcome in $N2_STATUS, $N2_TASKS_RUN, and $N2_DURATION (in seconds), the
+/// path of the summary in $N2_SUMMARY, and the path of a file listing the
+/// first output of each failed build, one per line, in $N2_FAILED_EDGES.
+pub fn on_complete(cmd: &str, summary: &Summary, summary_path: &Path) -> anyhow::Result<()> {
+    let failed_path = failed_edges_path(summary.builddir.as_deref());
+    let failed: String = summary
+        .failed
+        .iter()
+        .map(|failed| format!("{}\n", failed.output))
+        .collect();
+    std::fs::write(&failed_path, failed)?;
+
+    let status = process::shell_command(cmd)
+        .env("N2_STATUS", summary.status.name())
+        .env("N2_TASKS_RUN", summary.tasks_run.to_string())
+        .env(
+            "N2_DURATION",
+            format!("{:.3}", summary.elapsed.as_secs_f64()),
+        )
+        .env("N2_SUMMARY", summary_path)
+        .env("N2_FAILED_EDGES", &failed_path)
+        .stdin(std::process::Stdio::null())
+        .status()?;
+    if !status.success() {
+        bail!("{}", status);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json() {
+        let summary = Summary {
+            status: Status::Failure,
+            interrupted: false,
+            tasks_run: 3,
+            tasks_skipped: 0,
+            tasks_predicted: Some(4),
+            up_to_date: 2,
+            elapsed: Duration::from_millis(1500),
+            failed: vec![FailedBuild {
+                output: "out/a\"b.o".to_owned(),
+                description: "CC a.c".to_owned(),
+            }],
+            slowest: vec![TimedBuild {
+                output: "b.o".to_owned(),
+                description: "CC b.c".to_owned(),
+                duration: Duration::from_millis(900),
+                usage: process::ResourceUsage {
+                    peak_rss_kb: 2048,
+                    cpu_time: Duration::from_millis(850),
+                },
+            }],
+            targets: vec![
+                Target {
+                    name: "out/a\"b.o".to_owned(),
+                    status: TargetStatus::Failed,
+                },
+                Target {
+                    name: "app".to_owned(),
+                    status: TargetStatus::NotBuilt,
+                },
+            ],
+            builddir: None,
+        };
+        assert_eq!(
+            summary.to_json(),
+            r#"{
+  "version": 1,
+  "status": "failure",
+  "interrupted": false,
+  "tasks_run": 3,
+  "tasks_failed": 1,
+  "tasks_skipped": 0,
+  "tasks_predicted": 4,
+  "duration_ms": 1500,
+  "failed": [{"output": "out/a\"b.o", "description": "CC a.c"}],
+  "slowest": [{"output": "b.o", "description": "CC b.c", "duration_ms": 900, "peak_rss_kb": 2048, "cpu_ms": 850}],
+  "targets": [{"target": "out/a\"b.o", "status": "failed"}, {"target": "app", "status": "not_built"}]
+}
+"#
+        );
+    }
+
+    #[test]
+    fn slowest() {
+        let timed = |output: &str, ms| TimedBuild {
+            output: output.to_owned(),
+            description: String::new(),
+            duration: Duration::from_millis(ms),
+            usage: Default::default(),
+        };
+        let mut summary = Summary::default();
+        summary.add_slowest((0..SLOWEST as u64).map(|i| timed("a", i)).collect());
+        summary.add_slowest(vec![timed("b", 5), timed("c", 100)]);
+        assert_eq!(summary.slowest.len(), SLOWEST);
+        assert_eq!(summary.slowest[0].output, "c");
+        assert_eq!(summary.slowest[1].duration, Duration::from_millis(9));
+        assert!(summary.slowest.iter().any(|timed| timed.output == "b"));
+        assert_eq!(
+            summary.slowest[SLOWEST - 1].duration,
+            Duration::from_millis(2)
+        );
+    }
+}
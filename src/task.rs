@@ -9,15 +9,24 @@
 //! parsing of depfiles.
 
 use crate::{
+    canon::{self, PhysicalCanon},
     depfile,
-    graph::{Build, BuildId, RspFile},
+    graph::{self, Build, BuildId, FileId, FileNames, RspFile, StatKind},
+    output::{self, OutputCapture, TaskOutput},
     process,
     scanner::{self, Scanner},
+    signal::CancelToken,
+    smallmap::SmallMap,
+    sourcedeps,
+    tempdir::TempDir,
+    terminal,
 };
-use anyhow::{anyhow, bail};
+use anyhow::bail;
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
-use std::sync::mpsc;
-use std::time::Instant;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
 
 pub struct FinishedTask {
     /// A (faked) "thread id", used to put different finished builds in different
@@ -32,30 +41,243 @@ pub struct FinishedTask {
 pub struct TaskResult {
     pub termination: process::Termination,
     /// Console output.
-    pub output: Vec<u8>,
-    pub discovered_deps: Option<Vec<String>>,
+    pub output: TaskOutput,
+    /// Deps read from the depfile or /showIncludes output, without
+    /// duplicates.  Failed commands may have written them too.
+    pub discovered_deps: Option<Vec<FileId>>,
+    /// A problem worth reporting that didn't fail the build.
+    pub warning: Option<String>,
+    /// If true, the command succeeded but its discovered deps couldn't be
+    /// read, so the build shouldn't be considered up to date next time.
+    pub deps_unreadable: bool,
+    /// Resources used by the command.
+    pub usage: process::ResourceUsage,
+    /// Set when the command failed but is to be retried: which attempt
+    /// this was, of how many retries.
+    pub retry: Option<(usize, usize)>,
 }
 
-/// Reads dependencies from a .d file path.
-fn read_depfile(path: &Path) -> anyhow::Result<Vec<String>> {
-    let bytes = match scanner::read_file_with_nul(path) {
+/// What to tag each line of task output with, so interleaved output from
+/// parallel tasks can be attributed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputPrefix {
+    /// The build's first output.
+    Output,
+    /// The build's rule name.
+    Rule,
+}
+
+/// A task that has been running for longer than the hang timeout.
+pub struct HungTask {
+    pub buildid: BuildId,
+    /// Process id of the subprocess, if it has been spawned.
+    pub pid: Option<u32>,
+    pub cmdline: String,
+    pub elapsed: Duration,
+}
+
+/// What wait() reports about tasks that exceed the hang timeout.
+pub enum Hang {
+    /// A task has just exceeded it.
+    Task(HungTask),
+    /// What the hang hook printed, once it finishes running on a hung task.
+    HookOutput(String),
+}
+
+/// Returns an error naming the build if its command line is longer than max.
+fn check_command_len(build: &Build, cmdline: &str, max: usize) -> anyhow::Result<()> {
+    let len = cmdline.len();
+    if len > max {
+        bail!(
+            "{}: command line is {} bytes, over the limit of {}; \
+             consider giving rule {:?} an rspfile or rspfile_template",
+            build.location,
+            len,
+            max,
+            build.rule.name
+        );
+    }
+    Ok(())
+}
+
+/// Directories known to exist, for --dir-cache, so that creating the output
+/// directories of each build doesn't call create_dir_all for the same
+/// directories over and over.  Safe to share between threads.  A directory
+/// that a command removes during the build isn't noticed, which is why the
+/// cache is optional.
+#[derive(Default)]
+pub struct DirCache {
+    dirs: Mutex<HashSet<PathBuf>>,
+    /// create_dir_all calls made, and those skipped thanks to the cache.
+    created: AtomicUsize,
+    skipped: AtomicUsize,
+}
+
+impl DirCache {
+    /// The number of directories created and of creations skipped so far.
+    pub fn counts(&self) -> (usize, usize) {
+        (
+            self.created.load(Ordering::Relaxed),
+            self.skipped.load(Ordering::Relaxed),
+        )
+    }
+
+    pub fn create_dir_all(&self, dir: &Path) -> std::io::Result<()> {
+        if self.dirs.lock().unwrap().contains(dir) {
+            self.skipped.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
+        std::fs::create_dir_all(dir)?;
+        self.created.fetch_add(1, Ordering::Relaxed);
+        // Its parents exist too.
+        let mut dirs = self.dirs.lock().unwrap();
+        for dir in dir.ancestors() {
+            if dir.as_os_str().is_empty() || !dirs.insert(dir.to_owned()) {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads dependencies from a .d file path, or from a /sourceDependencies
+/// JSON file if json is set.  outputs are the canonical paths of the build's
+/// outputs, used to pick which of the .d file's targets to read; the returned
+/// string is a warning if none of them matched.  cwd is the directory the
+/// command ran in, which relative targets in the file are relative to.
+fn read_depfile(
+    path: &Path,
+    json: bool,
+    outputs: &[String],
+    cwd: Option<&Path>,
+) -> anyhow::Result<(Vec<String>, Option<String>)> {
+    let bytes = if json {
+        std::fs::read(path)
+    } else {
+        scanner::read_file_with_nul(path)
+    };
+    let bytes = match bytes {
         Ok(b) => b,
         // See discussion of missing depfiles in #80.
         // TODO(#99): warn or error in this circumstance?
-        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((Vec::new(), None)),
         Err(e) => bail!("read {}: {}", path.display(), e),
     };
+    if json {
+        let deps = sourcedeps::parse(&bytes)
+            .map_err(|err| anyhow::anyhow!("parse {}: {}", path.display(), err))?;
+        return Ok((deps, None));
+    }
 
     let mut scanner = Scanner::new(&bytes);
-    let parsed_deps = depfile::parse(&mut scanner)
-        .map_err(|err| anyhow!(scanner.format_parse_error(path, err)))?;
-    // TODO verify deps refers to correct output
-    let deps: Vec<String> = parsed_deps
+    let parsed_deps = depfile::parse(&mut scanner).map_err(|err| scanner.to_error(path, err))?;
+    if let Some(deps) = matching_deps(&parsed_deps, outputs, cwd) {
+        return Ok((deps, None));
+    }
+    // Rather than drop deps we can't attribute, use them all.
+    let deps = parsed_deps
         .values()
         .flat_map(|x| x.iter())
         .map(|&dep| dep.to_owned())
         .collect();
-    Ok(deps)
+    let targets: Vec<&str> = parsed_deps.iter().map(|&(target, _)| target).collect();
+    let warning = format!(
+        "depfile {} lists none of the build's outputs (found {})",
+        path.display(),
+        targets.join(" ")
+    );
+    Ok((deps, Some(warning)))
+}
+
+/// How tasks treat the deps they discover.
+pub struct DepsOptions {
+    /// Warn rather than fail on unparseable depfiles.
+    pub errors_warn: bool,
+    /// Canonicalizes deps when --physical-paths is set.
+    pub physical: Option<Arc<PhysicalCanon>>,
+    /// The graph's file names, for deps to become FileIds.
+    pub names: Arc<FileNames>,
+    /// graph::glob_match patterns of deps to drop, e.g. system headers.
+    pub ignore: Vec<String>,
+}
+
+/// Resolve a path written by a command that ran in cwd, such as a dep from
+/// its depfile, against the build root instead.
+fn in_cwd(cwd: Option<&Path>, path: String) -> String {
+    match cwd {
+        Some(cwd) if Path::new(&path).is_relative() => format!("{}/{}", cwd.display(), path),
+        _ => path,
+    }
+}
+
+/// Canonicalize discovered deps and turn them into FileIds, dropping
+/// duplicates and ignored deps.  Relative deps are relative to cwd, where the
+/// command ran.  Done on the task's thread, as builds can have thousands.
+fn intern_deps(names: Vec<String>, cwd: Option<&Path>, options: &DepsOptions) -> Vec<FileId> {
+    let mut seen = HashSet::with_capacity(names.len());
+    let mut ids = Vec::with_capacity(names.len());
+    for name in names {
+        let mut name = in_cwd(cwd, name);
+        match &options.physical {
+            Some(physical) => physical.canonicalize(&mut name),
+            None => canon::canonicalize_path(&mut name),
+        }
+        if options
+            .ignore
+            .iter()
+            .any(|pattern| graph::glob_match(pattern.as_bytes(), name.as_bytes()))
+        {
+            continue;
+        }
+        let id = options.names.intern(name);
+        if seen.insert(id) {
+            ids.push(id);
+        }
+    }
+    ids
+}
+
+/// Gathers the deps of the depfile targets that are among outputs.  Other
+/// targets are ignored: a compiler may list extra targets, such as the empty
+/// rule for each header written by gcc's -MP, or outputs of another build.
+/// Returns None if the depfile has targets and none of them match.
+fn matching_deps(
+    parsed: &SmallMap<&str, Vec<&str>>,
+    outputs: &[String],
+    cwd: Option<&Path>,
+) -> Option<Vec<String>> {
+    let mut matched = false;
+    let mut deps = Vec::new();
+    for (target, target_deps) in parsed.iter() {
+        let target = canon::to_owned_canon_path(in_cwd(cwd, (*target).to_owned()));
+        if outputs.contains(&target) {
+            matched = true;
+            deps.extend(target_deps.iter().map(|&dep| dep.to_owned()));
+        }
+    }
+    if !matched && !parsed.iter().all(|(_, deps)| deps.is_empty()) {
+        return None;
+    }
+    Some(deps)
+}
+
+/// Create the parent directories of a build's outputs.
+fn create_parent_dirs(outputs: &[String], cache: Option<&DirCache>) -> anyhow::Result<()> {
+    let mut dirs: Vec<&Path> = Vec::new();
+    for out in outputs {
+        if let Some(parent) = Path::new(out).parent() {
+            if dirs.contains(&parent) {
+                continue;
+            }
+            match cache {
+                Some(cache) => cache.create_dir_all(parent),
+                None => std::fs::create_dir_all(parent),
+            }
+            .map_err(|err| anyhow::anyhow!("create directory {}: {}", parent.display(), err))?;
+            dirs.push(parent);
+        }
+    }
+    Ok(())
 }
 
 fn write_rspfile(rspfile: &RspFile) -> anyhow::Result<()> {
@@ -109,44 +331,223 @@ fn find_last_line(buf: &[u8]) -> &[u8] {
     &buf[start..end]
 }
 
+/// The parts of a Build needed to run its command, copied out so they can be
+/// sent to the task thread.
+struct TaskCommand {
+    cmdline: String,
+    depfile: Option<PathBuf>,
+    /// If true, extract "/showIncludes" lines from output.
+    parse_showincludes: bool,
+    /// Canonical paths of the build's outputs, to match against depfile
+    /// targets.
+    outputs: Vec<String>,
+    /// For a build with atomic_outputs, (temporary path, output) pairs to
+    /// rename into place if the command succeeds.
+    temp_outputs: Vec<(String, String)>,
+    /// Whether the outputs are trees; see Build::dir_outputs.
+    dir_outputs: bool,
+    /// If true, the depfile is /sourceDependencies JSON.
+    depfile_json: bool,
+    /// If true, an unparseable depfile is a warning rather than a failure.
+    depfile_errors_warn: bool,
+    rspfile: Option<RspFile>,
+    cwd: Option<PathBuf>,
+    /// If true, run with direct access to the terminal rather than capturing
+    /// output.
+    console: bool,
+    /// If true, run in a new process group, so a timeout or cancellation can
+    /// kill everything the command started.
+    new_group: bool,
+    /// Inserted at the start of each line of output, from --prefix-output.
+    prefix: Option<String>,
+    /// Where to spill large output, or None to keep it in memory.
+    spill_dir: Option<Arc<TempDir>>,
+}
+
+impl TaskCommand {
+    fn new(
+        build: &Build,
+        outputs: Vec<String>,
+        wrapper: Option<&str>,
+        windows_shell: bool,
+        depfile_errors_warn: bool,
+    ) -> Self {
+        let mut outputs = outputs;
+        let mut temp_outputs = Vec::new();
+        if build.atomic_outputs {
+            for out in &outputs[..build.outs.explicit] {
+                temp_outputs.push((graph::temp_output(out), out.clone()));
+            }
+            // The depfile names the temporary paths, as $out did.
+            outputs.extend(temp_outputs.iter().map(|(temp, _)| temp.clone()));
+        }
+        let mut cmdline = build.cmdline.clone().unwrap();
+        if let Some(prefix) = &build.command_prefix {
+            cmdline.insert_str(0, prefix);
+        }
+        if windows_shell {
+            cmdline = process::windows_command_line(&cmdline).into_owned();
+        }
+        if let Some(wrapper) = wrapper {
+            cmdline = wrap_command(wrapper, &cmdline);
+        }
+        TaskCommand {
+            cmdline,
+            depfile: build.depfile.clone().map(PathBuf::from),
+            parse_showincludes: build.parse_showincludes,
+            outputs,
+            temp_outputs,
+            dir_outputs: build.dir_outputs,
+            depfile_json: build.depfile_json,
+            depfile_errors_warn,
+            rspfile: build.rspfile.clone(),
+            cwd: build.cwd.clone().map(PathBuf::from),
+            console: build.is_console(),
+            new_group: false,
+            prefix: None,
+            spill_dir: None,
+        }
+    }
+}
+
+/// Run cmdline under wrapper, passing the wrapper the whole command as
+/// arguments.  On posix commands are shell syntax, so the wrapper runs a shell
+/// with the command quoted as a single argument.
+fn wrap_command(wrapper: &str, cmdline: &str) -> String {
+    if cfg!(unix) {
+        format!(
+            "{} /bin/sh -c '{}'",
+            wrapper,
+            cmdline.replace('\'', "'\\''")
+        )
+    } else {
+        format!("{} {}", wrapper, cmdline)
+    }
+}
+
 /// Executes a build task as a subprocess.
 /// Returns an Err() if we failed outside of the process itself.
 /// This is run as a separate thread from the main n2 process and will block
 /// on the subprocess, so any additional per-subprocess work we can do belongs
 /// here.
 fn run_task(
-    cmdline: &str,
-    depfile: Option<&Path>,
-    parse_showincludes: bool,
-    rspfile: Option<&RspFile>,
+    cmd: &TaskCommand,
+    commands: &dyn process::CommandRunner,
+    dirs: Option<&DirCache>,
+    deps: &DepsOptions,
+    mut spawned_cb: impl FnMut(u32),
     mut last_line_cb: impl FnMut(&[u8]),
 ) -> anyhow::Result<TaskResult> {
-    if let Some(rspfile) = rspfile {
+    create_parent_dirs(&cmd.outputs, dirs)?;
+    if let Some(cwd) = &cmd.cwd {
+        if !cwd.is_dir() {
+            bail!("cwd {}: not a directory", cwd.display());
+        }
+    }
+    if let Some(rspfile) = &cmd.rspfile {
         write_rspfile(rspfile)?;
     }
 
-    let mut output = Vec::new();
-    let termination = process::run_command(cmdline, |buf| {
-        output.extend_from_slice(buf);
-        last_line_cb(find_last_line(&output));
-    })?;
+    // /showIncludes output is filtered after the fact, so can't be spilled,
+    // and is prefixed after filtering.
+    let mut capture = if cmd.parse_showincludes {
+        OutputCapture::new(None)
+    } else if let Some(prefix) = &cmd.prefix {
+        OutputCapture::with_prefix(cmd.spill_dir.clone(), prefix.clone())
+    } else {
+        OutputCapture::new(cmd.spill_dir.clone())
+    };
+    let mut capture_err = None;
+    let (termination, usage) = commands.run(
+        &cmd.cmdline,
+        cmd.cwd.as_deref(),
+        cmd.console,
+        cmd.new_group,
+        &mut spawned_cb,
+        &mut |buf| {
+            if let Err(err) = capture.push(buf) {
+                capture_err.get_or_insert(err);
+            }
+            last_line_cb(find_last_line(capture.tail()));
+        },
+    )?;
+    if let Some(err) = capture_err {
+        bail!("capturing output: {}", err);
+    }
+    let mut output = capture.finish();
 
     let mut discovered_deps = None;
-    if parse_showincludes {
+    let mut warning = None;
+    let mut deps_unreadable = false;
+    if cmd.parse_showincludes {
         // Remove /showIncludes lines from output, regardless of success/fail.
-        let (includes, filtered) = extract_showincludes(output);
-        output = filtered;
+        let (includes, mut filtered) = extract_showincludes(output.tail().to_vec());
+        if let Some(prefix) = &cmd.prefix {
+            filtered = output::prefix_lines(&filtered, prefix);
+        }
+        output = filtered.into();
         discovered_deps = Some(includes);
     }
+    if !output.is_spilled() {
+        if let Some(text) = terminal::console_text(output.tail()) {
+            output = text.into();
+        }
+    }
     if termination == process::Termination::Success {
-        if let Some(depfile) = depfile {
-            discovered_deps = Some(read_depfile(depfile)?);
+        for (temp, out) in &cmd.temp_outputs {
+            // Commands needn't write every output, e.g. with restat.
+            let temp_path = Path::new(temp);
+            if temp_path.exists() {
+                // A directory can't be renamed over one with contents.
+                if temp_path.is_dir() {
+                    if let Err(err) = graph::remove_output(Path::new(out), cmd.dir_outputs) {
+                        bail!("remove {}: {}", out, err);
+                    }
+                }
+                if let Err(err) = std::fs::rename(temp, out) {
+                    bail!("rename {} to {}: {}", temp, out, err);
+                }
+            }
+        }
+        if let Some(depfile) = &cmd.depfile {
+            match read_depfile(depfile, cmd.depfile_json, &cmd.outputs, cmd.cwd.as_deref()) {
+                Ok((deps, depfile_warning)) => {
+                    discovered_deps = Some(deps);
+                    warning = depfile_warning;
+                }
+                Err(err) if cmd.depfile_errors_warn => {
+                    warning = Some(format!(
+                        "{}\nignoring depfile; will rebuild next time",
+                        err.to_string().trim_end()
+                    ));
+                    deps_unreadable = true;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    } else {
+        // Leave the outputs as they were rather than half written.
+        for (temp, _) in &cmd.temp_outputs {
+            let _ = graph::remove_output(Path::new(temp), true);
+        }
+        // Compilers often still write a depfile when they fail.  Keep its
+        // deps for next time, if it's there and readable.
+        if let Some(depfile) = &cmd.depfile {
+            if let Ok((deps, _)) =
+                read_depfile(depfile, cmd.depfile_json, &cmd.outputs, cmd.cwd.as_deref())
+            {
+                discovered_deps = Some(deps);
+            }
         }
     }
     Ok(TaskResult {
         termination,
         output,
-        discovered_deps,
+        discovered_deps: discovered_deps.map(|names| intern_deps(names, cmd.cwd.as_deref(), deps)),
+        warning,
+        deps_unreadable,
+        usage,
+        retry: None,
     })
 }
 
@@ -177,9 +578,66 @@ impl ThreadIds {
     }
 }
 
+/// The mtimes of a ready build's files, read off the scheduler thread; see
+/// Runner::stat.
+pub struct Stated {
+    pub buildid: BuildId,
+    pub mtimes: Vec<(FileId, StatKind, std::io::Result<graph::MTime>)>,
+}
+
+/// What Runner::wait waits for.
+pub enum Event {
+    Finished(FinishedTask),
+    Stated(Stated),
+    /// The time passed to wait() as wake came.
+    Woken,
+}
+
 enum Message {
+    Spawned((BuildId, u32)),
     Output((BuildId, Vec<u8>)),
     Done(FinishedTask),
+    Stated(Stated),
+    /// What the hang hook printed.
+    HookOutput(String),
+}
+
+/// A file to read the mtime of for Runner::stat.
+type StatRequest = (FileId, PathBuf, StatKind);
+
+/// Threads reading mtimes for Runner::stat.  They exit once the Runner, and
+/// with it jobs, is dropped.
+struct StatPool {
+    jobs: mpsc::Sender<(BuildId, Vec<StatRequest>)>,
+    /// Requests whose results haven't been returned by wait() yet.
+    pending: usize,
+}
+
+/// A running task with a timeout.
+struct Deadline {
+    limit: Duration,
+    /// When the command was spawned, and its pid.  The limit counts from
+    /// there.
+    spawned: Option<(Instant, u32)>,
+    /// Set once the task has been killed for running too long.
+    expired: bool,
+}
+
+/// The output lines wait() has passed on for a running task.
+struct TaskOutputs {
+    /// When the last line was passed on.
+    sent: Instant,
+    /// The latest line since then, held until OUTPUT_INTERVAL passes.
+    pending: Option<Vec<u8>>,
+}
+
+/// A running task, as tracked for hang detection.
+struct Watched {
+    start: Instant,
+    pid: Option<u32>,
+    cmdline: String,
+    /// True once the task has been reported as hung, so it is reported once.
+    reported: bool,
 }
 
 pub struct Runner {
@@ -188,10 +646,64 @@ pub struct Runner {
     pub running: usize,
     tids: ThreadIds,
     parallelism: usize,
+    /// Tasks running longer than this are reported by wait().
+    hang_timeout: Option<Duration>,
+    watched: HashMap<BuildId, Watched>,
+    /// Command to run on hung tasks, from --hang-hook.
+    hang_hook: Option<String>,
+    /// Command to run each task under, from --wrapper.
+    wrapper: Option<String>,
+    /// Run commands that need cmd under it, from --windows-shell; see
+    /// process::windows_command_line.
+    windows_shell: bool,
+    /// How tasks treat discovered deps, shared with the task threads.
+    deps: Arc<DepsOptions>,
+    /// Output directories known to exist, with --dir-cache.
+    dirs: Option<Arc<DirCache>>,
+    /// Runs the tasks' commands.
+    commands: Arc<dyn process::CommandRunner>,
+    /// Holds large task output; removed once the last task output spilled
+    /// to it is dropped.
+    temp_dir: Arc<TempDir>,
+    /// When cancelled, running tasks are killed and wait() gives up.
+    cancel: Option<CancelToken>,
+    /// Process ids of running tasks, to kill on cancellation.
+    pids: HashMap<BuildId, u32>,
+    /// Tasks whose commands run in their own process groups.
+    groups: HashSet<BuildId>,
+    /// Set by kill_all(); tasks that haven't spawned their command yet fail
+    /// rather than spawn it.
+    killed: Arc<AtomicBool>,
+    /// Timeout for builds that don't set the `timeout` variable.
+    default_timeout: Option<Duration>,
+    deadlines: HashMap<BuildId, Deadline>,
+    /// Whether a ctrl-c has been passed on to commands in their own process
+    /// groups.
+    interrupt_forwarded: bool,
+    /// Set by start_stat_threads().
+    stat_pool: Option<StatPool>,
+    outputs: HashMap<BuildId, TaskOutputs>,
 }
 
+/// How often wait() checks for cancellation.
+const CANCEL_POLL: Duration = Duration::from_millis(100);
+
+/// wait() passes on a task's last line of output at most this often, rather
+/// than on every read, as a chatty task at high parallelism would otherwise
+/// keep the progress display's lock busy.  The latest line is held until then,
+/// so the last one a task prints is always shown.
+const OUTPUT_INTERVAL: Duration = Duration::from_millis(50);
+
 impl Runner {
-    pub fn new(parallelism: usize) -> Self {
+    pub fn new(
+        parallelism: usize,
+        hang_timeout: Option<Duration>,
+        wrapper: Option<String>,
+        deps: DepsOptions,
+        cancel: Option<CancelToken>,
+        default_timeout: Option<Duration>,
+        dirs: Option<Arc<DirCache>>,
+    ) -> Self {
         let (tx, rx) = mpsc::channel();
         Runner {
             tx,
@@ -199,9 +711,102 @@ impl Runner {
             running: 0,
             tids: ThreadIds::default(),
             parallelism,
+            hang_timeout,
+            watched: HashMap::new(),
+            hang_hook: None,
+            wrapper,
+            windows_shell: false,
+            deps: Arc::new(deps),
+            dirs,
+            commands: Arc::new(process::ProcessRunner),
+            temp_dir: Arc::new(TempDir::new(None)),
+            cancel,
+            pids: HashMap::new(),
+            groups: HashSet::new(),
+            killed: Arc::new(AtomicBool::new(false)),
+            default_timeout,
+            deadlines: HashMap::new(),
+            interrupt_forwarded: false,
+            stat_pool: None,
+            outputs: HashMap::new(),
         }
     }
 
+    /// Run commands with commands rather than as processes, e.g. to fake
+    /// them in tests.
+    pub fn set_command_runner(&mut self, commands: Arc<dyn process::CommandRunner>) {
+        self.commands = commands;
+    }
+
+    /// Run commands that need cmd under it; only has an effect on Windows.
+    pub fn set_windows_shell(&mut self, windows_shell: bool) {
+        self.windows_shell = windows_shell;
+    }
+
+    /// Run hook on tasks that exceed the hang timeout, e.g. to dump their
+    /// stacks.
+    pub fn set_hang_hook(&mut self, hook: String) {
+        self.hang_hook = Some(hook);
+    }
+
+    /// Spill large task output within base, from --temp-dir, rather than the
+    /// system's temporary directory.
+    pub fn set_temp_dir(&mut self, base: PathBuf) {
+        self.temp_dir = Arc::new(TempDir::new(Some(base)));
+    }
+
+    /// Start threads to read mtimes on for stat(), so that slow stat()s (e.g.
+    /// over NFS) of the builds to check overlap with running commands.
+    pub fn start_stat_threads(&mut self, threads: usize) {
+        let (jobs, rx) = mpsc::channel::<(BuildId, Vec<StatRequest>)>();
+        let rx = Arc::new(Mutex::new(rx));
+        for _ in 0..threads {
+            let rx = rx.clone();
+            let tx = self.tx.clone();
+            std::thread::spawn(move || loop {
+                let job = rx.lock().unwrap().recv();
+                let Ok((buildid, files)) = job else {
+                    break;
+                };
+                let mtimes = files
+                    .into_iter()
+                    .map(|(id, path, kind)| {
+                        let mtime = match kind {
+                            StatKind::Stat => graph::stat(&path),
+                            StatKind::Lstat => graph::lstat(&path),
+                        };
+                        (id, kind, mtime)
+                    })
+                    .collect();
+                if tx
+                    .send(Message::Stated(Stated { buildid, mtimes }))
+                    .is_err()
+                {
+                    break;
+                }
+            });
+        }
+        self.stat_pool = Some(StatPool { jobs, pending: 0 });
+    }
+
+    /// Whether stat() can be used, i.e. start_stat_threads() was called.
+    pub fn can_stat(&self) -> bool {
+        self.stat_pool.is_some()
+    }
+
+    /// Read the mtimes of a build's files on the stat threads.  wait()
+    /// returns them as an Event::Stated.
+    pub fn stat(&mut self, buildid: BuildId, files: Vec<StatRequest>) {
+        let pool = self.stat_pool.as_mut().expect("no stat threads");
+        pool.pending += 1;
+        pool.jobs.send((buildid, files)).unwrap();
+    }
+
+    /// Whether there are results of stat() still to wait for.
+    pub fn is_stating(&self) -> bool {
+        self.stat_pool.as_ref().is_some_and(|pool| pool.pending > 0)
+    }
+
     pub fn can_start_more(&self) -> bool {
         self.running < self.parallelism
     }
@@ -210,30 +815,104 @@ impl Runner {
         self.running > 0
     }
 
-    pub fn start(&mut self, id: BuildId, build: &Build) {
-        let cmdline = build.cmdline.clone().unwrap();
-        let depfile = build.depfile.clone().map(PathBuf::from);
-        let rspfile = build.rspfile.clone();
-        let parse_showincludes = build.parse_showincludes;
+    /// Start running a build.  outputs are the names of the build's
+    /// outputs.  If prefix is given, it's inserted at the start of each line
+    /// of the build's output.
+    pub fn start(
+        &mut self,
+        id: BuildId,
+        build: &Build,
+        outputs: Vec<String>,
+        prefix: Option<String>,
+    ) {
+        let mut cmd = TaskCommand::new(
+            build,
+            outputs,
+            self.wrapper.as_deref(),
+            self.windows_shell,
+            self.deps.errors_warn,
+        );
+        cmd.prefix = prefix;
+        cmd.spill_dir = Some(self.temp_dir.clone());
+        // As in ninja, each command gets its own process group, so that
+        // killing it reaches whatever it started.  Console commands share our
+        // process group, to keep the terminal.
+        cmd.new_group = cfg!(unix) && !cmd.console;
+        if cmd.new_group {
+            self.groups.insert(id);
+        }
+        let timeout = build.timeout.or(self.default_timeout);
+        if let Some(limit) = timeout.filter(|limit| !limit.is_zero()) {
+            self.deadlines.insert(
+                id,
+                Deadline {
+                    limit,
+                    spawned: None,
+                    expired: false,
+                },
+            );
+        }
+        let command_len_check = match process::MAX_COMMAND_LEN {
+            Some(max) => check_command_len(build, &cmd.cmdline, max),
+            None => Ok(()),
+        };
+
+        if self.hang_timeout.is_some() {
+            self.watched.insert(
+                id,
+                Watched {
+                    start: Instant::now(),
+                    pid: None,
+                    cmdline: cmd.cmdline.clone(),
+                    reported: false,
+                },
+            );
+        }
 
         let tid = self.tids.claim();
         let tx = self.tx.clone();
+        let cancel = self.cancel.clone();
+        let killed = self.killed.clone();
+        let dirs = self.dirs.clone();
+        let deps = self.deps.clone();
+        let commands = self.commands.clone();
         std::thread::spawn(move || {
+            let mut last_output: Option<Vec<u8>> = None;
             let start = Instant::now();
-            let result = run_task(
-                &cmdline,
-                depfile.as_deref(),
-                parse_showincludes,
-                rspfile.as_ref(),
-                |line| {
-                    let _ = tx.send(Message::Output((id, line.to_owned())));
-                },
-            )
-            .unwrap_or_else(|err| TaskResult {
-                termination: process::Termination::Failure,
-                output: format!("{}\n", err).into_bytes(),
-                discovered_deps: None,
-            });
+            let result = command_len_check
+                .and_then(|()| {
+                    if cancel.is_some_and(|cancel| cancel.is_cancelled()) {
+                        bail!("build cancelled");
+                    }
+                    if killed.load(Ordering::Relaxed) {
+                        bail!("killed");
+                    }
+                    run_task(
+                        &cmd,
+                        &*commands,
+                        dirs.as_deref(),
+                        &deps,
+                        |pid| {
+                            let _ = tx.send(Message::Spawned((id, pid)));
+                        },
+                        |line| {
+                            if last_output.as_deref() == Some(line) {
+                                return;
+                            }
+                            last_output = Some(line.to_owned());
+                            let _ = tx.send(Message::Output((id, line.to_owned())));
+                        },
+                    )
+                })
+                .unwrap_or_else(|err| TaskResult {
+                    termination: process::Termination::Failure,
+                    output: format!("{}\n", err).into_bytes().into(),
+                    discovered_deps: None,
+                    warning: None,
+                    deps_unreadable: false,
+                    usage: Default::default(),
+                    retry: None,
+                });
             let finish = Instant::now();
 
             let task = FinishedTask {
@@ -242,22 +921,291 @@ impl Runner {
                 span: (start, finish),
                 result,
             };
+            // Let go of the temp dir before the task is seen to be done, so
+            // the dir is removed with the Runner rather than left behind if
+            // the process exits first.
+            drop(cmd);
             // The send will only fail if the receiver disappeared, e.g. due to shutting down.
             let _ = tx.send(Message::Done(task));
         });
         self.running += 1;
     }
 
-    /// Wait for a build to complete.  May block for a long time.
-    pub fn wait(&mut self, mut output: impl FnMut(BuildId, Vec<u8>)) -> FinishedTask {
-        loop {
+    /// Returns how long to wait before the next running task exceeds the
+    /// hang timeout, or None to wait indefinitely.
+    fn next_hang_check(&self) -> Option<Duration> {
+        let timeout = self.hang_timeout?;
+        let now = Instant::now();
+        self.watched
+            .values()
+            .filter(|w| !w.reported)
+            .map(|w| (w.start + timeout).saturating_duration_since(now))
+            .min()
+    }
+
+    /// Kill a running task's command, along with anything it started.
+    fn kill(&self, bid: BuildId, pid: u32) {
+        if self.groups.contains(&bid) {
+            process::kill_group(pid);
+        } else {
+            process::kill(pid);
+        }
+    }
+
+    /// Kill all running tasks, and wait for their commands to exit so none
+    /// is left running or unreaped.  Their results are dropped.
+    pub fn kill_all(&mut self) {
+        // Keep tasks that haven't spawned their command yet from doing so.
+        self.killed.store(true, Ordering::Relaxed);
+        for (bid, &pid) in &self.pids {
+            self.kill(*bid, pid);
+        }
+        while self.running > 0 {
             match self.rx.recv().unwrap() {
-                Message::Output((bid, line)) => output(bid, line),
+                Message::Spawned((bid, pid)) => {
+                    // Spawned just before the killed flag was set.
+                    self.kill(bid, pid);
+                }
+                Message::Output(_) | Message::HookOutput(_) => {}
                 Message::Done(task) => {
+                    self.groups.remove(&task.buildid);
+                    self.tids.release(task.tid);
+                    self.running -= 1;
+                }
+                Message::Stated(_) => self.stat_pool.as_mut().unwrap().pending -= 1,
+            }
+        }
+        self.pids.clear();
+        self.groups.clear();
+        self.deadlines.clear();
+        self.watched.clear();
+        self.outputs.clear();
+    }
+
+    /// Pass on a task's line of output, or hold it if the last one was
+    /// passed on too recently.
+    fn task_output(
+        &mut self,
+        bid: BuildId,
+        line: Vec<u8>,
+        output: &mut impl FnMut(BuildId, Vec<u8>),
+    ) {
+        let now = Instant::now();
+        match self.outputs.get_mut(&bid) {
+            Some(o) if now < o.sent + OUTPUT_INTERVAL => o.pending = Some(line),
+            _ => {
+                self.outputs.insert(
+                    bid,
+                    TaskOutputs {
+                        sent: now,
+                        pending: None,
+                    },
+                );
+                output(bid, line);
+            }
+        }
+    }
+
+    /// Pass on held lines of output whose interval has passed, returning how
+    /// long until the next is due.
+    fn flush_outputs(&mut self, output: &mut impl FnMut(BuildId, Vec<u8>)) -> Option<Duration> {
+        let now = Instant::now();
+        let mut next = None;
+        for (&bid, o) in self.outputs.iter_mut() {
+            if o.pending.is_none() {
+                continue;
+            }
+            let due = o.sent + OUTPUT_INTERVAL;
+            if now >= due {
+                output(bid, o.pending.take().unwrap());
+                o.sent = now;
+            } else {
+                let left = due - now;
+                next = Some(next.map_or(left, |n: Duration| n.min(left)));
+            }
+        }
+        next
+    }
+
+    /// Returns how long to wait before the next running task exceeds its
+    /// timeout, or None if no running task has one.
+    fn next_deadline(&self) -> Option<Duration> {
+        let now = Instant::now();
+        let next = self
+            .deadlines
+            .values()
+            .filter(|d| !d.expired)
+            .filter_map(|d| {
+                let (start, _) = d.spawned?;
+                Some((start + d.limit).saturating_duration_since(now))
+            })
+            .min();
+        if cfg!(unix) && !self.groups.is_empty() {
+            // Poll to pass on any ctrl-c to commands in their own groups.
+            return Some(next.map_or(CANCEL_POLL, |next| next.min(CANCEL_POLL)));
+        }
+        next
+    }
+
+    /// Kill tasks that have run past their timeouts, and pass on any ctrl-c
+    /// to those that the terminal doesn't reach.
+    fn check_deadlines(&mut self) {
+        let now = Instant::now();
+        for (&bid, d) in self.deadlines.iter_mut() {
+            let Some((start, pid)) = d.spawned else {
+                continue;
+            };
+            if !d.expired && now >= start + d.limit {
+                d.expired = true;
+                if self.groups.contains(&bid) {
+                    process::kill_group(pid);
+                } else {
+                    process::kill(pid);
+                }
+            }
+        }
+        #[cfg(unix)]
+        if !self.interrupt_forwarded && crate::signal::was_interrupted() {
+            self.interrupt_forwarded = true;
+            for (bid, &pid) in &self.pids {
+                if self.groups.contains(bid) {
+                    process::interrupt_group(pid);
+                }
+            }
+        }
+    }
+
+    /// Run the hang hook on a hung task, on its own thread so a slow hook
+    /// doesn't hold up the build.  Its output comes back through wait().
+    fn run_hang_hook(&self, pid: u32, cmdline: &str) {
+        let Some(hook) = &self.hang_hook else {
+            return;
+        };
+        let mut cmd = process::shell_command(hook);
+        cmd.env("N2_HUNG_PID", pid.to_string())
+            .env("N2_HUNG_COMMAND", cmdline)
+            .stdin(std::process::Stdio::null());
+        let tx = self.tx.clone();
+        std::thread::spawn(move || {
+            let text = match cmd.output() {
+                Ok(out) => {
+                    let mut text = String::from_utf8_lossy(&out.stdout).into_owned();
+                    text.push_str(&String::from_utf8_lossy(&out.stderr));
+                    text.truncate(text.trim_end().len());
+                    text
+                }
+                Err(err) => format!("n2: hang hook: {}", err),
+            };
+            let _ = tx.send(Message::HookOutput(text));
+        });
+    }
+
+    /// Wait for a build to complete, or for the mtimes read by a stat().  May
+    /// block for a long time.
+    /// Tasks that exceed the hang timeout while waiting, and the output of the
+    /// hang hook run on them, are passed to `hung`.
+    /// Returns Event::Woken if wake comes first, and None, after killing
+    /// running tasks, if the build is cancelled.
+    pub fn wait(
+        &mut self,
+        wake: Option<Instant>,
+        mut output: impl FnMut(BuildId, Vec<u8>),
+        mut hung: impl FnMut(Hang),
+    ) -> Option<Event> {
+        loop {
+            self.check_deadlines();
+            let mut timeout = self.next_hang_check();
+            if let Some(next) = self.next_deadline() {
+                timeout = Some(timeout.map_or(next, |t| t.min(next)));
+            }
+            if let Some(next) = self.flush_outputs(&mut output) {
+                timeout = Some(timeout.map_or(next, |t| t.min(next)));
+            }
+            if let Some(wake) = wake {
+                let next = wake.saturating_duration_since(Instant::now());
+                timeout = Some(timeout.map_or(next, |t| t.min(next)));
+            }
+            if let Some(cancel) = &self.cancel {
+                if cancel.is_cancelled() {
+                    self.kill_all();
+                    return None;
+                }
+                let poll = cancel
+                    .time_left()
+                    .map_or(CANCEL_POLL, |left| left.min(CANCEL_POLL));
+                timeout = Some(timeout.map_or(poll, |t| t.min(poll)));
+            }
+            let msg = match timeout {
+                None => self.rx.recv().unwrap(),
+                Some(delay) => match self.rx.recv_timeout(delay) {
+                    Ok(msg) => msg,
+                    Err(mpsc::RecvTimeoutError::Timeout) => {
+                        if wake.is_some_and(|wake| Instant::now() >= wake) {
+                            return Some(Event::Woken);
+                        }
+                        let Some(timeout) = self.hang_timeout else {
+                            continue;
+                        };
+                        let mut newly_hung = Vec::new();
+                        for (&buildid, w) in self.watched.iter_mut() {
+                            let elapsed = w.start.elapsed();
+                            if w.reported || elapsed < timeout {
+                                continue;
+                            }
+                            w.reported = true;
+                            newly_hung.push(HungTask {
+                                buildid,
+                                pid: w.pid,
+                                cmdline: w.cmdline.clone(),
+                                elapsed,
+                            });
+                        }
+                        for task in newly_hung {
+                            if let Some(pid) = task.pid {
+                                self.run_hang_hook(pid, &task.cmdline);
+                            }
+                            hung(Hang::Task(task));
+                        }
+                        continue;
+                    }
+                    Err(err) => panic!("{}", err),
+                },
+            };
+            match msg {
+                Message::Spawned((bid, pid)) => {
+                    if let Some(w) = self.watched.get_mut(&bid) {
+                        w.pid = Some(pid);
+                    }
+                    if let Some(d) = self.deadlines.get_mut(&bid) {
+                        d.spawned = Some((Instant::now(), pid));
+                    }
+                    self.pids.insert(bid, pid);
+                }
+                Message::Output((bid, line)) => self.task_output(bid, line, &mut output),
+                Message::Done(mut task) => {
+                    // The task's last line goes out before it finishes.
+                    if let Some(line) = self.outputs.remove(&task.buildid).and_then(|o| o.pending) {
+                        output(task.buildid, line);
+                    }
+                    if self
+                        .deadlines
+                        .remove(&task.buildid)
+                        .is_some_and(|d| d.expired)
+                    {
+                        task.result.termination = process::Termination::TimedOut;
+                    }
+                    self.watched.remove(&task.buildid);
+                    self.pids.remove(&task.buildid);
+                    self.groups.remove(&task.buildid);
                     self.tids.release(task.tid);
                     self.running -= 1;
-                    return task;
+                    return Some(Event::Finished(task));
                 }
+                Message::Stated(stated) => {
+                    self.stat_pool.as_mut().unwrap().pending -= 1;
+                    return Some(Event::Stated(stated));
+                }
+                Message::HookOutput(text) => hung(Hang::HookOutput(text)),
             }
         }
     }
@@ -303,9 +1251,260 @@ more text
         assert_eq!(find_last_line(b"hello\nt\n\n"), b"t");
     }
 
+    #[cfg(unix)]
+    #[test]
+    fn wrap() {
+        assert_eq!(
+            wrap_command("strace -f", "echo 'hi' > out"),
+            "strace -f /bin/sh -c 'echo '\\''hi'\\'' > out'"
+        );
+    }
+
     #[test]
     fn missing_depfile_allowed() {
-        let deps = read_depfile(Path::new("/missing/dep/file")).unwrap();
+        let (deps, warning) =
+            read_depfile(Path::new("/missing/dep/file"), false, &[], None).unwrap();
         assert_eq!(deps.len(), 0);
+        assert_eq!(warning, None);
+    }
+
+    #[test]
+    fn depfile_targets() {
+        let buf = b"./out.o: a.h b.h\nother.o: c.h\na.h:\n\0";
+        let mut scanner = Scanner::new(buf);
+        let parsed = depfile::parse(&mut scanner).unwrap();
+        assert_eq!(
+            matching_deps(&parsed, &["out.o".to_owned()], None),
+            Some(vec!["a.h".to_owned(), "b.h".to_owned()])
+        );
+        assert_eq!(matching_deps(&parsed, &["x.o".to_owned()], None), None);
+        // A command run in another directory names its outputs from there.
+        assert_eq!(
+            matching_deps(&parsed, &["sub/out.o".to_owned()], Some(Path::new("sub"))),
+            Some(vec!["a.h".to_owned(), "b.h".to_owned()])
+        );
+    }
+
+    #[test]
+    fn intern_deps_dedup() {
+        let options = DepsOptions {
+            errors_warn: false,
+            physical: None,
+            names: Arc::default(),
+            ignore: Vec::new(),
+        };
+        let names = ["./a.h", "b/../a.h", "b.h", "a.h", "./b.h"];
+        let names = names.iter().map(|&name| name.to_owned()).collect();
+        let ids = intern_deps(names, None, &options);
+        assert_eq!(ids.len(), 2);
+        assert_eq!(options.names.lookup("a.h"), Some(ids[0]));
+        assert_eq!(options.names.lookup("b.h"), Some(ids[1]));
+    }
+
+    #[test]
+    fn intern_deps_cwd() {
+        let options = DepsOptions {
+            errors_warn: false,
+            physical: None,
+            names: Arc::default(),
+            ignore: Vec::new(),
+        };
+        let names = ["a.h", "../b.h"];
+        let names = names.iter().map(|&name| name.to_owned()).collect();
+        let ids = intern_deps(names, Some(Path::new("sub")), &options);
+        assert_eq!(options.names.lookup("sub/a.h"), Some(ids[0]));
+        assert_eq!(options.names.lookup("b.h"), Some(ids[1]));
+    }
+
+    #[test]
+    fn intern_deps_ignore() {
+        let options = DepsOptions {
+            errors_warn: false,
+            physical: None,
+            names: Arc::default(),
+            ignore: vec!["/usr/include/**".to_owned(), "*.inc".to_owned()],
+        };
+        let names = [
+            "/usr/include/stdio.h",
+            "/usr/include/sys/types.h",
+            "a.h",
+            "b/../c.inc",
+            "d/e.inc",
+        ];
+        let names = names.iter().map(|&name| name.to_owned()).collect();
+        let ids = intern_deps(names, None, &options);
+        assert_eq!(ids.len(), 2);
+        assert_eq!(options.names.lookup("a.h"), Some(ids[0]));
+        assert_eq!(options.names.lookup("d/e.inc"), Some(ids[1]));
+        assert_eq!(options.names.lookup("/usr/include/stdio.h"), None);
+    }
+
+    #[test]
+    fn dir_cache() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let cache = DirCache::default();
+        cache.create_dir_all(&dir.path().join("a/b"))?;
+        cache.create_dir_all(&dir.path().join("a/b"))?;
+        cache.create_dir_all(&dir.path().join("a"))?;
+        cache.create_dir_all(&dir.path().join("a/c"))?;
+        assert!(dir.path().join("a/c").is_dir());
+        assert_eq!(cache.counts(), (2, 2));
+        Ok(())
+    }
+
+    #[test]
+    fn parent_dirs() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let out = |name: &str| dir.path().join(name).to_string_lossy().into_owned();
+        create_parent_dirs(&[out("a/b/1"), out("a/b/2"), out("c/3")], None)?;
+        assert!(dir.path().join("a/b").is_dir());
+        assert!(dir.path().join("c").is_dir());
+
+        std::fs::write(dir.path().join("file"), "")?;
+        let err = create_parent_dirs(&[out("file/4")], None).unwrap_err();
+        assert!(err.to_string().starts_with("create directory "));
+        Ok(())
+    }
+
+    fn fake_command(cmdline: &str) -> TaskCommand {
+        TaskCommand {
+            cmdline: cmdline.to_owned(),
+            depfile: None,
+            parse_showincludes: false,
+            outputs: Vec::new(),
+            temp_outputs: Vec::new(),
+            dir_outputs: false,
+            depfile_json: false,
+            depfile_errors_warn: false,
+            rspfile: None,
+            cwd: None,
+            console: false,
+            new_group: false,
+            prefix: None,
+            spill_dir: None,
+        }
+    }
+
+    fn run_fake(
+        cmd: &TaskCommand,
+        commands: &process::FakeRunner,
+        lines: &mut Vec<String>,
+    ) -> anyhow::Result<TaskResult> {
+        let deps = DepsOptions {
+            errors_warn: false,
+            physical: None,
+            names: Arc::default(),
+            ignore: Vec::new(),
+        };
+        run_task(
+            cmd,
+            commands,
+            None,
+            &deps,
+            |_| {},
+            |line| lines.push(String::from_utf8_lossy(line).into_owned()),
+        )
+    }
+
+    #[test]
+    fn fake_output() -> anyhow::Result<()> {
+        let mut commands = process::FakeRunner::default();
+        commands.add(
+            "gen",
+            process::Termination::Success,
+            &["one\ntw", "o\n", "three"],
+        );
+        let mut cmd = fake_command("gen");
+        let mut lines = Vec::new();
+        let result = run_fake(&cmd, &commands, &mut lines)?;
+        assert_eq!(result.termination, process::Termination::Success);
+        assert_eq!(result.output.tail(), b"one\ntwo\nthree");
+        // The progress line follows the last line so far.
+        assert_eq!(lines, ["tw", "two", "three"]);
+
+        cmd.prefix = Some("[gen] ".to_owned());
+        let result = run_fake(&cmd, &commands, &mut Vec::new())?;
+        assert_eq!(result.output.tail(), b"[gen] one\n[gen] two\n[gen] three");
+
+        let err = run_fake(&fake_command("other"), &commands, &mut lines).err();
+        assert_eq!(
+            err.map(|err| err.to_string()),
+            Some("no fake command \"other\"".to_owned())
+        );
+        assert_eq!(*commands.ran.lock().unwrap(), ["gen", "gen", "other"]);
+        Ok(())
+    }
+
+    #[test]
+    fn fake_failures_leave_outputs() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = |name: &str| dir.path().join(name).to_string_lossy().into_owned();
+        let mut commands = process::FakeRunner::default();
+        commands.add("fail", process::Termination::Failure, &["error\n"]);
+        commands.add("interrupted", process::Termination::Interrupted, &[]);
+        for cmdline in ["fail", "interrupted"] {
+            std::fs::write(path("out"), "old")?;
+            std::fs::write(path("out.tmp"), "half written")?;
+            let mut cmd = fake_command(cmdline);
+            cmd.temp_outputs = vec![(path("out.tmp"), path("out"))];
+            let result = run_fake(&cmd, &commands, &mut Vec::new())?;
+            assert_ne!(result.termination, process::Termination::Success);
+            assert_eq!(std::fs::read_to_string(path("out"))?, "old");
+            assert!(!Path::new(&path("out.tmp")).exists());
+        }
+
+        // Whereas success moves the temporary output into place.
+        commands.add("ok", process::Termination::Success, &[]);
+        std::fs::write(path("out.tmp"), "new")?;
+        let mut cmd = fake_command("ok");
+        cmd.temp_outputs = vec![(path("out.tmp"), path("out"))];
+        run_fake(&cmd, &commands, &mut Vec::new())?;
+        assert_eq!(std::fs::read_to_string(path("out"))?, "new");
+        Ok(())
+    }
+
+    #[test]
+    fn output_batching() -> anyhow::Result<()> {
+        let mut graph = crate::load::parse("build.ninja", b"build out: phony\n".to_vec())?;
+        let id = graph.builds.all_ids().next().unwrap();
+        crate::load::evaluate_build(&mut graph, id)?;
+        graph.builds[id].cmdline = Some("chatty".to_owned());
+        let mut commands = process::FakeRunner::default();
+        commands.add(
+            "chatty",
+            process::Termination::Success,
+            &["1\n", "2\n", "3\n"],
+        );
+        let deps = DepsOptions {
+            errors_warn: false,
+            physical: None,
+            names: Arc::default(),
+            ignore: Vec::new(),
+        };
+        let mut runner = Runner::new(1, None, None, deps, None, None, None);
+        runner.set_command_runner(Arc::new(commands));
+        runner.start(id, &graph.builds[id], Vec::new(), None);
+        let mut lines = Vec::new();
+        let event = runner.wait(None, |_, line| lines.push(line), |_| {});
+        assert!(matches!(event, Some(Event::Finished(_))));
+        // Lines following within OUTPUT_INTERVAL of the first are held, and
+        // only the latest is sent once the task exits.
+        assert_eq!(lines, [b"1", b"3"]);
+        Ok(())
+    }
+
+    #[test]
+    fn shared_depfile() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("out.d");
+        std::fs::write(&path, "a.o: x.h\nb.o: y.h\n")?;
+
+        // Two builds sharing the depfile each get their own output's deps.
+        let (deps, warning) = read_depfile(&path, false, &["b.o".to_owned()], None)?;
+        assert_eq!(deps, vec!["y.h".to_owned()]);
+        assert_eq!(warning, None);
+        let (deps, _) = read_depfile(&path, false, &["a.o".to_owned()], None)?;
+        assert_eq!(deps, vec!["x.h".to_owned()]);
+        Ok(())
     }
 }
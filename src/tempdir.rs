@@ -0,0 +1,131 @@
+//! A directory for n2's own temporary files, such as spilled task output.
+//! (The temporary outputs of builds, see graph::temp_output, are instead
+//! written next to the outputs they replace.)
+//!
+//! Each build gets its own directory, created on first use within $TMPDIR or
+//! the directory from --temp-dir, e.g. to put it on a ramdisk.  As with
+//! mkdtemp, its name is unpredictable and only we can use it, so another user
+//! can't plant files or symlinks in it.  It is removed along with its contents
+//! once the build and the files created in it are done with.
+
+use std::fs::File;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// A temporary directory, created on first use by create() and removed when
+/// dropped.
+pub struct TempDir {
+    /// Where to create the directory, or None for the system default.
+    base: Option<PathBuf>,
+    /// The directory, once created.
+    dir: Mutex<Option<PathBuf>>,
+    /// Numbers the files created, to keep their names apart.
+    counter: AtomicUsize,
+}
+
+/// A name no one can guess, with the process id to show whose it is.
+fn unique_name() -> String {
+    // RandomState is randomly seeded, which is all the randomness std offers.
+    let random = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish();
+    format!("n2-{}-{:016x}", std::process::id(), random)
+}
+
+/// Create a new directory within base, which fails rather than reuse an
+/// existing one.
+fn create_dir(base: &Path) -> io::Result<PathBuf> {
+    let mut builder = std::fs::DirBuilder::new();
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    loop {
+        let dir = base.join(unique_name());
+        match builder.create(&dir) {
+            Ok(()) => return Ok(dir),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => {
+                return Err(io::Error::new(
+                    err.kind(),
+                    format!("create {}: {}", dir.display(), err),
+                ))
+            }
+        }
+    }
+}
+
+impl TempDir {
+    /// Put temporary files within base, or the system default if None.
+    pub fn new(base: Option<PathBuf>) -> Self {
+        TempDir {
+            base,
+            dir: Mutex::new(None),
+            counter: AtomicUsize::new(0),
+        }
+    }
+
+    /// Create a new file in the directory, named after prefix.
+    pub fn create(&self, prefix: &str) -> io::Result<(PathBuf, File)> {
+        let dir = {
+            let mut dir = self.dir.lock().unwrap();
+            match &*dir {
+                Some(dir) => dir.clone(),
+                None => {
+                    let base = self.base.clone().unwrap_or_else(std::env::temp_dir);
+                    dir.insert(create_dir(&base)?).clone()
+                }
+            }
+        };
+        let path = dir.join(format!(
+            "{}-{}",
+            prefix,
+            self.counter.fetch_add(1, Ordering::Relaxed)
+        ));
+        // Never open a file that is already there, or follow a symlink.
+        let file = File::options().write(true).create_new(true).open(&path)?;
+        Ok((path, file))
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        if let Some(dir) = self.dir.get_mut().unwrap().take() {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lifetime() -> io::Result<()> {
+        let base = tempfile::tempdir()?;
+        let temp = TempDir::new(Some(base.path().to_owned()));
+        // Nothing is created until it's needed.
+        assert_eq!(std::fs::read_dir(base.path())?.count(), 0);
+
+        let (a, _) = temp.create("x")?;
+        let (b, _) = temp.create("x")?;
+        assert_ne!(a, b);
+        let dir = a.parent().unwrap().to_owned();
+        assert_eq!(b.parent(), Some(dir.as_path()));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&dir)?.permissions().mode();
+            assert_eq!(mode & 0o777, 0o700);
+        }
+
+        // Another build's directory is separate.
+        let (other, _) = TempDir::new(Some(base.path().to_owned())).create("x")?;
+        assert_ne!(other.parent(), Some(dir.as_path()));
+
+        drop(temp);
+        assert!(!dir.exists());
+        Ok(())
+    }
+}
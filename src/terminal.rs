@@ -68,6 +68,104 @@ mod windows {
 #[cfg(windows)]
 pub use windows::*;
 
+#[cfg(windows)]
+mod windows_code_page {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use windows_sys::Win32::{Globalization::MultiByteToWideChar, System::Console::*};
+
+    const CP_UTF8: u32 = 65001;
+
+    /// The console's output code page before switch_to_utf8 changed it, or
+    /// 0 if it wasn't changed.
+    static ORIGINAL_CP: AtomicU32 = AtomicU32::new(0);
+
+    pub fn switch_to_utf8() -> bool {
+        unsafe {
+            let handle = GetStdHandle(STD_OUTPUT_HANDLE);
+            let mut mode = 0;
+            if GetConsoleMode(handle, &mut mode) == 0 {
+                // Redirected; leave output bytes as they are.
+                return false;
+            }
+            let cp = GetConsoleOutputCP();
+            if cp == 0 || cp == CP_UTF8 || SetConsoleOutputCP(CP_UTF8) == 0 {
+                return false;
+            }
+            ORIGINAL_CP.store(cp, Ordering::Relaxed);
+            true
+        }
+    }
+
+    pub fn restore_code_page() {
+        let cp = ORIGINAL_CP.swap(0, Ordering::Relaxed);
+        if cp != 0 {
+            unsafe {
+                SetConsoleOutputCP(cp);
+            }
+        }
+    }
+
+    pub fn from_console_code_page(buf: &[u8]) -> Option<Vec<u8>> {
+        let cp = ORIGINAL_CP.load(Ordering::Relaxed);
+        if cp == 0 || buf.is_empty() || std::str::from_utf8(buf).is_ok() {
+            return None;
+        }
+        let len = i32::try_from(buf.len()).ok()?;
+        unsafe {
+            let wide_len = MultiByteToWideChar(cp, 0, buf.as_ptr(), len, std::ptr::null_mut(), 0);
+            if wide_len <= 0 {
+                return None;
+            }
+            let mut wide = vec![0u16; wide_len as usize];
+            if MultiByteToWideChar(cp, 0, buf.as_ptr(), len, wide.as_mut_ptr(), wide_len) <= 0 {
+                return None;
+            }
+            Some(String::from_utf16_lossy(&wide).into_bytes())
+        }
+    }
+}
+
+#[cfg(windows)]
+use windows_code_page::*;
+
+#[cfg(not(windows))]
+fn switch_to_utf8() -> bool {
+    false
+}
+
+#[cfg(not(windows))]
+fn restore_code_page() {}
+
+#[cfg(not(windows))]
+fn from_console_code_page(_buf: &[u8]) -> Option<Vec<u8>> {
+    None
+}
+
+/// While alive, the Windows console shows output as UTF-8; see
+/// use_utf8_console.
+pub struct Utf8Console(());
+
+impl Drop for Utf8Console {
+    fn drop(&mut self) {
+        restore_code_page();
+    }
+}
+
+/// Commands like clang print UTF-8, which a Windows console in a legacy code
+/// page such as 850 shows as mojibake.  If stdout is such a console, switch
+/// it to UTF-8 until the returned value is dropped.  Output from commands
+/// that print in the console's old code page instead is converted by
+/// console_text.  Redirected output is left alone.
+pub fn use_utf8_console() -> Option<Utf8Console> {
+    switch_to_utf8().then_some(Utf8Console(()))
+}
+
+/// Convert command output that isn't UTF-8 from the console's original code
+/// page, if use_utf8_console switched it.  Returns None to keep buf as is.
+pub fn console_text(buf: &[u8]) -> Option<Vec<u8>> {
+    from_console_code_page(buf)
+}
+
 #[cfg(target_arch = "wasm32")]
 mod wasm {
     pub fn use_fancy() -> bool {
@@ -81,3 +179,40 @@ mod wasm {
 
 #[cfg(target_arch = "wasm32")]
 pub use wasm::*;
+
+/// Whether to ask commands to print colored output, from --color.  Only on
+/// request, as the variables set leak into everything commands run, such as
+/// tools whose output is parsed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ColorMode {
+    /// When stdout is a terminal that supports color.
+    Auto,
+    Always,
+    #[default]
+    Never,
+}
+
+impl ColorMode {
+    pub fn enabled(self) -> bool {
+        match self {
+            ColorMode::Always => true,
+            ColorMode::Never => false,
+            ColorMode::Auto => {
+                use_fancy()
+                    && std::env::var_os("NO_COLOR").is_none()
+                    && std::env::var_os("TERM").map_or(true, |term| term != "dumb")
+            }
+        }
+    }
+}
+
+/// Commands see a pipe rather than n2's terminal, so typically disable their
+/// colors.  Set the environment variables that well-known tools check to
+/// force color back on, unless the user already set them.
+pub fn force_child_color() {
+    for var in ["CLICOLOR_FORCE", "FORCE_COLOR"] {
+        if std::env::var_os(var).is_none() {
+            std::env::set_var(var, "1");
+        }
+    }
+}
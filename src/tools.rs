@@ -0,0 +1,1061 @@
+//! Subtools (`-t name`) that query the loaded build graph instead of building.
+
+use crate::{
+    canon::to_owned_canon_path,
+    db,
+    densemap::Index,
+    graph::{self, Build, BuildId, FileId, FileState, Graph, MTime},
+    hash, json, load, work,
+};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Write;
+use std::rc::Rc;
+
+/// Whether tool is one implemented here, i.e. one that needs the build graph.
+pub fn is_graph_tool(tool: &str) -> bool {
+    matches!(
+        tool,
+        "aliases"
+            | "caseconflicts"
+            | "changes"
+            | "clean"
+            | "dump"
+            | "inputs"
+            | "outputs"
+            | "recompact"
+            | "rules"
+            | "verify"
+            | "why"
+    )
+}
+
+/// Run a graph tool.  args are the command line arguments following the
+/// tool name.  Returns the process exit code.
+pub fn run(
+    tool: &str,
+    args: Vec<String>,
+    mut state: load::State,
+    options: &work::Options,
+) -> anyhow::Result<i32> {
+    if let Some(name) = state.pending_subninjas.first() {
+        // Without the builds it declares, tools would give wrong answers,
+        // and recompact would drop their records.
+        anyhow::bail!(
+            "-t {}: subninja {} hasn't been generated yet; build first",
+            tool,
+            name
+        );
+    }
+    match tool {
+        "aliases" => aliases(args, &state),
+        "caseconflicts" => case_conflicts(args, &state),
+        "changes" => changes(args, &mut state, options),
+        "clean" => clean(args, &mut state),
+        "dump" => dump(args, &mut state),
+        "inputs" => inputs(args, &state),
+        "rules" => rules(args, &state),
+        "outputs" => outputs(args, &state),
+        "recompact" => recompact(args, state),
+        "verify" => verify(args, &mut state, options),
+        "why" => why(args, &mut state, options),
+        _ => unreachable!("{:?} is not a graph tool", tool),
+    }
+}
+
+fn lookup_all(names: &[String], graph: &Graph) -> anyhow::Result<Vec<FileId>> {
+    names
+        .iter()
+        .map(|name| {
+            graph
+                .files
+                .lookup(&to_owned_canon_path(name))
+                .ok_or_else(|| graph.files.unknown_path(name))
+        })
+        .collect()
+}
+
+/// Resolve target names to files, falling back to the default targets.
+/// If there are neither, all files are used.
+fn targets(names: &[String], state: &load::State) -> anyhow::Result<Vec<FileId>> {
+    if !names.is_empty() {
+        return lookup_all(names, &state.graph);
+    }
+    if !state.default.is_empty() {
+        return Ok(state.default.clone());
+    }
+    Ok(state.graph.files.all_ids().collect())
+}
+
+/// Which edges of the graph a walk follows.
+#[derive(Default)]
+struct Edges {
+    order_only: bool,
+    discovered: bool,
+}
+
+/// Collect the transitive source (non-generated) inputs of the targets.
+fn collect_inputs<'a>(graph: &'a Graph, targets: &[FileId], edges: &Edges) -> Vec<&'a str> {
+    let mut visited = HashSet::new();
+    let mut stack = Vec::new();
+    let mut inputs = Vec::new();
+    for &target in targets {
+        if let Some(bid) = graph.file(target).input {
+            stack.push(bid);
+        }
+    }
+    while let Some(bid) = stack.pop() {
+        if !visited.insert(bid) {
+            continue;
+        }
+        let build = &graph.builds[bid];
+        let ins = if edges.order_only {
+            build.ordering_ins()
+        } else {
+            build.dirtying_ins()
+        };
+        let discovered = if edges.discovered {
+            build.discovered_ins()
+        } else {
+            &[]
+        };
+        for &id in ins.iter().chain(discovered) {
+            let file = graph.file(id);
+            match file.input {
+                Some(input) => stack.push(input),
+                None => inputs.push(file.name.as_str()),
+            }
+        }
+    }
+    inputs.sort_unstable();
+    inputs.dedup();
+    inputs
+}
+
+fn inputs(args: Vec<String>, state: &load::State) -> anyhow::Result<i32> {
+    use lexopt::prelude::*;
+    let mut edges = Edges::default();
+    let mut names = Vec::new();
+    let mut parser = lexopt::Parser::from_args(args);
+    while let Some(arg) = parser.next()? {
+        match arg {
+            Short('h') | Long("help") => {
+                println!(
+                    "usage: n2 -t inputs [options] [targets...]
+
+Print the source files the targets are built from, transitively.
+
+options:
+--order-only  also follow order-only inputs
+--discovered  also follow inputs discovered by previous builds (depfiles)
+"
+                );
+                return Ok(0);
+            }
+            Long("order-only") => edges.order_only = true,
+            Long("discovered") => edges.discovered = true,
+            Value(name) => names.push(name.to_string_lossy().into_owned()),
+            _ => anyhow::bail!("-t inputs: {}", arg.unexpected()),
+        }
+    }
+
+    let targets = targets(&names, state)?;
+    for name in collect_inputs(&state.graph, &targets, &edges) {
+        println!("{}", name);
+    }
+    Ok(0)
+}
+
+fn rules(args: Vec<String>, state: &load::State) -> anyhow::Result<i32> {
+    use lexopt::prelude::*;
+    let mut filter = None;
+    let mut parser = lexopt::Parser::from_args(args);
+    while let Some(arg) = parser.next()? {
+        match arg {
+            Short('h') | Long("help") => {
+                println!(
+                    "usage: n2 -t rules [--used|--unused]
+
+Print each rule with its command, description, and deps type.
+
+options:
+--used    only rules that some build uses
+--unused  only rules that no build uses
+"
+                );
+                return Ok(0);
+            }
+            Long("used") => filter = Some(true),
+            Long("unused") => filter = Some(false),
+            _ => anyhow::bail!("-t rules: {}", arg.unexpected()),
+        }
+    }
+
+    let mut used = HashSet::new();
+    for id in state.graph.builds.all_ids() {
+        used.insert(Rc::as_ptr(&state.graph.builds[id].rule));
+    }
+    let mut rules: Vec<_> = state
+        .rules
+        .values()
+        .filter(|rule| rule.name != "phony")
+        .filter(|rule| filter.map_or(true, |want| used.contains(&Rc::as_ptr(rule)) == want))
+        .collect();
+    rules.sort_by(|a, b| a.name.cmp(&b.name));
+    for rule in rules {
+        println!("{}", rule.name);
+        for key in ["command", "description", "deps"] {
+            if let Some(val) = rule.vars.get(key) {
+                println!("  {} = {}", key, val);
+            }
+        }
+    }
+    Ok(0)
+}
+
+fn aliases(args: Vec<String>, state: &load::State) -> anyhow::Result<i32> {
+    use lexopt::prelude::*;
+    let mut parser = lexopt::Parser::from_args(args);
+    if let Some(arg) = parser.next()? {
+        match arg {
+            Short('h') | Long("help") => {
+                println!(
+                    "usage: n2 -t aliases
+
+Print each phony target with the targets it builds.  Phony builds without
+inputs, which only mark files that may be missing, are left out.
+"
+                );
+                return Ok(0);
+            }
+            _ => anyhow::bail!("-t aliases: {}", arg.unexpected()),
+        }
+    }
+
+    let graph = &state.graph;
+    let mut aliases = Vec::new();
+    for id in graph.builds.all_ids() {
+        let build = &graph.builds[id];
+        if !build.is_phony() || build.ordering_ins().is_empty() {
+            continue;
+        }
+        let ins: Vec<&str> = build
+            .ordering_ins()
+            .iter()
+            .map(|&id| graph.file(id).name.as_str())
+            .collect();
+        for &out in build.outs() {
+            aliases.push((graph.file(out).name.as_str(), ins.join(" ")));
+        }
+    }
+    aliases.sort_unstable();
+    for (alias, ins) in aliases {
+        println!("{}: {}", alias, ins);
+    }
+    Ok(0)
+}
+
+/// Print pairs of files whose names differ only by case, which are the same
+/// file on case-insensitive filesystems (as on Windows and macOS).  Exits
+/// with 1 if there are any.
+fn case_conflicts(args: Vec<String>, state: &load::State) -> anyhow::Result<i32> {
+    use lexopt::prelude::*;
+    let mut parser = lexopt::Parser::from_args(args);
+    if let Some(arg) = parser.next()? {
+        match arg {
+            Short('h') | Long("help") => {
+                println!(
+                    "usage: n2 -t caseconflicts
+
+Print each pair of files whose names differ only by case, which are the same
+file on a case-insensitive filesystem, and fail if there are any.
+"
+                );
+                return Ok(0);
+            }
+            _ => anyhow::bail!("-t caseconflicts: {}", arg.unexpected()),
+        }
+    }
+
+    let conflicts = state.graph.files.case_conflicts();
+    for &(a, b) in &conflicts {
+        println!("{} {}", state.graph.file(a).name, state.graph.file(b).name);
+    }
+    Ok(if conflicts.is_empty() { 0 } else { 1 })
+}
+
+/// Collect the outputs of every build that would rerun, transitively, if any
+/// of the given files changed.
+fn collect_outputs<'a>(graph: &'a Graph, files: &[FileId]) -> Vec<&'a str> {
+    // File::dependents covers the manifest's inputs; discovered inputs are
+    // only known per build, so index them in reverse here.
+    let mut discovered: HashMap<FileId, Vec<BuildId>> = HashMap::new();
+    for id in graph.builds.all_ids() {
+        for &input in graph.builds[id].discovered_ins() {
+            discovered.entry(input).or_default().push(id);
+        }
+    }
+
+    let mut visited = HashSet::new();
+    let mut stack = files.to_vec();
+    let mut outputs = Vec::new();
+    while let Some(id) = stack.pop() {
+        let file = graph.file(id);
+        let dependents = file
+            .dependents
+            .iter()
+            // Order-only and validation inputs don't cause a rebuild.
+            .filter(|&&bid| graph.builds[bid].dirtying_ins().contains(&id))
+            .chain(discovered.get(&id).into_iter().flatten());
+        for &bid in dependents {
+            if !visited.insert(bid) {
+                continue;
+            }
+            for &out in graph.builds[bid].outs() {
+                outputs.push(graph.file(out).name.as_str());
+                stack.push(out);
+            }
+        }
+    }
+    outputs.sort_unstable();
+    outputs.dedup();
+    outputs
+}
+
+fn outputs(args: Vec<String>, state: &load::State) -> anyhow::Result<i32> {
+    use lexopt::prelude::*;
+    let mut names = Vec::new();
+    let mut parser = lexopt::Parser::from_args(args);
+    while let Some(arg) = parser.next()? {
+        match arg {
+            Short('h') | Long("help") => {
+                println!(
+                    "usage: n2 -t outputs files...
+
+Print every output that would be rebuilt, transitively, if the files changed.
+Inputs discovered by previous builds (depfiles) are included.
+"
+                );
+                return Ok(0);
+            }
+            Value(name) => names.push(name.to_string_lossy().into_owned()),
+            _ => anyhow::bail!("-t outputs: {}", arg.unexpected()),
+        }
+    }
+    if names.is_empty() {
+        anyhow::bail!("-t outputs: expected at least one file");
+    }
+
+    let files = lookup_all(&names, &state.graph)?;
+    for name in collect_outputs(&state.graph, &files) {
+        println!("{}", name);
+    }
+    Ok(0)
+}
+
+/// Which builds `-t clean` removes the outputs of.
+enum CleanScope {
+    /// Every build, leaving out generators unless `generators` is set.
+    All { generators: bool },
+    /// The builds of these targets, and of their inputs transitively.
+    Targets(Vec<FileId>),
+    /// The builds of these rules.
+    Rules(HashSet<String>),
+}
+
+/// Collect the builds in scope for a clean, in order, evaluating them.
+fn clean_builds(graph: &mut Graph, scope: &CleanScope) -> anyhow::Result<Vec<BuildId>> {
+    let mut builds: Vec<BuildId> = match scope {
+        CleanScope::All { .. } => graph.builds.all_ids().collect(),
+        CleanScope::Targets(targets) => {
+            let mut visited = HashSet::new();
+            let mut stack: Vec<BuildId> = targets
+                .iter()
+                .filter_map(|&id| graph.file(id).input)
+                .collect();
+            while let Some(bid) = stack.pop() {
+                if visited.insert(bid) {
+                    for &id in graph.builds[bid].ordering_ins() {
+                        stack.extend(graph.file(id).input);
+                    }
+                }
+            }
+            visited.into_iter().collect()
+        }
+        CleanScope::Rules(rules) => graph
+            .builds
+            .all_ids()
+            .filter(|&id| rules.contains(&graph.builds[id].rule.name))
+            .collect(),
+    };
+    builds.retain(|&id| !graph.builds[id].is_phony());
+    builds.sort_unstable();
+    for &id in &builds {
+        load::evaluate_build(graph, id)?;
+    }
+    // As in Ninja, only a clean of everything spares the build files, which
+    // would otherwise need regenerating before anything could be built.
+    if let CleanScope::All { generators: false } = scope {
+        builds.retain(|&id| !graph.builds[id].generator);
+    }
+    Ok(builds)
+}
+
+/// The files a clean of builds removes: their outputs, and the depfiles and
+/// rspfiles that -d keepdepfile and -d keeprsp leave behind.
+fn clean_files(graph: &Graph, builds: &[BuildId]) -> Vec<(String, bool)> {
+    let mut files = Vec::new();
+    for &id in builds {
+        let build = &graph.builds[id];
+        files.extend(
+            build
+                .outs()
+                .iter()
+                .map(|&out| (graph.file(out).name.clone(), build.dir_outputs)),
+        );
+        files.extend(build.depfile.iter().map(|name| (name.clone(), false)));
+        if let Some(rspfile) = &build.rspfile {
+            files.push((rspfile.path.to_string_lossy().into_owned(), false));
+        }
+    }
+    files.sort_unstable();
+    // A file is a tree if any build writes it as one.
+    files.dedup_by(|(name, tree), (prev, prev_tree)| {
+        let same = name == prev;
+        if same {
+            *prev_tree |= *tree;
+        }
+        same
+    });
+    files
+}
+
+fn is_empty_dir(path: &std::path::Path) -> anyhow::Result<bool> {
+    let mut entries = std::fs::read_dir(path)
+        .map_err(|err| anyhow::anyhow!("read {}: {}", path.display(), err))?;
+    Ok(entries.next().is_none())
+}
+
+fn clean(args: Vec<String>, state: &mut load::State) -> anyhow::Result<i32> {
+    use lexopt::prelude::*;
+    let mut dry_run = false;
+    let mut generators = false;
+    let mut by_rule = false;
+    let mut names = Vec::new();
+    let mut parser = lexopt::Parser::from_args(args);
+    while let Some(arg) = parser.next()? {
+        match arg {
+            Short('h') | Long("help") => {
+                println!(
+                    "usage: n2 -t clean [options] [targets...]
+
+Remove the outputs of the targets and of everything they're built from, or
+with no targets, of every build but those that write build files.
+
+options:
+-n  print the files that would be removed, without removing them
+-g  also remove the outputs of builds that write build files (generator = 1)
+-r  take the arguments as rules, and remove the outputs of their builds
+"
+                );
+                return Ok(0);
+            }
+            Short('n') => dry_run = true,
+            Short('g') => generators = true,
+            Short('r') => by_rule = true,
+            Value(name) => names.push(name.to_string_lossy().into_owned()),
+            _ => anyhow::bail!("-t clean: {}", arg.unexpected()),
+        }
+    }
+
+    let scope = if by_rule {
+        if names.is_empty() {
+            anyhow::bail!("-t clean -r: expected at least one rule");
+        }
+        if let Some(name) = names.iter().find(|name| !state.rules.contains_key(*name)) {
+            anyhow::bail!("-t clean -r: unknown rule {:?}", name);
+        }
+        CleanScope::Rules(names.into_iter().collect())
+    } else if names.is_empty() {
+        CleanScope::All { generators }
+    } else {
+        CleanScope::Targets(lookup_all(&names, &state.graph)?)
+    };
+
+    let builds = clean_builds(&mut state.graph, &scope)?;
+    let mut removed = 0;
+    for (name, tree) in clean_files(&state.graph, &builds) {
+        let path = std::path::Path::new(&name);
+        match std::fs::symlink_metadata(path) {
+            Ok(meta) if meta.is_dir() && !tree && !is_empty_dir(path)? => {
+                // Only builds with dir_outputs own what's inside.
+                println!("n2: not removing {}: directory isn't empty", name);
+                continue;
+            }
+            Ok(_) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(err) => anyhow::bail!("stat {}: {}", name, err),
+        }
+        if dry_run {
+            println!("{}", name);
+            continue;
+        }
+        graph::remove_output(path, tree)
+            .map_err(|err| anyhow::anyhow!("remove {}: {}", name, err))?;
+        removed += 1;
+    }
+    if !dry_run {
+        println!(
+            "n2: removed {} file{}",
+            removed,
+            if removed == 1 { "" } else { "s" }
+        );
+    }
+    Ok(0)
+}
+
+fn recompact(args: Vec<String>, state: load::State) -> anyhow::Result<i32> {
+    use lexopt::prelude::*;
+    let mut parser = lexopt::Parser::from_args(args);
+    if let Some(arg) = parser.next()? {
+        match arg {
+            Short('h') | Long("help") => {
+                println!(
+                    "usage: n2 -t recompact
+
+Rewrite .n2_db with only the latest record of each build in the build files,
+dropping records that were superseded or whose builds no longer exist.
+"
+                );
+                return Ok(0);
+            }
+            _ => anyhow::bail!("-t recompact: {}", arg.unexpected()),
+        }
+    }
+
+    let load::State {
+        graph,
+        db,
+        hashes,
+        db_path,
+        lock,
+        ..
+    } = state;
+    let manifests = db.recorded_manifests().cloned();
+    // Close the db before replacing it, and keep it locked until then.
+    drop(db);
+    let (before, after) = db::recompact(&db_path, &graph, &hashes, manifests)?;
+    drop(lock);
+    println!(
+        "n2: recompacted {}: {} bytes -> {} bytes",
+        db_path.display(),
+        before,
+        after
+    );
+    Ok(0)
+}
+
+/// Evaluate a build, and the builds of its inputs, so that its hash can be
+/// computed, with the outputs of builds with dir_outputs stat()ed as trees.
+fn prepare_check(
+    state: &mut load::State,
+    file_state: &mut FileState,
+    bid: BuildId,
+) -> anyhow::Result<()> {
+    load::evaluate_build(&mut state.graph, bid)?;
+    let producers: Vec<BuildId> = state.graph.builds[bid]
+        .dirtying_ins()
+        .iter()
+        .chain(state.graph.builds[bid].discovered_ins())
+        .filter_map(|&id| state.graph.file(id).input)
+        .collect();
+    for producer in producers {
+        load::evaluate_build(&mut state.graph, producer)?;
+        file_state.add_dir_outputs(&state.graph.builds[producer]);
+    }
+    file_state.add_dir_outputs(&state.graph.builds[bid]);
+    Ok(())
+}
+
+/// The inputs of a stat()ed build that are newer than its oldest output,
+/// each with that output.  The db only records a hash of the inputs, so we
+/// can't tell exactly what changed, but these are the usual culprits.
+fn newer_inputs(file_state: &FileState, build: &Build) -> Vec<(FileId, FileId)> {
+    let oldest_out = build
+        .outs()
+        .iter()
+        .filter_map(|&id| match file_state.get(id) {
+            Some(MTime::Stamp(t)) => Some((t, id)),
+            _ => None,
+        })
+        .min_by_key(|&(t, _)| t);
+    let Some((out_time, out)) = oldest_out else {
+        return Vec::new();
+    };
+    build
+        .dirtying_ins()
+        .iter()
+        .chain(build.discovered_ins())
+        .filter(|&&id| matches!(file_state.get(id), Some(MTime::Stamp(t)) if t > out_time))
+        .map(|&id| (id, out))
+        .collect()
+}
+
+/// Explain whether the build producing a file would run, and why, by
+/// performing the same checks as Work::check_build_dirty for just that
+/// (non-phony) build.
+/// Inputs are taken as they are on disk now; builds they depend on are not
+/// considered.
+fn explain_dirty(
+    state: &mut load::State,
+    file_state: &mut FileState,
+    bid: BuildId,
+) -> anyhow::Result<Vec<String>> {
+    prepare_check(state, file_state, bid)?;
+    let graph = &state.graph;
+    let build = &graph.builds[bid];
+    let mut reasons = Vec::new();
+    for &id in build.dirtying_ins() {
+        let file = graph.file(id);
+        if file_state.stat(id, file.path())? == MTime::Missing {
+            reasons.push(match file.input {
+                Some(_) => format!("input {} missing, but would be built first", file.name),
+                None => format!("input {} missing and no build generates it", file.name),
+            });
+        }
+    }
+    for &id in build.discovered_ins() {
+        let file = graph.file(id);
+        if file_state.stat(id, file.path())? == MTime::Missing {
+            reasons.push(format!("discovered input {} missing", file.name));
+        }
+    }
+    for &id in build.outs() {
+        let file = graph.file(id);
+        if file_state.lstat(id, file.path())? == MTime::Missing {
+            reasons.push(format!("output {} missing", file.name));
+        }
+    }
+    if !reasons.is_empty() {
+        return Ok(reasons);
+    }
+
+    let prev_hash = match state.hashes.get(bid) {
+        None => return Ok(vec!["no previous build recorded".into()]),
+        Some(hash) => hash,
+    };
+    if hash::hash_build(&graph.files, file_state, build) == prev_hash {
+        return Ok(reasons);
+    }
+
+    if let Some(diff) = hash::explain_cmdline_change(build) {
+        reasons.push(format!("command changed: {}", diff));
+    }
+
+    for (id, out) in newer_inputs(file_state, build) {
+        reasons.push(format!(
+            "input {} is newer than output {}",
+            graph.file(id).name,
+            graph.file(out).name
+        ));
+    }
+    if reasons.is_empty() {
+        reasons.push("command line or inputs changed since the last build".into());
+    }
+    Ok(reasons)
+}
+
+fn why(args: Vec<String>, state: &mut load::State, options: &work::Options) -> anyhow::Result<i32> {
+    use lexopt::prelude::*;
+    let mut names = Vec::new();
+    let mut parser = lexopt::Parser::from_args(args);
+    while let Some(arg) = parser.next()? {
+        match arg {
+            Short('h') | Long("help") => {
+                println!(
+                    "usage: n2 -t why targets...
+
+Print why the build producing each target would run, without running it.
+"
+                );
+                return Ok(0);
+            }
+            Value(name) => names.push(name.to_string_lossy().into_owned()),
+            _ => anyhow::bail!("-t why: {}", arg.unexpected()),
+        }
+    }
+    if names.is_empty() {
+        anyhow::bail!("-t why: expected at least one target");
+    }
+
+    let mut file_state = FileState::new(&state.graph, options.mtime_granularity);
+    file_state.set_content_roots(options.content_roots.clone());
+    for (name, id) in names.iter().zip(lookup_all(&names, &state.graph)?) {
+        let bid = match state.graph.file(id).input {
+            None => {
+                println!("{}: source file, not built", name);
+                continue;
+            }
+            Some(bid) => bid,
+        };
+        if state.graph.builds[bid].is_phony() {
+            println!("{}: phony, so never runs a command", name);
+            continue;
+        }
+        let reasons = explain_dirty(state, &mut file_state, bid)?;
+        if reasons.is_empty() {
+            println!("{}: up to date", name);
+        } else {
+            println!("{}: dirty", name);
+            for reason in reasons {
+                println!("  {}", reason);
+            }
+        }
+    }
+    Ok(0)
+}
+
+/// The changes since a build last ran that make it run again, as (file,
+/// how it changed) pairs.  Inputs that another build generates are left to
+/// that build to explain.
+fn build_changes(
+    state: &mut load::State,
+    file_state: &mut FileState,
+    bid: BuildId,
+) -> anyhow::Result<Vec<(String, String)>> {
+    prepare_check(state, file_state, bid)?;
+    let graph = &state.graph;
+    let build = &graph.builds[bid];
+    let out_name = &graph.file(build.outs()[0]).name;
+    let mut changes = Vec::new();
+    let mut missing = false;
+    for &id in build.dirtying_ins().iter().chain(build.discovered_ins()) {
+        let file = graph.file(id);
+        if file_state.stat(id, file.path())? == MTime::Missing {
+            missing = true;
+            if file.input.is_none() {
+                changes.push((file.name.clone(), "missing".to_owned()));
+            }
+        }
+    }
+    for &id in build.outs() {
+        let file = graph.file(id);
+        if file_state.lstat(id, file.path())? == MTime::Missing {
+            missing = true;
+            changes.push((file.name.clone(), "output missing".to_owned()));
+        }
+    }
+    if missing {
+        return Ok(changes);
+    }
+
+    let prev_hash = match state.hashes.get(bid) {
+        None => return Ok(vec![(out_name.clone(), "never built".to_owned())]),
+        Some(hash) => hash,
+    };
+    if hash::hash_build(&graph.files, file_state, build) == prev_hash {
+        return Ok(changes);
+    }
+    if hash::explain_cmdline_change(build).is_some() {
+        changes.push((out_name.clone(), "command changed".to_owned()));
+    }
+    for (id, _) in newer_inputs(file_state, build) {
+        changes.push((graph.file(id).name.clone(), "modified".to_owned()));
+    }
+    if changes.is_empty() {
+        changes.push((
+            out_name.clone(),
+            "inputs differ from its last build".to_owned(),
+        ));
+    }
+    Ok(changes)
+}
+
+/// Print what changed since the targets were last built, across every build
+/// they depend on, without building anything.
+fn changes(
+    args: Vec<String>,
+    state: &mut load::State,
+    options: &work::Options,
+) -> anyhow::Result<i32> {
+    use lexopt::prelude::*;
+    let mut names = Vec::new();
+    let mut parser = lexopt::Parser::from_args(args);
+    while let Some(arg) = parser.next()? {
+        match arg {
+            Short('h') | Long("help") => {
+                println!(
+                    "usage: n2 -t changes [targets...]
+
+Print the files that changed since the targets were last built, making them
+rebuild: modified or missing inputs, missing outputs, builds whose command
+changed, and builds never run.  Without targets, the default targets are used.
+"
+                );
+                return Ok(0);
+            }
+            Value(name) => names.push(name.to_string_lossy().into_owned()),
+            _ => anyhow::bail!("-t changes: {}", arg.unexpected()),
+        }
+    }
+
+    // The builds the targets depend on.
+    let mut builds = Vec::new();
+    let mut seen = HashSet::new();
+    let mut stack = targets(&names, state)?;
+    while let Some(id) = stack.pop() {
+        let Some(bid) = state.graph.file(id).input else {
+            continue;
+        };
+        if !seen.insert(bid) {
+            continue;
+        }
+        let build = &state.graph.builds[bid];
+        stack.extend(build.dirtying_ins().iter().chain(build.discovered_ins()));
+        if !build.is_phony() {
+            builds.push(bid);
+        }
+    }
+
+    let mut file_state = FileState::new(&state.graph, options.mtime_granularity);
+    file_state.set_content_roots(options.content_roots.clone());
+    let mut changes = BTreeMap::new();
+    for bid in builds {
+        for (name, how) in build_changes(state, &mut file_state, bid)? {
+            changes.entry(name).or_insert(how);
+        }
+    }
+    if changes.is_empty() {
+        println!("no changes since the last build");
+    }
+    for (name, how) in changes {
+        println!("{}: {}", name, how);
+    }
+    Ok(0)
+}
+
+/// Check every build that has been built, i.e. whose outputs exist, and
+/// print a JSON line for each that would run again, with the reasons from
+/// explain_dirty.  Exits with 1 if any would.  Meant for CI after a build,
+/// to catch commands that don't leave their outputs up to date.
+fn verify(
+    args: Vec<String>,
+    state: &mut load::State,
+    options: &work::Options,
+) -> anyhow::Result<i32> {
+    use lexopt::prelude::*;
+    let mut parser = lexopt::Parser::from_args(args);
+    if let Some(arg) = parser.next()? {
+        match arg {
+            Short('h') | Long("help") => {
+                println!(
+                    "usage: n2 --verify
+
+Print a JSON line for each built output whose build would run again, and
+fail if there are any.
+"
+                );
+                return Ok(0);
+            }
+            _ => anyhow::bail!("--verify: {}", arg.unexpected()),
+        }
+    }
+
+    let mut file_state = FileState::new(&state.graph, options.mtime_granularity);
+    file_state.set_content_roots(options.content_roots.clone());
+    let mut dirty = 0;
+    for bid in state.graph.builds.all_ids() {
+        let build = &state.graph.builds[bid];
+        if build.is_phony() {
+            continue;
+        }
+        let mut built = true;
+        for &id in build.outs() {
+            if file_state.lstat(id, state.graph.file(id).path())? == MTime::Missing {
+                built = false;
+            }
+        }
+        if !built {
+            continue;
+        }
+        let out = build.outs()[0];
+        let reasons = explain_dirty(state, &mut file_state, bid)?;
+        if reasons.is_empty() {
+            continue;
+        }
+        dirty += 1;
+        let reasons: Vec<String> = reasons.iter().map(|r| json::quote(r)).collect();
+        println!(
+            "{{\"output\":{},\"reasons\":[{}]}}",
+            json::quote(&state.graph.file(out).name),
+            reasons.join(",")
+        );
+    }
+    Ok(if dirty > 0 { 1 } else { 0 })
+}
+
+fn json_opt_str(s: Option<&str>) -> String {
+    s.map_or_else(|| "null".to_string(), json::quote)
+}
+
+fn json_ids(ids: &[FileId]) -> String {
+    let ids: Vec<String> = ids.iter().map(|id| id.index().to_string()).collect();
+    format!("[{}]", ids.join(","))
+}
+
+/// Write the whole build graph as JSON, with every build evaluated.  Files
+/// and builds are referred to by their index in the "files" and "builds"
+/// arrays.  Written incrementally, as output for large graphs is large.
+fn dump_json(state: &mut load::State, w: &mut dyn Write) -> anyhow::Result<()> {
+    let graph = &mut state.graph;
+    writeln!(w, "{{")?;
+
+    write!(w, "\"files\": [")?;
+    for (i, id) in graph.files.all_ids().enumerate() {
+        let sep = if i == 0 { "" } else { "," };
+        write!(w, "{}\n  {}", sep, json::quote(&graph.file(id).name))?;
+    }
+    writeln!(w, "\n],")?;
+
+    let mut rules = Vec::new();
+    write!(w, "\"builds\": [")?;
+    for (i, bid) in graph.builds.all_ids().enumerate() {
+        // Capture the build's own variables before evaluation consumes them.
+        let vars: Vec<String> = match &graph.builds[bid].bindings {
+            None => Vec::new(),
+            Some(bindings) => bindings
+                .vars
+                .iter()
+                .map(|(key, val)| {
+                    let val = val.evaluate(&[&*bindings.env]);
+                    format!("{}:{}", json::quote(key), json::quote(&val))
+                })
+                .collect(),
+        };
+        load::evaluate_build(graph, bid)?;
+        let build = &graph.builds[bid];
+        if !rules
+            .iter()
+            .any(|r: &std::rc::Rc<_>| std::rc::Rc::ptr_eq(r, &build.rule))
+        {
+            rules.push(build.rule.clone());
+        }
+
+        let dirtying = build.dirtying_ins();
+        let ordering = build.ordering_ins();
+        let sep = if i == 0 { "" } else { "," };
+        writeln!(w, "{}\n  {{", sep)?;
+        writeln!(
+            w,
+            "    \"location\": {},",
+            json::quote(&build.location.to_string())
+        )?;
+        writeln!(w, "    \"rule\": {},", json::quote(&build.rule.name))?;
+        writeln!(w, "    \"ins\": {},", json_ids(build.explicit_ins()))?;
+        writeln!(
+            w,
+            "    \"implicit_ins\": {},",
+            json_ids(&dirtying[build.explicit_ins().len()..])
+        )?;
+        writeln!(
+            w,
+            "    \"order_only_ins\": {},",
+            json_ids(&ordering[dirtying.len()..])
+        )?;
+        writeln!(
+            w,
+            "    \"validation_ins\": {},",
+            json_ids(build.validation_ins())
+        )?;
+        writeln!(
+            w,
+            "    \"discovered_ins\": {},",
+            json_ids(build.discovered_ins())
+        )?;
+        writeln!(w, "    \"outs\": {},", json_ids(build.explicit_outs()))?;
+        writeln!(
+            w,
+            "    \"implicit_outs\": {},",
+            json_ids(&build.outs()[build.explicit_outs().len()..])
+        )?;
+        writeln!(
+            w,
+            "    \"command\": {},",
+            json_opt_str(build.cmdline.as_deref())
+        )?;
+        writeln!(
+            w,
+            "    \"description\": {},",
+            json_opt_str(build.desc.as_deref())
+        )?;
+        writeln!(
+            w,
+            "    \"depfile\": {},",
+            json_opt_str(build.depfile.as_deref())
+        )?;
+        let rspfile = build.rspfile.as_ref().map(|rsp| rsp.path.to_string_lossy());
+        writeln!(w, "    \"rspfile\": {},", json_opt_str(rspfile.as_deref()))?;
+        writeln!(w, "    \"pool\": {},", json_opt_str(build.pool.as_deref()))?;
+        writeln!(w, "    \"vars\": {{{}}}", vars.join(","))?;
+        write!(w, "  }}")?;
+    }
+    writeln!(w, "\n],")?;
+
+    write!(w, "\"rules\": {{")?;
+    for (i, rule) in rules.iter().enumerate() {
+        let vars: Vec<String> = rule
+            .vars
+            .iter()
+            .map(|(key, val)| format!("{}:{}", json::quote(key), json::quote(&val.to_string())))
+            .collect();
+        let sep = if i == 0 { "" } else { "," };
+        write!(
+            w,
+            "{}\n  {}: {{{}}}",
+            sep,
+            json::quote(&rule.name),
+            vars.join(",")
+        )?;
+    }
+    writeln!(w, "\n}},")?;
+
+    let pools: Vec<String> = state
+        .pools
+        .iter()
+        .map(|(name, depth)| format!("{}:{}", json::quote(name), depth))
+        .collect();
+    writeln!(w, "\"pools\": {{{}}},", pools.join(","))?;
+    writeln!(w, "\"defaults\": {}", json_ids(&state.default))?;
+    writeln!(w, "}}")?;
+    Ok(())
+}
+
+fn dump(args: Vec<String>, state: &mut load::State) -> anyhow::Result<i32> {
+    use lexopt::prelude::*;
+    let mut parser = lexopt::Parser::from_args(args);
+    while let Some(arg) = parser.next()? {
+        match arg {
+            Short('h') | Long("help") => {
+                println!(
+                    "usage: n2 -t dump [--format=json]
+
+Print the evaluated build graph: files, builds, rules, pools, and defaults.
+Builds refer to files by their index in the files list.
+"
+                );
+                return Ok(0);
+            }
+            Long("format") => {
+                let format = parser.value()?;
+                if format != "json" {
+                    anyhow::bail!("-t dump: unknown format {:?}, expected json", format);
+                }
+            }
+            _ => anyhow::bail!("-t dump: {}", arg.unexpected()),
+        }
+    }
+
+    let mut w = std::io::BufWriter::new(std::io::stdout().lock());
+    dump_json(state, &mut w)?;
+    w.flush()?;
+    Ok(0)
+}
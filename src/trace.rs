@@ -1,8 +1,10 @@
-//! Chrome trace output.
+//! Chrome trace output, and the per-phase timings of --profile-startup.
 
+use std::cell::RefCell;
 use std::fs::File;
 use std::io::{BufWriter, Write};
-use std::time::Instant;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 static mut TRACE: Option<Trace> = None;
 
@@ -13,7 +15,7 @@ pub struct Trace {
 }
 
 impl Trace {
-    fn new(path: &str) -> std::io::Result<Self> {
+    fn new(path: &Path) -> std::io::Result<Self> {
         let mut w = BufWriter::new(File::create(path)?);
         writeln!(w, "[")?;
         Ok(Trace {
@@ -38,14 +40,58 @@ impl Trace {
     }
 
     pub fn write_complete(&mut self, name: &str, tid: usize, start: Instant, end: Instant) {
+        self.write_complete_with_args(name, tid, start, end, &[]);
+    }
+
+    pub fn write_complete_with_args(
+        &mut self,
+        name: &str,
+        tid: usize,
+        start: Instant,
+        end: Instant,
+        args: &[(&str, u64)],
+    ) {
         self.write_event_prefix(name, start);
-        writeln!(
+        write!(
             self.w,
-            "\"tid\": {}, \"ph\":\"X\", \"dur\":{}}}",
+            "\"tid\": {}, \"ph\":\"X\", \"dur\":{}",
             tid,
             end.duration_since(start).as_micros()
         )
         .unwrap();
+        if !args.is_empty() {
+            write!(self.w, ", \"args\":{{").unwrap();
+            for (i, (key, value)) in args.iter().enumerate() {
+                if i > 0 {
+                    write!(self.w, ",").unwrap();
+                }
+                write!(self.w, "{:?}:{}", key, value).unwrap();
+            }
+            write!(self.w, "}}").unwrap();
+        }
+        writeln!(self.w, "}}").unwrap();
+    }
+
+    /// Write a metadata ("M") event, which trace viewers show alongside the
+    /// process rather than on the timeline.
+    pub fn write_metadata(&mut self, name: &str, args: &[(&str, &str)]) {
+        if self.count > 0 {
+            write!(self.w, ",").unwrap();
+        }
+        self.count += 1;
+        write!(
+            self.w,
+            "{{\"pid\":0, \"name\":{:?}, \"ph\":\"M\", \"args\":{{",
+            name
+        )
+        .unwrap();
+        for (i, (key, value)) in args.iter().enumerate() {
+            if i > 0 {
+                write!(self.w, ",").unwrap();
+            }
+            write!(self.w, "{:?}:{:?}", key, value).unwrap();
+        }
+        writeln!(self.w, "}}}}").unwrap();
     }
 
     /*
@@ -80,8 +126,15 @@ impl Trace {
     }
 }
 
-pub fn open(path: &str) -> std::io::Result<()> {
-    let trace = Trace::new(path)?;
+/// If path is an existing directory, a new trace file named by the current
+/// time is created within it, so each invocation keeps its own trace.
+pub fn open(path: &Path) -> std::io::Result<()> {
+    let path = if path.is_dir() {
+        invocation_path(path)
+    } else {
+        path.to_path_buf()
+    };
+    let trace = Trace::new(&path)?;
     // Safety: accessing global mut, not threadsafe.
     unsafe {
         TRACE = Some(trace);
@@ -89,6 +142,17 @@ pub fn open(path: &str) -> std::io::Result<()> {
     Ok(())
 }
 
+fn invocation_path(dir: &Path) -> PathBuf {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    dir.join(format!(
+        "trace-{}-{}.json",
+        now.as_millis(),
+        std::process::id()
+    ))
+}
+
 pub fn enabled() -> bool {
     // Safety: accessing global mut, not threadsafe.
     unsafe { matches!(TRACE, Some(_)) }
@@ -103,14 +167,114 @@ pub fn write_complete(name: &str, tid: usize, start: Instant, end: Instant) {
     }
 }
 
+pub fn write_complete_with_args(
+    name: &str,
+    tid: usize,
+    start: Instant,
+    end: Instant,
+    args: &[(&str, u64)],
+) {
+    // Safety: accessing global mut, not threadsafe.
+    unsafe {
+        if let Some(ref mut t) = TRACE {
+            t.write_complete_with_args(name, tid, start, end, args);
+        }
+    }
+}
+
+pub fn write_metadata(name: &str, args: &[(&str, &str)]) {
+    // Safety: accessing global mut, not threadsafe.
+    unsafe {
+        if let Some(ref mut t) = TRACE {
+            t.write_metadata(name, args);
+        }
+    }
+}
+
 pub fn scope<T>(name: &'static str, f: impl FnOnce() -> T) -> T {
     let start = Instant::now();
-    let result = f();
+    let result = profile_scope(name, f);
     let end = Instant::now();
     write_complete(name, 0, start, end);
     result
 }
 
+/// Time spent in scopes of one name, excluding scopes nested within them.
+#[derive(Debug)]
+pub struct ProfilePhase {
+    pub name: &'static str,
+    pub time: Duration,
+    pub count: usize,
+}
+
+#[derive(Default)]
+struct Profile {
+    /// Phases in the order they were first entered.
+    phases: Vec<ProfilePhase>,
+    /// For each scope being run, the time spent in scopes nested within it.
+    nested: Vec<Duration>,
+}
+
+impl Profile {
+    fn finish(&mut self, name: &'static str, elapsed: Duration) {
+        let nested = self.nested.pop().unwrap_or_default();
+        if let Some(parent) = self.nested.last_mut() {
+            *parent += elapsed;
+        }
+        let phase = match self.phases.iter().position(|phase| phase.name == name) {
+            Some(index) => &mut self.phases[index],
+            None => {
+                self.phases.push(ProfilePhase {
+                    name,
+                    time: Duration::ZERO,
+                    count: 0,
+                });
+                self.phases.last_mut().unwrap()
+            }
+        };
+        phase.time += elapsed.saturating_sub(nested);
+        phase.count += 1;
+    }
+}
+
+thread_local! {
+    static PROFILE: RefCell<Option<Profile>> = const { RefCell::new(None) };
+}
+
+/// Start accumulating the time spent in scopes on this thread.
+pub fn profile_start() {
+    PROFILE.with(|profile| *profile.borrow_mut() = Some(Profile::default()));
+}
+
+/// Stop profiling and return the time spent per phase.
+pub fn profile_finish() -> Vec<ProfilePhase> {
+    PROFILE.with(|profile| profile.borrow_mut().take().map_or(Vec::new(), |p| p.phases))
+}
+
+/// Like scope, but only counted in the profile and not traced, for spans too
+/// small and numerous to show in a trace.
+pub fn profile_scope<T>(name: &'static str, f: impl FnOnce() -> T) -> T {
+    let profiling = PROFILE.with(|profile| match profile.borrow_mut().as_mut() {
+        Some(profile) => {
+            profile.nested.push(Duration::ZERO);
+            true
+        }
+        None => false,
+    });
+    if !profiling {
+        return f();
+    }
+    let start = Instant::now();
+    let result = f();
+    let elapsed = start.elapsed();
+    PROFILE.with(|profile| {
+        if let Some(profile) = profile.borrow_mut().as_mut() {
+            profile.finish(name, elapsed);
+        }
+    });
+    result
+}
+
 pub fn close() {
     // Safety: accessing global mut, not threadsafe.
     unsafe {
@@ -119,3 +283,31 @@ pub fn close() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profile() {
+        let sleep = || std::thread::sleep(Duration::from_millis(10));
+        profile_start();
+        scope("outer", || {
+            sleep();
+            for _ in 0..2 {
+                profile_scope("inner", sleep);
+            }
+        });
+        let phases = profile_finish();
+        assert_eq!(phases.len(), 2);
+        let (inner, outer) = (&phases[0], &phases[1]);
+        assert_eq!((inner.name, inner.count), ("inner", 2));
+        assert_eq!((outer.name, outer.count), ("outer", 1));
+        assert!(inner.time >= Duration::from_millis(20));
+        assert!(outer.time >= Duration::from_millis(10));
+
+        assert!(profile_finish().is_empty());
+        assert_eq!(profile_scope("after", || 1), 1);
+        assert!(profile_finish().is_empty());
+    }
+}
@@ -1,18 +1,116 @@
 //! Build runner, choosing and executing tasks as determined by out of date inputs.
 
 use crate::{
-    canon::{canonicalize_path, to_owned_canon_path},
+    canon::{self, to_owned_canon_path},
     db,
     densemap::DenseMap,
+    error::Error,
     graph::*,
-    hash, process,
+    hash, load, process,
     progress::{self, Progress},
-    signal,
+    signal::{self, CancelToken},
     smallmap::SmallMap,
-    task, trace,
+    summary, task, trace,
 };
+use std::cmp::Reverse;
+use std::collections::HashMap;
 use std::collections::HashSet;
-use std::collections::VecDeque;
+use std::collections::{BinaryHeap, VecDeque};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Delay before the first retry of a failed command; doubled on each
+/// subsequent retry.
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+/// How much older than a build's record its files must be for --trust-mtimes,
+/// enough for FAT's two-second mtimes.
+const MTIME_SLACK: Duration = Duration::from_secs(2);
+
+/// Open a file or directory, just to set its mtime.
+fn open_to_touch(path: &std::path::Path) -> std::io::Result<std::fs::File> {
+    let mut options = std::fs::OpenOptions::new();
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::OpenOptionsExt;
+        use windows_sys::Win32::Storage::FileSystem::{
+            FILE_FLAG_BACKUP_SEMANTICS, FILE_WRITE_ATTRIBUTES,
+        };
+        options
+            .access_mode(FILE_WRITE_ATTRIBUTES)
+            .custom_flags(FILE_FLAG_BACKUP_SEMANTICS);
+    }
+    #[cfg(not(windows))]
+    options.read(true);
+    options.open(path)
+}
+
+fn retry_backoff(attempt: usize) -> Duration {
+    RETRY_BACKOFF * (1 << (attempt - 1).min(5))
+}
+
+/// Totals over the commands run for one rule, for --rule-stats.
+#[derive(Default)]
+struct RuleStats {
+    runs: usize,
+    time: Duration,
+    failures: usize,
+}
+
+/// Render rule stats as a table, slowest rules first.
+fn format_rule_stats(stats: &HashMap<String, RuleStats>) -> String {
+    let mut rules: Vec<_> = stats.iter().collect();
+    rules.sort_by(|a, b| b.1.time.cmp(&a.1.time).then_with(|| a.0.cmp(b.0)));
+    let width = rules
+        .iter()
+        .map(|(name, _)| name.len())
+        .max()
+        .unwrap_or(0)
+        .max("rule".len());
+    let mut out = format!(
+        "{:width$}  {:>6}  {:>9}  {:>8}  {:>6}",
+        "rule", "runs", "total", "average", "failed"
+    );
+    for (name, stats) in rules {
+        out.push_str(&format!(
+            "\n{:width$}  {:>6}  {:>8.1}s  {:>7.2}s  {:>6}",
+            name,
+            stats.runs,
+            stats.time.as_secs_f64(),
+            stats.time.as_secs_f64() / stats.runs as f64,
+            stats.failures
+        ));
+    }
+    out
+}
+
+/// Render the critical path, a chain of (description, duration) from first
+/// to last, with each build's share of the total wall time.
+fn format_critical_path(path: &[(&str, Duration)], wall: Duration) -> String {
+    let sum: Duration = path.iter().map(|&(_, time)| time).sum();
+    let mut out = format!(
+        "critical path: {} build{}, {:.1}s of {:.1}s wall time",
+        path.len(),
+        if path.len() == 1 { "" } else { "s" },
+        sum.as_secs_f64(),
+        wall.as_secs_f64()
+    );
+    for &(desc, time) in path {
+        let percent = if wall.is_zero() {
+            0.0
+        } else {
+            100.0 * time.as_secs_f64() / wall.as_secs_f64()
+        };
+        out.push_str(&format!(
+            "\n{:>8.1}s  {:>3.0}%  {}",
+            time.as_secs_f64(),
+            percent,
+            desc
+        ));
+    }
+    out
+}
 
 /// Build steps go through this sequence of states.
 /// See "Build states" in the design notes.
@@ -35,6 +133,10 @@ pub enum BuildState {
     /// Builds who have been determined not up to date and which are ready
     /// to be executed.
     Queued,
+    /// Builds determined not up to date but held back from the queue: with
+    /// --dedup-commands, until an identical command finishes, or after a
+    /// failure, until the retry backoff passes.
+    Waiting,
     /// Currently executing.
     Running,
     /// Already up to date, or finished executing successfully.
@@ -49,7 +151,13 @@ pub enum BuildState {
 /// Only covers builds not in the "unknown" state, which means it's only builds
 /// that are considered part of the current build.
 #[derive(Clone, Debug, Default)]
-pub struct StateCounts([usize; 6]);
+pub struct StateCounts {
+    counts: [usize; 7],
+    /// How many of the Done builds were up to date, rather than run.
+    up_to_date: usize,
+    /// How many builds are expected to run, when checked up front.
+    to_run: Option<usize>,
+}
 impl StateCounts {
     fn idx(state: BuildState) -> usize {
         match state {
@@ -57,20 +165,43 @@ impl StateCounts {
             BuildState::Want => 0,
             BuildState::Ready => 1,
             BuildState::Queued => 2,
-            BuildState::Running => 3,
-            BuildState::Done => 4,
-            BuildState::Failed => 5,
+            BuildState::Waiting => 3,
+            BuildState::Running => 4,
+            BuildState::Done => 5,
+            BuildState::Failed => 6,
         }
     }
     pub fn add(&mut self, state: BuildState, delta: isize) {
-        self.0[StateCounts::idx(state)] =
-            (self.0[StateCounts::idx(state)] as isize + delta) as usize;
+        self.counts[StateCounts::idx(state)] =
+            (self.counts[StateCounts::idx(state)] as isize + delta) as usize;
     }
     pub fn get(&self, state: BuildState) -> usize {
-        self.0[StateCounts::idx(state)]
+        self.counts[StateCounts::idx(state)]
     }
     pub fn total(&self) -> usize {
-        self.0[0] + self.0[1] + self.0[2] + self.0[3] + self.0[4] + self.0[5]
+        self.counts.iter().sum()
+    }
+    /// Count a build that became Done without running, as it was up to date
+    /// (or adopted as such).
+    pub fn add_up_to_date(&mut self) {
+        self.up_to_date += 1;
+    }
+    /// Done builds that were up to date.
+    pub fn up_to_date(&self) -> usize {
+        self.up_to_date
+    }
+    /// Done builds whose commands ran.
+    pub fn ran(&self) -> usize {
+        self.get(BuildState::Done) - self.up_to_date
+    }
+    /// How many builds are expected to run in all, if known (from --prescan).
+    /// Only an estimate: e.g. a build whose outputs didn't change lets the
+    /// builds using them skip running.
+    pub fn to_run(&self) -> Option<usize> {
+        self.to_run
+    }
+    pub fn set_to_run(&mut self, to_run: usize) {
+        self.to_run = Some(to_run);
     }
 }
 
@@ -115,24 +246,58 @@ struct BuildStates {
     /// Named pools of queued and running builds.
     /// Builds otherwise default to using an unnamed infinite pool.
     pools: SmallMap<String, PoolState>,
+
+    /// Per-rule limits on running builds, from --rule-jobs.
+    /// Maps rule name to (running, limit).
+    rule_jobs: SmallMap<String, (usize, usize)>,
+
+    /// Sum of the memory_estimate of running builds, in megabytes.
+    memory_running: usize,
+    /// Limit on memory_running, from --memory-budget.
+    memory_budget: Option<usize>,
+
+    /// From --deterministic: start builds in a fixed order; see pop_ready.
+    deterministic: bool,
+    /// With deterministic, ready builds waiting for the running ones to
+    /// finish, smallest BuildId first.
+    held: BinaryHeap<Reverse<BuildId>>,
+    /// With deterministic, the wave of held builds being released, in
+    /// BuildId order.
+    wave: VecDeque<BuildId>,
 }
 
 impl BuildStates {
-    fn new(size: BuildId, depths: SmallMap<String, usize>) -> Self {
+    /// Depths from the manifest are overridden by any --pool-depth flags
+    /// in options.
+    fn new(size: BuildId, depths: SmallMap<String, usize>, options: &Options) -> Self {
         let mut pools = SmallMap::default();
         // The implied default pool.
         pools.insert(String::from(""), PoolState::new(0));
-        // TODO: the console pool is just a depth-1 pool for now.
+        // The console pool runs one task at a time with the terminal; see
+        // Build::is_console.
         pools.insert(String::from("console"), PoolState::new(1));
         for (name, depth) in depths.into_iter() {
             pools.insert(name, PoolState::new(depth));
         }
+        for (name, depth) in &options.pool_depths {
+            pools.insert(name.clone(), PoolState::new(*depth));
+        }
+        let mut rule_jobs = SmallMap::default();
+        for (name, limit) in &options.rule_jobs {
+            rule_jobs.insert(name.clone(), (0, *limit));
+        }
         BuildStates {
             states: DenseMap::new_sized(size, BuildState::Unknown),
             counts: StateCounts::default(),
             total_pending: 0,
             ready: VecDeque::new(),
             pools,
+            rule_jobs,
+            memory_running: 0,
+            memory_budget: options.memory_budget,
+            deterministic: options.deterministic,
+            held: BinaryHeap::new(),
+            wave: VecDeque::new(),
         }
     }
 
@@ -146,7 +311,7 @@ impl BuildStates {
         let prev = std::mem::replace(&mut self.states[id], state);
 
         // We skip user-facing counters for phony builds.
-        let skip_ui_count = build.cmdline.is_none();
+        let skip_ui_count = build.is_phony();
 
         // println!("{:?} {:?}=>{:?} {:?}", id, prev, state, self.counts);
         if prev == BuildState::Unknown {
@@ -154,6 +319,10 @@ impl BuildStates {
         } else {
             if prev == BuildState::Running {
                 self.get_pool(build).unwrap().running -= 1;
+                if let Some((running, _)) = self.get_rule_jobs(build) {
+                    *running -= 1;
+                }
+                self.memory_running -= build.memory_estimate;
             }
             if !skip_ui_count {
                 self.counts.add(prev, -1);
@@ -171,6 +340,10 @@ impl BuildStates {
                 //     trace::if_enabled(|t| t.write_instant("first build"));
                 // }
                 self.get_pool(build).unwrap().running += 1;
+                if let Some((running, _)) = self.get_rule_jobs(build) {
+                    *running += 1;
+                }
+                self.memory_running += build.memory_estimate;
             }
             BuildState::Done | BuildState::Failed => {
                 self.total_pending -= 1;
@@ -179,6 +352,11 @@ impl BuildStates {
         };
         if !skip_ui_count {
             self.counts.add(state, 1);
+            // Builds that ran were Running first; those only checked weren't.
+            if state == BuildState::Done && !matches!(prev, BuildState::Running | BuildState::Done)
+            {
+                self.counts.add_up_to_date();
+            }
         }
 
         /*
@@ -257,12 +435,12 @@ impl BuildStates {
     ) -> anyhow::Result<bool> {
         // Check for a dependency cycle.
         if let Some(cycle) = stack.iter().position(|&sid| sid == id) {
-            let mut err = "dependency cycle: ".to_string();
-            for &id in stack[cycle..].iter() {
-                err.push_str(&format!("{} -> ", graph.file(id).name));
-            }
-            err.push_str(&graph.file(id).name);
-            anyhow::bail!(err);
+            let mut path: Vec<String> = stack[cycle..]
+                .iter()
+                .map(|&id| graph.file(id).name.clone())
+                .collect();
+            path.push(graph.file(id).name.clone());
+            return Err(Error::Cycle { path }.into());
         }
 
         let mut ready = true;
@@ -282,11 +460,31 @@ impl BuildStates {
     }
 
     pub fn pop_ready(&mut self) -> Option<BuildId> {
+        if self.deterministic {
+            // Which builds are ready at any moment depends on which commands
+            // happened to finish first.  Instead, proceed in waves: builds
+            // that become ready wait until every running and queued build is
+            // done, and are then all released together in BuildId order, to
+            // run in parallel as pop_queued starts them in that order.
+            self.held.extend(self.ready.drain(..).map(Reverse));
+            if self.wave.is_empty() && !self.busy() {
+                self.wave
+                    .extend(std::iter::from_fn(|| self.held.pop()).map(|Reverse(id)| id));
+            }
+            return self.wave.pop_front();
+        }
         // Here is where we might consider prioritizing from among the available
         // ready set.
         self.ready.pop_front()
     }
 
+    /// Whether any build is running or queued to run.
+    fn busy(&self) -> bool {
+        self.pools
+            .iter()
+            .any(|(_, pool)| pool.running > 0 || !pool.queued.is_empty())
+    }
+
     /// Look up a PoolState by name.
     fn get_pool(&mut self, build: &Build) -> Option<&mut PoolState> {
         let name = build.pool.as_deref().unwrap_or("");
@@ -298,6 +496,14 @@ impl BuildStates {
         None
     }
 
+    /// Look up the --rule-jobs (running, limit) counts for a build's rule.
+    fn get_rule_jobs(&mut self, build: &Build) -> Option<&mut (usize, usize)> {
+        self.rule_jobs
+            .iter_mut()
+            .find(|(name, _)| *name == build.rule.name)
+            .map(|(_, counts)| counts)
+    }
+
     /// Mark a build as ready to run.
     /// May fail if the build references an unknown pool.
     pub fn enqueue(&mut self, id: BuildId, build: &Build) -> anyhow::Result<()> {
@@ -315,12 +521,64 @@ impl BuildStates {
         Ok(())
     }
 
+    /// Fill gauges with the load on each bounded pool that has builds running
+    /// or waiting, for progress display.  This runs on every pass of the
+    /// build loop, so it reuses the gauges (and their names) already there.
+    fn pool_gauges(&self, gauges: &mut Vec<progress::PoolGauge>) {
+        let mut len = 0;
+        for (name, pool) in self
+            .pools
+            .iter()
+            .filter(|(_, pool)| pool.depth > 0 && (pool.running > 0 || !pool.queued.is_empty()))
+        {
+            if len == gauges.len() {
+                gauges.push(progress::PoolGauge::default());
+            }
+            let gauge = &mut gauges[len];
+            gauge.name.clone_from(name);
+            gauge.running = pool.running;
+            gauge.depth = pool.depth;
+            gauge.queued = pool.queued.len();
+            len += 1;
+        }
+        gauges.truncate(len);
+    }
+
     /// Pop a ready to run queued build.
-    pub fn pop_queued(&mut self) -> Option<BuildId> {
+    /// Builds whose rule is at its --rule-jobs limit, or that would exceed
+    /// the memory budget, are skipped over.
+    pub fn pop_queued(&mut self, graph: &Graph) -> Option<BuildId> {
+        let rule_jobs = &self.rule_jobs;
+        let (memory_running, memory_budget) = (self.memory_running, self.memory_budget);
+        let blocked = |id: BuildId| {
+            let build = &graph.builds[id];
+            let rule_full = rule_jobs
+                .get(&build.rule.name)
+                .is_some_and(|&(running, limit)| limit != 0 && running >= limit);
+            // A build bigger than the whole budget still runs, but alone.
+            let over_budget = memory_budget.is_some_and(|budget| {
+                memory_running > 0 && memory_running + build.memory_estimate > budget
+            });
+            rule_full || over_budget
+        };
+        if self.deterministic {
+            // Start strictly in BuildId order, waiting if the next build
+            // can't start yet rather than starting a later one; which
+            // builds can start depends on which have finished.
+            let (_, pool) = self
+                .pools
+                .iter_mut()
+                .filter(|(_, pool)| !pool.queued.is_empty())
+                .min_by_key(|(_, pool)| pool.queued[0])?;
+            if (pool.depth != 0 && pool.running >= pool.depth) || blocked(pool.queued[0]) {
+                return None;
+            }
+            return pool.queued.pop_front();
+        }
         for (_, pool) in self.pools.iter_mut() {
             if pool.depth == 0 || pool.running < pool.depth {
-                if let Some(id) = pool.queued.pop_front() {
-                    return Some(id);
+                if let Some(pos) = pool.queued.iter().position(|&id| !blocked(id)) {
+                    return pool.queued.remove(pos);
                 }
             }
         }
@@ -328,14 +586,117 @@ impl BuildStates {
     }
 }
 
+/// What to do with running tasks once too many builds have failed, from
+/// --halt-on-error.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HaltMode {
+    /// Let them finish, recording any that succeed.
+    #[default]
+    Wait,
+    /// Kill them, to exit promptly.  Their outputs are left to be rebuilt.
+    Kill,
+}
+
 #[derive(Clone, Default)]
 pub struct Options {
     pub failures_left: Option<usize>,
     pub parallelism: usize,
     /// When true, verbosely explain why targets are considered dirty.
     pub explain: bool,
-    /// When true, just mark targets up to date without running anything.
+    /// From -d keepdepfile/keeprsp: keep each command's depfile/rspfile as
+    /// the command saw it, and print its path after the command runs.
+    pub keep_depfile: bool,
+    pub keep_rsp: bool,
+    /// From --adopt: record dirty builds as up to date, as their outputs
+    /// stand, without running anything.
     pub adopt: bool,
+    /// From -t touch: set the mtimes of the outputs of the requested
+    /// targets' builds to now and adopt those builds, so that builds using
+    /// them are rerun.  Other builds aren't adopted; if any they depend on is
+    /// dirty, the touch fails rather than run it.
+    pub touch: bool,
+    /// Pool depths that override those in the manifest, from --pool-depth.
+    pub pool_depths: Vec<(String, usize)>,
+    /// Number of times to rerun a failed command, for rules that don't
+    /// specify `retries`.
+    pub retries: usize,
+    /// Read the mtimes of the files of builds to check on this many threads,
+    /// from --stat-threads, so that slow stat()s overlap with running
+    /// commands.  With 0, or with deterministic, they're read as the builds
+    /// are checked.
+    pub stat_threads: usize,
+    /// Resolve symlinks before ".." components in paths, rather than
+    /// canonicalizing them lexically.
+    pub physical_paths: bool,
+    /// When set, builds fail if any input or discovered dependency lies
+    /// outside these directories or the working directory.
+    pub hermetic_roots: Option<Vec<String>>,
+    /// Limits on concurrently running builds per rule, from --rule-jobs.
+    pub rule_jobs: Vec<(String, usize)>,
+    /// Only start builds while the sum of their memory_estimate stays under
+    /// this many megabytes.
+    pub memory_budget: Option<usize>,
+    /// Report tasks that run for longer than this.
+    pub hang_timeout: Option<std::time::Duration>,
+    /// Tag each line of task output with this, from --prefix-output.
+    pub output_prefix: Option<task::OutputPrefix>,
+    /// Command to run when a task is reported hung, e.g. to dump its stack.
+    /// It receives the task's pid and command in N2_HUNG_PID/N2_HUNG_COMMAND.
+    pub hang_hook: Option<String>,
+    /// Command to run every task under, from --wrapper.
+    pub wrapper: Option<String>,
+    /// On Windows, run commands that need cmd under it, from
+    /// --windows-shell; see process::windows_command_line.
+    pub windows_shell: bool,
+    /// Fail builds whose command failed last time with the same inputs,
+    /// rather than running them again.
+    pub skip_known_failures: bool,
+    /// File mtimes are truncated to this before comparing; see
+    /// graph::truncate_mtime.
+    pub mtime_granularity: Duration,
+    /// Take builds whose files are all older than their last run as up to
+    /// date without hashing them, from --trust-mtimes; see
+    /// Work::older_than_record.
+    pub trust_mtimes: bool,
+    /// Directories whose files are hashed by their contents rather than
+    /// their mtimes, from --hash-contents; see FileState::set_content_roots.
+    pub content_roots: Vec<String>,
+    /// Print a table of time spent per rule after running.
+    pub rule_stats: bool,
+    /// Print the chain of commands that determined the wall time after
+    /// running.
+    pub critical_path: bool,
+    /// Warn rather than fail when a succeeding command's depfile can't be
+    /// parsed, from -w depfileparse=warn.
+    pub depfile_errors_warn: bool,
+    /// When set, commands that succeed but print output fail, unless their
+    /// rule is in this list.
+    pub fail_on_output: Option<Vec<String>>,
+    /// List the directories of wanted inputs to read their mtimes before
+    /// building, rather than stat()ing each one.
+    pub prestat: bool,
+    /// Check which builds will run before starting any, from --prescan, so
+    /// that progress can be shown against them.
+    pub prescan: bool,
+    /// Remember which output directories exist, rather than creating the
+    /// parent directories of every build's outputs; see task::DirCache.
+    pub dir_cache: bool,
+    /// Stops the build when cancelled, for programs embedding n2.
+    pub cancel: Option<CancelToken>,
+    /// From --temp-dir: where to put n2's own temporary files.
+    pub temp_dir: Option<std::path::PathBuf>,
+    /// Runs build commands in place of running them as processes, for tests.
+    pub command_runner: Option<Arc<dyn process::CommandRunner>>,
+    /// Run a command only once for builds with the same command line and
+    /// inputs, counting the others as done by it.
+    pub dedup_commands: bool,
+    /// What to do with running tasks when the build fails.
+    pub halt: HaltMode,
+    /// How long commands may run, for builds that don't set `timeout`.
+    pub timeout: Option<Duration>,
+    /// Start builds in the same order on every run, for reproducing bugs
+    /// that depend on it; see BuildStates::pop_ready.
+    pub deterministic: bool,
 }
 
 pub struct Work<'a> {
@@ -347,6 +708,39 @@ pub struct Work<'a> {
     last_hashes: Hashes,
     build_states: BuildStates,
     pub tasks_run: usize,
+    /// Builds recorded as up to date without running, with adopt.
+    pub adopted: usize,
+    /// Builds of requested targets whose outputs to touch, with touch.
+    to_touch: HashSet<BuildId>,
+    /// The targets passed to want_target(), for target_statuses().
+    targets: Vec<FileId>,
+    /// Builds not run as known failures, with skip_known_failures.  They
+    /// are among failures too.
+    pub skipped: usize,
+    /// Builds whose commands failed or were interrupted during run().
+    pub failures: Vec<Error>,
+    /// The builds of failures, in the same order.
+    failed: Vec<BuildId>,
+    /// Number of times each build has been retried after failing.
+    retried: HashMap<BuildId, usize>,
+    /// Failed builds waiting out their backoff, with when to requeue them.
+    retrying: Vec<(Instant, BuildId)>,
+    /// Canonicalizes discovered deps when --physical-paths is set, on the
+    /// tasks' threads.
+    physical: Option<Arc<canon::PhysicalCanon>>,
+    /// Commands run by rule name, for --rule-stats.
+    rule_stats: HashMap<String, RuleStats>,
+    /// When each build's command ran, for --dump-critical-path.
+    spans: HashMap<BuildId, (Instant, Instant)>,
+    /// How long each command ran, for the summary's slowest builds.
+    durations: Vec<(Duration, BuildId)>,
+    /// The first build seen with each command line and inputs, for
+    /// --dedup-commands.
+    dedup: HashMap<(String, Vec<FileId>), BuildId>,
+    /// Builds waiting on a running build with the same command, keyed by it.
+    dedup_waiting: HashMap<BuildId, Vec<BuildId>>,
+    /// Output directories known to exist, with --dir-cache.
+    dirs: Option<Arc<task::DirCache>>,
 }
 
 impl<'a> Work<'a> {
@@ -358,7 +752,8 @@ impl<'a> Work<'a> {
         progress: &'a dyn Progress,
         pools: SmallMap<String, usize>,
     ) -> Self {
-        let file_state = FileState::new(&graph);
+        let mut file_state = FileState::new(&graph, options.mtime_granularity);
+        file_state.set_content_roots(options.content_roots.clone());
         let build_count = graph.builds.next_id();
         Work {
             graph,
@@ -367,28 +762,152 @@ impl<'a> Work<'a> {
             options: options.clone(),
             file_state,
             last_hashes,
-            build_states: BuildStates::new(build_count, pools),
+            build_states: BuildStates::new(build_count, pools, options),
             tasks_run: 0,
+            adopted: 0,
+            to_touch: HashSet::new(),
+            targets: Vec::new(),
+            skipped: 0,
+            failures: Vec::new(),
+            failed: Vec::new(),
+            retried: HashMap::new(),
+            retrying: Vec::new(),
+            physical: if options.physical_paths {
+                canon::PhysicalCanon::new().ok().map(Arc::new)
+            } else {
+                None
+            },
+            rule_stats: HashMap::new(),
+            spans: HashMap::new(),
+            durations: Vec::new(),
+            dedup: HashMap::new(),
+            dedup_waiting: HashMap::new(),
+            dirs: options.dir_cache.then(Arc::default),
         }
     }
 
+    /// Record the build files, by name, in the db with their mtimes now, as
+    /// loading them does; for when regenerating them left their contents as
+    /// they were, so they aren't loaded again.
+    pub fn record_manifests(&mut self, names: &[String]) -> anyhow::Result<()> {
+        let ids: Vec<FileId> = names.iter().filter_map(|name| self.lookup(name)).collect();
+        let manifests = self.graph.stat_manifests(&ids)?;
+        self.db.write_manifests(&self.graph, manifests)?;
+        Ok(())
+    }
+
     pub fn lookup(&self, name: &str) -> Option<FileId> {
         self.graph.files.lookup(&to_owned_canon_path(name))
     }
 
+    /// The error for a target that isn't in the graph, with any suggestion.
+    pub fn unknown_path(&self, name: &str) -> anyhow::Error {
+        self.graph.files.unknown_path(name)
+    }
+
+    /// The builds that failed during run(), for --on-complete.
+    pub fn failed_builds(&self) -> Vec<summary::FailedBuild> {
+        self.failed
+            .iter()
+            .map(|&id| {
+                let build = &self.graph.builds[id];
+                summary::FailedBuild {
+                    output: self.graph.file(build.outs()[0]).name.clone(),
+                    description: progress::build_message(build).to_string(),
+                }
+            })
+            .collect()
+    }
+
+    /// How each target passed to want_target() fared in run().
+    pub fn target_statuses(&self) -> Vec<summary::Target> {
+        let mut seen = HashSet::new();
+        self.targets
+            .iter()
+            .filter(|&&id| seen.insert(id))
+            .map(|&id| {
+                let file = self.graph.file(id);
+                let status = match file.input.map(|bid| self.build_states.get(bid)) {
+                    None | Some(BuildState::Done) => summary::TargetStatus::Satisfied,
+                    Some(BuildState::Failed) => summary::TargetStatus::Failed,
+                    Some(_) => summary::TargetStatus::NotBuilt,
+                };
+                summary::Target {
+                    name: file.name.clone(),
+                    status,
+                }
+            })
+            .collect()
+    }
+
+    /// The n longest-running commands, longest first.
+    pub fn slowest_builds(&self, n: usize) -> Vec<summary::TimedBuild> {
+        let mut durations = self.durations.clone();
+        durations.sort_by(|a, b| b.cmp(a));
+        durations
+            .into_iter()
+            .take(n)
+            .map(|(duration, id)| {
+                let build = &self.graph.builds[id];
+                summary::TimedBuild {
+                    output: self.graph.file(build.outs()[0]).name.clone(),
+                    description: progress::build_message(build).to_string(),
+                    duration,
+                    usage: build.last_usage.unwrap_or_default(),
+                }
+            })
+            .collect()
+    }
+
+    /// Find an output of the first build that uses a file as an input, other
+    /// than phony builds, for the `file^` target syntax.
+    pub fn consumer(&self, id: FileId) -> Option<FileId> {
+        self.graph
+            .file(id)
+            .dependents
+            .iter()
+            .map(|&bid| &self.graph.builds[bid])
+            .find(|build| !build.is_phony())
+            .map(|build| build.outs()[0])
+    }
+
+    /// Find the generated files matching a graph::glob_match pattern.
+    pub fn glob(&self, pattern: &str) -> Vec<FileId> {
+        self.graph.files.glob(pattern)
+    }
+
     pub fn want_file(&mut self, id: FileId) -> anyhow::Result<()> {
         let mut stack = Vec::new();
         self.build_states.want_file(&self.graph, &mut stack, id)?;
         Ok(())
     }
 
-    pub fn want_every_file(&mut self, exclude: Option<FileId>) -> anyhow::Result<()> {
-        for id in self.graph.files.all_ids() {
-            if let Some(exclude) = exclude {
-                if id == exclude {
-                    continue;
-                }
+    /// Like want_file, for a target named on the command line.  With touch,
+    /// the outputs of its build are touched when it's reached.
+    pub fn want_target(&mut self, id: FileId) -> anyhow::Result<()> {
+        self.targets.push(id);
+        if self.options.touch {
+            if let Some(bid) = self.graph.file(id).input {
+                self.to_touch.insert(bid);
             }
+        }
+        self.want_file(id)
+    }
+
+    /// Want every root of the graph: the generated files that nothing else
+    /// depends on.  Every other generated file is reached from these, except
+    /// for the builds of a dependency cycle that nothing outside it uses.
+    pub fn want_every_file(&mut self, exclude: &[FileId]) -> anyhow::Result<()> {
+        let roots: Vec<FileId> = self
+            .graph
+            .files
+            .all_ids()
+            .filter(|&id| {
+                let file = self.graph.file(id);
+                file.input.is_some() && file.dependents.is_empty() && !exclude.contains(&id)
+            })
+            .collect();
+        for id in roots {
             self.want_file(id)?;
         }
         Ok(())
@@ -460,30 +979,28 @@ impl<'a> Work<'a> {
         Ok(None)
     }
 
+    /// Drop discovered deps that are already dirtying inputs of the build.
+    fn discovered_ins(&mut self, id: BuildId, ids: Vec<FileId>) -> Vec<FileId> {
+        // Files the task added join the graph.
+        self.graph.files.sync();
+        let mut deps = Vec::with_capacity(ids.len());
+        for fileid in ids {
+            // Filter out any deps that were already dirtying in the build file.
+            // Note that it's allowed to have a duplicate against an order-only
+            // dep; see `discover_existing_dep` test.
+            if self.graph.builds[id].dirtying_ins().contains(&fileid) {
+                continue;
+            }
+            deps.push(fileid);
+        }
+        deps
+    }
+
     /// Given a task that just finished, record any discovered deps and hash.
     /// Postcondition: all outputs have been stat()ed.
     fn record_finished(&mut self, id: BuildId, result: task::TaskResult) -> anyhow::Result<()> {
-        let build = &self.graph.builds[id];
-
         // Update the deps discovered from the task.
-        let mut deps = Vec::new();
-        if let Some(names) = result.discovered_deps {
-            for mut name in names {
-                canonicalize_path(&mut name);
-                let fileid = self.graph.files.id_from_canonical(name);
-                // Filter duplicates from the file list.
-                if deps.contains(&fileid) {
-                    continue;
-                }
-                // Filter out any deps that were already dirtying in the build file.
-                // Note that it's allowed to have a duplicate against an order-only
-                // dep; see `discover_existing_dep` test.
-                if build.dirtying_ins().contains(&fileid) {
-                    continue;
-                }
-                deps.push(fileid);
-            }
-        }
+        let deps = self.discovered_ins(id, result.discovered_deps.unwrap_or_default());
         self.graph.builds[id].set_discovered_ins(deps);
         let build = &self.graph.builds[id];
 
@@ -506,10 +1023,152 @@ impl<'a> Work<'a> {
             // It will be considered dirty next time anyway due to the missing file.
             return Ok(());
         }
+        if result.deps_unreadable {
+            // Without a record the build is dirty next time, when the
+            // depfile may be readable.
+            return Ok(());
+        }
 
         let hash = hash::hash_build(&self.graph.files, &mut self.file_state, build);
-        self.db.write_build(&self.graph, id, hash)?;
+        self.db
+            .write_build(&self.graph, id, hash, result.usage, self.options.explain)?;
+
+        Ok(())
+    }
+
+    /// With adopt, act as if a dirty build already finished, recording its
+    /// outputs as they are.  A build with a missing output can't be adopted.
+    fn adopt(&mut self, id: BuildId) -> anyhow::Result<()> {
+        let build = &self.graph.builds[id];
+        if let Some(missing) = Self::stat_all_outputs(&self.graph, &mut self.file_state, build)? {
+            self.progress.log(&format!(
+                "n2: not adopting {}: output {} missing",
+                progress::build_message(build),
+                self.graph.file(missing).name
+            ));
+            return Ok(());
+        }
+        // Keep the deps discovered last time, which would otherwise be lost.
+        let deps = build.discovered_ins().to_vec();
+        self.record_finished(
+            id,
+            task::TaskResult {
+                termination: process::Termination::Success,
+                output: Default::default(),
+                discovered_deps: Some(deps),
+                warning: None,
+                deps_unreadable: false,
+                usage: Default::default(),
+                retry: None,
+            },
+        )?;
+        self.adopted += 1;
+        Ok(())
+    }
 
+    /// With touch, set the mtimes of a build's existing outputs, which may be
+    /// directories, to now.  Missing outputs are left for adopt to report.
+    fn touch_outputs(&self, id: BuildId) -> anyhow::Result<()> {
+        let now = SystemTime::now();
+        for &out in self.graph.builds[id].outs() {
+            let path = self.graph.file(out).path();
+            let file = match open_to_touch(path) {
+                Ok(file) => file,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(err) => anyhow::bail!("touch {}: {}", path.display(), err),
+            };
+            file.set_modified(now)
+                .map_err(|err| anyhow::anyhow!("touch {}: {}", path.display(), err))?;
+        }
+        Ok(())
+    }
+
+    /// With --dedup-commands, check for an earlier build with the same command
+    /// and inputs.  If it succeeded this build is finished along with it, and
+    /// if it's still running this build waits for it; returns true in either
+    /// case.  Builds with an rspfile never match, as its contents may differ.
+    fn dedup(&mut self, id: BuildId) -> anyhow::Result<bool> {
+        let build = &self.graph.builds[id];
+        let (Some(cmdline), None) = (&build.cmdline, &build.rspfile) else {
+            return Ok(false);
+        };
+        let key = (cmdline.clone(), build.dirtying_ins().to_vec());
+        let first = *self.dedup.entry(key).or_insert(id);
+        if first == id {
+            return Ok(false);
+        }
+        match self.build_states.get(first) {
+            BuildState::Done => {
+                self.finish_duplicate(id, first)?;
+                Ok(true)
+            }
+            BuildState::Failed => Ok(false),
+            _ => {
+                self.build_states.set(id, build, BuildState::Waiting);
+                self.dedup_waiting.entry(first).or_default().push(id);
+                Ok(true)
+            }
+        }
+    }
+
+    /// Record a build as finished by running the identical command of first.
+    fn finish_duplicate(&mut self, id: BuildId, first: BuildId) -> anyhow::Result<()> {
+        let deps = self.graph.builds[first].discovered_ins().to_vec();
+        self.record_finished(
+            id,
+            task::TaskResult {
+                termination: process::Termination::Success,
+                output: Default::default(),
+                discovered_deps: Some(deps),
+                warning: None,
+                deps_unreadable: false,
+                usage: Default::default(),
+                retry: None,
+            },
+        )?;
+        self.ready_dependents(id);
+        Ok(())
+    }
+
+    /// Hash a build's inputs, as recorded for failed builds, or None if an
+    /// input is missing.
+    fn hash_inputs(&mut self, id: BuildId) -> anyhow::Result<Option<hash::BuildHash>> {
+        let build = &self.graph.builds[id];
+        for &id in build.dirtying_ins().iter().chain(build.discovered_ins()) {
+            if self.file_state.stat(id, self.graph.file(id).path())? == MTime::Missing {
+                return Ok(None);
+            }
+        }
+        Ok(Some(hash::hash_inputs(
+            &self.graph.files,
+            &self.file_state,
+            build,
+        )))
+    }
+
+    /// Record a failed build in the db, so that with skip_known_failures it
+    /// isn't rerun until its inputs change.
+    fn record_failure(&mut self, id: BuildId) -> anyhow::Result<()> {
+        if let Some(hash) = self.hash_inputs(id)? {
+            self.db.write_failure(&self.graph, id, hash)?;
+        }
+        Ok(())
+    }
+
+    /// Check whether a build failed last time with the same inputs.
+    fn is_known_failure(&mut self, id: BuildId) -> anyhow::Result<bool> {
+        let last_failure = self.graph.builds[id].last_failure;
+        Ok(last_failure.is_some() && self.hash_inputs(id)? == last_failure)
+    }
+
+    /// Record in the db that the builds still running were interrupted, so
+    /// they rerun next time even if their outputs look up to date.
+    fn record_interrupted(&mut self) -> anyhow::Result<()> {
+        for id in self.graph.builds.all_ids() {
+            if self.build_states.get(id) == BuildState::Running {
+                self.db.write_interrupted(&self.graph, id)?;
+            }
+        }
         Ok(())
     }
 
@@ -547,7 +1206,7 @@ impl<'a> Work<'a> {
         let mut missing = None;
         for &id in build.outs() {
             let file = graph.file(id);
-            let mtime = file_state.stat(id, file.path())?;
+            let mtime = file_state.lstat(id, file.path())?;
             if mtime == MTime::Missing && missing.is_none() {
                 missing = Some(id);
             }
@@ -572,7 +1231,11 @@ impl<'a> Work<'a> {
         {
             let file = graph.file(missing);
             if file.input.is_none() {
-                anyhow::bail!("{}: input {} missing", build.location, file.name);
+                return Err(Error::MissingInput {
+                    location: build.location.to_string(),
+                    path: file.name.clone(),
+                }
+                .into());
             }
             return Ok(Some(missing));
         }
@@ -619,11 +1282,42 @@ impl<'a> Work<'a> {
         Ok(())
     }
 
+    /// In hermetic mode, returns why build fails if any of the given inputs
+    /// to it lie outside the allowed roots.
+    fn check_hermetic<'i>(
+        &self,
+        build: &Build,
+        ins: impl Iterator<Item = &'i FileId>,
+    ) -> Option<String> {
+        let roots = self.options.hermetic_roots.as_ref()?;
+        let name = ins
+            .map(|&id| &self.graph.file(id).name)
+            .find(|name| !canon::is_within_roots(name, roots))?;
+        Some(format!(
+            "{}: input {} is outside the hermetic roots",
+            build.location, name
+        ))
+    }
+
+    /// Fail a build without running it, e.g. for a hermetic violation.
+    fn fail_unrun(&mut self, id: BuildId, why: &str) {
+        let build = &self.graph.builds[id];
+        let desc = progress::build_message(build).to_string();
+        self.progress.log(&format!("n2: failing {}: {}", desc, why));
+        self.failures.push(Error::CommandFailed {
+            desc,
+            output: Vec::new(),
+            status: process::Termination::Failure,
+        });
+        self.failed.push(id);
+        self.build_states.set(id, build, BuildState::Failed);
+    }
+
     /// Check a ready build for whether it needs to run, returning true if so.
     /// Prereq: any dependent input is already generated.
     fn check_build_dirty(&mut self, id: BuildId) -> anyhow::Result<bool> {
         let build = &self.graph.builds[id];
-        let phony = build.cmdline.is_none();
+        let phony = build.is_phony();
         let file_missing = if phony {
             Self::check_build_files_missing_phony(&self.graph, &mut self.file_state, build)?;
             return Ok(false); // Phony builds never need to run anything.
@@ -644,6 +1338,18 @@ impl<'a> Work<'a> {
             return Ok(true);
         }
 
+        // An interrupted command may have left its outputs half written,
+        // in a state that matches the last hash.
+        if build.last_interrupted {
+            if self.options.explain {
+                self.progress.log(&format!(
+                    "explain: {}: interrupted last time",
+                    build.location
+                ));
+            }
+            return Ok(true);
+        }
+
         // If we get here, all the relevant files are present and stat()ed,
         // so compare the hash against the last hash.
 
@@ -663,11 +1369,20 @@ impl<'a> Work<'a> {
             Some(prev_hash) => prev_hash,
         };
 
+        if self.older_than_record(build) {
+            return Ok(false);
+        }
         let hash = hash::hash_build(&self.graph.files, &self.file_state, build);
         if prev_hash != hash {
             if self.options.explain {
                 self.progress
                     .log(&format!("explain: {}: manifest changed", build.location));
+                if let Some(diff) = hash::explain_cmdline_change(build) {
+                    self.progress.log(&format!(
+                        "explain: {}: command changed: {}",
+                        build.location, diff
+                    ));
+                }
                 self.progress.log(&hash::explain_hash_build(
                     &self.graph.files,
                     &self.file_state,
@@ -680,32 +1395,430 @@ impl<'a> Work<'a> {
         Ok(false)
     }
 
-    /// Create the parent directories of a given list of fileids.
-    /// Used to create directories used for outputs.
-    /// TODO: do this within the thread executing the subtask?
-    fn create_parent_dirs(&self, ids: &[FileId]) -> anyhow::Result<()> {
-        let mut dirs: Vec<&std::path::Path> = Vec::new();
-        for &out in ids {
-            if let Some(parent) = self.graph.file(out).path().parent() {
-                if dirs.iter().any(|&p| p == parent) {
-                    continue;
-                }
-                std::fs::create_dir_all(parent)?;
-                dirs.push(parent);
+    /// With trust_mtimes, whether a build's files are all older than its last
+    /// successful run, as is its build file, and its command is the same, so
+    /// that it can be taken as up to date without hashing.  This misses files
+    /// replaced by older versions, e.g. restored from a backup, which hashing
+    /// would catch.
+    fn older_than_record(&self, build: &Build) -> bool {
+        let Some(built) = build.last_built.filter(|_| self.options.trust_mtimes) else {
+            return false;
+        };
+        // Leave room for filesystems that store coarse times, so a file
+        // written just after the record can't appear to predate it.
+        let slack = self.options.mtime_granularity.max(MTIME_SLACK);
+        let Some(cutoff) = built.checked_sub(slack) else {
+            return false;
+        };
+        // An rspfile's content isn't in its command line, a changed build
+        // file may have changed the build's files, and files hashed by their
+        // contents may have changed without their mtimes.
+        if build.rspfile.is_some()
+            || build.last_cmdline_hash
+                != Some(hash::hash_cmdline(build.cmdline.as_deref().unwrap_or("")))
+            || self.graph.newest_manifest.map_or(true, |m| m >= cutoff)
+            || build
+                .dirtying_ins()
+                .iter()
+                .chain(build.discovered_ins())
+                .any(|&id| self.file_state.content_digest(id).is_some())
+        {
+            return false;
+        }
+        build
+            .dirtying_ins()
+            .iter()
+            .chain(build.discovered_ins())
+            .chain(build.outs())
+            .all(|&id| matches!(self.file_state.get(id), Some(MTime::Stamp(mtime)) if mtime < cutoff))
+    }
+
+    /// Queue the failed builds whose retry backoff has passed.  Returns true
+    /// if any were.
+    fn requeue_retries(&mut self) -> anyhow::Result<bool> {
+        let now = Instant::now();
+        let mut due = Vec::new();
+        self.retrying.retain(|&(at, id)| {
+            if at > now {
+                return true;
             }
+            due.push(id);
+            false
+        });
+        for &id in &due {
+            self.build_states.enqueue(id, &self.graph.builds[id])?;
+        }
+        Ok(!due.is_empty())
+    }
+
+    /// Prints the command of a task that exceeded the hang timeout, and what
+    /// the hang hook printed about it.
+    fn report_hung(&self, hang: task::Hang) {
+        match hang {
+            task::Hang::Task(hung) => {
+                let build = &self.graph.builds[hung.buildid];
+                self.progress.log(&format!(
+                    "n2: running for {}s: {}\n  {}",
+                    hung.elapsed.as_secs(),
+                    progress::build_message(build),
+                    hung.cmdline
+                ));
+            }
+            task::Hang::HookOutput(text) => self.progress.log(&text),
         }
-        Ok(())
     }
 
     /// Runs the build.
     /// Returns true on successful builds.
     pub fn run(&mut self) -> anyhow::Result<bool> {
+        let start = Instant::now();
+        let result = self.run_tasks();
+        // Get the db on disk before it might be read again, e.g. after
+        // regenerating build.ninja.
+        let flushed = self.db.flush();
+        if let Some((created, skipped)) = self
+            .dirs
+            .as_deref()
+            .map(task::DirCache::counts)
+            .filter(|&counts| counts != (0, 0))
+        {
+            trace::write_metadata(
+                "dir_cache",
+                &[
+                    ("created", &created.to_string()),
+                    ("skipped", &skipped.to_string()),
+                ],
+            );
+        }
+        if self.options.rule_stats && !self.rule_stats.is_empty() {
+            self.progress.log(&format_rule_stats(&self.rule_stats));
+        }
+        if self.options.critical_path && !self.spans.is_empty() {
+            let path: Vec<_> = self
+                .critical_path()
+                .into_iter()
+                .map(|id| {
+                    let (start, end) = self.spans[&id];
+                    (progress::build_message(&self.graph.builds[id]), end - start)
+                })
+                .collect();
+            self.progress
+                .log(&format_critical_path(&path, start.elapsed()));
+        }
+        let ok = result?;
+        flushed?;
+        Ok(ok)
+    }
+
+    /// Find the chain of builds that ran that ends with the last to finish,
+    /// where each is preceded by the input build it waited for longest.
+    /// Returned in the order they ran.
+    fn critical_path(&self) -> Vec<BuildId> {
+        let mut path = Vec::new();
+        let mut next = self
+            .spans
+            .iter()
+            .max_by_key(|(_, &(_, end))| end)
+            .map(|(&id, _)| id);
+        while let Some(id) = next {
+            path.push(id);
+            next = self.graph.builds[id]
+                .ordering_ins()
+                .iter()
+                .filter_map(|&file| self.graph.file(file).input)
+                .filter_map(|input| self.spans.get(&input).map(|&(_, end)| (input, end)))
+                .max_by_key(|&(_, end)| end)
+                .map(|(input, _)| input);
+        }
+        path.reverse();
+        path
+    }
+
+    /// Read the mtimes of the source files wanted builds use, in bulk; see
+    /// FileState::prestat.
+    fn prestat(&mut self) {
+        let mut ids = Vec::new();
+        for id in self.graph.builds.all_ids() {
+            if self.build_states.get(id) == BuildState::Unknown {
+                continue;
+            }
+            let build = &self.graph.builds[id];
+            ids.extend_from_slice(build.dirtying_ins());
+            ids.extend_from_slice(build.discovered_ins());
+        }
+        self.file_state
+            .prestat(&self.graph, &ids, self.options.parallelism);
+    }
+
+    /// How many wanted builds were found already up to date, without running.
+    pub fn up_to_date(&self) -> usize {
+        self.build_states.counts.up_to_date()
+    }
+
+    /// How many commands --prescan expected run() to run, if it was used.
+    pub fn predicted_tasks(&self) -> Option<usize> {
+        self.build_states.counts.to_run()
+    }
+
+    /// Count the wanted builds that will run, before running any: those out
+    /// of date given the files as they are now, and those depending on them.
+    pub fn prescan(&mut self) -> anyhow::Result<usize> {
+        // Whether each build will run, or for phony builds whether their
+        // dependents will.  Visited inputs first, with an explicit stack as
+        // graphs can be deep.
+        let mut will_run: HashMap<BuildId, bool> = HashMap::new();
+        let mut stack: Vec<(BuildId, bool)> = self
+            .graph
+            .builds
+            .all_ids()
+            .filter(|&id| self.build_states.get(id) != BuildState::Unknown)
+            .map(|id| (id, false))
+            .collect();
+        while let Some((id, ins_visited)) = stack.pop() {
+            if will_run.contains_key(&id) {
+                continue;
+            }
+            // Discovered inputs aren't ordered on, so they're only stat()ed.
+            let producers: Vec<BuildId> = self.graph.builds[id]
+                .dirtying_ins()
+                .iter()
+                .filter_map(|&file| self.graph.file(file).input)
+                .filter(|&input| self.build_states.get(input) != BuildState::Unknown)
+                .collect();
+            if !ins_visited {
+                stack.push((id, true));
+                stack.extend(
+                    producers
+                        .into_iter()
+                        .filter(|input| !will_run.contains_key(input))
+                        .map(|input| (input, false)),
+                );
+                continue;
+            }
+            let runs = producers.iter().any(|input| will_run[input]) || self.predict_dirty(id)?;
+            will_run.insert(id, runs);
+        }
+        Ok(will_run
+            .into_iter()
+            .filter(|&(id, runs)| runs && !self.graph.builds[id].is_phony())
+            .count())
+    }
+
+    /// Like check_build_dirty, but without explaining, for a build whose
+    /// inputs may not have been brought up to date yet.
+    fn predict_dirty(&mut self, id: BuildId) -> anyhow::Result<bool> {
+        load::evaluate_build(&mut self.graph, id)?;
+        let build = &self.graph.builds[id];
+        self.file_state.add_dir_outputs(build);
+        if build.is_phony() {
+            return Ok(false);
+        }
+        // A missing generated input is expected, and means the build runs.
+        let missing =
+            match Self::check_build_files_missing(&self.graph, &mut self.file_state, build) {
+                Ok(missing) => missing.is_some(),
+                Err(_) => true,
+            };
+        if missing || build.last_interrupted {
+            return Ok(true);
+        }
+        Ok(match self.last_hashes.get(id) {
+            None => true,
+            Some(_) if self.older_than_record(build) => false,
+            Some(prev_hash) => {
+                prev_hash != hash::hash_build(&self.graph.files, &self.file_state, build)
+            }
+        })
+    }
+
+    /// The files of a ready build whose mtimes its check will read, to read
+    /// them on the stat threads.  Trees are left to the check.
+    fn files_to_stat(&self, id: BuildId) -> Vec<(FileId, PathBuf, StatKind)> {
+        let build = &self.graph.builds[id];
+        let mut files = Vec::new();
+        if !build.is_phony() {
+            for &id in build.dirtying_ins().iter().chain(build.discovered_ins()) {
+                if self.file_state.get(id).is_none() && !self.file_state.is_tree(id) {
+                    let path = self.graph.file(id).path().to_path_buf();
+                    files.push((id, path, StatKind::Stat));
+                }
+            }
+        }
+        for &id in build.outs() {
+            if !self.file_state.is_tree(id) {
+                let path = self.graph.file(id).path().to_path_buf();
+                files.push((id, path, StatKind::Lstat));
+            }
+        }
+        files
+    }
+
+    /// Decide what to do with a build that's ready: finish it if it's up to
+    /// date, or else queue it to run.  Returns true if it's instead failed
+    /// without running, as a known failure.
+    fn check_ready(&mut self, id: BuildId) -> anyhow::Result<bool> {
+        let build = &self.graph.builds[id];
+        if !build.is_phony() {
+            let ins = build.dirtying_ins().iter().chain(build.discovered_ins());
+            if let Some(why) = self.check_hermetic(build, ins) {
+                // Fails just this build, so -k carries on with the rest.
+                self.fail_unrun(id, &why);
+                return Ok(true);
+            }
+        }
+        if !self.check_build_dirty(id)? {
+            // Not dirty; go directly to the Done state.
+            self.ready_dependents(id);
+        } else if self.options.adopt || self.to_touch.contains(&id) {
+            self.adopt(id)?;
+            self.ready_dependents(id);
+        } else if self.options.touch {
+            let build = &self.graph.builds[id];
+            anyhow::bail!(
+                "-t touch: {} is out of date; build it first",
+                self.graph.file(build.outs()[0]).name
+            );
+        } else if self.options.skip_known_failures && self.is_known_failure(id)? {
+            let build = &self.graph.builds[id];
+            let desc = progress::build_message(build).to_string();
+            self.progress.log(&format!(
+                "n2: skipped {}: failed last time with the same inputs",
+                desc
+            ));
+            self.failures.push(Error::CommandFailed {
+                desc,
+                output: Vec::new(),
+                status: process::Termination::Failure,
+            });
+            self.failed.push(id);
+            self.skipped += 1;
+            self.build_states.set(id, build, BuildState::Failed);
+            return Ok(true);
+        } else if self.options.dedup_commands && self.dedup(id)? {
+            // Finished or waiting on an identical command.
+        } else {
+            self.build_states.enqueue(id, &self.graph.builds[id])?;
+        }
+        Ok(false)
+    }
+
+    /// Count a failed build against failures_left, returning true if the
+    /// build should halt.
+    fn count_failure(&mut self) -> bool {
+        match &mut self.options.failures_left {
+            Some(failures_left) => {
+                *failures_left -= 1;
+                *failures_left == 0
+            }
+            None => false,
+        }
+    }
+
+    /// Stop the build once it has failed: wait for the running tasks,
+    /// recording any that succeed, or with HaltMode::Kill, kill them.
+    fn halt(&mut self, runner: &mut task::Runner) -> anyhow::Result<bool> {
+        if self.options.halt == HaltMode::Kill {
+            if runner.is_running() {
+                self.progress.log(&format!(
+                    "n2: killing {} running task{}",
+                    runner.running,
+                    if runner.running == 1 { "" } else { "s" }
+                ));
+                runner.kill_all();
+            }
+            self.record_interrupted()?;
+            return Ok(false);
+        }
+        while runner.is_running() {
+            let Some(event) = runner.wait(
+                None,
+                |id, line| {
+                    self.progress.task_output(id, line);
+                },
+                |hung| self.report_hung(hung),
+            ) else {
+                self.record_interrupted()?;
+                return Err(Error::Cancelled.into());
+            };
+            // Builds still to be checked are left unchecked.
+            let task::Event::Finished(task) = event else {
+                continue;
+            };
+            let build = &self.graph.builds[task.buildid];
+            self.progress
+                .task_finished(task.buildid, build, &task.result);
+            if task.result.termination == process::Termination::Success {
+                self.tasks_run += 1;
+                self.build_states.set(task.buildid, build, BuildState::Done);
+                self.record_finished(task.buildid, task.result)?;
+            } else {
+                if task.result.termination != process::Termination::Interrupted {
+                    self.build_states
+                        .set(task.buildid, build, BuildState::Failed);
+                }
+                self.failures.push(Error::CommandFailed {
+                    desc: progress::build_message(build).to_string(),
+                    output: task.result.output.tail().to_vec(),
+                    status: task.result.termination,
+                });
+                self.failed.push(task.buildid);
+            }
+        }
+        Ok(false)
+    }
+
+    fn run_tasks(&mut self) -> anyhow::Result<bool> {
         #[cfg(unix)]
         signal::register_sigint();
+        if self.options.prestat {
+            trace::scope("prestat", || self.prestat());
+        }
+        if self.options.prescan {
+            let to_run = trace::scope("prescan", || self.prescan())?;
+            self.build_states.counts.set_to_run(to_run);
+        }
         let mut tasks_failed = 0;
-        let mut runner = task::Runner::new(self.options.parallelism);
+        let mut runner = task::Runner::new(
+            self.options.parallelism,
+            self.options.hang_timeout,
+            self.options.wrapper.clone(),
+            task::DepsOptions {
+                errors_warn: self.options.depfile_errors_warn,
+                physical: self.physical.clone(),
+                names: self.graph.files.names(),
+                ignore: self.graph.deps_ignore.clone(),
+            },
+            self.options.cancel.clone(),
+            self.options.timeout,
+            self.dirs.clone(),
+        );
+        if let Some(commands) = &self.options.command_runner {
+            runner.set_command_runner(commands.clone());
+        }
+        if let Some(base) = &self.options.temp_dir {
+            runner.set_temp_dir(base.clone());
+        }
+        runner.set_windows_shell(cfg!(windows) && self.options.windows_shell);
+        if let Some(hook) = &self.options.hang_hook {
+            runner.set_hang_hook(hook.clone());
+        }
+        // Deterministic order doesn't allow for checks finishing in any order.
+        if self.options.stat_threads > 0 && !self.options.deterministic {
+            runner.start_stat_threads(self.options.stat_threads);
+        }
+        let mut pool_gauges = Vec::new();
         while self.build_states.unfinished() {
             self.progress.update(&self.build_states.counts);
+            self.build_states.pool_gauges(&mut pool_gauges);
+            self.progress.update_pools(&pool_gauges);
+
+            if let Some(cancel) = &self.options.cancel {
+                if cancel.is_cancelled() {
+                    runner.kill_all();
+                    self.record_interrupted()?;
+                    return Err(Error::Cancelled.into());
+                }
+            }
 
             // Approach:
             // - First make sure we're running as many queued tasks as the runner
@@ -717,46 +1830,59 @@ impl<'a> Work<'a> {
             // - If neither made progress, wait for a task to complete and
             //   loop.
 
-            let mut made_progress = false;
+            let mut made_progress = self.requeue_retries()?;
             while runner.can_start_more() {
-                let id = match self.build_states.pop_queued() {
+                let id = match self.build_states.pop_queued(&self.graph) {
                     Some(id) => id,
                     None => break,
                 };
                 let build = &self.graph.builds[id];
                 self.build_states.set(id, build, BuildState::Running);
-                self.create_parent_dirs(build.outs())?;
-                runner.start(id, build);
+                let prefix = self.options.output_prefix.map(|prefix| match prefix {
+                    task::OutputPrefix::Output => {
+                        format!("[{}] ", self.graph.file(build.outs()[0]).name)
+                    }
+                    task::OutputPrefix::Rule => format!("[{}] ", build.rule.name),
+                });
+                let outputs = build
+                    .outs()
+                    .iter()
+                    .map(|&out| self.graph.file(out).name.clone())
+                    .collect();
+                runner.start(id, build, outputs, prefix);
                 self.progress.task_started(id, build);
                 made_progress = true;
             }
 
             while let Some(id) = self.build_states.pop_ready() {
-                if !self.check_build_dirty(id)? {
-                    // Not dirty; go directly to the Done state.
-                    self.ready_dependents(id);
-                } else if self.options.adopt {
-                    // Act as if the target already finished.
-                    self.record_finished(
-                        id,
-                        task::TaskResult {
-                            termination: process::Termination::Success,
-                            output: vec![],
-                            discovered_deps: None,
-                        },
-                    )?;
-                    self.ready_dependents(id);
-                } else {
-                    self.build_states.enqueue(id, &self.graph.builds[id])?;
-                }
                 made_progress = true;
+                // Rule variables are only expanded once a build is needed.
+                load::evaluate_build(&mut self.graph, id)?;
+                self.file_state.add_dir_outputs(&self.graph.builds[id]);
+                if self.to_touch.contains(&id) {
+                    self.touch_outputs(id)?;
+                }
+                if runner.can_stat() {
+                    let files = self.files_to_stat(id);
+                    if !files.is_empty() {
+                        // Checked once they're read; see Event::Stated below.
+                        runner.stat(id, files);
+                        continue;
+                    }
+                }
+                if self.check_ready(id)? {
+                    tasks_failed += 1;
+                    if self.count_failure() {
+                        return self.halt(&mut runner);
+                    }
+                }
             }
 
             if made_progress {
                 continue;
             }
 
-            if !runner.is_running() {
+            if !runner.is_running() && !runner.is_stating() && self.retrying.is_empty() {
                 if tasks_failed > 0 {
                     // No more progress can be made, hopefully due to tasks that failed.
                     break;
@@ -764,37 +1890,183 @@ impl<'a> Work<'a> {
                 panic!("BUG: no work to do and runner not running");
             }
 
-            let task = runner.wait(|id, line| {
-                self.progress.task_output(id, line);
-            });
+            let next_retry = self.retrying.iter().map(|&(at, _)| at).min();
+            let Some(event) = runner.wait(
+                next_retry,
+                |id, line| {
+                    self.progress.task_output(id, line);
+                },
+                |hung| self.report_hung(hung),
+            ) else {
+                self.record_interrupted()?;
+                return Err(Error::Cancelled.into());
+            };
+            let mut task = match event {
+                task::Event::Finished(task) => task,
+                // A retry is due; requeued at the top of the loop.
+                task::Event::Woken => continue,
+                task::Event::Stated(stated) => {
+                    self.file_state.prefetch(stated.mtimes);
+                    let failed = self.check_ready(stated.buildid);
+                    self.file_state.clear_prefetched();
+                    if failed? {
+                        tasks_failed += 1;
+                        if self.count_failure() {
+                            return self.halt(&mut runner);
+                        }
+                    }
+                    continue;
+                }
+            };
+            self.graph.builds[task.buildid].last_usage = Some(task.result.usage);
+            if self.options.hermetic_roots.is_some()
+                && task.result.termination == process::Termination::Success
+            {
+                // Files the task discovered join the graph, to check them.
+                self.graph.files.sync();
+                let build = &self.graph.builds[task.buildid];
+                let deps = task.result.discovered_deps.iter().flatten();
+                if let Some(why) = self.check_hermetic(build, deps) {
+                    self.progress.log(&format!(
+                        "n2: failing {}: {}",
+                        progress::build_message(build),
+                        why
+                    ));
+                    task.result.termination = process::Termination::Failure;
+                }
+            }
             let build = &self.graph.builds[task.buildid];
+            if let Some(allowed) = &self.options.fail_on_output {
+                if task.result.termination == process::Termination::Success
+                    && !task.result.output.is_empty()
+                    && !allowed.contains(&build.rule.name)
+                {
+                    self.progress.log(&format!(
+                        "n2: failing {}: it printed output, and --fail-on-output is set",
+                        progress::build_message(build)
+                    ));
+                    task.result.termination = process::Termination::Failure;
+                }
+            }
             if trace::enabled() {
                 let desc = progress::build_message(build);
-                trace::write_complete(desc, task.tid + 1, task.span.0, task.span.1);
+                let usage = &task.result.usage;
+                trace::write_complete_with_args(
+                    desc,
+                    task.tid + 1,
+                    task.span.0,
+                    task.span.1,
+                    &[
+                        ("peak_rss_kb", usage.peak_rss_kb),
+                        ("cpu_ms", usage.cpu_time.as_millis() as u64),
+                    ],
+                );
+            }
+
+            if self.options.critical_path {
+                self.spans.insert(task.buildid, task.span);
+            }
+            self.durations
+                .push((task.span.1 - task.span.0, task.buildid));
+            let stats = self.rule_stats.entry(build.rule.name.clone()).or_default();
+            stats.runs += 1;
+            stats.time += task.span.1 - task.span.0;
+            if task.result.termination != process::Termination::Success {
+                stats.failures += 1;
+            }
+            if matches!(
+                task.result.termination,
+                process::Termination::Failure | process::Termination::TimedOut
+            ) {
+                let retries = build.retries.unwrap_or(self.options.retries);
+                let attempts = self.retried.entry(task.buildid).or_insert(0);
+                if *attempts < retries {
+                    *attempts += 1;
+                    task.result.retry = Some((*attempts, retries));
+                }
             }
 
             self.progress
                 .task_finished(task.buildid, build, &task.result);
+            if self.options.keep_depfile {
+                if let Some(depfile) = &build.depfile {
+                    self.progress.log(&format!(
+                        "n2: depfile of {}: {}",
+                        progress::build_message(build),
+                        depfile
+                    ));
+                }
+            }
+            if self.options.keep_rsp {
+                if let Some(rspfile) = &build.rspfile {
+                    self.progress.log(&format!(
+                        "n2: rspfile of {}: {}",
+                        progress::build_message(build),
+                        rspfile.path.display()
+                    ));
+                }
+            }
+            if let Some(warning) = &task.result.warning {
+                self.progress.log(&format!(
+                    "n2: warning: {}: {}",
+                    progress::build_message(build),
+                    warning
+                ));
+            }
+            if let Some((attempt, _)) = task.result.retry {
+                // Requeued once the backoff passes, without holding a slot
+                // meanwhile.
+                self.build_states
+                    .set(task.buildid, build, BuildState::Waiting);
+                self.retrying
+                    .push((Instant::now() + retry_backoff(attempt), task.buildid));
+                continue;
+            }
+            if task.result.termination != process::Termination::Success {
+                self.failures.push(Error::CommandFailed {
+                    desc: progress::build_message(build).to_string(),
+                    output: task.result.output.tail().to_vec(),
+                    status: task.result.termination,
+                });
+                self.failed.push(task.buildid);
+            }
             match task.result.termination {
-                process::Termination::Failure => {
-                    if let Some(failures_left) = &mut self.options.failures_left {
-                        *failures_left -= 1;
-                        if *failures_left == 0 {
-                            return Ok(false);
-                        }
+                process::Termination::Failure | process::Termination::TimedOut => {
+                    // Keep any deps the failed command reported, so the next
+                    // run's checks see them; recording the failure doesn't
+                    // make the build clean.
+                    let ids = task.result.discovered_deps.take();
+                    // A command that failed early may not get to report any.
+                    if let Some(ids) = ids.filter(|ids| !ids.is_empty()) {
+                        let deps = self.discovered_ins(task.buildid, ids);
+                        self.graph.builds[task.buildid].set_discovered_ins(deps);
                     }
-                    tasks_failed += 1;
+                    self.record_failure(task.buildid)?;
+                    let build = &self.graph.builds[task.buildid];
                     self.build_states
                         .set(task.buildid, build, BuildState::Failed);
+                    if self.count_failure() {
+                        return self.halt(&mut runner);
+                    }
+                    tasks_failed += 1;
+                    // Builds that were waiting on it run their own command.
+                    for id in self.dedup_waiting.remove(&task.buildid).unwrap_or_default() {
+                        self.build_states.enqueue(id, &self.graph.builds[id])?;
+                    }
                 }
                 process::Termination::Interrupted => {
-                    // If the task was interrupted bail immediately.
+                    // If the task was interrupted bail immediately, leaving
+                    // it and any others still running to rerun next time.
+                    self.record_interrupted()?;
                     return Ok(false);
                 }
                 process::Termination::Success => {
                     self.tasks_run += 1;
                     self.record_finished(task.buildid, task.result)?;
                     self.ready_dependents(task.buildid);
+                    for id in self.dedup_waiting.remove(&task.buildid).unwrap_or_default() {
+                        self.finish_duplicate(id, task.buildid)?;
+                    }
                 }
             };
         }
@@ -812,6 +2084,88 @@ impl<'a> Work<'a> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn rule_stats_table() {
+        let mut stats = HashMap::new();
+        stats.insert(
+            "cc".to_owned(),
+            RuleStats {
+                runs: 4,
+                time: Duration::from_secs(10),
+                failures: 1,
+            },
+        );
+        stats.insert(
+            "link_long".to_owned(),
+            RuleStats {
+                runs: 1,
+                time: Duration::from_millis(500),
+                failures: 0,
+            },
+        );
+        assert_eq!(
+            format_rule_stats(&stats),
+            "rule         runs      total   average  failed
+cc              4      10.0s     2.50s       1
+link_long       1       0.5s     0.50s       0"
+        );
+    }
+
+    #[test]
+    fn critical_path_table() {
+        let path = [
+            ("cc main.o", Duration::from_millis(1500)),
+            ("link app", Duration::from_millis(500)),
+        ];
+        assert_eq!(
+            format_critical_path(&path, Duration::from_secs(4)),
+            "critical path: 2 builds, 2.0s of 4.0s wall time
+     1.5s   38%  cc main.o
+     0.5s   12%  link app"
+        );
+    }
+
+    #[test]
+    fn fake_command_failure() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let dir = dir.path().to_string_lossy();
+        std::fs::write(format!("{}/in", dir), "")?;
+        let manifest = format!(
+            "rule cc\n  command = cc $in\nbuild {dir}/out: cc {dir}/in\n",
+            dir = dir
+        );
+        let mut graph = crate::load::parse("build.ninja", manifest.into_bytes())?;
+        let mut hashes = Hashes::default();
+        let db = db::open(
+            std::path::Path::new(&format!("{}/.n2_db", dir)),
+            &mut graph,
+            &mut hashes,
+        )?;
+
+        let cmdline = format!("cc {}/in", dir);
+        let mut commands = process::FakeRunner::default();
+        commands.add(&cmdline, process::Termination::Failure, &["in:1: error\n"]);
+        let commands = Arc::new(commands);
+        let options = Options {
+            parallelism: 1,
+            failures_left: Some(1),
+            command_runner: Some(commands.clone()),
+            ..Options::default()
+        };
+        let progress = crate::progress::TraceProgress::default();
+        let mut work = Work::new(graph, hashes, db, &options, &progress, SmallMap::default());
+        let out = work.lookup(&format!("{}/out", dir)).unwrap();
+        work.want_file(out)?;
+        assert!(!work.run()?);
+
+        assert_eq!(*commands.ran.lock().unwrap(), [cmdline]);
+        match &work.failures[..] {
+            [Error::CommandFailed { output, .. }] => assert_eq!(output, b"in:1: error\n"),
+            _ => panic!("expected one command failure"),
+        }
+        Ok(())
+    }
+
     #[test]
     fn build_cycle() -> Result<(), anyhow::Error> {
         let file = "
@@ -821,11 +2175,21 @@ build c: phony a
 ";
         let mut graph = crate::load::parse("build.ninja", file.as_bytes().to_vec())?;
         let a_id = graph.files.id_from_canonical("a".to_owned());
-        let mut states = BuildStates::new(graph.builds.next_id(), SmallMap::default());
+        let mut states = BuildStates::new(
+            graph.builds.next_id(),
+            SmallMap::default(),
+            &Options::default(),
+        );
         let mut stack = Vec::new();
         match states.want_file(&graph, &mut stack, a_id) {
             Ok(_) => panic!("expected build cycle error"),
-            Err(err) => assert_eq!(err.to_string(), "dependency cycle: a -> b -> c -> a"),
+            Err(err) => {
+                assert_eq!(err.to_string(), "dependency cycle: a -> b -> c -> a");
+                match err.downcast_ref::<Error>() {
+                    Some(Error::Cycle { path }) => assert_eq!(path, &["a", "b", "c", "a"]),
+                    _ => panic!("expected Error::Cycle, got {:?}", err),
+                }
+            }
         }
         Ok(())
     }
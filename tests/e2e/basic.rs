@@ -87,6 +87,198 @@ build baz: touch in
     Ok(())
 }
 
+#[cfg(unix)]
+#[test]
+fn keep_depfile_and_rsp() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule cc
+  command = cat ${out}.rsp > $out && echo \"$out: in\" > ${out}.d
+  depfile = ${out}.d
+  rspfile = ${out}.rsp
+  rspfile_content = $in
+build out: cc in
+",
+    )?;
+    space.write("in", "")?;
+
+    let out = space.run_expect(&mut n2_command(vec![
+        "-d",
+        "keepdepfile",
+        "-d",
+        "keeprsp",
+        "out",
+    ]))?;
+    assert_output_contains(&out, "n2: depfile of cat out.rsp");
+    assert_output_contains(&out, ": out.d\n");
+    assert_output_contains(&out, ": out.rsp\n");
+    assert_eq!(space.read("out.rsp")?, b"in");
+    assert_eq!(space.read("out.d")?, b"out: in\n");
+    Ok(())
+}
+
+#[test]
+fn debug_graph_stats() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build a: touch in",
+            "build b: touch in",
+            "build all: phony a b",
+            "default a",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.write("in", "")?;
+
+    let out = space.run_expect(&mut n2_command(vec!["--debug-graph-stats"]))?;
+    assert_output_contains(&out, "graph stats:");
+    // in, a, b, all, and build.ninja itself.
+    assert_output_contains(&out, "  files            5\n");
+    assert_output_contains(&out, "  builds           3\n");
+    assert_output_contains(&out, "  edges            7\n");
+    assert_output_contains(&out, "  defaults         1\n");
+    assert_output_contains(&out, "  rule touch       2\n");
+    assert_output_contains(&out, "  rule phony       1\n");
+    assert_output_contains(&out, "ran 1 task");
+    Ok(())
+}
+
+#[test]
+fn profile_startup() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "include rules.ninja", ""].join("\n"),
+    )?;
+    space.write("rules.ninja", "build out: touch in\n")?;
+    space.write("in", "")?;
+
+    let out = space.run_expect(&mut n2_command(vec!["--profile-startup", "out"]))?;
+    assert_output_contains(&out, "startup profile:");
+    for phase in [
+        "read file",
+        "parse",
+        "evaluate paths",
+        "add build",
+        "db::open",
+    ] {
+        assert_output_contains(&out, &format!("\n  {} ", phase));
+    }
+    assert_output_contains(&out, "\n  total ");
+    assert_output_contains(&out, "ran 1 task");
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn deterministic() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule log
+  command = sleep $t && touch $out
+  description = start $out
+build a: log
+  t = 0.5
+build b: log
+  t = 0
+build d: log a
+  t = 0
+build c: log b
+  t = 0
+",
+    )?;
+
+    // The order builds start in, as announced.
+    let starts = |out: &std::process::Output| -> anyhow::Result<String> {
+        Ok(std::str::from_utf8(&out.stdout)?
+            .lines()
+            .filter_map(|line| line.strip_prefix("start "))
+            .collect())
+    };
+
+    // Normally c starts as soon as b is done, while a is still running.
+    let out = space.run_expect(&mut n2_command(vec!["-j4", "c", "d"]))?;
+    assert!(starts(&out)?.ends_with("cd"));
+
+    // Deterministically, c and d wait for a, and then start in order.
+    for out in ["a", "b", "c", "d"] {
+        std::fs::remove_file(space.path().join(out))?;
+    }
+    let out = space.run_expect(&mut n2_command(vec!["-j4", "--deterministic", "c", "d"]))?;
+    assert_eq!(starts(&out)?, "abdc");
+    Ok(())
+}
+
+/// Each wave of --deterministic builds still runs in parallel: the builds
+/// here only succeed if they run at the same time.
+#[cfg(unix)]
+#[test]
+fn deterministic_parallel() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule meet
+  command = touch $out.started && for i in $$(seq 50); do test -f $other.started && break; sleep 0.1; done && test -f $other.started && touch $out
+build a: meet
+  other = b
+build b: meet
+  other = a
+",
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["-j2", "--deterministic", "a", "b"]))?;
+    assert_output_contains(&out, "ran 2 tasks");
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn build_dir_lock() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule slow
+  command = touch started && sleep 1 && touch $out
+build out: slow
+",
+    )?;
+
+    let mut first = n2_command(vec!["out"])
+        .current_dir(space.path())
+        .stdout(std::process::Stdio::null())
+        .spawn()?;
+    while space.metadata("started").is_err() {
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+
+    // Tools that only read don't need the lock.
+    let out = space.run_expect(&mut n2_command(vec!["--lock=fail", "-t", "inputs", "out"]))?;
+    assert!(out.stdout.is_empty());
+
+    let out = space.run(&mut n2_command(vec!["--lock=fail", "out"]))?;
+    assert!(!out.status.success());
+    assert_output_contains(
+        &out,
+        &format!("another n2 (pid {}) is building here", first.id()),
+    );
+
+    // By default, wait for the first one to finish.
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, &format!("waiting for other n2, pid {}", first.id()));
+    assert_output_contains(&out, "no work to do");
+    assert!(first.wait()?.success());
+    Ok(())
+}
+
 /// Run a task that prints something, and verify it shows up.
 #[cfg(unix)]
 #[test]
@@ -205,6 +397,37 @@ fn explain() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[cfg(unix)]
+#[test]
+fn explain_command_change() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    let manifest = |flags: &str| {
+        format!(
+            "rule cc\n  command = touch $out && echo cc {} -c $in\nbuild out: cc in\n",
+            flags
+        )
+    };
+    space.write("build.ninja", &manifest("-O2 -g"))?;
+    space.write("in", "")?;
+    space.run_expect(&mut n2_command(vec!["out"]))?;
+
+    // Command lines are only kept by builds that explain.
+    space.write("build.ninja", &manifest("-O3 -g"))?;
+    let out = space.run_expect(&mut n2_command(vec!["-d", "explain", "out"]))?;
+    assert_output_contains(
+        &out,
+        "explain: build.ninja:3: command changed: the previous one wasn't recorded",
+    );
+
+    space.write("build.ninja", &manifest("-O2 -g"))?;
+    let out = space.run_expect(&mut n2_command(vec!["-d", "explain", "out"]))?;
+    assert_output_contains(
+        &out,
+        "explain: build.ninja:3: command changed: ... cc [--O3-] {+-O2+} -g ...",
+    );
+    Ok(())
+}
+
 /// Meson generates a build step that writes to one of its inputs.
 #[test]
 fn write_to_input() -> anyhow::Result<()> {
@@ -305,6 +528,27 @@ fn builddir() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// A db written by another version of n2 is discarded rather than read.
+#[test]
+fn db_other_version() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build out: touch", ""].join("\n"),
+    )?;
+    let mut db = b"n2db".to_vec();
+    db.extend_from_slice(&1u32.to_le_bytes());
+    db.extend_from_slice(b"records in an older format");
+    std::fs::write(space.path().join(".n2_db"), db)?;
+
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "from another version of n2; starting over");
+    assert_output_contains(&out, "ran 1 task");
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "no work to do");
+    Ok(())
+}
+
 /// Verify the error message when a command doesn't exist.
 #[test]
 fn missing_command() -> anyhow::Result<()> {
@@ -330,3 +574,1511 @@ fn missing_command() -> anyhow::Result<()> {
     }
     Ok(())
 }
+
+#[test]
+fn chrome_trace() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build out: touch in", ""].join("\n"),
+    )?;
+    space.write("in", "")?;
+    space.run_expect(&mut n2_command(vec!["--chrome-trace", "t.json", "out"]))?;
+    let trace = String::from_utf8(space.read("t.json")?)?;
+    assert!(trace.contains("\"n2_invocation\""));
+    assert!(trace.contains("\"work.run\""));
+    // Phases are recorded too.
+    assert!(trace.contains("\"load\""));
+    assert!(trace.contains("\"plan\""));
+    assert!(trace.contains("\"n2_summary\""));
+    assert!(trace.contains("\"peak_rss_kb\""));
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn hang_timeout() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule slow
+  command = sleep 2 && touch $out
+build out: slow
+",
+    )?;
+    let out = space.run_expect(&mut n2_command(vec![
+        "--hang-timeout",
+        "0.01",
+        "--hang-hook",
+        "echo hook pid=$N2_HUNG_PID",
+        "out",
+    ]))?;
+    assert_output_contains(&out, "sleep 2 && touch out");
+    assert_output_contains(&out, "hook pid=");
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn retry_flaky_command() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule flaky
+  command = if [ -f tried ]; then touch $out; else touch tried; exit 1; fi
+  retries = 1
+build out: flaky
+",
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "retrying (1/1): if [ -f tried ]");
+    // The first attempt isn't reported as a failure.
+    assert_output_not_contains(&out, "failed:");
+    assert!(space.read("out").is_ok());
+    Ok(())
+}
+
+#[test]
+fn hermetic() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build out: touch ../outside",
+            "build ok: touch",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    let out = space.run(&mut n2_command(vec!["--hermetic", "-k", "2", "out", "ok"]))?;
+    assert!(!out.status.success());
+    assert_output_contains(&out, "input ../outside is outside the hermetic roots");
+    // Only the offending build fails.
+    assert!(space.read("ok").is_ok());
+    Ok(())
+}
+
+/// A build that generates a dangling symlink is up to date once run.
+#[cfg(unix)]
+#[test]
+fn symlink_output() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule link
+  command = ln -sf nonexistent $out
+build out: link
+",
+    )?;
+    space.run_expect(&mut n2_command(vec!["out"]))?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "no work to do");
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn serialize_output_started() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule run
+  command = $cmd && touch $out
+  description = start $out
+build slow: run
+  cmd = sleep 0.5 && echo output-slow
+build fast: run
+  cmd = echo output-fast
+",
+    )?;
+    let out = space.run_expect(&mut n2_command(vec![
+        "-j2",
+        "--serialize-output=started",
+        "slow",
+        "fast",
+    ]))?;
+    let stdout = std::str::from_utf8(&out.stdout)?;
+    let pos = |text: &str| stdout.find(text).unwrap();
+    // Output follows the order tasks were started in, even though the
+    // first-started one finished last.
+    assert_eq!(
+        pos("start slow") < pos("start fast"),
+        pos("output-slow") < pos("output-fast"),
+        "{}",
+        stdout
+    );
+    Ok(())
+}
+
+/// Output too large to buffer in memory is still printed in full.
+#[cfg(unix)]
+#[test]
+fn large_output() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule spew
+  command = head -c 3000000 /dev/zero | tr '\\0' x && echo && echo end-of-output && false
+build out: spew
+",
+    )?;
+    let out = space.run(&mut n2_command(vec!["out"]))?;
+    assert!(!out.status.success());
+    assert!(out.stdout.len() > 3000000);
+    assert_output_contains(&out, "failed: head -c");
+    assert_output_contains(&out, "end-of-output");
+    Ok(())
+}
+
+#[test]
+fn prefix_output() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[ECHO_RULE, "build out: echo", "  text = hello", ""].join("\n"),
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["--prefix-output", "out"]))?;
+    assert_output_contains(&out, "[out] hello");
+
+    let out = space.run_expect(&mut n2_command(vec!["--prefix-output=rule", "out"]))?;
+    assert_output_contains(&out, "[echo] hello");
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn wrapper() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            ECHO_RULE,
+            "build a: echo",
+            "  text = wrapped=$$WRAPPED && echo 'quoted'",
+            "build b: echo",
+            "  text = prefixed",
+            "  command_prefix = echo ",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["--wrapper", "env WRAPPED=1", "a"]))?;
+    assert_output_contains(&out, "wrapped=1\nquoted");
+
+    let out = space.run_expect(&mut n2_command(vec!["b"]))?;
+    assert_output_contains(&out, "echo prefixed");
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn cwd() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule touch_here
+  command = touch $name
+  cwd = sub
+build sub/out: touch_here
+  name = out
+build missing/out: touch_here
+  name = out
+  cwd = missing/dir
+",
+    )?;
+    space.run_expect(&mut n2_command(vec!["sub/out"]))?;
+    space.read("sub/out")?;
+
+    let out = space.run(&mut n2_command(vec!["missing/out"]))?;
+    assert_output_contains(&out, "cwd missing/dir: not a directory");
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn cwd_change() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    let manifest = |dir: &str| {
+        format!(
+            "
+rule pwd
+  command = /bin/pwd > ../out
+  cwd = {}
+build out: pwd
+",
+            dir
+        )
+    };
+    std::fs::create_dir(space.path().join("a"))?;
+    std::fs::create_dir(space.path().join("b"))?;
+    space.write("build.ninja", &manifest("a"))?;
+    space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert!(String::from_utf8(space.read("out")?)?.ends_with("/a\n"));
+
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "no work to do");
+
+    // Though the command is the same, it runs somewhere else.
+    space.write("build.ninja", &manifest("b"))?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    assert!(String::from_utf8(space.read("out")?)?.ends_with("/b\n"));
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn cwd_depfile() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule cc
+  command = touch out.o && echo 'out.o: in.h ../top.h' > out.d
+  cwd = sub
+  depfile = sub/out.d
+build sub/out.o: cc
+",
+    )?;
+    std::fs::create_dir(space.path().join("sub"))?;
+    space.write("sub/in.h", "")?;
+    space.write("top.h", "")?;
+    let out = space.run_expect(&mut n2_command(vec!["sub/out.o"]))?;
+    assert_output_not_contains(&out, "lists none of the build's outputs");
+
+    let out = space.run_expect(&mut n2_command(vec!["sub/out.o"]))?;
+    assert_output_contains(&out, "no work to do");
+
+    // The deps, named from sub, are found relative to it.
+    space.write("sub/in.h", "x")?;
+    let out = space.run_expect(&mut n2_command(vec!["sub/out.o"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    space.write("top.h", "x")?;
+    let out = space.run_expect(&mut n2_command(vec!["sub/out.o"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn skip_known_failures() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule fail
+  command = echo ran $in; exit 1
+  description = fail $out
+build out: fail in
+",
+    )?;
+    space.write("in", "")?;
+    let out = space.run(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran in");
+
+    let out = space.run(&mut n2_command(vec!["--skip-known-failures", "out"]))?;
+    assert!(!out.status.success());
+    assert_output_contains(
+        &out,
+        "skipped fail out: failed last time with the same inputs",
+    );
+    assert_output_not_contains(&out, "ran in");
+
+    // Without the flag, the command runs again.
+    let out = space.run(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran in");
+
+    // Changing an input reruns.
+    space.sub_mtime("in", std::time::Duration::from_secs(1))?;
+    let out = space.run(&mut n2_command(vec!["--skip-known-failures", "out"]))?;
+    assert_output_contains(&out, "ran in");
+
+    let out = space.run(&mut n2_command(vec![
+        "--skip-known-failures",
+        "--forget-failures",
+        "out",
+    ]))?;
+    assert_output_contains(&out, "ran in");
+    Ok(())
+}
+
+#[test]
+fn mtime_granularity() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build out: touch in", ""].join("\n"),
+    )?;
+    space.write("in", "")?;
+    let out = space.run_expect(&mut n2_command(vec![
+        "-d",
+        "mtime",
+        "--mtime-granularity=1s",
+        "in",
+        "missing",
+    ]))?;
+    let stdout = std::str::from_utf8(&out.stdout)?;
+    assert!(stdout.contains("in: raw "), "{}", stdout);
+    assert!(stdout.contains(".000000000\n"), "{}", stdout);
+    assert!(stdout.contains("missing: missing"), "{}", stdout);
+
+    space.run_expect(&mut n2_command(vec!["--mtime-granularity=1s", "out"]))?;
+    let out = space.run_expect(&mut n2_command(vec!["--mtime-granularity=1s", "out"]))?;
+    assert_output_contains(&out, "no work to do");
+    Ok(())
+}
+
+#[test]
+fn trust_mtimes() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build out: touch in", ""].join("\n"),
+    )?;
+    space.write("in", "1")?;
+    let trusting = || n2_command(vec!["--trust-mtimes", "out"]);
+    space.run_expect(&mut trusting())?;
+    let out = space.run_expect(&mut trusting())?;
+    assert_output_contains(&out, "no work to do");
+
+    // A file changed after the last run is noticed.
+    space.write("in", "2")?;
+    let out = space.run_expect(&mut trusting())?;
+    assert_output_contains(&out, "ran 1 task");
+
+    // A file replaced by one older than the last run isn't, unless hashed.
+    let old = std::time::Duration::from_secs(10);
+    space.sub_mtime("build.ninja", old)?;
+    space.sub_mtime("out", old)?;
+    space.write("in", "3")?;
+    space.sub_mtime("in", old)?;
+    let out = space.run_expect(&mut trusting())?;
+    assert_output_contains(&out, "no work to do");
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    Ok(())
+}
+
+#[test]
+fn hash_contents() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build out: touch src/in other", ""].join("\n"),
+    )?;
+    std::fs::create_dir(space.path().join("src"))?;
+    space.write("src/in", "1")?;
+    space.write("other", "")?;
+    let hashing = || n2_command(vec!["--hash-contents", "src/", "out"]);
+    space.run_expect(&mut hashing())?;
+
+    // Files within the root are judged by their contents alone...
+    let old = std::time::Duration::from_secs(10);
+    space.sub_mtime("src/in", old)?;
+    let out = space.run_expect(&mut hashing())?;
+    assert_output_contains(&out, "no work to do");
+    space.write("src/in", "2")?;
+    space.sub_mtime("src/in", old)?;
+    let out = space.run_expect(&mut hashing())?;
+    assert_output_contains(&out, "ran 1 task");
+
+    // ...and files outside it by their mtimes.
+    space.sub_mtime("other", old)?;
+    let out = space.run_expect(&mut hashing())?;
+    assert_output_contains(&out, "ran 1 task");
+    Ok(())
+}
+
+#[test]
+fn undefined_description_vars() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule touch
+  command = touch $out
+  description = $touchdesc
+build a: touch
+build b: touch
+",
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["a", "b"]))?;
+    let stdout = std::str::from_utf8(&out.stdout)?;
+    assert_eq!(
+        stdout
+            .matches("description of rule touch refers to undefined $touchdesc")
+            .count(),
+        1,
+        "{}",
+        stdout
+    );
+    assert_output_contains(&out, "touch a\n");
+    Ok(())
+}
+
+#[test]
+fn missing_manifest() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    let out = space.run(&mut n2_command(vec![]))?;
+    assert_output_contains(&out, "n2: error: build.ninja not found\n");
+
+    space.write("CMakeLists.txt", "")?;
+    let out = space.run(&mut n2_command(vec![]))?;
+    assert_output_contains(&out, "hint: this looks like a CMake source directory");
+    Ok(())
+}
+
+#[test]
+fn look_up() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build sub/out: touch", ""].join("\n"),
+    )?;
+    space.run_expect(&mut n2_command(vec!["sub/out"]))?;
+
+    let out = space.run(&mut n2_command(vec!["-C", "sub", "sub/out"]))?;
+    assert_output_contains(&out, "hint: found build.ninja in ");
+
+    let out = space.run_expect(&mut n2_command(vec!["-C", "sub", "--look-up", "sub/out"]))?;
+    assert_output_contains(&out, "n2: Entering directory");
+    assert_output_contains(&out, "no work to do");
+    Ok(())
+}
+
+#[test]
+fn multiple_build_files() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write("a.ninja", &[TOUCH_RULE, "build a: touch", ""].join("\n"))?;
+    // Rules from earlier files are visible to later ones.
+    space.write("b.ninja", "build b: touch a\n")?;
+    space.write("dup.ninja", "build a: touch\n")?;
+
+    let out = space.run_expect(&mut n2_command(vec!["-f", "a.ninja", "-f", "b.ninja", "b"]))?;
+    assert_output_contains(&out, "ran 2 tasks");
+    space.read("b")?;
+
+    let out = space.run(&mut n2_command(vec![
+        "-f",
+        "a.ninja",
+        "-f",
+        "dup.ninja",
+        "a",
+    ]))?;
+    assert_output_contains(&out, "\"a\" is already an output at a.ninja:");
+    Ok(())
+}
+
+#[test]
+fn rule_stats() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build a: touch", "build b: touch", ""].join("\n"),
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["--rule-stats", "a", "b"]))?;
+    assert_output_contains(
+        &out,
+        "rule     runs      total   average  failed\ntouch       2",
+    );
+    Ok(())
+}
+
+#[test]
+fn critical_path() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build a: touch",
+            "build b: touch a",
+            "build c: touch b",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["--dump-critical-path", "c"]))?;
+    assert_output_contains(&out, "critical path: 3 builds");
+    let out = std::str::from_utf8(&out.stdout)?;
+    let path = out.split_once("critical path").unwrap().1;
+    assert!(path.find("touch a").unwrap() < path.find("touch b").unwrap());
+    assert!(path.find("touch b").unwrap() < path.find("touch c").unwrap());
+    Ok(())
+}
+
+#[test]
+fn console_pool() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            ECHO_RULE,
+            "build a: echo",
+            "  text = captured",
+            "build b: echo",
+            "  text = direct",
+            "  pool = console",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    // Console output goes straight to the terminal, bypassing the prefixing
+    // applied to captured output.
+    let out = space.run_expect(&mut n2_command(vec!["--prefix-output", "a", "b"]))?;
+    assert_output_contains(&out, "[a] captured");
+    assert_output_contains(&out, "direct");
+    assert_output_not_contains(&out, "[b] direct");
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn color_env() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            ECHO_RULE,
+            "build out: echo",
+            "  text = color=$$CLICOLOR_FORCE",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    let out =
+        space.run_expect(n2_command(vec!["--color=always", "out"]).env_remove("CLICOLOR_FORCE"))?;
+    assert_output_contains(&out, "color=1");
+
+    let out =
+        space.run_expect(n2_command(vec!["--color=never", "out"]).env_remove("CLICOLOR_FORCE"))?;
+    assert_output_not_contains(&out, "color=1");
+    Ok(())
+}
+
+#[test]
+fn ninja_required_version() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    let manifest = |version: &str| {
+        [
+            &format!("ninja_required_version = {}", version),
+            TOUCH_RULE,
+            "build out: touch",
+            "",
+        ]
+        .join("\n")
+    };
+
+    space.write("build.ninja", &manifest("1.5"))?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_not_contains(&out, "ninja_required_version");
+
+    space.write("build.ninja", &manifest("1.10"))?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "n2 doesn't implement: dyndep");
+
+    // Requiring a newer ninja is only a warning.
+    space.write("build.ninja", &manifest("1.99"))?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(
+        &out,
+        "warn: build.ninja: ninja_required_version is 1.99, but n2 is compatible with ninja 1.10.2",
+    );
+
+    // Syntax errors in a file requiring a newer ninja also report the version.
+    space.write("build.ninja", &(manifest("1.99") + "newfeature x\n"))?;
+    let out = space.run(&mut n2_command(vec!["out"]))?;
+    assert!(!out.status.success());
+    assert_output_contains(
+        &out,
+        "ninja_required_version is 1.99, but n2 is compatible with ninja 1.10.2",
+    );
+    Ok(())
+}
+
+/// A program embedding n2 can cancel a build, killing its commands.
+#[cfg(unix)]
+#[test]
+fn cancel() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule sleep
+  command = sleep 10 && touch $out
+build out: sleep
+",
+    )?;
+    let start = std::time::Instant::now();
+    let cancel = n2::CancelToken::with_deadline(start + std::time::Duration::from_millis(500));
+    let err = space.run_embedded(&[], cancel)?.unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<n2::Error>(),
+        Some(n2::Error::Cancelled)
+    ));
+    assert!(start.elapsed() < std::time::Duration::from_secs(5));
+    assert!(space.metadata("out").is_err());
+    Ok(())
+}
+
+/// An embedded build doesn't change the directory of the program embedding
+/// it.
+#[test]
+fn embedded_no_chdir() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write("build.ninja", "")?;
+    let sub = space.path().join("sub");
+    std::fs::create_dir(&sub)?;
+    let err = space
+        .run_embedded(&["-C", sub.to_str().unwrap()], n2::CancelToken::new())?
+        .unwrap_err();
+    assert!(err.to_string().contains("run from the build directory"));
+    Ok(())
+}
+
+/// A build cut off by cancellation reruns next time, though its output was
+/// already written.
+#[cfg(unix)]
+#[test]
+fn cancel_reruns_interrupted() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule run
+  command = touch $out && (test -f quick || sleep 10)
+build out: run
+",
+    )?;
+    let cancel = n2::CancelToken::with_deadline(
+        std::time::Instant::now() + std::time::Duration::from_millis(500),
+    );
+    space.run_embedded(&[], cancel)?.unwrap_err();
+    assert!(space.metadata("out").is_ok());
+
+    space.write("quick", "")?;
+    let out = space.run_expect(&mut n2_command(vec!["-d", "explain", "out"]))?;
+    assert_output_contains(&out, "interrupted last time");
+    assert_output_contains(&out, "ran 1 task");
+
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "no work to do");
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn fail_on_output() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule chatty
+  command = echo warning: hmm && touch $out
+rule allowed
+  command = echo fine && touch $out
+build out: chatty
+build out2: allowed
+",
+    )?;
+    let out = space.run(&mut n2_command(vec!["--fail-on-output", "out"]))?;
+    assert!(!out.status.success());
+    assert_output_contains(&out, "it printed output, and --fail-on-output is set");
+    assert_output_contains(&out, "warning: hmm");
+
+    let out = space.run_expect(&mut n2_command(vec!["--fail-on-output=allowed", "out2"]))?;
+    assert_output_contains(&out, "fine");
+    Ok(())
+}
+
+#[test]
+fn all_target() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build mid: touch",
+            "build a: touch mid",
+            "build b: touch",
+            "default a",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["all"]))?;
+    assert_output_contains(&out, "ran 3 tasks, now up to date");
+    space.metadata("b")?;
+
+    // Changing mid reruns it and a, while b is already up to date.
+    space.write("mid", "")?;
+    let out = space.run_expect(&mut n2_command(vec!["all"]))?;
+    assert_output_contains(&out, "ran 2 tasks, 1 already up to date");
+
+    // A target named all in the build files takes precedence.
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build all: touch", ""].join("\n"),
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["all"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    space.metadata("all")?;
+    Ok(())
+}
+
+#[test]
+fn target_patterns() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build obj/a.o: touch",
+            "build obj/b.o: touch",
+            "build obj/sub/c.o: touch",
+            "build other: touch",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["obj/*.o"]))?;
+    assert_output_contains(&out, "ran 2 tasks");
+    assert!(space.metadata("obj/sub/c.o").is_err());
+
+    let out = space.run_expect(&mut n2_command(vec!["obj/..."]))?;
+    assert_output_contains(&out, "ran 1 task");
+    assert!(space.metadata("other").is_err());
+
+    let out = space.run(&mut n2_command(vec!["*.x"]))?;
+    assert!(!out.status.success());
+    assert_output_contains(&out, "no outputs match \"*.x\"");
+    Ok(())
+}
+
+#[test]
+fn targets_from_file() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build a: touch",
+            "build b: touch",
+            "build c: touch",
+            "build d: touch",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.write("targets.txt", "a\n\nb\r\n")?;
+    let out = space.run_expect(&mut n2_command(vec!["@targets.txt", "c"]))?;
+    assert_output_contains(&out, "ran 3 tasks");
+    assert!(space.metadata("d").is_err());
+
+    space.write("stdin.txt", "d\n")?;
+    let out = space.run_expect(
+        n2_command(vec!["-"]).stdin(std::fs::File::open(space.path().join("stdin.txt"))?),
+    )?;
+    assert_output_contains(&out, "ran 1 task");
+    space.metadata("d")?;
+
+    let out = space.run(&mut n2_command(vec!["@missing.txt"]))?;
+    assert!(!out.status.success());
+    assert_output_contains(&out, "read missing.txt");
+    Ok(())
+}
+
+#[test]
+fn caret_target() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build all_srcs: phony foo.c",
+            "build foo.o: touch foo.c",
+            "build bar.o: touch bar.c",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.write("foo.c", "")?;
+    space.write("bar.c", "")?;
+    let out = space.run_expect(&mut n2_command(vec!["foo.c^"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    space.metadata("foo.o")?;
+    assert!(space.metadata("bar.o").is_err());
+
+    let out = space.run(&mut n2_command(vec!["foo.o^"]))?;
+    assert!(!out.status.success());
+    assert_output_contains(&out, "\"foo.o\" isn't used by any build");
+    Ok(())
+}
+
+#[test]
+fn prestat() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build gen: touch",
+            "build out: touch in sub/in gen",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.write("in", "")?;
+    std::fs::create_dir(space.path().join("sub"))?;
+    space.write("sub/in", "")?;
+    let out = space.run_expect(&mut n2_command(vec!["--prestat", "out"]))?;
+    assert_output_contains(&out, "ran 2 tasks");
+    let out = space.run_expect(&mut n2_command(vec!["--prestat", "out"]))?;
+    assert_output_contains(&out, "no work to do");
+
+    space.sub_mtime("out", std::time::Duration::from_secs(10))?;
+    let out = space.run_expect(&mut n2_command(vec!["--prestat", "out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+
+    std::fs::remove_file(space.path().join("sub/in"))?;
+    let out = space.run(&mut n2_command(vec!["--prestat", "out"]))?;
+    assert_output_contains(&out, "input sub/in missing");
+    Ok(())
+}
+
+#[test]
+fn prescan() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build mid: touch in",
+            "build out: touch mid",
+            "build other: touch",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.write("in", "")?;
+    let predicted = |n: usize| -> anyhow::Result<bool> {
+        let summary = String::from_utf8(space.read(".n2_summary.json")?)?;
+        Ok(summary.contains(&format!("\"tasks_predicted\": {},", n)))
+    };
+    let out = space.run_expect(&mut n2_command(vec!["--prescan", "out", "other"]))?;
+    assert_output_contains(&out, "ran 3 tasks");
+    assert!(predicted(3)?);
+
+    // A changed input reruns the builds depending on it, and only them.
+    space.sub_mtime("mid", std::time::Duration::from_secs(10))?;
+    space.sub_mtime("out", std::time::Duration::from_secs(10))?;
+    let out = space.run_expect(&mut n2_command(vec!["--prescan", "out", "other"]))?;
+    assert_output_contains(&out, "ran 2 tasks");
+    assert!(predicted(2)?);
+
+    // Errors are still reported as the build gets to them.
+    std::fs::remove_file(space.path().join("in"))?;
+    let out = space.run(&mut n2_command(vec!["--prescan", "out"]))?;
+    assert_output_contains(&out, "input in missing");
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn dedup_commands() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule stamp
+  command = echo ran >> runs && touch a b
+rule cat
+  command = cat $in > $out
+build a: stamp in
+build b: stamp in
+build out: cat a b
+",
+    )?;
+    space.write("in", "")?;
+    space.run_expect(&mut n2_command(vec!["--dedup-commands", "out"]))?;
+    assert_eq!(space.read("runs")?, b"ran\n");
+    let out = space.run_expect(&mut n2_command(vec!["--dedup-commands", "out"]))?;
+    assert_output_contains(&out, "no work to do");
+
+    // Without the flag, each build runs the command.
+    space.write("in", "changed")?;
+    space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_eq!(space.read("runs")?, b"ran\nran\nran\n");
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn halt_on_error() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule run
+  command = $cmd
+build fail: run
+  cmd = sleep 0.2 && false
+build slow: run
+  cmd = sleep 1 && touch slow
+",
+    )?;
+
+    // By default, running commands finish and are recorded.
+    let out = space.run(&mut n2_command(vec!["-k", "1", "-j", "2", "fail", "slow"]))?;
+    assert_output_contains(&out, "failed: sleep 0.2 && false");
+    assert!(space.read("slow").is_ok());
+    let out = space.run_expect(&mut n2_command(vec!["slow"]))?;
+    assert_output_contains(&out, "no work to do");
+
+    // With kill, n2 exits without waiting for them.
+    std::fs::remove_file(space.path().join("slow"))?;
+    let out = space.run(&mut n2_command(vec![
+        "-k",
+        "1",
+        "-j",
+        "2",
+        "--halt-on-error=kill",
+        "fail",
+        "slow",
+    ]))?;
+    assert_output_contains(&out, "n2: killing 1 running task\n");
+    assert!(space.read("slow").is_err());
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn kill_reaches_children() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule run
+  command = $cmd
+build fail: run
+  cmd = sleep 0.2 && false
+build spawner: run
+  cmd = (sleep 1 && touch leaked) & wait
+",
+    )?;
+
+    // Killing a command also kills the processes it started.
+    let out = space.run(&mut n2_command(vec![
+        "-k",
+        "1",
+        "-j",
+        "2",
+        "--halt-on-error=kill",
+        "fail",
+        "spawner",
+    ]))?;
+    assert_output_contains(&out, "n2: killing 1 running task\n");
+    std::thread::sleep(std::time::Duration::from_millis(1500));
+    assert!(space.read("leaked").is_err());
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn timeout() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule run
+  command = $cmd
+build slow: run
+  cmd = true && sleep 5 && touch slow
+  timeout = 0.2
+build quick: run
+  cmd = touch quick
+",
+    )?;
+
+    let start = std::time::Instant::now();
+    let out = space.run(&mut n2_command(vec!["slow"]))?;
+    assert_output_contains(&out, "timed out: true && sleep 5 && touch slow");
+    assert!(start.elapsed() < std::time::Duration::from_secs(4));
+    assert!(space.read("slow").is_err());
+
+    // The global default doesn't stop commands that finish in time.
+    let out = space.run_expect(&mut n2_command(vec!["--timeout", "10", "quick"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn on_complete() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+builddir = out
+rule run
+  command = $cmd
+build out/ok: run
+  cmd = touch out/ok
+build out/bad: run
+  cmd = false
+",
+    )?;
+
+    let hook = "echo $N2_STATUS $N2_TASKS_RUN > hook && cat $N2_FAILED_EDGES >> hook";
+    let out = space.run(&mut n2_command(vec![
+        "-k",
+        "2",
+        "--on-complete",
+        hook,
+        "out/ok",
+        "out/bad",
+    ]))?;
+    assert!(!out.status.success());
+    assert_eq!(space.read("hook")?, b"failure 1\nout/bad\n");
+    let summary = String::from_utf8(space.read("out/.n2_summary.json")?)?;
+    assert!(summary.contains("\"status\": \"failure\""));
+    assert!(summary.contains("{\"output\": \"out/bad\", \"description\": \"false\"}"));
+
+    // A failing hook is reported without changing the build's result.
+    let out = space.run_expect(&mut n2_command(vec!["--on-complete", "exit 3", "out/ok"]))?;
+    assert_output_contains(&out, "n2: --on-complete exit 3: exit status: 3");
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn on_complete_builddir_mtime() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+builddir = out
+rule list
+  command = ls -a out > $out
+build list: list out
+",
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["--on-complete", "true", "list"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    // The failed outputs file was made before the build, so writing it
+    // afterwards didn't touch builddir.
+    assert!(String::from_utf8(space.read("list")?)?.contains(".n2_failed_edges"));
+    let out = space.run_expect(&mut n2_command(vec!["--on-complete", "true", "list"]))?;
+    assert_output_contains(&out, "no work to do");
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn summary_json() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+builddir = out
+rule run
+  command = $cmd
+build out/ok: run
+  cmd = sleep 0.2 && touch out/ok
+build out/bad: run
+  cmd = false
+",
+    )?;
+
+    // Tools don't build, so leave no summary.
+    space.run_expect(&mut n2_command(vec!["-t", "inputs", "out/ok"]))?;
+    assert!(space.read("out/.n2_summary.json").is_err());
+
+    space.run(&mut n2_command(vec!["-k", "2", "out/ok", "out/bad"]))?;
+    let summary = String::from_utf8(space.read("out/.n2_summary.json")?)?;
+    assert!(summary.contains("\"status\": \"failure\""));
+    assert!(summary.contains("\"interrupted\": false"));
+    assert!(summary.contains("\"tasks_run\": 1,"));
+    assert!(summary.contains("\"tasks_failed\": 1,"));
+    assert!(summary.contains(
+        "\"slowest\": [{\"output\": \"out/ok\", \"description\": \"sleep 0.2 && touch out/ok\""
+    ));
+    assert!(summary.contains("\"peak_rss_kb\": "));
+
+    // Written even when there's nothing to do.
+    space.run_expect(&mut n2_command(vec!["out/ok"]))?;
+    let summary = String::from_utf8(space.read("out/.n2_summary.json")?)?;
+    assert!(summary.contains("\"status\": \"success\""));
+    assert!(summary.contains("\"tasks_run\": 0,"));
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn target_statuses() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+builddir = out
+rule run
+  command = $cmd
+build out/ok: run
+  cmd = touch out/ok
+build out/bad: run
+  cmd = false
+build out/after: run out/bad
+  cmd = touch out/after
+",
+    )?;
+
+    let out = space.run(&mut n2_command(vec![
+        "-k",
+        "5",
+        "out/ok",
+        "out/bad",
+        "out/after",
+    ]))?;
+    assert!(!out.status.success());
+    assert_output_contains(
+        &out,
+        "n2: 1 of 3 targets built; not built: out/bad (failed), out/after\n",
+    );
+    let summary = String::from_utf8(space.read("out/.n2_summary.json")?)?;
+    assert!(summary.contains(
+        "\"targets\": [{\"target\": \"out/ok\", \"status\": \"satisfied\"}, \
+         {\"target\": \"out/bad\", \"status\": \"failed\"}, \
+         {\"target\": \"out/after\", \"status\": \"not_built\"}]"
+    ));
+
+    // A single target's failure speaks for itself.
+    let out = space.run(&mut n2_command(vec!["out/bad"]))?;
+    assert!(!String::from_utf8_lossy(&out.stdout).contains("targets built"));
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn atomic_outputs() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule run
+  command = echo $out > $out && test -f ok
+  description = run $out
+  atomic_outputs = 1
+build out: run
+",
+    )?;
+
+    // A failing command leaves no output, half written or otherwise.
+    let out = space.run(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "failed: run out");
+    assert!(space.read("out").is_err());
+    assert!(space.read("out.n2tmp").is_err());
+
+    space.write("ok", "")?;
+    space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_eq!(space.read("out")?, b"out.n2tmp\n");
+    assert!(space.read("out.n2tmp").is_err());
+    Ok(())
+}
+
+#[test]
+fn adopt() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build out1: touch in",
+            "build out2: touch in",
+            "build out3: touch in",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.write("in", "")?;
+    // Outputs restored from elsewhere, e.g. a cache.
+    space.write("out1", "")?;
+    space.write("out2", "")?;
+
+    let out = space.run_expect(&mut n2_command(vec!["--adopt", "out1", "out3"]))?;
+    assert_output_contains(&out, "n2: not adopting touch out3: output out3 missing");
+    assert_output_contains(&out, "n2: adopted 1 build as up to date");
+    assert!(space.read("out3").is_err());
+
+    // Only the adopted target is up to date.
+    let out = space.run_expect(&mut n2_command(vec!["out1"]))?;
+    assert_output_contains(&out, "no work to do");
+    let out = space.run_expect(&mut n2_command(vec!["out2"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    Ok(())
+}
+
+#[test]
+fn hide_success() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            ECHO_RULE,
+            "build hidden: echo",
+            "  text = hidden-output",
+            "  hide_success = 1",
+            "build shown: echo",
+            "  text = shown-output",
+            "  hide_success = 0",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["hidden", "shown"]))?;
+    assert_output_not_contains(&out, "hidden-output");
+    assert_output_contains(&out, "shown-output");
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn hide_success_shows_failure() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule fail
+  command = echo failure-output; exit 1
+  hide_success = 1
+build out: fail
+",
+    )?;
+    let out = space.run(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "failed: echo failure-output; exit 1");
+    assert_output_contains(&out, "failure-output\n");
+    Ok(())
+}
+
+#[test]
+fn hide_progress() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build hidden: touch in",
+            "  hide_progress = true",
+            "build shown: touch in",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.write("in", "")?;
+    let out = space.run_expect(&mut n2_command(vec!["hidden", "shown"]))?;
+    assert_output_not_contains(&out, "touch hidden");
+    assert_output_contains(&out, "touch shown");
+    assert_output_contains(&out, "ran 2 tasks");
+    Ok(())
+}
+
+#[test]
+fn hide_success_invalid() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build out: touch", "  hide_success = yes", ""].join("\n"),
+    )?;
+    let out = space.run(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "invalid hide_success \"yes\", expected 0 or 1");
+    Ok(())
+}
+
+#[test]
+fn hide_success_ninja_compat() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE.trim_end(),
+            "  hide_success = 1",
+            "build out: touch",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    let out = space.run(&mut n2_command(vec!["-d", "ninja_compat", "out"]))?;
+    assert_output_contains(
+        &out,
+        "rule touch: hide_success is not supported in ninja compatibility mode",
+    );
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    Ok(())
+}
+
+#[test]
+fn stat_threads() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build a: touch in1",
+            "build b: touch in2",
+            "build c: touch a b",
+            "build d: phony c",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.write("in1", "")?;
+    space.write("in2", "")?;
+
+    let stat_threads = || n2_command(vec!["--stat-threads", "2", "d"]);
+    let out = space.run_expect(&mut stat_threads())?;
+    assert_output_contains(&out, "ran 3 tasks");
+    let out = space.run_expect(&mut stat_threads())?;
+    assert_output_contains(&out, "no work to do");
+
+    space.write("in2", "changed")?;
+    let out = space.run_expect(&mut stat_threads())?;
+    assert_output_contains(&out, "ran 2 tasks");
+    assert_output_not_contains(&out, "touch a");
+    Ok(())
+}
+
+/// Large command output is spilled within --temp-dir, which is cleaned up.
+#[cfg(unix)]
+#[test]
+fn temp_dir() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule spill
+  command = head -c 1200000 /dev/zero | tr '\\0' x && echo && ls tmp/n2-*
+
+build out: spill
+",
+    )?;
+    std::fs::create_dir(space.path().join("tmp"))?;
+    let out = space.run_expect(&mut n2_command(vec!["--temp-dir", "tmp", "out"]))?;
+    assert_output_contains(&out, "output-");
+    assert_eq!(std::fs::read_dir(space.path().join("tmp"))?.count(), 0);
+    Ok(())
+}
+
+/// --output-base moves outputs elsewhere, without mixing up the records of
+/// the builds there with those of the builds in place.
+#[test]
+fn output_base() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build mid: touch in",
+            "build out: touch mid",
+            "build all: phony out",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.write("in", "")?;
+    let moved = || n2_command(vec!["--output-base", "base", "all"]);
+    let out = space.run_expect(&mut moved())?;
+    assert_output_contains(&out, "touch base/out");
+    assert!(space.metadata("base/mid").is_ok());
+    assert!(space.metadata("out").is_err());
+    let out = space.run_expect(&mut moved())?;
+    assert_output_contains(&out, "no work to do");
+
+    let out = space.run_expect(&mut n2_command(vec!["all"]))?;
+    assert_output_contains(&out, "ran 2 tasks");
+    assert!(space.metadata("out").is_ok());
+    let out = space.run_expect(&mut moved())?;
+    assert_output_contains(&out, "no work to do");
+
+    // Outputs can still be named as the build files name them.
+    space.write("in", "changed")?;
+    let out = space.run_expect(&mut n2_command(vec!["--output-base", "base", "mid"]))?;
+    assert_output_contains(&out, "ran 1 task");
+
+    // Tools that only read the db leave it alone.
+    let db = space.read(".n2_db")?;
+    space.run_expect(&mut n2_command(vec!["-t", "inputs", "out"]))?;
+    space.run_expect(&mut n2_command(vec![
+        "--output-base",
+        "x",
+        "-t",
+        "inputs",
+        "out",
+    ]))?;
+    assert_eq!(space.read(".n2_db")?, db);
+    Ok(())
+}
+
+/// n2.toml and ~/.n2.toml supply default flags, in order of precedence.
+#[cfg(unix)]
+#[test]
+fn config_files() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule say
+  command = echo hello
+  description = SAY $out
+
+build out: say
+",
+    )?;
+    std::fs::create_dir(space.path().join("home"))?;
+    space.write("home/.n2.toml", "verbose = true\n")?;
+    let n2 = |args: Vec<&str>| {
+        let mut cmd = n2_command(args);
+        cmd.env("HOME", space.path().join("home"));
+        cmd
+    };
+
+    let out = space.run_expect(&mut n2(vec!["out"]))?;
+    assert_output_contains(&out, "echo hello");
+
+    let out = space.run_expect(&mut n2(vec!["--no-config", "out"]))?;
+    assert_output_not_contains(&out, "echo hello");
+
+    // The build directory's file overrides the home directory's.
+    space.write("n2.toml", "v = false  # quiet\n")?;
+    let out = space.run_expect(&mut n2(vec!["out"]))?;
+    assert_output_not_contains(&out, "echo hello");
+
+    // And the command line overrides both.
+    let out = space.run_expect(&mut n2(vec!["-v", "out"]))?;
+    assert_output_contains(&out, "echo hello");
+
+    // Run as ninja, n2 takes only ninja's flags.
+    std::fs::remove_file(space.path().join("n2.toml"))?;
+    let ninja = space.path().join("ninja");
+    std::os::unix::fs::symlink(n2_binary(), &ninja)?;
+    let mut cmd = std::process::Command::new(&ninja);
+    cmd.arg("out").env("HOME", space.path().join("home"));
+    let out = space.run_expect(&mut cmd)?;
+    assert_output_not_contains(&out, "echo hello");
+
+    space.write("n2.toml", "[n2]\n")?;
+    let out = space.run(&mut n2(vec!["out"]))?;
+    assert_output_contains(&out, "n2.toml:1: tables aren't supported");
+
+    // Errors in the flags themselves name the file too.
+    space.write("n2.toml", "no-such-flag = true\n")?;
+    let out = space.run(&mut n2(vec!["out"]))?;
+    assert_output_contains(&out, "n2.toml: invalid option '--no-such-flag'");
+    Ok(())
+}
@@ -147,3 +147,43 @@ build foo: write_file
     assert_eq!(space.read("foo")?, b"Hello, world!\n");
     Ok(())
 }
+
+#[cfg(unix)]
+#[test]
+fn env_vars() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+dir = ${env.N2_TEST_DIR}
+rule gen
+  command = echo ${env.N2_TEST_MSG} > $out
+build $dir/out: gen
+",
+    )?;
+
+    // Without the flag, the references are empty, so there's no sub/out.
+    let out = space.run(n2_command(vec!["sub/out"]).env("N2_TEST_DIR", "sub"))?;
+    assert!(!out.status.success());
+    assert_output_contains(&out, "unknown path requested: \"sub/out\"");
+
+    let run = |msg: &str| {
+        space.run_expect(
+            n2_command(vec!["--env-vars", "sub/out"])
+                .env("N2_TEST_DIR", "sub")
+                .env("N2_TEST_MSG", msg),
+        )
+    };
+    let out = run("hello")?;
+    assert_output_contains(&out, "ran 1 task");
+    assert_eq!(space.read("sub/out")?, b"hello\n");
+
+    let out = run("hello")?;
+    assert_output_contains(&out, "no work to do");
+
+    // A changed value changes the command, which reruns it.
+    let out = run("goodbye")?;
+    assert_output_contains(&out, "ran 1 task");
+    assert_eq!(space.read("sub/out")?, b"goodbye\n");
+    Ok(())
+}
@@ -30,3 +30,47 @@ build out: list_files .
 
     Ok(())
 }
+
+#[cfg(unix)]
+#[test]
+fn dir_outputs() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule unpack
+    command = mkdir -p $out/sub && cp $in $out/sub/file
+    dir_outputs = 1
+
+rule list
+    command = cat $in/sub/file > $out
+
+build tree: unpack src
+build out: list tree
+",
+    )?;
+    space.write("src", "1")?;
+
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 2 tasks");
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "no work to do");
+    // --prescan sees the tree as up to date too.
+    space.run_expect(&mut n2_command(vec!["--prescan", "out"]))?;
+    let summary = String::from_utf8(space.read(".n2_summary.json")?)?;
+    assert!(summary.contains("\"tasks_predicted\": 0,"), "{}", summary);
+
+    // Rewriting a file within the tree leaves the mtime of the tree itself
+    // alone, but still marks it changed.
+    space.write("tree/sub/file", "2")?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 2 tasks");
+    assert_eq!(space.read("out")?, b"1");
+
+    // An update that rewrites the same file rebuilds dependents too.
+    space.write("src", "3")?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 2 tasks");
+    assert_eq!(space.read("out")?, b"3");
+    Ok(())
+}
@@ -38,6 +38,32 @@ build out: gendep
     Ok(())
 }
 
+/// -w depfileparse=warn turns a bad depfile into a warning and a rebuild.
+#[test]
+fn bad_depfile_warn() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            GENDEP_RULE,
+            "
+build out: gendep
+  dep_content = garbage text
+",
+            "",
+        ]
+        .join("\n"),
+    )?;
+
+    let out = space.run_expect(&mut n2_command(vec!["-w", "depfileparse=warn", "out"]))?;
+    assert_output_contains(&out, "n2: warning: gendep out: parse error:");
+    assert_output_contains(&out, "ignoring depfile; will rebuild next time");
+    assert_output_contains(&out, "ran 1 task");
+    let out = space.run_expect(&mut n2_command(vec!["-w", "depfileparse=warn", "out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    Ok(())
+}
+
 /// depfile contains reference to existing order-only dep.
 #[test]
 fn discover_existing_dep() -> anyhow::Result<()> {
@@ -72,6 +98,41 @@ build out: gendep || in
     Ok(())
 }
 
+/// Discovered deps matching deps_ignore are neither recorded nor checked.
+#[test]
+fn deps_ignore() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            GENDEP_RULE,
+            "deps_ignore = sys/**",
+            "
+build out: gendep
+  dep_content = out: sys/a.h in.h
+",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.write("in.h", "")?;
+    std::fs::create_dir(space.path().join("sys"))?;
+    space.write("sys/a.h", "")?;
+
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+
+    space.write("sys/a.h", "x")?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "no work");
+
+    space.write("in.h", "x")?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+
+    Ok(())
+}
+
 #[cfg(unix)]
 #[test]
 fn multi_output_depfile() -> anyhow::Result<()> {
@@ -135,3 +196,105 @@ build out: myrule
     assert_output_contains(&out, "no work");
     Ok(())
 }
+
+#[cfg(unix)]
+#[test]
+fn source_dependencies_json() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule myrule
+    command = printf '{\"Version\": \"1.1\", \"Data\": {\"Includes\": [\"foo.h\"]}}' > out.json && touch out
+    depfile = out.json
+    deps = json
+
+build out: myrule
+",
+    )?;
+    space.write("foo.h", "")?;
+
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "no work");
+    space.write("foo.h", "x")?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    Ok(())
+}
+
+/// depfile lists targets other than the build's output.
+#[cfg(unix)]
+#[test]
+fn depfile_other_targets() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule myrule
+    command = echo \"./out: foo\" > out.d && echo \"other: foo2\" >> out.d && touch out
+    depfile = out.d
+
+build out: myrule
+",
+    )?;
+    space.write("foo", "")?;
+    space.write("foo2", "")?;
+
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    space.write("foo2", "x")?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "no work");
+    space.write("foo", "x")?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+
+    // A depfile naming none of the outputs warns, and keeps all its deps.
+    space.write(
+        "build.ninja",
+        "
+rule myrule
+    command = echo \"other: foo\" > out.d && touch out
+    depfile = out.d
+
+build out: myrule
+",
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "depfile out.d lists none of the build's outputs");
+    space.write("foo", "y")?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    Ok(())
+}
+
+/// A failed command's depfile is still recorded, without making it clean.
+#[cfg(unix)]
+#[test]
+fn failed_build_deps() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule cc
+    command = echo \"out: dep.h\" > out.d && test -f ok && touch out
+    depfile = out.d
+
+build out: cc
+",
+    )?;
+    space.write("dep.h", "")?;
+
+    space.run(&mut n2_command(vec!["out"]))?;
+    let out = space.run_expect(&mut n2_command(vec!["-t", "outputs", "dep.h"]))?;
+    assert_eq!(std::str::from_utf8(&out.stdout)?, "out\n");
+
+    space.write("ok", "")?;
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "no work");
+    Ok(())
+}
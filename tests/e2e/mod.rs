@@ -5,7 +5,9 @@ mod bindings;
 mod directories;
 mod discovered;
 mod missing;
+mod pools;
 mod regen;
+mod tools;
 mod validations;
 
 use anyhow::anyhow;
@@ -23,6 +25,9 @@ pub fn n2_binary() -> std::path::PathBuf {
 pub fn n2_command(args: Vec<&str>) -> std::process::Command {
     let mut cmd = std::process::Command::new(n2_binary());
     cmd.args(args);
+    // Keep the config file in the user's home directory out of the tests.
+    cmd.env_remove("HOME");
+    cmd.env_remove("USERPROFILE");
     cmd
 }
 
@@ -63,6 +68,10 @@ impl TestSpace {
         Ok(TestSpace { dir })
     }
 
+    pub fn path(&self) -> &std::path::Path {
+        self.dir.path()
+    }
+
     /// Write a file into the working space.
     pub fn write(&self, path: &str, content: &str) -> std::io::Result<()> {
         std::fs::write(self.dir.path().join(path), content)
@@ -91,6 +100,23 @@ impl TestSpace {
         cmd.current_dir(self.dir.path()).output()
     }
 
+    /// Run n2 within the test process, as a program embedding it would.  It
+    /// builds in the current directory, which is shared by all tests, so only
+    /// one such build runs at a time.
+    pub fn run_embedded(
+        &self,
+        args: &[&str],
+        cancel: n2::CancelToken,
+    ) -> anyhow::Result<anyhow::Result<i32>> {
+        static CWD: std::sync::Mutex<()> = std::sync::Mutex::new(());
+        let _guard = CWD.lock().unwrap_or_else(|err| err.into_inner());
+        let prev = std::env::current_dir()?;
+        std::env::set_current_dir(self.dir.path())?;
+        let res = n2::run::run_with(args, cancel);
+        std::env::set_current_dir(prev)?;
+        Ok(res)
+    }
+
     /// Like run, but also print output if the build failed.
     pub fn run_expect(
         &self,
@@ -0,0 +1,174 @@
+//! Tests for limiting concurrency via pools and command-line flags.
+
+use crate::e2e::*;
+
+/// A rule that fails if another instance of it is running at the same time.
+#[cfg(unix)]
+const EXCLUSIVE_RULE: &str = "
+rule exclusive
+  command = mkdir lock && sleep 0.2 && rmdir lock && touch $out
+";
+
+#[cfg(unix)]
+#[test]
+fn pool_depth_flag() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            EXCLUSIVE_RULE,
+            "pool p",
+            "  depth = 2",
+            "build a: exclusive",
+            "  pool = p",
+            "build b: exclusive",
+            "  pool = p",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.run_expect(&mut n2_command(vec![
+        "-j2",
+        "--pool-depth",
+        "p=1",
+        "a",
+        "b",
+    ]))?;
+    Ok(())
+}
+
+/// A rule that only succeeds if the build of $other runs at the same time.
+#[cfg(unix)]
+const MEET_RULE: &str = "
+rule meet
+  command = touch $out.started && for i in $$(seq 50); do test -f $other.started && break; sleep 0.1; done && test -f $other.started && touch $out
+";
+
+#[cfg(unix)]
+#[test]
+fn pool_depth_zero() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    let manifest = |depth: &str| {
+        [
+            MEET_RULE,
+            "pool p",
+            depth,
+            "build a: meet",
+            "  pool = p",
+            "  other = b",
+            "build b: meet",
+            "  pool = p",
+            "  other = a",
+            "",
+        ]
+        .join("\n")
+    };
+
+    // A depth of 0 leaves the pool unbounded.
+    space.write("build.ninja", &manifest("  depth = 0"))?;
+    space.run_expect(&mut n2_command(vec!["-j2", "a", "b"]))?;
+
+    // As does overriding the depth with 0.
+    for file in ["a", "a.started", "b", "b.started"] {
+        std::fs::remove_file(space.path().join(file))?;
+    }
+    space.write("build.ninja", &manifest("  depth = 1"))?;
+    space.run_expect(&mut n2_command(vec![
+        "-j2",
+        "--pool-depth",
+        "p=0",
+        "a",
+        "b",
+    ]))?;
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn pool_depth_flag_bounds_unbounded_pool() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            EXCLUSIVE_RULE,
+            "pool p",
+            "build a: exclusive",
+            "  pool = p",
+            "build b: exclusive",
+            "  pool = p",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.run_expect(&mut n2_command(vec![
+        "-j2",
+        "--pool-depth",
+        "p=1",
+        "a",
+        "b",
+    ]))?;
+    Ok(())
+}
+
+#[test]
+fn invalid_pool_depth() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write("build.ninja", "pool p\n  depth = -1\n")?;
+    let out = space.run(&mut n2_command(vec![]))?;
+    assert!(!out.status.success());
+    assert_output_contains(
+        &out,
+        "pool depth must be a non-negative integer, got \"-1\"",
+    );
+    assert_output_contains(&out, "build.ninja:2:");
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn rule_jobs_flag() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            EXCLUSIVE_RULE,
+            "build a: exclusive",
+            "build b: exclusive",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.run_expect(&mut n2_command(vec![
+        "-j2",
+        "--rule-jobs",
+        "exclusive=1",
+        "a",
+        "b",
+    ]))?;
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn memory_budget() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            EXCLUSIVE_RULE.trim_end(),
+            "  memory_estimate = 600",
+            "build a: exclusive",
+            "build b: exclusive",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.run_expect(&mut n2_command(vec![
+        "-j2",
+        "--memory-budget",
+        "1000",
+        "a",
+        "b",
+    ]))?;
+    Ok(())
+}
@@ -39,6 +39,56 @@ EOT
     Ok(())
 }
 
+#[cfg(unix)]
+#[test]
+fn regenerate_unchanged_build_file() -> anyhow::Result<()> {
+    // A generator that rewrites build.ninja with the same content shouldn't
+    // cause a reload.
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule regen
+  command = cp build.in build.ninja
+  generator = 1
+build build.ninja: regen build.in
+rule touch
+  command = touch $out
+build out: touch
+",
+    )?;
+    space.write("build.in", &String::from_utf8(space.read("build.ninja")?)?)?;
+
+    let out = space.run_expect(&mut n2_command(vec!["-d", "explain", "out"]))?;
+    assert_output_contains(&out, "explain: build files regenerated without changes");
+    assert_output_contains(&out, "ran 2 tasks");
+
+    // The db has build.ninja's new mtime, so it's not seen as modified.
+    let out = space.run_expect(&mut n2_command(vec!["-d", "explain", "out"]))?;
+    assert_output_not_contains(&out, "explain: build file");
+    assert_output_contains(&out, "no work to do");
+
+    // A change does reload.
+    space.write(
+        "build.in",
+        "
+rule regen
+  command = cp build.in build.ninja
+  generator = 1
+build build.ninja: regen build.in
+rule touch
+  command = touch $out
+build out: touch
+build out2: touch
+",
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["-d", "explain", "out2"]))?;
+    assert_output_not_contains(&out, "without changes");
+    assert_output_contains(&out, "ran 2 tasks");
+
+    Ok(())
+}
+
 #[cfg(unix)]
 #[test]
 fn shared_regen_input() -> anyhow::Result<()> {
@@ -174,3 +224,87 @@ fn restat() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn explain_manifest_change() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "include rules.ninja", ""].join("\n"),
+    )?;
+    space.write("rules.ninja", "build out: touch\n")?;
+    space.run_expect(&mut n2_command(vec!["out"]))?;
+
+    let out = space.run_expect(&mut n2_command(vec!["-d", "explain", "out"]))?;
+    assert_output_not_contains(&out, "explain: build file");
+
+    space.sub_mtime("rules.ninja", std::time::Duration::from_secs(10))?;
+    // Tools don't record the build files, so the next build still sees the
+    // change.
+    space.run_expect(&mut n2_command(vec!["-t", "inputs", "out"]))?;
+    let out = space.run_expect(&mut n2_command(vec!["-d", "explain", "out"]))?;
+    assert_output_contains(&out, "explain: build file rules.ninja modified");
+    assert_output_not_contains(&out, "build.ninja modified");
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn generated_subninja() -> anyhow::Result<()> {
+    // A subninja that another build generates is built before loading it,
+    // even when it subninjas another generated file in turn.
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule gen
+  command = cp $in $out
+subninja sub.ninja
+build sub.ninja: gen sub.ninja.in
+",
+    )?;
+    space.write(
+        "sub.ninja.in",
+        "
+subninja subsub.ninja
+build subsub.ninja: gen subsub.ninja.in
+",
+    )?;
+    space.write(
+        "subsub.ninja.in",
+        "
+rule touch
+  command = touch $out
+build out: touch
+",
+    )?;
+
+    // Only on request, as ninja fails instead.
+    let out = space.run(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(
+        &out,
+        "subninja sub.ninja doesn't exist yet; pass --build-subninjas",
+    );
+
+    let out = space.run_expect(&mut n2_command(vec!["--build-subninjas", "out"]))?;
+    assert_output_contains(&out, "ran 3 tasks");
+    assert!(space.read("out").is_ok());
+
+    let out = space.run_expect(&mut n2_command(vec!["--build-subninjas", "out"]))?;
+    assert_output_contains(&out, "no work");
+
+    // Until it's generated again, tools would miss the builds it declares.
+    std::fs::remove_file(space.path().join("subsub.ninja"))?;
+    for tool in [&["-t", "recompact"][..], &["--verify"], &["-t", "outputs"]] {
+        let mut args = vec!["--build-subninjas"];
+        args.extend(tool);
+        let out = space.run(&mut n2_command(args))?;
+        assert_output_contains(&out, "subninja subsub.ninja hasn't been generated yet");
+    }
+
+    // A missing subninja that nothing generates is still an error.
+    space.write("build.ninja", "subninja nope.ninja\n")?;
+    let out = space.run(&mut n2_command(vec![]))?;
+    assert_output_contains(&out, "read nope.ninja");
+    Ok(())
+}
@@ -0,0 +1,469 @@
+//! Tests for -t subtools that inspect the build graph.
+
+use crate::e2e::*;
+
+const INPUTS_MANIFEST: &str = "
+build mid: touch b a | c || d
+build out: touch mid e
+build other: touch f
+default out
+";
+
+#[test]
+fn inputs() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write("build.ninja", &[TOUCH_RULE, INPUTS_MANIFEST].join("\n"))?;
+
+    let out = space.run_expect(&mut n2_command(vec!["-t", "inputs", "out"]))?;
+    assert_eq!(std::str::from_utf8(&out.stdout)?, "a\nb\nc\ne\n");
+
+    let out = space.run_expect(&mut n2_command(vec!["-t", "inputs", "--order-only"]))?;
+    assert_eq!(std::str::from_utf8(&out.stdout)?, "a\nb\nc\nd\ne\n");
+
+    let out = space.run_expect(&mut n2_command(vec!["-t", "inputs", "out", "other"]))?;
+    assert_eq!(std::str::from_utf8(&out.stdout)?, "a\nb\nc\ne\nf\n");
+
+    // Nothing was built.
+    assert!(space.metadata("mid").is_err());
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn outputs() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "
+rule gendep
+  command = echo $out: hdr > $out.d && touch $out
+  depfile = $out.d
+build obj: gendep src
+build bin: touch obj || stamp
+build stamp: touch src2
+build all: phony bin
+",
+        ]
+        .join("\n"),
+    )?;
+    space.write("src", "")?;
+    space.write("src2", "")?;
+    space.write("hdr", "")?;
+
+    let out = space.run_expect(&mut n2_command(vec!["-t", "outputs", "src"]))?;
+    assert_eq!(std::str::from_utf8(&out.stdout)?, "all\nbin\nobj\n");
+
+    // Order-only dependents aren't rebuilt.
+    let out = space.run_expect(&mut n2_command(vec!["-t", "outputs", "src2"]))?;
+    assert_eq!(std::str::from_utf8(&out.stdout)?, "stamp\n");
+
+    // hdr is only known to be an input once the depfile has been read.
+    space.run_expect(&mut n2_command(vec!["obj"]))?;
+    let out = space.run_expect(&mut n2_command(vec!["-t", "outputs", "hdr"]))?;
+    assert_eq!(std::str::from_utf8(&out.stdout)?, "all\nbin\nobj\n");
+    Ok(())
+}
+
+#[test]
+fn why() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build out: touch in",
+            "build all: phony out",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.write("in", "")?;
+
+    let out = space.run_expect(&mut n2_command(vec!["-t", "why", "out", "in", "all"]))?;
+    assert_eq!(
+        std::str::from_utf8(&out.stdout)?,
+        "out: dirty\n  output out missing\nin: source file, not built\nall: phony, so never runs a command\n"
+    );
+    assert!(space.metadata("out").is_err());
+
+    space.run_expect(&mut n2_command(vec!["out"]))?;
+    let out = space.run_expect(&mut n2_command(vec!["-t", "why", "out"]))?;
+    assert_eq!(std::str::from_utf8(&out.stdout)?, "out: up to date\n");
+
+    space.sub_mtime("out", std::time::Duration::from_secs(10))?;
+    let out = space.run_expect(&mut n2_command(vec!["-t", "why", "out"]))?;
+    assert_eq!(
+        std::str::from_utf8(&out.stdout)?,
+        "out: dirty\n  input in is newer than output out\n"
+    );
+    Ok(())
+}
+
+#[test]
+fn changes() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build mid: touch in",
+            "build out: touch mid in2",
+            "build other: touch in3",
+            "build all: phony out other",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.write("in", "")?;
+    space.write("in2", "")?;
+    space.write("in3", "")?;
+
+    let out = space.run_expect(&mut n2_command(vec!["-t", "changes", "out"]))?;
+    assert_eq!(
+        std::str::from_utf8(&out.stdout)?,
+        "mid: output missing\nout: output missing\n"
+    );
+
+    space.run_expect(&mut n2_command(vec!["all"]))?;
+    let out = space.run_expect(&mut n2_command(vec!["-t", "changes", "all"]))?;
+    assert_eq!(
+        std::str::from_utf8(&out.stdout)?,
+        "no changes since the last build\n"
+    );
+
+    // Only the root causes are listed, not out, which mid would rebuild.
+    space.sub_mtime("mid", std::time::Duration::from_secs(10))?;
+    space.sub_mtime("other", std::time::Duration::from_secs(10))?;
+    std::fs::remove_file(space.path().join("in2"))?;
+    let out = space.run_expect(&mut n2_command(vec!["-t", "changes", "out"]))?;
+    assert_eq!(
+        std::str::from_utf8(&out.stdout)?,
+        "in: modified\nin2: missing\n"
+    );
+    Ok(())
+}
+
+#[test]
+fn dump_json() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule cc
+  command = cc $flags -c $in -o $out
+  description = CC \"$out\"
+build a.o: cc a.c
+  flags = -O2
+default a.o
+",
+    )?;
+    let out = space.run_expect(&mut n2_command(vec!["-t", "dump", "--format=json"]))?;
+    assert_output_contains(&out, r#""command": "cc -O2 -c a.c -o a.o","#);
+    assert_output_contains(&out, r#""description": "CC \"a.o\"","#);
+    assert_output_contains(&out, r#""vars": {"flags":"-O2"}"#);
+    assert_output_contains(&out, r#""cc": {"command":"cc ${flags} -c ${in} -o ${out}""#);
+    assert_output_contains(&out, r#""defaults": [2]"#);
+    Ok(())
+}
+
+#[test]
+fn rules() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule cc
+  command = gcc -c $in -o $out
+  description = CC $out
+  deps = gcc
+rule unused
+  command = false
+build a.o: cc a.c
+",
+    )?;
+
+    let out = space.run_expect(&mut n2_command(vec!["-t", "rules"]))?;
+    assert_eq!(
+        std::str::from_utf8(&out.stdout)?,
+        "cc
+  command = gcc -c ${in} -o ${out}
+  description = CC ${out}
+  deps = gcc
+unused
+  command = false
+"
+    );
+
+    let out = space.run_expect(&mut n2_command(vec!["-t", "rules", "--unused"]))?;
+    assert_eq!(
+        std::str::from_utf8(&out.stdout)?,
+        "unused\n  command = false\n"
+    );
+    Ok(())
+}
+
+#[test]
+fn verify() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build out: touch in",
+            "build unbuilt: touch in",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.write("in", "")?;
+    space.run_expect(&mut n2_command(vec!["out"]))?;
+
+    // Builds that haven't run aren't checked.
+    let out = space.run_expect(&mut n2_command(vec!["--verify"]))?;
+    assert_eq!(std::str::from_utf8(&out.stdout)?, "");
+
+    space.sub_mtime("out", std::time::Duration::from_secs(10))?;
+    let out = space.run(&mut n2_command(vec!["--verify"]))?;
+    assert!(!out.status.success());
+    assert_eq!(
+        std::str::from_utf8(&out.stdout)?,
+        "{\"output\":\"out\",\"reasons\":[\"input in is newer than output out\"]}\n"
+    );
+    Ok(())
+}
+
+#[test]
+fn case_conflicts() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build out: touch Foo.h foo.h", ""].join("\n"),
+    )?;
+    let out = space.run(&mut n2_command(vec!["-t", "caseconflicts"]))?;
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(std::str::from_utf8(&out.stdout)?, "Foo.h foo.h\n");
+
+    space.write(
+        "build.ninja",
+        &[TOUCH_RULE, "build out: touch foo.h", ""].join("\n"),
+    )?;
+    space.run_expect(&mut n2_command(vec!["-t", "caseconflicts"]))?;
+    Ok(())
+}
+
+#[test]
+fn aliases() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build out/app: touch in",
+            "build out/tests: touch in",
+            "build app: phony out/app",
+            "build check: phony out/tests || app",
+            "build header.h: phony",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.write("in", "")?;
+
+    let out = space.run_expect(&mut n2_command(vec!["-t", "aliases"]))?;
+    assert_eq!(
+        std::str::from_utf8(&out.stdout)?,
+        "app: out/app\ncheck: out/tests app\n"
+    );
+
+    let out = space.run(&mut n2_command(vec!["chek"]))?;
+    assert_output_contains(
+        &out,
+        "unknown path requested: \"chek\", did you mean \"check\"?",
+    );
+    let out = space.run(&mut n2_command(vec!["unrelated"]))?;
+    assert_output_contains(&out, "unknown path requested: \"unrelated\"\n");
+    Ok(())
+}
+
+#[test]
+fn touch() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "build mid: touch in",
+            "build out: touch mid",
+            "",
+        ]
+        .join("\n"),
+    )?;
+    space.write("in", "")?;
+    space.run_expect(&mut n2_command(vec!["out"]))?;
+
+    // Edited by hand, mid would be rebuilt, losing the edit.
+    space.write("mid", "edited")?;
+    let out = space.run_expect(&mut n2_command(vec!["-t", "touch", "mid"]))?;
+    assert_output_contains(&out, "n2: adopted 1 build as up to date");
+    assert_output_not_contains(&out, "touch mid");
+    let out = space.run_expect(&mut n2_command(vec!["mid"]))?;
+    assert_output_contains(&out, "no work to do");
+    assert_eq!(space.read("mid")?, b"edited");
+
+    // An up to date target is touched too, so what uses it is rebuilt.
+    space.run_expect(&mut n2_command(vec!["out"]))?;
+    let out = space.run_expect(&mut n2_command(vec!["-t", "touch", "mid"]))?;
+    assert_output_contains(&out, "n2: adopted 1 build as up to date");
+    let out = space.run_expect(&mut n2_command(vec!["out"]))?;
+    assert_output_contains(&out, "ran 1 task");
+
+    // Only the named targets are adopted; a dirty build they depend on
+    // stops the touch rather than be adopted or run.
+    space.write("in", "changed")?;
+    space.sub_mtime("mid", std::time::Duration::from_secs(10))?;
+    let out = space.run(&mut n2_command(vec!["-t", "touch", "out"]))?;
+    assert_output_contains(&out, "-t touch: mid is out of date; build it first");
+    assert_output_not_contains(&out, "touch mid");
+
+    let out = space.run(&mut n2_command(vec!["-t", "touch"]))?;
+    assert_output_contains(&out, "-t touch: expected at least one target");
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn touch_dir_outputs() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule unpack
+    command = mkdir -p $out && cp $in $out/file
+    dir_outputs = 1
+build tree: unpack src
+",
+    )?;
+    space.write("src", "")?;
+    space.run_expect(&mut n2_command(vec!["tree"]))?;
+    let past = std::time::SystemTime::now() - std::time::Duration::from_secs(10);
+    std::fs::File::open(space.path().join("tree"))?.set_modified(past)?;
+    let before = space.metadata("tree")?.modified()?;
+    space.run_expect(&mut n2_command(vec!["-t", "touch", "tree"]))?;
+    assert!(space.metadata("tree")?.modified()? > before);
+    Ok(())
+}
+
+#[test]
+fn recompact() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    let build_ninja = |builds: &[&str]| {
+        let mut lines = vec![TOUCH_RULE];
+        lines.extend(builds);
+        lines.push("");
+        lines.join("\n")
+    };
+    space.write(
+        "build.ninja",
+        &build_ninja(&["build a: touch in", "build b: touch in"]),
+    )?;
+    for content in ["1", "2", "3"] {
+        space.write("in", content)?;
+        let out = space.run_expect(&mut n2_command(vec!["a", "b"]))?;
+        assert_output_contains(&out, "ran 2 tasks");
+    }
+    space.write("build.ninja", &build_ninja(&["build a: touch in"]))?;
+
+    let before = space.metadata(".n2_db")?.len();
+    let out = space.run_expect(&mut n2_command(vec!["-t", "recompact"]))?;
+    assert_output_contains(&out, "n2: recompacted .n2_db");
+    assert!(space.metadata(".n2_db")?.len() < before);
+
+    let out = space.run_expect(&mut n2_command(vec!["a"]))?;
+    assert_output_contains(&out, "no work to do");
+    Ok(())
+}
+
+#[test]
+fn clean() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        &[
+            TOUCH_RULE,
+            "
+rule gen
+  command = touch $out
+  generator = 1
+rule stamp
+  command = touch $out
+build gen.out: gen
+build mid: touch in
+build out: touch mid
+build other: stamp
+",
+        ]
+        .join("\n"),
+    )?;
+    space.write("in", "")?;
+    let out = space.run_expect(&mut n2_command(vec!["gen.out", "out", "other"]))?;
+    assert_output_contains(&out, "ran 4 tasks");
+
+    let out = space.run_expect(&mut n2_command(vec!["-t", "clean", "-n"]))?;
+    assert_eq!(std::str::from_utf8(&out.stdout)?, "mid\nother\nout\n");
+    let out = space.run_expect(&mut n2_command(vec!["-t", "clean", "-n", "mid"]))?;
+    assert_eq!(std::str::from_utf8(&out.stdout)?, "mid\n");
+    assert!(space.metadata("mid").is_ok());
+
+    let out = space.run_expect(&mut n2_command(vec!["-t", "clean", "-r", "stamp"]))?;
+    assert_output_contains(&out, "removed 1 file\n");
+    assert!(space.metadata("other").is_err());
+    assert!(space.metadata("out").is_ok());
+
+    let out = space.run_expect(&mut n2_command(vec!["-t", "clean"]))?;
+    assert_output_contains(&out, "removed 2 files\n");
+    assert!(space.metadata("gen.out").is_ok());
+    assert!(space.metadata("in").is_ok());
+
+    let out = space.run_expect(&mut n2_command(vec!["-t", "clean", "-g"]))?;
+    assert_output_contains(&out, "removed 1 file\n");
+    assert!(space.metadata("gen.out").is_err());
+
+    let out = space.run(&mut n2_command(vec!["-t", "clean", "-r", "nope"]))?;
+    assert_output_contains(&out, "unknown rule \"nope\"");
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn clean_directories() -> anyhow::Result<()> {
+    let space = TestSpace::new()?;
+    space.write(
+        "build.ninja",
+        "
+rule mkdir
+  command = mkdir -p $out
+rule unpack
+  command = mkdir -p $out && touch $out/file
+  dir_outputs = 1
+build dir: mkdir
+build tree: unpack
+",
+    )?;
+    space.run_expect(&mut n2_command(vec!["dir", "tree"]))?;
+    space.write("dir/mine", "")?;
+
+    // Only a build with dir_outputs owns the contents of its directory.
+    let out = space.run_expect(&mut n2_command(vec!["-t", "clean"]))?;
+    assert_output_contains(&out, "n2: not removing dir: directory isn't empty");
+    assert_output_contains(&out, "removed 1 file\n");
+    assert!(space.metadata("dir/mine").is_ok());
+    assert!(space.metadata("tree").is_err());
+
+    std::fs::remove_file(space.path().join("dir/mine"))?;
+    let out = space.run_expect(&mut n2_command(vec!["-t", "clean"]))?;
+    assert_output_contains(&out, "removed 1 file\n");
+    assert!(space.metadata("dir").is_err());
+    Ok(())
+}